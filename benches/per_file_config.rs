@@ -0,0 +1,65 @@
+//! Measures how long creating the configs of the tests takes in a suite of 5000 tests, which
+//! [`ui_test::default_per_file_config`] does by cloning the config of the suite for every test.
+//!
+//! Run with `cargo bench --bench per_file_config`.
+
+use std::time::{Duration, Instant};
+
+use ui_test::{default_per_file_config, Config, CustomComment};
+
+const TESTS: usize = 5000;
+
+/// The time `f` took per test, keeping its results alive until then so that their drop
+/// doesn't count.
+fn per_test<T>(mut f: impl FnMut(usize) -> T) -> Duration {
+    let mut results = Vec::with_capacity(TESTS);
+    let start = Instant::now();
+    for i in 0..TESTS {
+        results.push(f(i));
+    }
+    start.elapsed() / TESTS as u32
+}
+
+fn main() {
+    let dir = tempfile::tempdir().unwrap();
+    let paths: Vec<_> = (0..TESTS)
+        .map(|i| {
+            let path = dir.path().join(format!("test_{i}.rs"));
+            std::fs::write(&path, "fn main() {}\n").unwrap();
+            path
+        })
+        .collect();
+    let mut config = Config::rustc(dir.path());
+    // Filters like the ones of a typical test suite.
+    config.path_stderr_filter(dir.path(), "$DIR");
+    config.stderr_filter("[0-9]+:[0-9]+", "LL:CC");
+    config.stderr_filter(r"\.exe", b"");
+    config.stderr_filter("/target/[^/]+/debug", "/target/$$TRIPLE/debug");
+    config.stdout_filter("[0-9]+ms", "$$TIME");
+    // Custom comments, conditions and program arguments, like the ones of Miri or Clippy.
+    for i in 0..20 {
+        config.custom_comments.insert(
+            format!("custom-{i}"),
+            CustomComment::new(|_| Err("unused".into())),
+        );
+    }
+    for i in 0..10 {
+        config
+            .custom_conditions
+            .insert(format!("feature-{i}"), i % 2 == 0);
+    }
+    for i in 0..10 {
+        config.program.args.push(format!("-Zflag-{i}").into());
+        config
+            .program
+            .envs
+            .push((format!("VAR_{i}").into(), Some("value".into())));
+    }
+
+    // Warm up the allocator and the file system cache.
+    per_test(|i| default_per_file_config(&config, &paths[i]));
+
+    let clone = per_test(|_| config.clone());
+    let per_file_config = per_test(|i| default_per_file_config(&config, &paths[i]));
+    println!("{TESTS} tests, per test: cloning the config {clone:?}, per-file config {per_file_config:?}");
+}
@@ -0,0 +1,74 @@
+//! Finding the ANSI escape sequences in the output of tests, see [`Config::ansi`].
+//!
+//! [`Config::ansi`]: crate::Config::ansi
+
+use crate::AnsiEscapes;
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+/// Strip, keep or render the escape sequences in `text`. Escape characters that don't start a
+/// complete sequence are always kept, so they show up in diffs.
+pub(crate) fn apply(text: Vec<u8>, ansi: AnsiEscapes) -> Vec<u8> {
+    if ansi == AnsiEscapes::Preserve || !text.contains(&ESC) {
+        return text;
+    }
+    let mut out = Vec::with_capacity(text.len());
+    let mut rest = &text[..];
+    while let Some(start) = rest.iter().position(|&b| b == ESC) {
+        out.extend_from_slice(&rest[..start]);
+        rest = &rest[start..];
+        let Some(len) = sequence_len(rest) else {
+            out.push(ESC);
+            rest = &rest[1..];
+            continue;
+        };
+        if ansi == AnsiEscapes::Render {
+            for &byte in &rest[..len] {
+                match byte {
+                    ESC => out.extend_from_slice("␛".as_bytes()),
+                    BEL => out.extend_from_slice("␇".as_bytes()),
+                    _ => out.push(byte),
+                }
+            }
+        }
+        rest = &rest[len..];
+    }
+    out.extend_from_slice(rest);
+    out
+}
+
+/// The length of the escape sequence at the start of `text`, which starts with an escape
+/// character, or `None` if it is incomplete.
+fn sequence_len(text: &[u8]) -> Option<usize> {
+    match *text.get(1)? {
+        // CSI, like `ESC [ 1 ; 31 m` or `ESC [ 2 K`: parameter and intermediate bytes, then a
+        // final byte.
+        b'[' => {
+            let end = text[2..].iter().position(|b| !(0x20..=0x3f).contains(b))?;
+            (0x40..=0x7e)
+                .contains(&text[2 + end])
+                .then_some(2 + end + 1)
+        }
+        // OSC, like hyperlinks or window titles, and the other control strings, terminated by
+        // BEL or `ESC \`.
+        b']' | b'P' | b'X' | b'^' | b'_' => {
+            let mut i = 2;
+            loop {
+                match *text.get(i)? {
+                    BEL => return Some(i + 1),
+                    ESC if text.get(i + 1) == Some(&b'\\') => return Some(i + 2),
+                    _ => i += 1,
+                }
+            }
+        }
+        // Other escape sequences, like `ESC ( B` or `ESC 7`: intermediate bytes, then a final
+        // byte.
+        _ => {
+            let end = text[1..].iter().position(|b| !(0x20..=0x2f).contains(b))?;
+            (0x30..=0x7e)
+                .contains(&text[1 + end])
+                .then_some(1 + end + 1)
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! Turning the bless command of [`OutputConflictHandling::Error`] into a command that only
+//! blesses specific tests.
+//!
+//! [`OutputConflictHandling::Error`]: crate::OutputConflictHandling::Error
+
+use std::path::Path;
+
+use crate::{relative_to_root, Config};
+
+/// Replace `{files}` in `template` with the paths of the `tests`, and `{filters}` with their
+/// paths relative to [`Config::root_dir`], each quoted for the shell.
+pub(crate) fn render<'a>(
+    template: &str,
+    config: &Config,
+    tests: impl Iterator<Item = &'a Path>,
+) -> String {
+    let mut tests: Vec<_> = tests.collect();
+    tests.sort();
+    tests.dedup();
+    let join = |quoted: &mut dyn Iterator<Item = String>| quoted.collect::<Vec<_>>().join(" ");
+    template
+        .replace(
+            "{files}",
+            &join(&mut tests.iter().map(|path| quote(&path.display().to_string()))),
+        )
+        .replace(
+            "{filters}",
+            &join(
+                &mut tests
+                    .iter()
+                    .map(|path| quote(&relative_to_root(config, path).display().to_string())),
+            ),
+        )
+}
+
+/// Whether the bless command depends on the tests it is rendered for.
+pub(crate) fn has_placeholders(template: &str) -> bool {
+    template.contains("{files}") || template.contains("{filters}")
+}
+
+/// Quote `arg` for the shell of the current platform, if it needs quoting.
+pub(crate) fn quote(arg: &str) -> String {
+    if cfg!(windows) {
+        quote_windows(arg)
+    } else {
+        quote_unix(arg)
+    }
+}
+
+/// Quote `arg` for POSIX shells, which take everything between single quotes literally.
+pub(crate) fn quote_unix(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_-.,/:=@%+".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.to_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// Quote `arg` the way programs on Windows split their command line, where backslashes only
+/// escape quotes and other backslashes in front of quotes.
+pub(crate) fn quote_windows(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains([' ', '\t', '\n', '"']) {
+        return arg.to_owned();
+    }
+    let mut quoted = String::from('"');
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                quoted.extend(std::iter::repeat('\\').take(backslashes * 2 + 1));
+                backslashes = 0;
+            }
+            _ => {
+                quoted.extend(std::iter::repeat('\\').take(backslashes));
+                backslashes = 0;
+            }
+        }
+        if c != '\\' {
+            quoted.push(c);
+        }
+    }
+    // The closing quote must not be escaped by trailing backslashes.
+    quoted.extend(std::iter::repeat('\\').take(backslashes * 2));
+    quoted.push('"');
+    quoted
+}
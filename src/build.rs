@@ -0,0 +1,84 @@
+//! Custom build steps that tests can request via `//@custom-build: NAME: ARGS`.
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    sync::{Arc, Mutex},
+};
+
+use crate::Config;
+
+/// Produces an artifact that tests need, e.g. by running a tool other than rustc.
+pub trait Build: Send + Sync {
+    /// Identifies the artifact built for `args`. Each key is only built once per test run,
+    /// no matter how many tests request it.
+    fn cache_key(&self, args: &str) -> String {
+        args.to_owned()
+    }
+
+    /// Build the artifact for `args`, and return the flags that tests requesting it need to be
+    /// compiled with. The error message is reported for every test that requested the build.
+    fn build(&self, args: &str, config: &Config) -> Result<Vec<OsString>, String>;
+}
+
+/// The result of a custom build, once it is done.
+type Built = Mutex<Option<Result<Vec<OsString>, String>>>;
+
+/// The results of custom builds, by the name of the build and the cache key.
+type BuiltByKey = HashMap<(String, String), Arc<Built>>;
+
+/// The custom builds that tests can request, by name. Clones share the results of builds.
+#[derive(Clone, Default)]
+pub struct CustomBuilds {
+    builds: HashMap<String, Arc<dyn Build>>,
+    built: Arc<Mutex<BuiltByKey>>,
+}
+
+impl CustomBuilds {
+    /// Make `build` available to tests via `//@custom-build: NAME: ARGS`.
+    pub fn add(&mut self, name: impl Into<String>, build: impl Build + 'static) {
+        self.builds.insert(name.into(), Arc::new(build));
+    }
+
+    /// Run the custom build called `name` with `args`, unless it already ran.
+    pub(crate) fn build(
+        &self,
+        name: &str,
+        args: &str,
+        config: &Config,
+    ) -> Result<Vec<OsString>, String> {
+        let build = match self.builds.get(name) {
+            Some(build) => build,
+            None => {
+                let mut names: Vec<_> = self.builds.keys().map(|n| format!("`{n}`")).collect();
+                names.sort();
+                return Err(format!(
+                    "no custom build called `{name}`, the registered ones are: {}",
+                    names.join(", ")
+                ));
+            }
+        };
+        let built = self
+            .built
+            .lock()
+            .unwrap()
+            .entry((name.to_owned(), build.cache_key(args)))
+            .or_default()
+            .clone();
+        // Other tests requesting the same build wait for it to finish.
+        let mut built = built.lock().unwrap();
+        built
+            .get_or_insert_with(|| build.build(args, config))
+            .clone()
+    }
+}
+
+impl std::fmt::Debug for CustomBuilds {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut names: Vec<_> = self.builds.keys().collect();
+        names.sort();
+        f.debug_struct("CustomBuilds")
+            .field("builds", &names)
+            .finish()
+    }
+}
@@ -1,9 +1,13 @@
 use std::{
-    ffi::OsString,
+    ffi::{OsStr, OsString},
     path::{Path, PathBuf},
     process::Command,
 };
 
+use color_eyre::eyre::{eyre, Result};
+
+use crate::bless_command::quote;
+
 #[derive(Debug, Clone)]
 /// A command, its args and its environment. Used for
 /// the main command, the dependency builder and the cfg-reader.
@@ -108,6 +112,48 @@ impl CommandBuilder {
         cmd
     }
 
+    /// Make sure that the program exists before running any tests with it, so that a missing
+    /// program is reported once, instead of by every single test. A program without a directory
+    /// is looked up in the `PATH` of [`CommandBuilder::envs`], or of the current process if they
+    /// don't set one, just like [`Command`] does.
+    pub(crate) fn check_program(&self) -> Result<()> {
+        let program = &self.program;
+        if program
+            .parent()
+            .map_or(true, |dir| dir.as_os_str().is_empty())
+        {
+            let path = match self.envs.iter().rev().find(|(var, _)| var == "PATH") {
+                Some((_, path)) => path.clone(),
+                None => std::env::var_os("PATH"),
+            }
+            .unwrap_or_default();
+            let found = std::env::split_paths(&path).any(|dir| {
+                let candidate = dir.join(program);
+                candidate.is_file()
+                    || !std::env::consts::EXE_SUFFIX.is_empty()
+                        && candidate.extension().is_none()
+                        && candidate
+                            .with_extension(&std::env::consts::EXE_SUFFIX[1..])
+                            .is_file()
+            });
+            if !found {
+                return Err(eyre!(
+                    "the program `{}` could not be found in any directory of the `PATH`: {}{}",
+                    program.display(),
+                    path.to_string_lossy(),
+                    missing_component_hint(program),
+                ));
+            }
+        } else if !program.is_file() {
+            return Err(eyre!(
+                "the program `{}` does not exist{}",
+                program.display(),
+                missing_component_hint(program),
+            ));
+        }
+        Ok(())
+    }
+
     pub(crate) fn apply_env(&self, cmd: &mut Command) {
         for (var, val) in self.envs.iter() {
             if let Some(val) = val {
@@ -118,3 +164,36 @@ impl CommandBuilder {
         }
     }
 }
+
+/// A hint about how to install well-known programs that were not found.
+fn missing_component_hint(program: &Path) -> String {
+    let component = match program.file_stem().and_then(OsStr::to_str) {
+        Some("miri" | "cargo-miri") => "miri",
+        Some("clippy-driver" | "cargo-clippy") => "clippy",
+        Some("rustc" | "cargo" | "rustdoc") => {
+            return "\nIs a Rust toolchain installed?".into();
+        }
+        _ => return String::new(),
+    };
+    format!("\nIs the `{component}` component installed? Try `rustup component add {component}`.")
+}
+
+/// Render `cmd` for the shell of the current platform, so that it can be copied into a terminal
+/// to reproduce a test failure. Like the [`Debug`](std::fmt::Debug) output of [`Command`], it
+/// starts with the working directory and the changes to the environment, if there are any.
+pub(crate) fn display_command(cmd: &Command) -> String {
+    let quote = |arg: &OsStr| quote(&arg.to_string_lossy());
+    let mut parts = vec![];
+    if let Some(dir) = cmd.get_current_dir() {
+        parts.push(format!("cd {} &&", quote(dir.as_os_str())));
+    }
+    for (key, value) in cmd.get_envs() {
+        match value {
+            Some(value) => parts.push(format!("{}={}", key.to_string_lossy(), quote(value))),
+            None => parts.push(format!("env -u {}", quote(key))),
+        }
+    }
+    parts.push(quote(cmd.get_program()));
+    parts.extend(cmd.get_args().map(quote));
+    parts.join(" ")
+}
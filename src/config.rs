@@ -1,18 +1,27 @@
 use regex::bytes::Regex;
 
-use crate::{dependencies::build_dependencies, CommandBuilder, Filter, Match, Mode};
+use crate::{
+    dependencies::build_dependencies, display_command, Build, CommandBuilder, CustomBuilds,
+    CustomComment, DiagnosticsParser, Error, Filter, Match, Mode, RustcDiagnostics,
+};
 pub use color_eyre;
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{eyre, Result};
+use rustfix::diagnostics::Applicability;
 use std::{
+    collections::{BTreeMap, HashMap},
     ffi::OsString,
+    fmt::Display,
     num::NonZeroUsize,
     path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
 #[derive(Debug, Clone)]
 /// Central datastructure containing all information to run the tests.
 pub struct Config {
-    /// Host triple; usually will be auto-detected.
+    /// Host triple; usually will be auto-detected, see [`Config::fill_host_and_target`].
     pub host: Option<String>,
     /// `None` to run on the host, otherwise a target triple
     pub target: Option<String>,
@@ -23,8 +32,52 @@ pub struct Config {
     /// Filters applied to stdout output before processing it.
     /// On windows, contains a filter to replace `\n` with `\r\n`.
     pub stdout_filters: Filter,
+    /// Normalizations of paths in stdout and stderr that are applied before all other filters.
+    pub builtin_normalizations: BuiltinNormalizations,
+    /// What happens to ANSI escape sequences, like colors, in stdout and stderr before all
+    /// other normalizations, unless a test specifies `//@ansi`. Defaults to
+    /// [`AnsiEscapes::Strip`].
+    pub ansi: AnsiEscapes,
+    /// How the output is compared to the `.stderr`, `.stdout` and `.fixed` files, unless a test
+    /// specifies `//@compare`.
+    pub compare: Compare,
+    /// What comments start with in test files, like `//` in `//@check-pass` and `//~ ERROR`.
+    /// Use `#` to test a language with `#` comments, whose tests then contain `#@check-pass`
+    /// and `#~ ERROR`.
+    pub comment_start: String,
+    /// Overrides [`Config::comment_start`] for files with an extension, like `("rs", "//")` so
+    /// `.rs` aux files keep their `//` comments in a suite of `#` commented tests.
+    pub comment_start_by_extension: Vec<(String, String)>,
+    /// The name of the files whose comments apply to all tests in their directory and its
+    /// subdirectories, like `ui_test.defaults` containing `//@compile-flags: --foo`. The
+    /// defaults of nearer directories take precedence over the ones further up, and the
+    /// comments of the test over all of them. `None` disables defaults files.
+    pub defaults_file_name: Option<String>,
+    /// The extensions of the files that [`run_tests`](crate::run_tests) and
+    /// [`run_test_roots`](crate::run_test_roots) run as tests, like `rs`. Other files, like
+    /// `.stderr` files, are never tests. The `file_filter` of
+    /// [`run_tests_generic`](crate::run_tests_generic) decides this itself.
+    pub test_extensions: Vec<String>,
+    /// The names of directories that are not searched for tests, like `auxiliary`, which
+    /// contains the files of `//@aux-build`s.
+    pub excluded_dirs: Vec<String>,
+    /// How many unchanged lines are shown before and after each change when
+    /// the output differs from the expected output.
+    pub diff_context: usize,
+    /// Placeholders that can be used in expected output files. A `[NAME]` in an expected output
+    /// file matches what the pattern of the placeholder called `NAME` matches. `[..]` always
+    /// matches any part of a line.
+    pub placeholders: Vec<(String, Match)>,
+    /// Functions applied to stderr output after all regex filters, in order.
+    pub stderr_filter_fns: Vec<FilterFn>,
+    /// Functions applied to stdout output after all regex filters, in order.
+    pub stdout_filter_fns: Vec<FilterFn>,
+    /// Functions applied to the output of rustfix before comparing it to the `.fixed` file, in order.
+    pub fixed_filter_fns: Vec<FilterFn>,
     /// The folder in which to start searching for .rs files
     pub root_dir: PathBuf,
+    /// Whether tests are single files or cargo packages.
+    pub test_kind: TestKind,
     /// The mode in which to run the tests.
     pub mode: Mode,
     /// The binary to actually execute.
@@ -35,25 +88,394 @@ pub struct Config {
     /// By default, errors in case of conflict, but emits a message informing the user
     /// that running `cargo test -- -- --bless` will automatically overwrite the
     /// `.stdout` and `.stderr` files with the latest output.
+    /// Defaults to [`OutputConflictHandling::Bless`] if the `UI_TEST_BLESS` environment variable
+    /// is `1` or `true`.
     pub output_conflict_handling: OutputConflictHandling,
     /// Path to a `Cargo.toml` that describes which dependencies the tests can access.
     pub dependencies_crate_manifest_path: Option<PathBuf>,
     /// The command to run can be changed from `cargo` to any custom command to build the
     /// dependencies in `dependencies_crate_manifest_path`
     pub dependency_builder: CommandBuilder,
-    /// How many threads to use for running tests. Defaults to number of cores
+    /// Features of the crate in `dependencies_crate_manifest_path` to enable.
+    pub dependency_features: Vec<String>,
+    /// Don't enable the default features of the crate in `dependencies_crate_manifest_path`.
+    pub dependency_no_default_features: bool,
+    /// Overrides of the cargo configuration used to build the dependencies, passed to cargo as
+    /// `--config`, e.g. `patch.crates-io.foo.path="../foo"`.
+    pub dependency_config_overrides: Vec<String>,
+    /// Builds that tests can request via `//@custom-build: NAME: ARGS`, for artifacts that are
+    /// shared by many tests but not built by rustc.
+    pub custom_builds: CustomBuilds,
+    /// Parsers of custom `//@NAME: ARGS` comments by their `NAME`, whose [`Flag`](crate::Flag)s
+    /// can change how tests are compiled and checked. Built-in comments take precedence.
+    pub custom_comments: Shared<BTreeMap<String, CustomComment>>,
+    /// Conditions that `//@only-when: NAME` and `//@ignore-when: NAME` look up by their `NAME`,
+    /// like whether a Cargo feature of the test harness is enabled. Tests using names that are
+    /// not in here fail.
+    pub custom_conditions: Shared<HashMap<String, bool>>,
+    /// Whether the [`Config::program`] itself was built with debug assertions, like a debug build
+    /// of Miri, for `//@needs-debug-assertions` and `//@ignore-debug-assertions`. The program
+    /// can't be asked for it, so tests using these conditions fail while this is `None`, the
+    /// default.
+    pub program_debug_assertions: Option<bool>,
+    /// Whether `${VAR}` in the values of `//@compile-flags`, `//@rustc-env`, `//@aux-build`,
+    /// `//@run-aux-bin`, `//@stdin` and `//@working-dir` is replaced by the environment variable
+    /// `VAR`, with `$$` for a literal `$`. Unset variables are errors. Variables are expanded
+    /// before the arguments are split, so quotes and whitespace in their values matter, and `$$`
+    /// turns into a single `$`. Disabled by default, so that existing comments keep their meaning.
+    pub expand_env_vars: bool,
+    /// Called before every attempt of every test and revision, e.g. to create fixtures the test
+    /// needs in [`Config::out_dir`]. If it fails, the test fails without running.
+    pub before_test: Option<TestHook>,
+    /// Called after every attempt of every test and revision whose [`Config::before_test`]
+    /// succeeded, even if the test failed, e.g. to remove the fixtures again.
+    /// If it fails, the test fails.
+    pub after_test: Option<TestHook>,
+    /// How many tests and aux builds to run at the same time. Defaults to the
+    /// `--test-threads` command line argument, the `UI_TEST_THREADS` environment variable or the
+    /// `RUST_TEST_THREADS` environment variable, like libtest, and otherwise to the number of
+    /// cores. When running under GNU make, each
+    /// test and aux build also needs a token from make's jobserver.
     pub num_test_threads: NonZeroUsize,
+    /// How often a failing test is run before it is reported as failed, unless the test
+    /// overrides it with `//@retry: N`. Tests that only pass on a later attempt are reported as
+    /// [`TestResult::Flaky`](crate::TestResult::Flaky). Defaults to 1, i.e. no retries.
+    pub test_attempts: usize,
+    /// Only run the tests of shard `index` of `count` shards, e.g. to split a test suite across
+    /// several CI jobs. Tests and their revisions are assigned to shards by a hash of their path
+    /// relative to [`Config::root_dir`], so the assignment is the same on every machine. The
+    /// other tests are reported as filtered out.
+    pub shard: Option<(usize, usize)>,
+    /// Only run the tests whose path relative to [`Config::root_dir`] matches one of these
+    /// globs, like `borrowck/**/*.rs`. `*` and `?` don't match `/`, and `**` matches any number
+    /// of directories. A glob can be followed by `::` and a glob for the revision, like
+    /// `a.rs::edition*`, which only matches these revisions of the test.
+    /// All tests run if this is empty. Defaults to the values of all `--filter`
+    /// command line arguments, or else to the whitespace separated globs in the `UI_TEST_FILTER`
+    /// environment variable. The other tests are reported as filtered out.
+    pub filters: Vec<String>,
+    /// Don't run the tests whose path relative to [`Config::root_dir`] matches one of these
+    /// globs, even if they match the [`Config::filters`]. Same syntax as the filters. Defaults
+    /// to the values of all `--skip` command line arguments, or else to the whitespace separated
+    /// globs in the `UI_TEST_SKIP` environment variable. These tests are reported as filtered
+    /// out.
+    pub skip: Vec<String>,
+    /// Compare the [`Config::filters`] and [`Config::skip`] to the id of every test instead of
+    /// matching them as globs. The id is the path of the test relative to [`Config::root_dir`]
+    /// with `/` separators, followed by `::revision` for every revision, like
+    /// `borrowck/a.rs::edition2021`. Defaults to whether the `--exact` command line argument
+    /// was passed.
+    pub exact: bool,
+    /// Print the id of every test that would run, see [`Config::exact`], marking the ignored
+    /// ones, instead of building and running the tests. Defaults to whether the `--list`
+    /// command line argument was passed.
+    pub list: bool,
+    /// Run every test twice and compare the normalized outputs of the two runs with each
+    /// other, to find tests with nondeterministic output, instead of comparing them to the
+    /// expected output files and checking the annotations. `//@run` tests also run their binary
+    /// twice. Defaults to whether the `--check-determinism` command line argument was passed.
+    pub check_determinism: bool,
+    /// If the stdout or stderr of a test gets larger than this, the test is killed and fails.
+    /// Unlimited by default.
+    pub max_test_output_bytes: Option<usize>,
     /// Where to dump files like the binaries compiled from tests.
     /// Defaults to `target/ui` in the current directory.
     pub out_dir: PathBuf,
+    /// Aux builds and other artifacts of a test go to a directory in [`Config::out_dir`] that
+    /// mirrors the path of the test. If that path is longer than this many bytes, the directory
+    /// is named after the test file and a hash of its path instead, to stay clear of path length
+    /// limits like the one on Windows. Defaults to 100.
+    pub max_test_out_dir_len: usize,
+    /// What happens to the artifacts that tests leave in [`Config::out_dir`]. With
+    /// [`CleanupPolicy::Keep`], tests compile into the [`Config::out_dir`] itself. Otherwise each
+    /// revision of a test compiles into its own directory in the directory of the test, which
+    /// [`CleanupPolicy::RemoveOnSuccess`] and [`CleanupPolicy::Fresh`] remove. The cached aux
+    /// builds of tests that still exist, which other revisions and shards share, and the
+    /// dependencies are always kept, as are the artifacts of [`TestKind::CargoProject`] tests,
+    /// which share cargo's build directory. The summary reports how much space was reclaimed.
+    /// Defaults to [`CleanupPolicy::Keep`].
+    pub cleanup: CleanupPolicy,
+    /// Aux builds are cached in [`Config::out_dir`] and reused by later runs as long as the aux
+    /// file, the command building it, its own aux builds and the version of the program are
+    /// unchanged. Setting this rebuilds them anyway, and runs all tests even if
+    /// [`Config::incremental`] is enabled.
+    pub force_rebuild: bool,
+    /// Skip tests that passed in an earlier run and whose inputs are unchanged: the test file,
+    /// its expected output and stdin files, its aux files, the configuration, the version of the
+    /// program and the dependencies. They are reported as
+    /// [`TestResult::Cached`](crate::TestResult::Cached).
+    /// The results are cached in [`Config::out_dir`]. Tests that request custom builds, and
+    /// [`TestKind::CargoProject`] tests, always run.
+    /// The closures in the config can't be compared between runs, so if it has filter
+    /// functions, test hooks or [`OutputConflictHandling::Custom`], or a test uses custom
+    /// comments, the tests always run unless [`Config::incremental_key`] is set.
+    pub incremental: bool,
+    /// Stands in for the closures in the config when deciding whether a test can be skipped by
+    /// [`Config::incremental`]. Change it whenever the closures change what they do.
+    pub incremental_key: Option<String>,
+    /// If set, the normalized stdout, stderr and `.fixed` output of every failing test is saved
+    /// to this directory, in the same directory structure as the tests below [`Config::root_dir`],
+    /// along with the commands that were run and their exit status. Everything in this directory
+    /// is deleted at the start of a test run.
+    pub failure_artifacts_dir: Option<PathBuf>,
+    /// Run the tests that failed in the last run before all other tests, to find out sooner
+    /// whether they pass now. The failed tests are remembered in [`Config::out_dir`].
+    /// Enabled by default.
+    pub run_failed_first: bool,
+    /// Fail for every `.stderr`, `.stdout` and `.fixed` file below [`Config::root_dir`] that no
+    /// test produces, e.g. because the test was renamed. Blessing deletes these files instead.
+    pub check_stale_output_files: bool,
+    /// Where [`run_tests`](crate::run_tests) additionally writes the results as JSON lines,
+    /// see [`status_emitter::Json`](crate::status_emitter::Json). Disabled by default.
+    pub json_output: Option<JsonOutput>,
+    /// Where [`run_tests`](crate::run_tests) additionally writes a Markdown report of the
+    /// results, e.g. to post it as a comment on a pull request, see
+    /// [`status_emitter::Markdown`](crate::status_emitter::Markdown). Disabled by default.
+    pub markdown_report: Option<PathBuf>,
+    /// How many bytes of every diff the [`Config::markdown_report`] shows before truncating
+    /// it. Defaults to 10 000.
+    pub markdown_max_diff_len: usize,
+    /// How [`run_tests`](crate::run_tests) reports the results. Defaults to [`Format::Quiet`]
+    /// with the `--quiet` command line flag, to the `--format` command line argument, like
+    /// libtest, and otherwise to [`Format::Pretty`].
+    pub format: Format,
+    /// Report this many of the slowest tests at the end of the test run, along with how long
+    /// all tests spent in each phase, like building aux files or comparing the output.
+    /// Disabled by default.
+    pub slowest_tests: Option<usize>,
+    /// Report the ignored tests at the end of the test run, grouped by the `//@ignore-*`,
+    /// `//@only-*` or `//@needs-*` comment that caused them to be ignored. Defaults to whether
+    /// the `--ignored-summary` command line argument was passed.
+    pub ignored_summary: bool,
+    /// Fail the test run if more than this fraction of the tests that were not filtered out
+    /// were ignored, e.g. because a new target matches `//@ignore-*` comments it shouldn't.
+    /// Defaults to the `--warn-on-ignored` command line argument, which is either a fraction
+    /// like `0.5` or a percentage like `50%`, and is otherwise disabled.
+    pub warn_on_ignored: Option<f64>,
+    /// A JSON file with the durations of the tests in an earlier run. Tests that take
+    /// considerably longer than their duration in the file are reported according to
+    /// [`Config::timing_regressions`], see [`Config::timing_threshold`] and
+    /// [`Config::timing_min_delta`]. [`Config::bless_timings`] writes the file.
+    pub timing_baseline: Option<PathBuf>,
+    /// How much slower than in the [`Config::timing_baseline`] a test may get, relative to its
+    /// duration there. Defaults to `1.0`, so tests may take twice as long.
+    pub timing_threshold: f64,
+    /// How much slower than in the [`Config::timing_baseline`] a test may always get, so that
+    /// quick tests don't get reported for noise. Defaults to one second.
+    pub timing_min_delta: Duration,
+    /// Whether tests that got slower than the [`Config::timing_baseline`] allows fail, or only
+    /// get a warning.
+    pub timing_regressions: Strictness,
+    /// How often tests that got too slow are run again, to report them only if the median of
+    /// these runs is too slow, too. Defaults to the `--timing-samples` command line argument,
+    /// and otherwise to 0.
+    pub timing_samples: usize,
+    /// Write the durations of this run to the [`Config::timing_baseline`] instead of comparing
+    /// them. Defaults to whether the `--bless-timings` command line argument was passed.
+    pub bless_timings: bool,
+    /// Put the `.stderr`, `.stdout` and `.fixed` files in this directory instead of next to
+    /// the tests, in the same directory structure as the tests below [`Config::root_dir`].
+    pub expected_output_dir: Option<PathBuf>,
     /// The default edition to use on all tests
     pub edition: Option<String>,
+    /// Whether annotations with an occurrence count (`//~ ERROR*3: ...`) also fail if
+    /// more matching diagnostics than declared are found. By default, surplus diagnostics
+    /// are left unmatched and reported like any other diagnostic without an annotation.
+    pub strict_annotation_counts: bool,
+    /// Whether recompiling the output of rustfix must not emit any warnings, as if every
+    /// `//@run-rustfix` test also had a `//@check-fixed` comment.
+    pub check_fixed: bool,
+    /// Which rustfix suggestions to apply in `//@run-rustfix` tests that don't
+    /// specify `//@apply-suggestions`.
+    pub apply_suggestions: ApplySuggestions,
+    /// How often `//@run-rustfix: loop` applies suggestions before giving up. Suggestions are
+    /// always applied at least once, so `0` behaves like `1`.
+    pub rustfix_max_iterations: usize,
+    /// How to report `//~` annotations that did not match any diagnostic in [`Mode::Yolo`].
+    pub check_annotations_in_yolo: Strictness,
+    /// How to report revisions that no `//@[rev]` or `//~[rev]` comment and no expected output
+    /// file refers to, which usually means they are left over from a refactoring. Tests with
+    /// an `//@allow-unused-revisions` comment are not checked, e.g. because their revisions only
+    /// differ in their `--cfg`. Not checked by default.
+    pub unused_revisions: Option<Strictness>,
+    /// Extracts the diagnostics from the output of `program`, which are checked against
+    /// the `//~` annotations.
+    pub diagnostics_parser: Arc<dyn DiagnosticsParser>,
+}
+
+/// The value of a libtest command line argument like `--test-threads 4` or `--test-threads=4`.
+/// The last occurrence wins.
+fn libtest_arg(name: &str) -> Option<String> {
+    libtest_args(name).pop()
+}
+
+/// The values of all occurrences of a command line argument like `--skip a` or `--skip=a`.
+fn libtest_args(name: &str) -> Vec<String> {
+    let mut args = std::env::args();
+    let mut values = vec![];
+    while let Some(arg) = args.next() {
+        if arg == name {
+            values.extend(args.next());
+        } else if let Some(v) = arg.strip_prefix(name).and_then(|v| v.strip_prefix('=')) {
+            values.push(v.to_owned());
+        }
+    }
+    values
+}
+
+/// A fraction between 0 and 1, either written as such or as a percentage like `50%`.
+pub(crate) fn parse_fraction(s: &str) -> Option<f64> {
+    let fraction = match s.strip_suffix('%') {
+        Some(percent) => percent.trim().parse::<f64>().ok()? / 100.0,
+        None => s.parse().ok()?,
+    };
+    (0.0..=1.0).contains(&fraction).then_some(fraction)
+}
+
+/// The number of threads requested on the command line via `--test-threads`, or in the
+/// `UI_TEST_THREADS` or `RUST_TEST_THREADS` environment variables, or the number of cores.
+fn default_test_threads() -> NonZeroUsize {
+    libtest_test_threads()
+        .or_else(|| env_var("UI_TEST_THREADS").and_then(|threads| parse_threads(&threads).ok()))
+        .unwrap_or_else(fallback_test_threads)
+}
+
+/// The number of threads passed as `--test-threads`.
+fn libtest_test_threads() -> Option<NonZeroUsize> {
+    libtest_arg("--test-threads").and_then(|threads| threads.parse().ok())
+}
+
+/// The number of threads if neither `--test-threads` nor `UI_TEST_THREADS` set it.
+pub(crate) fn fallback_test_threads() -> NonZeroUsize {
+    std::env::var("RUST_TEST_THREADS")
+        .ok()
+        .and_then(|threads| threads.parse().ok())
+        .unwrap_or_else(|| std::thread::available_parallelism().unwrap())
+}
+
+/// The value of the environment variable `name`, unless it is unset or not unicode.
+/// [`check_env_vars`] reports the latter.
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+/// The globs in the environment variable `name`, separated by whitespace, unless they were
+/// passed on the command line as `arg`.
+fn env_globs(arg: &str, name: &str) -> Vec<String> {
+    let args = libtest_args(arg);
+    if !args.is_empty() {
+        return args;
+    }
+    env_var(name)
+        .map(|globs| globs.split_whitespace().map(str::to_owned).collect())
+        .unwrap_or_default()
+}
+
+/// The value of `UI_TEST_BLESS`: `1` or `true` to bless, `0`, `false` or nothing not to.
+pub(crate) fn parse_bless(value: &str) -> std::result::Result<bool, String> {
+    match value {
+        "1" | "true" => Ok(true),
+        "0" | "false" | "" => Ok(false),
+        _ => Err(format!(
+            "must be `1`, `true`, `0` or `false`, not `{value}`"
+        )),
+    }
+}
+
+/// The value of `UI_TEST_THREADS`, a positive number.
+pub(crate) fn parse_threads(value: &str) -> std::result::Result<NonZeroUsize, String> {
+    value
+        .parse()
+        .map_err(|_| format!("must be a positive number, not `{value}`"))
 }
 
+/// Fail if one of the `UI_TEST_*` environment variables that [`Config::rustc`] reads has an
+/// invalid value, which it would have ignored.
+pub(crate) fn check_env_vars(config: &Config) -> Result<()> {
+    for name in [
+        "UI_TEST_BLESS",
+        "UI_TEST_THREADS",
+        "UI_TEST_FILTER",
+        "UI_TEST_SKIP",
+    ] {
+        check_env_var(config, name, std::env::var(name))?;
+    }
+    Ok(())
+}
+
+/// Check the `value` of the environment variable `name`, if the config uses it.
+pub(crate) fn check_env_var(
+    config: &Config,
+    name: &str,
+    value: std::result::Result<String, std::env::VarError>,
+) -> Result<()> {
+    // Variables are not used if the command line takes precedence, or if the field of the
+    // config was changed from the value an invalid variable falls back to.
+    let used = match name {
+        "UI_TEST_BLESS" => matches!(
+            config.output_conflict_handling,
+            OutputConflictHandling::Error(_)
+        ),
+        "UI_TEST_THREADS" => {
+            libtest_test_threads().is_none() && config.num_test_threads == fallback_test_threads()
+        }
+        "UI_TEST_FILTER" => libtest_args("--filter").is_empty(),
+        "UI_TEST_SKIP" => libtest_args("--skip").is_empty(),
+        _ => unreachable!(),
+    };
+    if !used {
+        return Ok(());
+    }
+    let value = match value {
+        Ok(value) => value,
+        Err(std::env::VarError::NotPresent) => return Ok(()),
+        Err(std::env::VarError::NotUnicode(_)) => {
+            return Err(eyre!("the `{name}` environment variable is not unicode"))
+        }
+    };
+    let checked = match name {
+        "UI_TEST_BLESS" => parse_bless(&value).map(drop),
+        "UI_TEST_THREADS" => parse_threads(&value).map(drop),
+        _ => Ok(()),
+    };
+    checked.map_err(|err| eyre!("the `{name}` environment variable {err}"))
+}
+
+lazy_static::lazy_static! {
+    /// The host of each program, so that it is only queried once per process, no matter how many
+    /// configs use the program.
+    static ref HOSTS: Mutex<HashMap<PathBuf, String>> = Default::default();
+    /// The cfgs of each program, target and flags, or why they couldn't be queried.
+    static ref CFGS: Mutex<HashMap<CfgsKey, Result<Cfgs, String>>> = Default::default();
+}
+
+/// The cfgs printed by `--print cfg`. Bare cfgs like `unix` have no value, and names like
+/// `target_feature` appear once per value.
+pub(crate) type Cfgs = Arc<Vec<(String, Option<String>)>>;
+
+/// The program, target and flags that [`Cfgs`] were printed for.
+type CfgsKey = (PathBuf, String, Vec<String>);
+
 impl Config {
     /// Create a configuration for testing the output of running
     /// `rustc` on the test files.
-    pub fn rustc(root_dir: PathBuf) -> Self {
+    ///
+    /// The program is the `RUSTC` env var or `rustc`, see [`CommandBuilder::rustc`], which emits
+    /// its diagnostics as JSON. Libraries and tests without a `main` function get the right
+    /// `--crate-type` from [`default_per_file_config`](crate::default_per_file_config). The
+    /// [`BuiltinNormalizations`] replace the directory of the test with `$DIR`, and similar.
+    /// The host and target are detected when the tests run, unless they are set.
+    ///
+    /// Every field can be changed afterwards, or overridden with struct update syntax:
+    ///
+    /// ```rust
+    /// # use ui_test::{Config, OutputConflictHandling};
+    /// let config = Config {
+    ///     output_conflict_handling: OutputConflictHandling::Bless,
+    ///     ..Config::rustc("tests/ui")
+    /// };
+    /// ```
+    pub fn rustc(root_dir: impl Into<PathBuf>) -> Self {
         Self {
             host: None,
             target: None,
@@ -66,29 +488,103 @@ impl Config {
                 #[cfg(windows)]
                 (Match::Exact(vec![b'\r']), b""),
             ],
-            root_dir,
+            builtin_normalizations: BuiltinNormalizations::default(),
+            ansi: AnsiEscapes::Strip,
+            compare: Compare::Exact,
+            comment_start: "//".into(),
+            comment_start_by_extension: vec![],
+            defaults_file_name: Some("ui_test.defaults".into()),
+            test_extensions: vec!["rs".into()],
+            excluded_dirs: vec!["auxiliary".into()],
+            diff_context: 2,
+            placeholders: vec![],
+            stderr_filter_fns: vec![],
+            stdout_filter_fns: vec![],
+            fixed_filter_fns: vec![],
+            root_dir: root_dir.into(),
+            test_kind: TestKind::File,
             mode: Mode::Fail {
                 require_patterns: true,
             },
             program: CommandBuilder::rustc(),
             cfgs: CommandBuilder::cfgs(),
-            output_conflict_handling: OutputConflictHandling::Error(
-                "cargo test -- -- --bless".into(),
-            ),
+            output_conflict_handling: if env_var("UI_TEST_BLESS")
+                .map_or(false, |bless| parse_bless(&bless) == Ok(true))
+            {
+                OutputConflictHandling::Bless
+            } else {
+                OutputConflictHandling::Error("cargo test -- -- --bless".into())
+            },
             dependencies_crate_manifest_path: None,
             dependency_builder: CommandBuilder::cargo(),
-            num_test_threads: std::thread::available_parallelism().unwrap(),
+            dependency_features: vec![],
+            dependency_no_default_features: false,
+            dependency_config_overrides: vec![],
+            custom_builds: CustomBuilds::default(),
+            custom_comments: Shared::default(),
+            custom_conditions: Shared::default(),
+            program_debug_assertions: None,
+            expand_env_vars: false,
+            before_test: None,
+            after_test: None,
+            num_test_threads: default_test_threads(),
+            test_attempts: 1,
+            shard: None,
+            filters: env_globs("--filter", "UI_TEST_FILTER"),
+            skip: env_globs("--skip", "UI_TEST_SKIP"),
+            exact: std::env::args().any(|arg| arg == "--exact"),
+            list: std::env::args().any(|arg| arg == "--list"),
+            check_determinism: std::env::args().any(|arg| arg == "--check-determinism"),
+            max_test_output_bytes: None,
             out_dir: std::env::var_os("CARGO_TARGET_DIR")
                 .map(PathBuf::from)
                 .unwrap_or_else(|| std::env::current_dir().unwrap().join("target"))
                 .join("ui"),
+            max_test_out_dir_len: 100,
+            cleanup: CleanupPolicy::Keep,
+            force_rebuild: false,
+            incremental: false,
+            incremental_key: None,
+            failure_artifacts_dir: None,
+            run_failed_first: true,
+            check_stale_output_files: false,
+            json_output: None,
+            markdown_report: None,
+            markdown_max_diff_len: 10_000,
+            format: if std::env::args().any(|arg| arg == "--quiet" || arg == "-q") {
+                Format::Quiet
+            } else {
+                libtest_arg("--format")
+                    .and_then(|format| format.parse().ok())
+                    .unwrap_or(Format::Pretty)
+            },
+            slowest_tests: None,
+            ignored_summary: std::env::args().any(|arg| arg == "--ignored-summary"),
+            warn_on_ignored: libtest_arg("--warn-on-ignored")
+                .and_then(|threshold| parse_fraction(&threshold)),
+            timing_baseline: None,
+            timing_threshold: 1.0,
+            timing_min_delta: Duration::from_secs(1),
+            timing_regressions: Strictness::Error,
+            timing_samples: libtest_arg("--timing-samples")
+                .and_then(|samples| samples.parse().ok())
+                .unwrap_or(0),
+            bless_timings: std::env::args().any(|arg| arg == "--bless-timings"),
+            expected_output_dir: None,
             edition: Some("2021".into()),
+            strict_annotation_counts: false,
+            check_fixed: false,
+            apply_suggestions: ApplySuggestions::MachineApplicable,
+            rustfix_max_iterations: 10,
+            check_annotations_in_yolo: Strictness::Error,
+            unused_revisions: None,
+            diagnostics_parser: Arc::new(RustcDiagnostics::default()),
         }
     }
 
     /// Create a configuration for testing the output of running
     /// `cargo` on the test `Cargo.toml` files.
-    pub fn cargo(root_dir: PathBuf) -> Self {
+    pub fn cargo(root_dir: impl Into<PathBuf>) -> Self {
         Self {
             program: CommandBuilder::cargo(),
             edition: None,
@@ -96,6 +592,72 @@ impl Config {
         }
     }
 
+    /// Like [`Config::cargo`], but every directory directly inside `root_dir` that contains a
+    /// `Cargo.toml` is a test, see [`TestKind::CargoProject`]. The packages are built with
+    /// `--message-format=json`, so their diagnostics can be matched against `//~` annotations.
+    pub fn cargo_packages(root_dir: impl Into<PathBuf>) -> Self {
+        let mut config = Self::cargo(root_dir);
+        config.program.args.push("--message-format=json".into());
+        config.test_kind = TestKind::CargoProject;
+        config
+    }
+
+    /// The file to read the `//@` and `//~` comments of a test from.
+    /// For cargo packages, that's the `src/main.rs` or `src/lib.rs` of the package.
+    pub(crate) fn comments_file(&self, path: &Path) -> PathBuf {
+        match self.test_kind {
+            TestKind::File => path.to_owned(),
+            TestKind::CargoProject => {
+                let src = path.parent().unwrap().join("src");
+                ["main.rs", "lib.rs"]
+                    .into_iter()
+                    .map(|file| src.join(file))
+                    .find(|file| file.exists())
+                    .unwrap_or_else(|| path.to_owned())
+            }
+        }
+    }
+
+    /// Whether `dir` is not searched for tests, see [`Config::excluded_dirs`].
+    pub(crate) fn is_excluded_dir(&self, dir: &Path) -> bool {
+        dir.file_name().map_or(false, |name| {
+            self.excluded_dirs
+                .iter()
+                .any(|excluded| name == excluded.as_str())
+        })
+    }
+
+    /// Whether `path` has one of the [`Config::test_extensions`].
+    pub(crate) fn has_test_extension(&self, path: &Path) -> bool {
+        path.extension().map_or(false, |ext| {
+            self.test_extensions.iter().any(|test| ext == test.as_str())
+        })
+    }
+
+    /// What the comments in `path` start with, see [`Config::comment_start`].
+    pub(crate) fn comment_start_for(&self, path: &Path) -> &str {
+        path.extension()
+            .and_then(|ext| {
+                self.comment_start_by_extension
+                    .iter()
+                    .find(|(extension, _)| ext == extension.as_str())
+            })
+            .map_or(&self.comment_start, |(_, comment_start)| comment_start)
+    }
+
+    /// The path by which diagnostics refer to the [`comments_file`](Self::comments_file).
+    /// Cargo reports paths relative to the package.
+    pub(crate) fn diagnostics_file(&self, path: &Path) -> PathBuf {
+        let file = self.comments_file(path);
+        match self.test_kind {
+            TestKind::File => file,
+            TestKind::CargoProject => file
+                .strip_prefix(path.parent().unwrap())
+                .map(Path::to_owned)
+                .unwrap_or(file),
+        }
+    }
+
     /// Replace all occurrences of a path in stderr with a byte string.
     pub fn path_stderr_filter(
         &mut self,
@@ -127,6 +689,33 @@ impl Config {
             .push((Regex::new(pattern).unwrap().into(), replacement.as_ref()));
     }
 
+    /// Make `build` available to tests via `//@custom-build: NAME: ARGS`.
+    pub fn add_custom_build(&mut self, name: impl Into<String>, build: impl Build + 'static) {
+        self.custom_builds.add(name, build);
+    }
+
+    /// Allow `[NAME]` in expected output files, matching what `pattern` matches.
+    pub fn add_placeholder(&mut self, name: impl Into<String>, pattern: impl Into<Match>) {
+        self.placeholders.push((name.into(), pattern.into()));
+    }
+
+    /// Modify stderr with a function, for normalizations that regexes can't express.
+    /// A panic in the function only fails the test whose output it was filtering.
+    pub fn add_stderr_filter_fn(&mut self, f: impl Fn(&mut Vec<u8>) + Send + Sync + 'static) {
+        self.stderr_filter_fns.push(FilterFn(Arc::new(f)));
+    }
+
+    /// Modify stdout with a function, see [`add_stderr_filter_fn`](Self::add_stderr_filter_fn).
+    pub fn add_stdout_filter_fn(&mut self, f: impl Fn(&mut Vec<u8>) + Send + Sync + 'static) {
+        self.stdout_filter_fns.push(FilterFn(Arc::new(f)));
+    }
+
+    /// Modify the output of rustfix with a function, see
+    /// [`add_stderr_filter_fn`](Self::add_stderr_filter_fn).
+    pub fn add_fixed_filter_fn(&mut self, f: impl Fn(&mut Vec<u8>) + Send + Sync + 'static) {
+        self.fixed_filter_fns.push(FilterFn(Arc::new(f)));
+    }
+
     /// Compile dependencies and make sure `Config::program` contains the right flags
     /// to find the dependencies.
     pub fn build_dependencies_and_link_them(&mut self) -> Result<()> {
@@ -147,21 +736,29 @@ impl Config {
         Ok(())
     }
 
-    /// Make sure we have the host and target triples.
+    /// Make sure we have the host and target triples. The host is asked from `rustc -vV` of the
+    /// [`Config::program`] only once per program and process.
     pub fn fill_host_and_target(&mut self) -> Result<()> {
         if self.host.is_none() {
-            self.host = Some(
-                rustc_version::VersionMeta::for_command(std::process::Command::new(
-                    &self.program.program,
-                ))
-                .map_err(|err| {
-                    color_eyre::eyre::Report::new(err).wrap_err(format!(
-                        "failed to parse rustc version info: {}",
-                        self.program.display()
+            let mut hosts = HOSTS.lock().unwrap();
+            let host = match hosts.get(&self.program.program) {
+                Some(host) => host.clone(),
+                None => {
+                    let host = rustc_version::VersionMeta::for_command(std::process::Command::new(
+                        &self.program.program,
                     ))
-                })?
-                .host,
-            );
+                    .map_err(|err| {
+                        color_eyre::eyre::Report::new(err).wrap_err(format!(
+                            "failed to parse rustc version info: {}",
+                            self.program.display()
+                        ))
+                    })?
+                    .host;
+                    hosts.insert(self.program.program.clone(), host.clone());
+                    host
+                }
+            };
+            self.host = Some(host);
         }
         if self.target.is_none() {
             self.target = Some(self.host.clone().unwrap());
@@ -169,6 +766,57 @@ impl Config {
         Ok(())
     }
 
+    /// Use `triple` as the host instead of asking [`Config::program`] for it, e.g. because a build
+    /// script already knows it. Other configs with the same program use it, too, if their host
+    /// is not set.
+    pub fn set_host(&mut self, triple: impl Into<String>) {
+        let triple = triple.into();
+        HOSTS
+            .lock()
+            .unwrap()
+            .insert(self.program.program.clone(), triple.clone());
+        self.host = Some(triple);
+    }
+
+    /// The cfgs of the target, as printed by `<program> --print cfg --target <target> <flags>`,
+    /// with the environment variables of the [`Config::program`]. The `flags` are the ones that
+    /// change the cfgs, like `-C debug-assertions`, see `cfg_flags`. They are only queried once
+    /// per program, target, flags and process.
+    pub(crate) fn target_cfgs(&self, flags: Vec<String>) -> Result<Cfgs, String> {
+        let target = self.target.as_ref().unwrap();
+        CFGS.lock()
+            .unwrap()
+            .entry((self.program.program.clone(), target.clone(), flags))
+            .or_insert_with_key(|(_, _, flags)| {
+                let mut cmd = std::process::Command::new(&self.program.program);
+                cmd.args(["--print", "cfg", "--target", target]).args(flags);
+                self.program.apply_env(&mut cmd);
+                let output = cmd
+                    .output()
+                    .map_err(|err| format!("failed to run `{}`: {err}", display_command(&cmd)))?;
+                if !output.status.success() {
+                    return Err(format!(
+                        "`{}` failed with {}: {}",
+                        display_command(&cmd),
+                        output.status,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    ));
+                }
+                let cfgs = String::from_utf8_lossy(&output.stdout)
+                    .lines()
+                    .filter(|line| !line.trim().is_empty())
+                    .map(|line| match line.split_once('=') {
+                        Some((name, value)) => {
+                            (name.to_owned(), Some(value.trim_matches('"').to_owned()))
+                        }
+                        None => (line.to_owned(), None),
+                    })
+                    .collect();
+                Ok(Arc::new(cfgs))
+            })
+            .clone()
+    }
+
     pub(crate) fn has_asm_support(&self) -> bool {
         static ASM_SUPPORTED_ARCHS: &[&str] = &[
             "x86", "x86_64", "arm", "aarch64", "riscv32",
@@ -182,16 +830,313 @@ impl Config {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What a single test consists of.
+pub enum TestKind {
+    /// Every file accepted by the file filter is a test. The default.
+    File,
+    /// Every directory directly inside `Config::root_dir` that contains a `Cargo.toml` is a test.
+    /// The file filter is invoked on the `Cargo.toml` files, which are passed to the program
+    /// (usually via `--manifest-path`). The `.stderr` and `.stdout` files are put next to them.
+    CargoProject,
+}
+
+#[derive(Clone)]
 /// The different options for what to do when stdout/stderr files differ from the actual output.
 pub enum OutputConflictHandling {
     /// The default: emit a diff of the expected/actual output.
     ///
-    /// The string should be a command that can be executed to bless all tests.
+    /// The string should be a command that can be executed to bless all tests. `{files}` in it
+    /// is replaced with the paths of the failed tests, and `{filters}` with their paths
+    /// relative to [`Config::root_dir`], so that the command only blesses these tests.
     Error(String),
     /// Ignore mismatches in the stderr/stdout files.
     Ignore,
     /// Instead of erroring if the stderr/stdout differs from the expected
     /// automatically replace it with the found output (after applying filters).
     Bless,
+    /// Call the function with the path of the file, the actual output and the expected output
+    /// whenever they differ. If it returns an error, the test fails with that error. The
+    /// function may write the actual output to the file itself to bless it.
+    #[allow(clippy::type_complexity)]
+    Custom(Arc<dyn Fn(&Path, &[u8], &[u8]) -> std::result::Result<(), Error> + Send + Sync>),
+}
+
+impl std::fmt::Debug for OutputConflictHandling {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Error(bless_command) => f.debug_tuple("Error").field(bless_command).finish(),
+            Self::Ignore => f.write_str("Ignore"),
+            Self::Bless => f.write_str("Bless"),
+            Self::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// A function that modifies the output of a test, see [`Config::add_stderr_filter_fn`].
+#[derive(Clone)]
+#[allow(clippy::type_complexity)]
+pub struct FilterFn(pub Arc<dyn Fn(&mut Vec<u8>) + Send + Sync>);
+
+impl std::fmt::Debug for FilterFn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("FilterFn(..)")
+    }
+}
+
+/// A function called with the config, path and revision of a test, see [`Config::before_test`]
+/// and [`Config::after_test`]. The error message fails the test.
+#[derive(Clone)]
+#[allow(clippy::type_complexity)]
+pub struct TestHook(pub Arc<dyn Fn(&Config, &Path, &str) -> Result<(), String> + Send + Sync>);
+
+impl TestHook {
+    /// Wrap `f`, for [`Config::before_test`] or [`Config::after_test`].
+    pub fn new(
+        f: impl Fn(&Config, &Path, &str) -> Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        Self(Arc::new(f))
+    }
+}
+
+impl std::fmt::Debug for TestHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TestHook(..)")
+    }
+}
+
+/// A value that the clones of a [`Config`] share until one of them changes it, so that cloning
+/// the config for every test doesn't copy it, like [`Config::custom_comments`]. It dereferences
+/// to the value, so it can be read and changed like one, and `.into()` turns a value into it.
+#[derive(Default)]
+pub struct Shared<T>(Arc<T>);
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> std::ops::Deref for Shared<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Clone> std::ops::DerefMut for Shared<T> {
+    /// Copies the value first if other configs share it.
+    fn deref_mut(&mut self) -> &mut T {
+        Arc::make_mut(&mut self.0)
+    }
+}
+
+impl<T> From<T> for Shared<T> {
+    fn from(value: T) -> Self {
+        Self(Arc::new(value))
+    }
+}
+
+impl<T: std::fmt::Debug> std::fmt::Debug for Shared<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Normalizations of machine specific paths in the output of tests, so that the
+/// `.stderr` and `.stdout` files are the same everywhere. All are enabled by default.
+pub struct BuiltinNormalizations {
+    /// Replace the directory of the test with `$DIR`, and a `//@working-dir` outside of it
+    /// with `$CWD`.
+    pub test_dir: bool,
+    /// Replace [`Config::out_dir`] with `$OUT_DIR`.
+    pub out_dir: bool,
+    /// Replace the system's temporary directory with `$TMP`.
+    pub temp_dir: bool,
+    /// Replace backslashes in paths with forward slashes: in absolute windows paths like
+    /// `C:\foo\bar.rs`, and on windows in the paths of files in the test directory and
+    /// [`Config::out_dir`]. Other backslashes, like escapes in string literals, are kept.
+    pub backslashes: bool,
+    /// Replace `\r\n` with `\n`, both in the output and in the files it is compared to.
+    /// Disable this when testing tools whose output depends on carriage returns.
+    pub line_endings: bool,
+}
+
+impl Default for BuiltinNormalizations {
+    fn default() -> Self {
+        Self {
+            test_dir: true,
+            out_dir: true,
+            temp_dir: true,
+            backslashes: true,
+            line_endings: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How the output of a test is compared to the expected output.
+pub enum Compare {
+    /// Byte for byte. The default.
+    Exact,
+    /// Ignore trailing whitespace on each line and blank lines at the end. Blessing writes
+    /// the output without them.
+    LenientWhitespace,
+}
+
+impl FromStr for Compare {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exact" => Ok(Self::Exact),
+            "lenient-whitespace" => Ok(Self::LenientWhitespace),
+            _ => Err(format!(
+                "unknown comparison `{s}`, expected `exact` or `lenient-whitespace`"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What happens to the ANSI escape sequences in the output of a test, see [`Config::ansi`].
+/// Escape characters that don't start a complete sequence are always kept.
+pub enum AnsiEscapes {
+    /// Remove them: CSI sequences like colors and cursor movement, OSC sequences like
+    /// hyperlinks, and all other escape sequences.
+    Strip,
+    /// Keep them as they are. Diffs show escape characters as `\x1b`.
+    Preserve,
+    /// Keep them, but replace the escape character with `␛` and the bell character that can end
+    /// OSC sequences with `␇`, so the output files show them, like `␛[1m`.
+    Render,
+}
+
+impl FromStr for AnsiEscapes {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strip" => Ok(Self::Strip),
+            "preserve" => Ok(Self::Preserve),
+            "render" => Ok(Self::Render),
+            _ => Err(format!(
+                "unknown ANSI escape handling `{s}`, expected `strip`, `preserve` or `render`"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+/// Which suggestions rustfix applies, by their applicability.
+/// Every variant also applies the suggestions of the variants before it.
+pub enum ApplySuggestions {
+    /// Only suggestions that rustc is sure about. This is the default.
+    MachineApplicable,
+    /// Also suggestions that may not be what the user intended.
+    MaybeIncorrect,
+    /// All suggestions, including ones with placeholders.
+    Everything,
+}
+
+impl ApplySuggestions {
+    /// Whether a suggestion with the given applicability should be applied.
+    pub(crate) fn applies(self, applicability: Option<Applicability>) -> bool {
+        match applicability {
+            Some(Applicability::MachineApplicable) => true,
+            Some(Applicability::MaybeIncorrect) => self >= Self::MaybeIncorrect,
+            Some(Applicability::HasPlaceholders | Applicability::Unspecified) | None => {
+                self >= Self::Everything
+            }
+        }
+    }
+}
+
+impl FromStr for ApplySuggestions {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "machine-applicable" => Ok(Self::MachineApplicable),
+            "maybe-incorrect" => Ok(Self::MaybeIncorrect),
+            "everything" => Ok(Self::Everything),
+            _ => Err(format!(
+                "unknown applicability `{s}`, expected one of `machine-applicable`, `maybe-incorrect` or `everything`"
+            )),
+        }
+    }
+}
+
+impl Display for ApplySuggestions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::MachineApplicable => "machine-applicable",
+            Self::MaybeIncorrect => "maybe-incorrect",
+            Self::Everything => "everything",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How seriously to take a class of problems.
+pub enum Strictness {
+    /// Report the problem, but do not fail the test.
+    Warn,
+    /// Fail the test.
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What to do with the artifacts of tests in [`Config::out_dir`], see [`Config::cleanup`].
+pub enum CleanupPolicy {
+    /// Keep all artifacts, so they can be inspected after the run.
+    Keep,
+    /// Remove the artifacts of each revision of a test as soon as it passed. The ones of failed
+    /// tests are kept for inspection.
+    RemoveOnSuccess,
+    /// Remove the artifacts that earlier runs left for the tests in [`Config::root_dir`] before
+    /// running them, including the aux builds of tests that don't exist anymore. This includes
+    /// the hashed directories of [`Config::max_test_out_dir_len`], of which the ones that don't
+    /// belong to a test in any of the roots of the run are removed entirely. With
+    /// [`Config::shard`], only the artifacts of the tests in the shard and of deleted tests are
+    /// removed, as other shards may be using the rest.
+    Fresh,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Where to write machine-readable results to.
+pub enum JsonOutput {
+    /// Write them to stdout, which the human readable output does not use.
+    Stdout,
+    /// Write them to this file, replacing it.
+    File(PathBuf),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How [`run_tests`](crate::run_tests) reports the results, named like the `--format` values
+/// of libtest.
+pub enum Format {
+    /// A line per test, and the errors of the failed tests at the end.
+    Pretty,
+    /// A character per test, and the errors of the failed tests at the end.
+    Terse,
+    /// Additionally write the events of libtest's JSON format to stdout, see
+    /// [`status_emitter::Libtest`](crate::status_emitter::Libtest).
+    Json,
+    /// Nothing for passing tests, the errors of failed tests as soon as they fail, and the
+    /// final counts. Selected with the `--quiet` or `-q` command line flag,
+    /// see [`status_emitter::OnlyFailures`](crate::status_emitter::OnlyFailures).
+    Quiet,
+}
+
+impl FromStr for Format {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(Self::Pretty),
+            "terse" => Ok(Self::Terse),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "unknown format `{s}`, expected one of `pretty`, `terse` or `json`"
+            )),
+        }
+    }
 }
@@ -0,0 +1,73 @@
+//! Custom `//@` comments that users of `ui_test` can register, see [`Config::custom_comments`].
+
+use std::{
+    path::Path,
+    process::{Command, Output},
+    sync::Arc,
+};
+
+use crate::Config;
+
+/// The effect of a custom `//@NAME: ARGS` comment on the test, created by the [`CustomComment`]
+/// registered as `NAME`.
+pub trait Flag: Send + Sync + std::fmt::Debug {
+    /// Change the command that compiles the test, e.g. by adding arguments or environment
+    /// variables.
+    fn apply(&self, _cmd: &mut Command) {}
+
+    /// Decide whether the test runs at all before anything is built for it, e.g. by probing the
+    /// machine. The error message fails the test.
+    fn pre_test_action(
+        &self,
+        _config: &Config,
+        _path: &Path,
+        _revision: &str,
+    ) -> Result<PreTest, String> {
+        Ok(PreTest::Continue)
+    }
+
+    /// Check the output of the compiler once the test was compiled. The error message fails
+    /// the test.
+    fn post_test_action(
+        &self,
+        _config: &Config,
+        _path: &Path,
+        _output: &Output,
+    ) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// What to do with a test, as decided by [`Flag::pre_test_action`].
+#[derive(Debug)]
+pub enum PreTest {
+    /// Run the test.
+    Continue,
+    /// Ignore the test, like `//@ignore-*` comments do.
+    Skip {
+        /// Why the test is ignored, reported along with the comment.
+        reason: String,
+    },
+    /// Run the test, but compile it with this command instead of the one built from the config
+    /// and comments. Its output is checked like the compiler's.
+    ReplaceCommand(Command),
+}
+
+/// Parses the arguments of a custom comment into its [`Flag`], see [`Config::custom_comments`].
+/// The error message is reported at the line of the comment.
+#[derive(Clone)]
+#[allow(clippy::type_complexity)]
+pub struct CustomComment(pub Arc<dyn Fn(&str) -> Result<Box<dyn Flag>, String> + Send + Sync>);
+
+impl CustomComment {
+    /// Wrap the parse function `f`, which gets the arguments of the comment, trimmed.
+    pub fn new(f: impl Fn(&str) -> Result<Box<dyn Flag>, String> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+}
+
+impl std::fmt::Debug for CustomComment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CustomComment(..)")
+    }
+}
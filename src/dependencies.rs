@@ -3,7 +3,7 @@ use cargo_platform::Cfg;
 use color_eyre::eyre::{bail, Result};
 use std::{
     collections::{HashMap, HashSet},
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::Command,
     str::FromStr,
 };
@@ -40,6 +40,64 @@ fn cfgs(config: &Config) -> Result<Vec<Cfg>> {
     Ok(cfgs)
 }
 
+/// Make sure the crate at `manifest_path` defines all features in `Config::dependency_features`,
+/// so a typo doesn't just show up as a missing item in every test.
+fn check_features(config: &Config, manifest_path: &Path) -> Result<()> {
+    // Features of dependencies like `serde/derive` are checked by cargo itself.
+    if config
+        .dependency_features
+        .iter()
+        .all(|feature| feature.contains('/'))
+    {
+        return Ok(());
+    }
+    let mut metadata = cargo_metadata::MetadataCommand::new();
+    metadata.manifest_path(manifest_path).no_deps();
+    metadata.other_options(
+        config
+            .dependency_config_overrides
+            .iter()
+            .flat_map(|config| ["--config".to_string(), config.clone()])
+            .collect::<Vec<_>>(),
+    );
+    let mut metadata = metadata.cargo_command();
+    config.dependency_builder.apply_env(&mut metadata);
+    let output = metadata.output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8(output.stderr)?;
+        bail!("failed to run cargo-metadata:\nstderr:\n{stderr}");
+    }
+    let output = String::from_utf8(output.stdout)?;
+    let metadata: cargo_metadata::Metadata = match output.lines().find(|l| l.starts_with('{')) {
+        Some(line) => serde_json::from_str(line)?,
+        None => bail!("no json found in cargo-metadata output"),
+    };
+    let root = metadata
+        .packages
+        .iter()
+        .find(|package| {
+            package.manifest_path.as_std_path().canonicalize().unwrap()
+                == manifest_path.canonicalize().unwrap()
+        })
+        .unwrap();
+    for feature in &config.dependency_features {
+        if !feature.contains('/') && !root.features.contains_key(feature) {
+            let mut features: Vec<_> = root.features.keys().map(|f| format!("`{f}`")).collect();
+            features.sort();
+            bail!(
+                "`{}` does not define the feature `{feature}` requested in `Config::dependency_features`, it defines {}",
+                manifest_path.display(),
+                if features.is_empty() {
+                    "no features".to_string()
+                } else {
+                    features.join(", ")
+                }
+            );
+        }
+    }
+    Ok(())
+}
+
 /// Compiles dependencies and returns the crate names and corresponding rmeta files.
 pub fn build_dependencies(config: &mut Config) -> Result<Dependencies> {
     let manifest_path = match &config.dependencies_crate_manifest_path {
@@ -65,7 +123,27 @@ pub fn build_dependencies(config: &mut Config) -> Result<Dependencies> {
         _ => {}
     };
 
+    // Reusable closure for selecting the same features and configuration for the build and
+    // `cargo_metadata`
+    let set_features = |cmd: &mut Command| {
+        if !config.dependency_features.is_empty() {
+            cmd.arg(format!(
+                "--features={}",
+                config.dependency_features.join(",")
+            ));
+        }
+        if config.dependency_no_default_features {
+            cmd.arg("--no-default-features");
+        }
+        for config in &config.dependency_config_overrides {
+            cmd.arg("--config").arg(config);
+        }
+    };
+
+    check_features(config, manifest_path)?;
+
     set_locking(&mut build);
+    set_features(&mut build);
     build.arg("--message-format=json");
 
     let output = build.output()?;
@@ -83,7 +161,7 @@ pub fn build_dependencies(config: &mut Config) -> Result<Dependencies> {
     let mut artifacts = HashMap::new();
     for line in artifact_output.lines() {
         let Ok(message) = serde_json::from_str::<cargo_metadata::Message>(line) else {
-            continue
+            continue;
         };
         if let cargo_metadata::Message::CompilerArtifact(artifact) = message {
             for filename in &artifact.filenames {
@@ -104,6 +182,7 @@ pub fn build_dependencies(config: &mut Config) -> Result<Dependencies> {
     metadata.arg("--manifest-path").arg(manifest_path);
     config.dependency_builder.apply_env(&mut metadata);
     set_locking(&mut metadata);
+    set_features(&mut metadata);
     let output = metadata.output()?;
 
     if !output.status.success() {
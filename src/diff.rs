@@ -1,64 +1,53 @@
+use bstr::ByteSlice;
 use colored::*;
-use diff::{chars, lines, Result, Result::*};
+use diff::{lines, slice, Result, Result::*};
+use std::fmt::Write;
 
-#[derive(Default)]
 struct DiffState<'a> {
+    /// The rendered diff.
+    out: String,
+    /// How many lines of context are displayed around the actual diffs.
+    context: usize,
+    /// Whether to highlight the changed words of modified lines with colors.
+    color: bool,
     /// Whether we've already printed something, so we should print starting context, too.
     print_start_context: bool,
-    /// When we skip lines, remember the last `CONTEXT` ones to
-    /// display after the "skipped N lines" message
+    /// When we skip lines, remember them to display the first and last `context` ones
+    /// around the "skipped N lines" message.
     skipped_lines: Vec<&'a str>,
     /// When we see a removed line, we don't print it, we
     /// keep it around to compare it with the next added line.
     prev_left: Option<&'a str>,
 }
 
-/// How many lines of context are displayed around the actual diffs
-const CONTEXT: usize = 2;
-
 impl<'a> DiffState<'a> {
-    /// Print `... n lines skipped ...` followed by the last `CONTEXT` lines.
-    fn print_end_skip(&self, skipped: usize) {
-        self.print_skipped_msg(skipped);
-        for line in self.skipped_lines.iter().rev().take(CONTEXT).rev() {
-            eprintln!(" {line}");
+    /// Print the first `head` and the last `tail` skipped lines, and a message about the ones in between.
+    fn print_skipped(&mut self, head: usize, tail: usize) {
+        let lines = std::mem::take(&mut self.skipped_lines);
+        let head = head.min(lines.len());
+        let tail_start = lines.len().saturating_sub(tail).max(head);
+        for line in &lines[..head] {
+            writeln!(self.out, " {line}").unwrap();
         }
-    }
-
-    fn print_skipped_msg(&self, skipped: usize) {
-        match skipped {
-            // When the amount of skipped lines is exactly `CONTEXT * 2`, we already
-            // print all the context and don't actually skip anything.
+        match tail_start - head {
             0 => {}
             // Instead of writing a line saying we skipped one line, print that one line
-            1 => eprintln!(" {}", self.skipped_lines[CONTEXT]),
-            _ => eprintln!("... {skipped} lines skipped ..."),
+            1 => writeln!(self.out, " {}", lines[head]).unwrap(),
+            skipped => writeln!(self.out, "... {skipped} lines skipped ...").unwrap(),
         }
-    }
-
-    /// Print an initial `CONTEXT` amount of lines.
-    fn print_start_skip(&self) {
-        for line in self.skipped_lines.iter().take(CONTEXT) {
-            eprintln!(" {line}");
+        for line in &lines[tail_start..] {
+            writeln!(self.out, " {line}").unwrap();
         }
     }
 
+    /// Print the context before a diff.
     fn print_skip(&mut self) {
-        let half = self.skipped_lines.len() / 2;
-        if !self.print_start_context {
-            self.print_start_context = true;
-            self.print_end_skip(self.skipped_lines.len().saturating_sub(CONTEXT));
-        } else if half < CONTEXT {
-            // Print all the skipped lines if the amount of context desired is less than the amount of lines
-            for line in self.skipped_lines.drain(..) {
-                eprintln!(" {line}");
-            }
+        if self.print_start_context {
+            self.print_skipped(self.context, self.context);
         } else {
-            self.print_start_skip();
-            let skipped = self.skipped_lines.len() - CONTEXT * 2;
-            self.print_end_skip(skipped);
+            self.print_start_context = true;
+            self.print_skipped(0, self.context);
         }
-        self.skipped_lines.clear();
     }
 
     fn skip(&mut self, line: &'a str) {
@@ -71,12 +60,63 @@ impl<'a> DiffState<'a> {
         }
     }
 
-    fn print_left(&self, l: &str) {
-        eprintln!("{}{}", "-".red(), l.red());
+    fn print_left(&mut self, l: &str) {
+        if self.color {
+            writeln!(self.out, "{}{}", "-".red(), l.red()).unwrap();
+        } else {
+            writeln!(self.out, "-{l}").unwrap();
+        }
     }
 
-    fn print_right(&self, r: &str) {
-        eprintln!("{}{}", "+".green(), r.green());
+    fn print_right(&mut self, r: &str) {
+        if self.color {
+            writeln!(self.out, "{}{}", "+".green(), r.green()).unwrap();
+        } else {
+            writeln!(self.out, "+{r}").unwrap();
+        }
+    }
+
+    /// Print a removed line followed by the added line replacing it, highlighting the words that changed.
+    fn print_changed(&mut self, l: &str, r: &str) {
+        let (l_words, r_words) = (words(l), words(r));
+        let diff = slice(&l_words, &r_words);
+        let is_change = |word: &&str| !word.trim().is_empty();
+        let seen_l = diff.iter().any(|w| matches!(w, Left(l) if is_change(l)));
+        let seen_r = diff.iter().any(|w| matches!(w, Right(r) if is_change(r)));
+        let out = &mut self.out;
+        if seen_l && seen_r {
+            // The line both adds and removes words, print both lines, but highlight their differences instead of
+            // drawing the entire line in red/green.
+            write!(out, "{}", "-".red()).unwrap();
+            for word in &diff {
+                match *word {
+                    Left(l) => write!(out, "{}", l.red()).unwrap(),
+                    Right(_) => {}
+                    Both(l, _) => write!(out, "{l}").unwrap(),
+                }
+            }
+            writeln!(out).unwrap();
+            write!(out, "{}", "+".green()).unwrap();
+            for word in &diff {
+                match *word {
+                    Left(_) => {}
+                    Right(r) => write!(out, "{}", r.green()).unwrap(),
+                    Both(l, _) => write!(out, "{l}").unwrap(),
+                }
+            }
+            writeln!(out).unwrap();
+        } else {
+            // The line only adds or only removes words, print a single line highlighting their differences.
+            write!(out, "{}", "~".yellow()).unwrap();
+            for word in &diff {
+                match *word {
+                    Left(l) => write!(out, "{}", l.red()).unwrap(),
+                    Both(l, _) => write!(out, "{l}").unwrap(),
+                    Right(r) => write!(out, "{}", r.green()).unwrap(),
+                }
+            }
+            writeln!(out).unwrap();
+        }
     }
 
     fn row(&mut self, row: Result<&'a str>) {
@@ -93,83 +133,116 @@ impl<'a> DiffState<'a> {
             Right(r) => {
                 // When there's an added line after a removed line, we'll want to special case some print cases.
                 // FIXME(oli-obk): also do special printing modes when there are multiple lines that only have minor changes.
-                if let Some(l) = self.prev_left.take() {
-                    let diff = chars(l, r);
-                    let mut seen_l = false;
-                    let mut seen_r = false;
-                    for char in &diff {
-                        match char {
-                            Left(l) if !l.is_whitespace() => seen_l = true,
-                            Right(r) if !r.is_whitespace() => seen_r = true,
-                            _ => {}
-                        }
+                match self.prev_left.take() {
+                    // Without colors the highlighting would be invisible, so print plain lines instead.
+                    Some(l) if self.color => self.print_changed(l, r),
+                    Some(l) => {
+                        self.print_left(l);
+                        self.print_right(r);
                     }
-                    if seen_l && seen_r {
-                        // The line both adds and removes chars, print both lines, but highlight their differences instead of
-                        // drawing the entire line in red/green.
-                        eprint!("{}", "-".red());
-                        for char in &diff {
-                            match *char {
-                                Left(l) => eprint!("{}", l.to_string().red()),
-                                Right(_) => {}
-                                Both(l, _) => eprint!("{l}"),
-                            }
-                        }
-                        eprintln!();
-                        eprint!("{}", "+".green());
-                        for char in diff {
-                            match char {
-                                Left(_) => {}
-                                Right(r) => eprint!("{}", r.to_string().green()),
-                                Both(l, _) => eprint!("{l}"),
-                            }
-                        }
-                        eprintln!();
-                    } else {
-                        // The line only adds or only removes chars, print a single line highlighting their differences.
-                        eprint!("{}", "~".yellow());
-                        for char in diff {
-                            match char {
-                                Left(l) => eprint!("{}", l.to_string().red()),
-                                Both(l, _) => eprint!("{l}"),
-                                Right(r) => eprint!("{}", r.to_string().green()),
-                            }
-                        }
-                        eprintln!();
+                    None => {
+                        self.print_skip();
+                        self.print_right(r);
                     }
-                } else {
-                    self.print_skip();
-                    self.print_right(r);
                 }
             }
         }
     }
 
-    fn finish(self) {
-        self.print_start_skip();
-        self.print_skipped_msg(self.skipped_lines.len().saturating_sub(CONTEXT));
-        eprintln!()
+    fn finish(mut self) -> String {
+        self.print_prev();
+        self.print_skipped(self.context, 0);
+        self.out
+    }
+}
+
+/// Split a line into words, runs of whitespace and single punctuation characters,
+/// so that changes are highlighted at word granularity.
+fn words(line: &str) -> Vec<&str> {
+    let class = |c: char| {
+        if c.is_alphanumeric() || c == '_' {
+            0
+        } else if c.is_whitespace() {
+            1
+        } else {
+            2
+        }
+    };
+    let mut words = vec![];
+    let mut start = 0;
+    let mut prev = None;
+    for (i, c) in line.char_indices() {
+        let class = class(c);
+        if i > start && (prev != Some(class) || class == 2) {
+            words.push(&line[start..i]);
+            start = i;
+        }
+        prev = Some(class);
+    }
+    if start < line.len() {
+        words.push(&line[start..]);
     }
+    words
 }
 
-pub fn print_diff(expected: &[u8], actual: &[u8]) {
-    let expected_str = String::from_utf8_lossy(expected);
-    let actual_str = String::from_utf8_lossy(actual);
+/// Turn output into text that can be diffed and printed without losing information:
+/// bytes that are not valid UTF-8 and control characters other than whitespace become `\xNN`.
+pub(crate) fn escape_bytes(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len());
+    for chunk in ByteSlice::utf8_chunks(bytes) {
+        for c in chunk.valid().chars() {
+            if c.is_ascii_control() && !c.is_ascii_whitespace() {
+                write!(text, "\\x{:02x}", c as u32).unwrap();
+            } else {
+                text.push(c);
+            }
+        }
+        for byte in chunk.invalid() {
+            write!(text, "\\x{byte:02x}").unwrap();
+        }
+    }
+    text
+}
 
-    if expected_str.as_bytes() != expected || actual_str.as_bytes() != actual {
-        eprintln!(
-            "{}",
-            "Non-UTF8 characters in output, diff may be imprecise.".red()
-        );
+/// Render the line diff between `expected` and `actual`, with `context` unchanged lines around
+/// each change. If `color` is set, the changed words of modified lines are highlighted.
+pub(crate) fn render_diff(expected: &[u8], actual: &[u8], context: usize, color: bool) -> String {
+    let mut out = String::new();
+    let mut notes = vec![];
+    if expected.to_str().is_err() || actual.to_str().is_err() {
+        notes.push("Non-UTF8 bytes in output are shown as `\\xNN`.");
+    }
+    // Preserved ANSI escape sequences would be invisible, or change how the diff looks.
+    if expected.contains(&0x1b) || actual.contains(&0x1b) {
+        notes.push("Escape characters in output are shown as `\\x1b`.");
+    }
+    for msg in notes {
+        if color {
+            writeln!(out, "{}", msg.red()).unwrap();
+        } else {
+            writeln!(out, "{msg}").unwrap();
+        }
     }
 
     let pat = |c: char| c.is_whitespace() && c != ' ' && c != '\n' && c != '\r';
-    let expected_str = expected_str.replace(pat, "░");
-    let actual_str = actual_str.replace(pat, "░");
-
-    let mut state = DiffState::default();
+    let expected_str = escape_bytes(expected).replace(pat, "░");
+    let actual_str = escape_bytes(actual).replace(pat, "░");
+
+    let mut state = DiffState {
+        out,
+        context,
+        color,
+        print_start_context: false,
+        skipped_lines: vec![],
+        prev_left: None,
+    };
     for row in lines(&expected_str, &actual_str) {
         state.row(row);
     }
-    state.finish();
+    state.finish()
+}
+
+pub fn print_diff(expected: &[u8], actual: &[u8], context: usize) {
+    let color = colored::control::SHOULD_COLORIZE.should_colorize();
+    eprintln!("{}", render_diff(expected, actual, context, color));
 }
@@ -1,5 +1,5 @@
-use crate::{parser::Pattern, rustc_stderr::Message, Mode};
-use std::{path::PathBuf, process::ExitStatus};
+use crate::{parser::Pattern, rustc_stderr::Message, ApplySuggestions, Mode};
+use std::{path::PathBuf, process::ExitStatus, time::Duration};
 
 /// All the ways in which a test can fail.
 #[derive(Debug)]
@@ -19,6 +19,91 @@ pub enum Error {
         pattern: Pattern,
         /// The line in which the pattern was defined.
         definition_line: usize,
+        /// The line the pattern expected a diagnostic on. `None` for `//@error-in-other-file`.
+        line: Option<usize>,
+        /// The unmatched diagnostics on `line`, or if there were none, on the closest lines
+        /// that had unmatched diagnostics.
+        nearby: Vec<(usize, Vec<Message>)>,
+    },
+    /// A diagnostic code pattern (`//~ ERROR [E0*]`) did not match the code of any diagnostic.
+    CodeNotFound {
+        /// The pattern that was missing a diagnostic
+        pattern: Pattern,
+        /// The line in which the pattern was defined.
+        definition_line: usize,
+        /// The codes of the unmatched diagnostics on the line the pattern refers to.
+        codes: Vec<String>,
+    },
+    /// A pattern with a column (`//~ ERROR@col:17 ...`) only matched diagnostics at other columns.
+    ColumnMismatch {
+        /// The pattern whose column did not match
+        pattern: Pattern,
+        /// The line in which the pattern was defined.
+        definition_line: usize,
+        /// The column the pattern declared.
+        expected: usize,
+        /// The columns of the diagnostics that matched the pattern otherwise.
+        found: Vec<usize>,
+    },
+    /// A pattern with an occurrence count (`//~ ERROR*3: ...`) matched a different
+    /// number of diagnostics than it declared.
+    PatternCountMismatch {
+        /// The pattern whose count did not match
+        pattern: Pattern,
+        /// The line in which the pattern was defined.
+        definition_line: usize,
+        /// The number of occurrences the pattern declared.
+        expected: usize,
+        /// The number of matching diagnostics that were found.
+        found: usize,
+    },
+    /// With `//@check-annotation-order`, an annotation matched a diagnostic that was emitted
+    /// before the diagnostic matched by a preceding annotation on the same line.
+    AnnotationOutOfOrder {
+        /// The pattern that matched out of order.
+        pattern: Pattern,
+        /// The line in which the pattern was defined.
+        definition_line: usize,
+        /// The preceding pattern that matched a later diagnostic.
+        preceding_pattern: Pattern,
+        /// The line in which the preceding pattern was defined.
+        preceding_definition_line: usize,
+    },
+    /// A problem that is reported, but does not fail the test.
+    Warning(Box<Error>),
+    /// Recompiling the output of rustfix emitted diagnostics, as checked by `//@check-fixed`.
+    FixedOutputNotClean {
+        /// The diagnostics emitted by the fixed code.
+        msgs: Vec<Message>,
+    },
+    /// The code produced by rustfix differs from the `.fixed` file.
+    FixedOutputDiffers {
+        /// Which suggestions were applied to produce the code.
+        applied: ApplySuggestions,
+        /// How often suggestions were applied, more than once only for `//@run-rustfix: loop`.
+        iterations: usize,
+        /// The diagnostics of the last compilation of the code, only for `//@run-rustfix: loop`.
+        msgs: Vec<Message>,
+        /// The [`Error::OutputDiffers`] with the actual difference.
+        diff: Box<Error>,
+    },
+    /// `//@run-rustfix: loop` still got suggestions after the maximum number of iterations.
+    RustfixIterationLimit {
+        /// How often suggestions were applied.
+        iterations: usize,
+        /// The diagnostics of the last compilation, which still contained suggestions.
+        msgs: Vec<Message>,
+    },
+    /// Two rustfix suggestions replace the same code, see `//@allow-suggestion-conflicts`.
+    SuggestionConflict {
+        /// The message of the diagnostic with the earlier suggestion.
+        first: String,
+        /// The `line:column-line:column` range replaced by the earlier suggestion.
+        first_span: String,
+        /// The message of the diagnostic with the conflicting suggestion.
+        second: String,
+        /// The `line:column-line:column` range replaced by the conflicting suggestion.
+        second_span: String,
     },
     /// A ui test checking for failure does not have any failure patterns
     NoPatternsFound,
@@ -34,6 +119,20 @@ pub enum Error {
         expected: Vec<u8>,
         /// A command, that when run, causes the output to get blessed instead of erroring.
         bless_command: String,
+        /// How many unchanged lines are shown around each change, see [`Config::diff_context`](crate::Config::diff_context).
+        context: usize,
+    },
+    /// Two runs of the same command had different output, see
+    /// [`Config::check_determinism`](crate::Config::check_determinism).
+    Nondeterministic {
+        /// `stderr` or `stdout`.
+        stream: &'static str,
+        /// The normalized output of the first run.
+        first: Vec<u8>,
+        /// The normalized output of the second run.
+        second: Vec<u8>,
+        /// How many unchanged lines are shown around each change, see [`Config::diff_context`](crate::Config::diff_context).
+        context: usize,
     },
     /// There were errors that don't have a pattern.
     ErrorsWithoutPattern {
@@ -58,6 +157,101 @@ pub enum Error {
     },
     /// This catches crashes of ui tests and reports them along the failed test.
     Bug(String),
+    /// A function of [`Config::stderr_filter_fns`](crate::Config::stderr_filter_fns) or
+    /// similar panicked.
+    FilterFnPanicked {
+        /// The output that was being filtered, like `stderr` or `a.fixed`.
+        kind: String,
+        /// The position of the function in the list of functions.
+        index: usize,
+        /// The panic message.
+        message: String,
+    },
+    /// The test wrote more than [`Config::max_test_output_bytes`](crate::Config::max_test_output_bytes)
+    /// to stdout or stderr and was killed.
+    OutputTooLarge {
+        /// `stdout` or `stderr`.
+        stream: String,
+        /// The limit that was exceeded.
+        limit: usize,
+        /// The beginning of the output.
+        head: Vec<u8>,
+        /// The end of the output up to the point where the test was killed.
+        tail: Vec<u8>,
+    },
+    /// The compiler crashed. Unlike other failures this is never blessed, and it is
+    /// reported in every mode but [`Mode::Panic`](crate::Mode::Panic).
+    InternalCompilerError {
+        /// The panic message of the compiler.
+        message: String,
+        /// The queries the compiler was executing when it crashed, outermost last.
+        query_stack: Vec<String>,
+    },
+    /// An expected output file that no test produces, found because
+    /// [`Config::check_stale_output_files`](crate::Config::check_stale_output_files) is set.
+    StaleOutputFile,
+    /// The test took considerably longer than in the
+    /// [`Config::timing_baseline`](crate::Config::timing_baseline).
+    TimingRegression {
+        /// The duration of the test in the baseline.
+        baseline: Duration,
+        /// The duration of the test in this run, the median of all samples.
+        actual: Duration,
+        /// How often the test ran to measure `actual`.
+        samples: usize,
+    },
+    /// Blessing could not write or remove an expected output file.
+    BlessFailed {
+        /// The expected output file.
+        path: PathBuf,
+        /// Why it could not be written.
+        message: String,
+    },
+    /// A file the test writes, like a [`Config::failure_artifacts_dir`](crate::Config::failure_artifacts_dir)
+    /// artifact, or a file it reads, could not be accessed.
+    Io {
+        /// The file.
+        path: PathBuf,
+        /// Why it could not be accessed.
+        message: String,
+    },
+    /// An aux binary started via `//@run-aux-bin` failed while the test ran, or the test failed.
+    AuxBin {
+        /// Path to the aux file.
+        path: PathBuf,
+        /// The exit status, if the aux binary exited before the test binary did.
+        status: Option<ExitStatus>,
+        /// The stdout of the aux binary.
+        stdout: Vec<u8>,
+        /// The stderr of the aux binary.
+        stderr: Vec<u8>,
+    },
+    /// The [`Config::before_test`](crate::Config::before_test) or
+    /// [`Config::after_test`](crate::Config::after_test) hook failed.
+    TestHook {
+        /// `before_test` or `after_test`.
+        hook: String,
+        /// The error message of the hook.
+        message: String,
+    },
+    /// The [`Flag`](crate::Flag) of a custom comment rejected the output of the test.
+    CustomComment {
+        /// The name the comment was registered under.
+        name: String,
+        /// Why the output was rejected.
+        message: String,
+        /// The line of the comment.
+        line: usize,
+    },
+    /// A build requested via `//@custom-build` failed.
+    CustomBuild {
+        /// The name the build was registered under.
+        name: String,
+        /// Why the build failed.
+        message: String,
+        /// The line in which the build was requested.
+        line: usize,
+    },
     /// An auxiliary build failed with its own set of errors.
     Aux {
         /// Path to the aux file.
@@ -67,6 +261,14 @@ pub enum Error {
         /// The line in which the aux file was requested to be built.
         line: usize,
     },
+    /// The comments in a defaults file of the test's directories are invalid,
+    /// see [`Config::defaults_file_name`](crate::Config::defaults_file_name).
+    Defaults {
+        /// Path to the defaults file.
+        path: PathBuf,
+        /// The errors in the defaults file, with lines that refer to it.
+        errors: Vec<Error>,
+    },
 }
 
 pub(crate) type Errors = Vec<Error>;
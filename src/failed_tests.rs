@@ -0,0 +1,70 @@
+//! Remembering which tests failed, so that the next run can start with them,
+//! see [`Config::run_failed_first`].
+
+use std::path::{Path, PathBuf};
+
+use crate::{relative_to_root, Config};
+
+/// The version of the format of [`FailedTests`]. Files of other versions are ignored.
+const VERSION: u32 = 1;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct FailedTests {
+    version: u32,
+    failed: Vec<FailedTest>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct FailedTest {
+    /// Relative to [`Config::root_dir`].
+    path: PathBuf,
+    revision: String,
+}
+
+fn file(config: &Config) -> PathBuf {
+    config.out_dir.join("failed_tests.json")
+}
+
+/// The tests that failed in the last run, without duplicates and in the order they were run in.
+/// Tests that don't exist anymore are left out.
+pub(crate) fn load(config: &Config) -> Vec<PathBuf> {
+    if !config.run_failed_first {
+        return vec![];
+    }
+    let failed = match std::fs::read(file(config))
+        .ok()
+        .and_then(|json| serde_json::from_slice::<FailedTests>(&json).ok())
+    {
+        Some(failed) if failed.version == VERSION => failed.failed,
+        _ => return vec![],
+    };
+    let mut paths: Vec<PathBuf> = vec![];
+    for test in failed {
+        let path = config.root_dir.join(test.path);
+        if path.exists() && !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+    paths
+}
+
+/// Remember the tests that failed in this run for the next one.
+pub(crate) fn save<'a>(config: &Config, failed: impl Iterator<Item = (&'a Path, &'a str)>) {
+    if !config.run_failed_first {
+        return;
+    }
+    let failed = FailedTests {
+        version: VERSION,
+        failed: failed
+            .map(|(path, revision)| FailedTest {
+                path: relative_to_root(config, path),
+                revision: revision.to_owned(),
+            })
+            .collect(),
+    };
+    // Remembering the failures is only an optimization, so errors are ignored.
+    if let Ok(json) = serde_json::to_vec(&failed) {
+        let _ = std::fs::create_dir_all(&config.out_dir);
+        let _ = std::fs::write(file(config), json);
+    }
+}
@@ -33,23 +33,37 @@ impl Write for Error {
     }
 }
 
+impl Error {
+    /// The workflow command that shows this error.
+    pub(crate) fn command(&self) -> String {
+        let Error {
+            file,
+            line,
+            title,
+            message,
+        } = self;
+        let mut command = format!("::error file={}", escape_property(file));
+        // Without a line, the error is shown at the top of the file.
+        if *line > 0 {
+            write!(command, ",line={line}").unwrap();
+        }
+        write!(command, ",title={}", escape_property(title)).unwrap();
+        let message = message.trim();
+        if message.is_empty() {
+            command.push_str("::no message");
+        } else {
+            write!(command, "::{}", github_action_multiline_escape(message)).unwrap();
+        }
+        command
+    }
+}
+
 impl Drop for Error {
     fn drop(&mut self) {
         if std::env::var_os("GITHUB_ACTION").is_some() {
-            let Error {
-                file,
-                line,
-                title,
-                message,
-            } = self;
-            let message = message.trim();
-            let message = if message.is_empty() {
-                "::no message".into()
-            } else {
-                format!("::{}", github_action_multiline_escape(message))
-            };
-            eprintln!("::error file={file},line={line},title={title}{message}");
-            eprintln!("error file={file},line={line},title={title}{message}");
+            let command = self.command();
+            eprintln!("{command}");
+            eprintln!("{}", &command[2..]);
         }
     }
 }
@@ -66,6 +80,13 @@ fn github_action_multiline_escape(s: &str) -> String {
         .replace('\r', "%0D")
 }
 
+/// Property values like file names and titles additionally end at `,` and `::`.
+fn escape_property(s: &str) -> String {
+    github_action_multiline_escape(s)
+        .replace(':', "%3A")
+        .replace(',', "%2C")
+}
+
 /// All github actions log messages from this call to the Drop of the return value
 /// will be grouped and hidden by default in logs. Note that nesting these does
 /// not really work.
@@ -0,0 +1,174 @@
+//! Matching test paths and revisions against globs, see [`Config::filters`] and [`Config::skip`].
+//!
+//! [`Config::filters`]: crate::Config::filters
+//! [`Config::skip`]: crate::Config::skip
+
+/// A glob like `borrowck/**/*.rs`, matched against paths with `/` separators.
+///
+/// * `*` matches any number of characters except `/`.
+/// * `?` matches a single character except `/`.
+/// * `[abc]`, `[a-z]` and `[!a-z]` match a single character from, or not from, the set.
+/// * `**` as a whole path component matches any number of path components.
+#[derive(Debug)]
+pub(crate) struct Glob(Vec<Component>);
+
+#[derive(Debug)]
+enum Component {
+    /// `**`
+    AnyComponents,
+    Name(Vec<Token>),
+}
+
+#[derive(Debug)]
+enum Token {
+    Char(char),
+    /// `*`
+    AnyChars,
+    /// `?`
+    AnyChar,
+    /// `[...]`, with its character ranges.
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+impl Glob {
+    pub(crate) fn parse(pattern: &str) -> Result<Self, String> {
+        if pattern.is_empty() {
+            return Err("the glob is empty".into());
+        }
+        pattern
+            .split('/')
+            .map(|component| {
+                if component == "**" {
+                    return Ok(Component::AnyComponents);
+                }
+                if component.contains("**") {
+                    return Err("`**` must be a whole path component, like `a/**/b`".to_string());
+                }
+                let mut tokens = vec![];
+                let mut chars = component.chars();
+                while let Some(c) = chars.next() {
+                    tokens.push(match c {
+                        '*' => Token::AnyChars,
+                        '?' => Token::AnyChar,
+                        '[' => parse_class(&mut chars)?,
+                        ']' => return Err("`]` without a matching `[`".into()),
+                        c => Token::Char(c),
+                    });
+                }
+                Ok(Component::Name(tokens))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    pub(crate) fn matches(&self, path: &str) -> bool {
+        let components: Vec<_> = path.split('/').collect();
+        match_components(&self.0, &components)
+    }
+}
+
+/// A glob of [`Config::filters`](crate::Config::filters) or [`Config::skip`](crate::Config::skip),
+/// optionally followed by `::` and a glob for the revision, like `borrowck/*.rs::edition*`.
+#[derive(Debug)]
+pub(crate) struct TestGlob {
+    path: Glob,
+    revision: Option<Glob>,
+}
+
+impl TestGlob {
+    pub(crate) fn parse(pattern: &str) -> Result<Self, String> {
+        let (path, revision) = match pattern.split_once("::") {
+            Some((path, revision)) => (path, Some(revision)),
+            None => (pattern, None),
+        };
+        Ok(Self {
+            path: Glob::parse(path)?,
+            revision: revision.map(Glob::parse).transpose()?,
+        })
+    }
+
+    /// Whether the test at `path`, relative to the root directory, matches in `revision`.
+    /// Globs without a revision match all revisions, and globs with one never match tests
+    /// without revisions.
+    pub(crate) fn matches(&self, path: &str, revision: &str) -> bool {
+        self.path.matches(path)
+            && self
+                .revision
+                .as_ref()
+                .map_or(true, |glob| !revision.is_empty() && glob.matches(revision))
+    }
+}
+
+/// Parse the rest of a `[...]` class, after the `[`.
+fn parse_class(chars: &mut std::str::Chars<'_>) -> Result<Token, String> {
+    let negated = chars.as_str().starts_with('!');
+    if negated {
+        chars.next();
+    }
+    let mut ranges = vec![];
+    loop {
+        let start = match chars.next() {
+            Some(']') if !ranges.is_empty() => break,
+            Some(c) => c,
+            None => return Err("`[` without a matching `]`".into()),
+        };
+        if chars.as_str().starts_with('-') && !chars.as_str()[1..].starts_with(']') {
+            chars.next();
+            let end = chars
+                .next()
+                .ok_or_else(|| "`[` without a matching `]`".to_string())?;
+            if end < start {
+                return Err(format!("the range `{start}-{end}` is empty"));
+            }
+            ranges.push((start, end));
+        } else {
+            ranges.push((start, start));
+        }
+    }
+    Ok(Token::Class { negated, ranges })
+}
+
+fn match_components(glob: &[Component], path: &[&str]) -> bool {
+    match glob.split_first() {
+        None => path.is_empty(),
+        Some((Component::AnyComponents, rest)) => {
+            (0..=path.len()).any(|skip| match_components(rest, &path[skip..]))
+        }
+        Some((Component::Name(tokens), rest)) => match path.split_first() {
+            Some((name, path)) => {
+                let name: Vec<_> = name.chars().collect();
+                match_name(tokens, &name) && match_components(rest, path)
+            }
+            None => false,
+        },
+    }
+}
+
+fn match_name(tokens: &[Token], name: &[char]) -> bool {
+    match tokens.split_first() {
+        None => name.is_empty(),
+        Some((Token::AnyChars, rest)) => {
+            (0..=name.len()).any(|skip| match_name(rest, &name[skip..]))
+        }
+        Some((token, rest)) => match name.split_first() {
+            Some((&c, name)) => {
+                let matches = match token {
+                    Token::Char(expected) => c == *expected,
+                    Token::AnyChar => true,
+                    Token::Class { negated, ranges } => {
+                        ranges
+                            .iter()
+                            .any(|&(start, end)| (start..=end).contains(&c))
+                            != *negated
+                    }
+                    Token::AnyChars => unreachable!(),
+                };
+                matches && match_name(rest, name)
+            }
+            None => false,
+        },
+    }
+}
@@ -0,0 +1,386 @@
+//! Skipping tests whose inputs did not change since they last passed, see [`Config::incremental`].
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Debug,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    aux_file, canonicalize, defaults_files, expected_output_base, output_files,
+    parse_comments_in_file, parser::Comments, program_version, stdin_files, Config,
+    OutputConflictHandling, TestKind, TestResult,
+};
+
+/// The first line of the cache file. Cache files starting with anything else were written by
+/// another version of ui_test and are ignored.
+const VERSION: &str = "ui_test incremental cache v2";
+
+/// FNV-1a, as the hashes are compared between runs, and unlike the hasher of the standard
+/// library, its results don't change with the version of Rust.
+pub(crate) struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// The hashes of the inputs of the tests that passed in earlier runs.
+#[derive(Default)]
+pub(crate) struct TestCache {
+    /// Where the cache is stored, or `None` if [`Config::incremental`] is disabled.
+    file: Option<PathBuf>,
+    /// The hash of the inputs of every test that passed, by the hash of its path and revision.
+    passed: HashMap<u64, u64>,
+}
+
+impl TestCache {
+    /// Read the results of earlier runs from the cache in [`Config::out_dir`].
+    pub(crate) fn load(config: &Config) -> Self {
+        // Passing the determinism check says nothing about the expected output.
+        if !config.incremental || config.check_determinism || config.test_kind != TestKind::File {
+            return Self::default();
+        }
+        let file = config.out_dir.join("incremental.cache");
+        let passed = std::fs::read_to_string(&file)
+            .ok()
+            .and_then(|cache| parse(&cache))
+            .unwrap_or_default();
+        Self {
+            file: Some(file),
+            passed,
+        }
+    }
+
+    /// The hash of everything the result of a test depends on, or `None` if the test must
+    /// always run.
+    pub(crate) fn inputs_hash(
+        &self,
+        path: &Path,
+        config: &Config,
+        comments: &Comments,
+        revision: &str,
+    ) -> Option<u64> {
+        self.file.as_ref()?;
+        // We can't tell whether the artifacts of custom builds changed.
+        if comments
+            .for_revision(revision)
+            .any(|r| !r.custom_builds.is_empty())
+        {
+            return None;
+        }
+        // Nor whether closures changed.
+        let custom_comments = comments
+            .for_revision(revision)
+            .any(|r| !r.custom.is_empty());
+        if config.incremental_key.is_none() && (custom_comments || has_closures(config)) {
+            return None;
+        }
+        let mut hasher = StableHasher::default();
+        revision.hash(&mut hasher);
+        std::fs::read(path).ok().hash(&mut hasher);
+        for file in defaults_files(config, path) {
+            std::fs::read(file).ok().hash(&mut hasher);
+        }
+        for file in output_files(&expected_output_base(config, path), comments, revision) {
+            std::fs::read(file).ok().hash(&mut hasher);
+        }
+        let stdin = comments
+            .for_revision(revision)
+            .filter_map(|r| r.stdin.as_ref());
+        for file in stdin
+            .map(|(file, _)| path.parent().unwrap().join(file))
+            .chain(stdin_files(path, revision))
+        {
+            std::fs::read(file).ok().hash(&mut hasher);
+        }
+        let aux_dir = path.parent().unwrap().join("auxiliary");
+        hash_aux_files(
+            config,
+            &aux_dir,
+            comments,
+            revision,
+            &mut HashSet::new(),
+            &mut hasher,
+        );
+        hash_config(config, &mut hasher);
+        Some(hasher.finish())
+    }
+
+    /// Whether the test passed in an earlier run with the same inputs. Blessing or
+    /// [`Config::force_rebuild`] run all tests anyway.
+    pub(crate) fn passed(&self, path: &Path, revision: &str, hash: u64, config: &Config) -> bool {
+        !config.force_rebuild
+            && !matches!(
+                config.output_conflict_handling,
+                OutputConflictHandling::Bless
+            )
+            && self.passed.get(&test_key(path, revision)) == Some(&hash)
+    }
+
+    /// Remember which tests passed for later runs, and forget the ones that failed.
+    pub(crate) fn save<'a>(
+        mut self,
+        results: impl Iterator<Item = (&'a Path, &'a str, &'a TestResult, u64)>,
+    ) {
+        let file = match self.file {
+            Some(file) => file,
+            None => return,
+        };
+        for (path, revision, result, hash) in results {
+            let key = test_key(path, revision);
+            match result {
+                TestResult::Ok | TestResult::Cached | TestResult::Flaky { .. } => {
+                    self.passed.insert(key, hash);
+                }
+                TestResult::Errored { .. } => {
+                    self.passed.remove(&key);
+                }
+                TestResult::Ignored { .. } | TestResult::Filtered => {}
+            }
+        }
+        let mut contents = format!("{VERSION}\n");
+        let mut passed: Vec<_> = self.passed.into_iter().collect();
+        passed.sort();
+        for (key, hash) in passed {
+            contents.push_str(&format!("{key:016x} {hash:016x}\n"));
+        }
+        // Written to a temporary file first, so that other processes sharing the
+        // `out_dir` never see a partial cache file.
+        let tmp = file.with_extension(format!("{}.tmp", std::process::id()));
+        // The cache is only an optimization, failing to write it just means running all tests
+        // next time.
+        let _ = std::fs::create_dir_all(file.parent().unwrap());
+        if std::fs::write(&tmp, contents).is_ok() && std::fs::rename(&tmp, &file).is_err() {
+            let _ = std::fs::remove_file(&tmp);
+        }
+    }
+}
+
+fn parse(cache: &str) -> Option<HashMap<u64, u64>> {
+    let mut lines = cache.lines();
+    if lines.next()? != VERSION {
+        return None;
+    }
+    lines
+        .map(|line| {
+            let (key, hash) = line.split_once(' ')?;
+            Some((
+                u64::from_str_radix(key, 16).ok()?,
+                u64::from_str_radix(hash, 16).ok()?,
+            ))
+        })
+        .collect()
+}
+
+fn test_key(path: &Path, revision: &str) -> u64 {
+    let mut hasher = StableHasher::default();
+    path.to_string_lossy().hash(&mut hasher);
+    revision.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Hash the sources of all aux files a test depends on, including the aux files of aux files.
+fn hash_aux_files(
+    config: &Config,
+    aux_dir: &Path,
+    comments: &Comments,
+    revision: &str,
+    seen: &mut HashSet<PathBuf>,
+    hasher: &mut StableHasher,
+) {
+    for rev in comments.for_revision(revision) {
+        let aux_builds = rev.aux_builds.iter().map(|(aux, ..)| aux);
+        let aux_bins = rev.run_aux_bins.iter().map(|(aux, ..)| aux);
+        for aux in aux_builds.chain(aux_bins) {
+            let aux_file = aux_file(aux_dir, aux);
+            if !seen.insert(canonicalize(&aux_file)) {
+                continue;
+            }
+            std::fs::read(&aux_file).ok().hash(hasher);
+            if let Ok(aux_comments) = parse_comments_in_file(&aux_file, config) {
+                hash_aux_files(
+                    config,
+                    aux_file.parent().unwrap(),
+                    &aux_comments,
+                    "",
+                    seen,
+                    hasher,
+                );
+            }
+        }
+    }
+}
+
+/// Whether the config has closures that change the result of every test.
+fn has_closures(config: &Config) -> bool {
+    !config.stderr_filter_fns.is_empty()
+        || !config.stdout_filter_fns.is_empty()
+        || !config.fixed_filter_fns.is_empty()
+        || config.before_test.is_some()
+        || config.after_test.is_some()
+        || matches!(
+            config.output_conflict_handling,
+            OutputConflictHandling::Custom(_)
+        )
+}
+
+/// Everything about the configuration that affects the result of a test: its flags, filters,
+/// target, the version of the program, and the dependencies built for the tests.
+fn hash_config(config: &Config, hasher: &mut StableHasher) {
+    // Every field is listed, so that new ones have to be either hashed or explicitly skipped.
+    let Config {
+        host,
+        target,
+        stderr_filters,
+        stdout_filters,
+        builtin_normalizations,
+        ansi,
+        compare,
+        comment_start,
+        comment_start_by_extension,
+        defaults_file_name,
+        test_extensions: _,
+        excluded_dirs: _,
+        diff_context: _,
+        placeholders,
+        // Closures, see `has_closures`.
+        stderr_filter_fns: _,
+        stdout_filter_fns: _,
+        fixed_filter_fns: _,
+        root_dir,
+        test_kind,
+        mode,
+        program,
+        cfgs,
+        output_conflict_handling,
+        dependencies_crate_manifest_path,
+        dependency_builder,
+        dependency_features,
+        dependency_no_default_features,
+        dependency_config_overrides,
+        // Tests using these always run or are covered by `incremental_key`.
+        custom_builds: _,
+        custom_comments: _,
+        custom_conditions,
+        program_debug_assertions,
+        expand_env_vars,
+        before_test: _,
+        after_test: _,
+        // These only decide how, not whether, tests run.
+        num_test_threads: _,
+        test_attempts: _,
+        shard: _,
+        filters: _,
+        skip: _,
+        exact: _,
+        list: _,
+        check_determinism: _,
+        max_test_output_bytes,
+        out_dir,
+        max_test_out_dir_len,
+        cleanup: _,
+        force_rebuild: _,
+        incremental: _,
+        incremental_key,
+        failure_artifacts_dir: _,
+        run_failed_first: _,
+        check_stale_output_files: _,
+        json_output: _,
+        markdown_report: _,
+        markdown_max_diff_len: _,
+        format: _,
+        slowest_tests: _,
+        ignored_summary: _,
+        warn_on_ignored: _,
+        timing_baseline,
+        timing_threshold,
+        timing_min_delta,
+        timing_regressions,
+        timing_samples: _,
+        bless_timings: _,
+        expected_output_dir,
+        edition,
+        strict_annotation_counts,
+        check_fixed,
+        apply_suggestions,
+        rustfix_max_iterations,
+        check_annotations_in_yolo,
+        unused_revisions,
+        diagnostics_parser,
+    } = config;
+    let custom_conditions: BTreeMap<_, _> = custom_conditions.iter().collect();
+    let fields: &[&dyn Debug] = &[
+        host,
+        target,
+        stderr_filters,
+        stdout_filters,
+        builtin_normalizations,
+        ansi,
+        compare,
+        comment_start,
+        comment_start_by_extension,
+        defaults_file_name,
+        placeholders,
+        root_dir,
+        test_kind,
+        mode,
+        program,
+        cfgs,
+        output_conflict_handling,
+        dependencies_crate_manifest_path,
+        dependency_builder,
+        dependency_features,
+        dependency_no_default_features,
+        dependency_config_overrides,
+        &custom_conditions,
+        program_debug_assertions,
+        expand_env_vars,
+        max_test_output_bytes,
+        out_dir,
+        max_test_out_dir_len,
+        incremental_key,
+        timing_baseline,
+        timing_threshold,
+        timing_min_delta,
+        timing_regressions,
+        expected_output_dir,
+        edition,
+        strict_annotation_counts,
+        check_fixed,
+        apply_suggestions,
+        rustfix_max_iterations,
+        check_annotations_in_yolo,
+        unused_revisions,
+        diagnostics_parser,
+    ];
+    for field in fields {
+        format!("{field:?}").hash(hasher);
+    }
+    program_version(config.program.program.as_os_str()).hash(hasher);
+    // Rebuilt dependencies keep their path, but not their size and modification time.
+    for pair in config.program.args.windows(2) {
+        if let (true, Some((_, path))) = (
+            pair[0] == "--extern",
+            pair[1].to_str().and_then(|dep| dep.split_once('=')),
+        ) {
+            let metadata = std::fs::metadata(path).ok();
+            metadata.as_ref().map(|m| m.len()).hash(hasher);
+            metadata.and_then(|m| m.modified().ok()).hash(hasher);
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! Stopping a test run on Ctrl-C, killing the processes it spawned.
+
+use std::{
+    collections::HashMap,
+    process::Child,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+};
+
+/// How often Ctrl-C was pressed during the current test run.
+static INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+/// How many test runs are in progress. Ctrl-C outside of test runs exits immediately.
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+/// Restores the Ctrl-C handler and panic hook that were installed before the test runs.
+type Restore = Box<dyn FnOnce() + Send>;
+
+lazy_static::lazy_static! {
+    /// The processes spawned by [`run_command`](crate::run_command) that are still running,
+    /// by their id.
+    static ref CHILDREN: Mutex<HashMap<u32, Child>> = Default::default();
+    /// Notified on Ctrl-C and whenever a test run ends, see [`watch`].
+    static ref WAKE: (Mutex<()>, Condvar) = Default::default();
+    /// Set while test runs are in progress, also serializes starting and ending them.
+    static ref RESTORE: Mutex<Option<Restore>> = Default::default();
+}
+
+/// Exit without waiting for anything on the second Ctrl-C, or on the first one outside of
+/// a test run. Only does things that are allowed in a signal handler.
+fn on_interrupt() {
+    if INTERRUPTS.fetch_add(1, Ordering::SeqCst) > 0 || RUNNING.load(Ordering::SeqCst) == 0 {
+        imp::exit();
+    }
+    imp::notify();
+}
+
+/// Wake up the watchers of all test runs.
+fn wake() {
+    let _lock = WAKE.0.lock().unwrap();
+    WAKE.1.notify_all();
+}
+
+/// Whether Ctrl-C was pressed during the current test run.
+pub(crate) fn interrupted() -> bool {
+    INTERRUPTS.load(Ordering::SeqCst) > 0
+}
+
+/// Install the Ctrl-C handler and a panic hook, and return how to restore the previous ones.
+fn install() -> Restore {
+    let restore_handler = imp::install();
+    let previous_hook = Arc::new(std::panic::take_hook());
+    let hook = previous_hook.clone();
+    std::panic::set_hook(Box::new(move |info| {
+        // Tests whose processes were killed may panic, which is not worth reporting.
+        if !(interrupted() && RUNNING.load(Ordering::SeqCst) > 0) {
+            hook(info)
+        }
+    }));
+    Box::new(move || {
+        restore_handler();
+        // The hook can't be changed while panicking, but outside of test runs it does
+        // the same as the previous one anyway.
+        if !std::thread::panicking() {
+            drop(std::panic::take_hook());
+            std::panic::set_hook(Box::new(move |info| previous_hook(info)));
+        }
+    })
+}
+
+/// Handle Ctrl-C while `f` runs: once it is pressed, [`interrupted`] returns `true` and all
+/// child processes are killed, until `f` returns. The handler is only installed while `f`
+/// runs.
+pub(crate) fn watch<T>(f: impl FnOnce() -> T) -> T {
+    {
+        let mut restore = RESTORE.lock().unwrap();
+        if RUNNING.fetch_add(1, Ordering::SeqCst) == 0 {
+            INTERRUPTS.store(0, Ordering::SeqCst);
+            *restore = Some(install());
+        }
+    }
+    let done = AtomicBool::new(false);
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            let mut lock = WAKE.0.lock().unwrap();
+            while !done.load(Ordering::SeqCst) && !interrupted() {
+                lock = WAKE.1.wait(lock).unwrap();
+            }
+            drop(lock);
+            if interrupted() && !done.load(Ordering::SeqCst) {
+                eprintln!();
+                eprintln!("interrupted, press Ctrl-C again to exit immediately");
+                // Processes spawned from now on are killed by `track`.
+                kill_children();
+            }
+        });
+        // Stop the watcher even if `f` panics, otherwise the scope never ends.
+        let _done = Done(&done);
+        f()
+    })
+}
+
+/// Ends the test run started by [`watch`] when dropped.
+struct Done<'a>(&'a AtomicBool);
+
+impl Drop for Done<'_> {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+        wake();
+        let mut restore = RESTORE.lock().unwrap();
+        if RUNNING.fetch_sub(1, Ordering::SeqCst) == 1 {
+            if let Some(restore) = restore.take() {
+                restore();
+            }
+        }
+    }
+}
+
+/// Track `child`, so that it is killed on Ctrl-C. Returns the id to [`kill`] or [`untrack`] it.
+pub(crate) fn track(mut child: Child) -> u32 {
+    let id = child.id();
+    let mut children = CHILDREN.lock().unwrap();
+    // The watcher only kills the processes that were already tracked when Ctrl-C was pressed.
+    if interrupted() && RUNNING.load(Ordering::SeqCst) > 0 {
+        let _ = child.kill();
+    }
+    children.insert(id, child);
+    id
+}
+
+/// Stop tracking the child with the given id, e.g. in order to wait for it.
+pub(crate) fn untrack(id: u32) -> Child {
+    CHILDREN.lock().unwrap().remove(&id).unwrap()
+}
+
+/// Kill the child with the given id.
+pub(crate) fn kill(id: u32) {
+    if let Some(child) = CHILDREN.lock().unwrap().get_mut(&id) {
+        let _ = child.kill();
+    }
+}
+
+/// Kill all tracked children. They are still waited for by whoever spawned them.
+pub(crate) fn kill_children() {
+    for child in CHILDREN.lock().unwrap().values_mut() {
+        let _ = child.kill();
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::sync::{
+        atomic::{AtomicI32, Ordering},
+        Once,
+    };
+
+    /// The write end of the pipe that wakes up the watchers, as signal handlers can't use
+    /// locks. `-1` if the pipe could not be created.
+    static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+    extern "C" fn handler(_signal: libc::c_int) {
+        super::on_interrupt();
+    }
+
+    pub(super) fn notify() {
+        let pipe = PIPE.load(Ordering::SeqCst);
+        if pipe >= 0 {
+            unsafe { libc::write(pipe, [0u8].as_ptr().cast(), 1) };
+        }
+    }
+
+    pub(super) fn install() -> impl FnOnce() + Send {
+        static PIPE_READER: Once = Once::new();
+        PIPE_READER.call_once(|| {
+            let mut fds = [0; 2];
+            if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+                return;
+            }
+            PIPE.store(fds[1], Ordering::SeqCst);
+            std::thread::spawn(move || {
+                let mut byte = 0u8;
+                loop {
+                    match unsafe { libc::read(fds[0], (&mut byte as *mut u8).cast(), 1) } {
+                        1 => super::wake(),
+                        -1 if std::io::Error::last_os_error().kind()
+                            == std::io::ErrorKind::Interrupted => {}
+                        _ => break,
+                    }
+                }
+            });
+        });
+        let handler: extern "C" fn(libc::c_int) = handler;
+        let previous = unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) };
+        move || {
+            unsafe { libc::signal(libc::SIGINT, previous) };
+        }
+    }
+
+    pub(super) fn exit() {
+        // `std::process::exit` runs destructors and flushes buffers, which may deadlock
+        // inside a signal handler.
+        unsafe { libc::_exit(130) }
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use winapi::shared::minwindef::{BOOL, DWORD, FALSE, TRUE};
+    use winapi::um::wincon::{CTRL_BREAK_EVENT, CTRL_C_EVENT};
+
+    unsafe extern "system" fn handler(event: DWORD) -> BOOL {
+        match event {
+            CTRL_C_EVENT | CTRL_BREAK_EVENT => {
+                super::on_interrupt();
+                TRUE
+            }
+            // Let the default handler deal with closing the console and logging off.
+            _ => FALSE,
+        }
+    }
+
+    pub(super) fn notify() {
+        // Console control handlers run on their own thread, so they can take locks.
+        super::wake()
+    }
+
+    pub(super) fn install() -> impl FnOnce() + Send {
+        unsafe { winapi::um::consoleapi::SetConsoleCtrlHandler(Some(handler), TRUE) };
+        || {
+            unsafe { winapi::um::consoleapi::SetConsoleCtrlHandler(Some(handler), FALSE) };
+        }
+    }
+
+    pub(super) fn exit() {
+        // The exit code of processes terminated by Ctrl-C.
+        std::process::exit(0xC000013Au32 as i32)
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod imp {
+    pub(super) fn notify() {
+        super::wake()
+    }
+
+    pub(super) fn install() -> impl FnOnce() + Send {
+        || {}
+    }
+
+    pub(super) fn exit() {
+        std::process::exit(130)
+    }
+}
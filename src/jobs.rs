@@ -0,0 +1,205 @@
+//! Limiting how many tests and aux builds run at the same time, see [`Config::num_test_threads`].
+//!
+//! [`Config::num_test_threads`]: crate::Config::num_test_threads
+
+use std::{
+    num::NonZeroUsize,
+    sync::{Condvar, Mutex},
+};
+
+/// The slots for running tests and aux builds. When ui_test runs under GNU make with a jobserver,
+/// every slot but one additionally needs a token from make.
+pub(crate) struct Jobs {
+    state: Mutex<State>,
+    freed: Condvar,
+    jobserver: Option<jobserver::Client>,
+}
+
+struct State {
+    /// How many slots are unused.
+    free: usize,
+    /// Whether the token every process gets from make for free is unused.
+    implicit_token: bool,
+}
+
+/// A slot to run a test or aux build in. It is released when dropped.
+pub(crate) struct Job<'a> {
+    jobs: &'a Jobs,
+    token: Token,
+}
+
+enum Token {
+    /// Only the limit of ui_test itself applies.
+    None,
+    /// The token every process gets from make for free.
+    Implicit,
+    /// A token read from make's jobserver, which must be written back.
+    Make(u8),
+}
+
+impl Jobs {
+    /// Allow `threads` tests or aux builds to run at the same time, and join make's jobserver if
+    /// it is available.
+    pub(crate) fn new(threads: NonZeroUsize) -> Self {
+        Self {
+            state: Mutex::new(State {
+                free: threads.get(),
+                implicit_token: true,
+            }),
+            freed: Condvar::new(),
+            jobserver: jobserver::Client::from_env(),
+        }
+    }
+
+    /// Wait until a test or aux build may run. Callers must not wait for other jobs while they
+    /// hold a slot, as these jobs may be waiting for the slot.
+    pub(crate) fn acquire(&self) -> Job<'_> {
+        let mut state = self.state.lock().unwrap();
+        while state.free == 0 {
+            state = self.freed.wait(state).unwrap();
+        }
+        state.free -= 1;
+        let token = match &self.jobserver {
+            None => Token::None,
+            Some(_) if state.implicit_token => {
+                state.implicit_token = false;
+                Token::Implicit
+            }
+            Some(jobserver) => {
+                drop(state);
+                // A broken jobserver is ignored, only our own limit applies then.
+                jobserver.acquire().map_or(Token::None, Token::Make)
+            }
+        };
+        Job { jobs: self, token }
+    }
+}
+
+impl Drop for Job<'_> {
+    fn drop(&mut self) {
+        let mut state = self.jobs.state.lock().unwrap();
+        match self.token {
+            Token::None => {}
+            Token::Implicit => state.implicit_token = true,
+            Token::Make(byte) => self.jobs.jobserver.as_ref().unwrap().release(byte),
+        }
+        state.free += 1;
+        self.jobs.freed.notify_one();
+    }
+}
+
+#[cfg(unix)]
+mod jobserver {
+    use std::{
+        fs::File,
+        io::{Read, Write},
+        mem::ManuallyDrop,
+        os::unix::io::{AsRawFd, FromRawFd},
+    };
+
+    /// A client of the jobserver that GNU make passes to its child processes via `MAKEFLAGS`.
+    pub(super) struct Client {
+        read: ManuallyDrop<File>,
+        write: ManuallyDrop<File>,
+        /// Whether we opened the files ourselves, and not make. Make's file descriptors are
+        /// never closed, as other jobserver clients in this process may use them.
+        owned: bool,
+    }
+
+    impl Client {
+        pub(super) fn from_env() -> Option<Self> {
+            let makeflags = std::env::var("MAKEFLAGS").ok()?;
+            // Older versions of make call it `--jobserver-fds`, the last one wins.
+            let auth = makeflags.split_whitespace().rev().find_map(|flag| {
+                flag.strip_prefix("--jobserver-auth=")
+                    .or_else(|| flag.strip_prefix("--jobserver-fds="))
+            })?;
+            if let Some(fifo) = auth.strip_prefix("fifo:") {
+                let read = File::options().read(true).write(true).open(fifo).ok()?;
+                let write = read.try_clone().ok()?;
+                return Some(Self {
+                    read: ManuallyDrop::new(read),
+                    write: ManuallyDrop::new(write),
+                    owned: true,
+                });
+            }
+            let (read, write) = auth.split_once(',')?;
+            let read: i32 = read.parse().ok()?;
+            let write: i32 = write.parse().ok()?;
+            // Make only passes the file descriptors to recipes it considers to be make
+            // invocations, otherwise they are closed or may even be reused for other files.
+            for fd in [read, write] {
+                if fd < 0 || unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+                    return None;
+                }
+            }
+            // SAFETY: the file descriptors are open, and make passed them to us for this purpose.
+            let (read, write) = unsafe { (File::from_raw_fd(read), File::from_raw_fd(write)) };
+            Some(Self {
+                read: ManuallyDrop::new(read),
+                write: ManuallyDrop::new(write),
+                owned: false,
+            })
+        }
+
+        /// Wait for a token from make.
+        pub(super) fn acquire(&self) -> Option<u8> {
+            let mut byte = [0];
+            loop {
+                match (&*self.read).read(&mut byte) {
+                    Ok(1) => return Some(byte[0]),
+                    Ok(_) => return None,
+                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                        // Make may have made the pipe non-blocking.
+                        let mut fd = libc::pollfd {
+                            fd: self.read.as_raw_fd(),
+                            events: libc::POLLIN,
+                            revents: 0,
+                        };
+                        unsafe { libc::poll(&mut fd, 1, -1) };
+                    }
+                    Err(_) => return None,
+                }
+            }
+        }
+
+        /// Give a token back to make.
+        pub(super) fn release(&self, byte: u8) {
+            // If make is gone, nobody is waiting for the token anymore.
+            let _ = (&*self.write).write_all(&[byte]);
+        }
+    }
+
+    impl Drop for Client {
+        fn drop(&mut self) {
+            if self.owned {
+                // SAFETY: the files are not used anymore.
+                unsafe {
+                    ManuallyDrop::drop(&mut self.read);
+                    ManuallyDrop::drop(&mut self.write);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod jobserver {
+    /// Make's jobserver is only supported on unix.
+    pub(super) enum Client {}
+
+    impl Client {
+        pub(super) fn from_env() -> Option<Self> {
+            None
+        }
+
+        pub(super) fn acquire(&self) -> Option<u8> {
+            match *self {}
+        }
+
+        pub(super) fn release(&self, _byte: u8) {
+            match *self {}
+        }
+    }
+}
@@ -2,6 +2,7 @@
     clippy::enum_variant_names,
     clippy::useless_format,
     clippy::too_many_arguments,
+    clippy::result_large_err,
     rustc::internal
 )]
 #![deny(missing_docs)]
@@ -12,41 +13,66 @@ use bstr::ByteSlice;
 pub use color_eyre;
 use color_eyre::eyre::{eyre, Result};
 use crossbeam_channel::{unbounded, Receiver, Sender};
-use parser::{ErrorMatch, Revisioned};
+use parser::{ErrorMatch, Pattern, Revisioned};
 use regex::bytes::Regex;
-use rustc_stderr::{Diagnostics, Level, Message};
 use status_emitter::StatusEmitter;
 use std::borrow::Cow;
-use std::collections::{HashSet, VecDeque};
-use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf, Prefix};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
+use crate::glob::TestGlob;
+use crate::incremental::{StableHasher, TestCache};
+use crate::jobs::Jobs;
 use crate::parser::{Comments, Condition};
+use crate::timing_baseline::TimingBaseline;
 
+mod ansi;
+mod bless_command;
+mod build;
 mod cmd;
 mod config;
+mod custom_comments;
 mod dependencies;
 mod diff;
 mod error;
+mod failed_tests;
 pub mod github_actions;
+mod glob;
+mod incremental;
+mod interrupt;
+mod jobs;
 mod mode;
 mod parser;
+mod placeholders;
 mod rustc_stderr;
 pub mod status_emitter;
 #[cfg(test)]
 mod tests;
+mod timing_baseline;
 
+pub use build::*;
 pub use cmd::*;
 pub use config::*;
+pub use custom_comments::*;
 pub use error::*;
 pub use mode::*;
+pub use rustc_stderr::{
+    Diagnostics, DiagnosticsParser, HumanDiagnostics, Level, Location, MacroExpansion, Message,
+    RustcDiagnostics, SpanLabel,
+};
 
 /// A filter's match rule.
 #[derive(Clone, Debug)]
 pub enum Match {
-    /// If the regex matches, the filter applies
-    Regex(Regex),
+    /// If the regex matches, the filter applies. The regex is shared by the clones of the filter,
+    /// as cloning a [`Regex`] is comparably expensive and configs are cloned for every test.
+    Regex(Arc<Regex>),
     /// If the exact byte sequence is found, the filter applies
     Exact(Vec<u8>),
 }
@@ -61,26 +87,31 @@ impl Match {
 
 impl From<&'_ Path> for Match {
     fn from(v: &Path) -> Self {
-        let mut v = v.display().to_string();
-        // Normalize away windows canonicalized paths.
-        if v.starts_with(r#"\\?\"#) {
-            v.drain(0..4);
-        }
-        let mut v = v.into_bytes();
-        // Normalize paths on windows to use slashes instead of backslashes,
-        // So that paths are rendered the same on all systems.
-        for c in &mut v {
-            if *c == b'\\' {
-                *c = b'/';
-            }
+        Self::Exact(path_bytes(v))
+    }
+}
+
+/// The path as it appears in the output after normalizing backslashes.
+fn path_bytes(path: &Path) -> Vec<u8> {
+    let mut v = path.display().to_string();
+    // Normalize away windows canonicalized paths.
+    if v.starts_with(r#"\\?\"#) {
+        v.drain(0..4);
+    }
+    let mut v = v.into_bytes();
+    // Normalize paths on windows to use slashes instead of backslashes,
+    // So that paths are rendered the same on all systems.
+    for c in &mut v {
+        if *c == b'\\' {
+            *c = b'/';
         }
-        Self::Exact(v)
     }
+    v
 }
 
 impl From<Regex> for Match {
     fn from(v: Regex) -> Self {
-        Self::Regex(v)
+        Self::Regex(Arc::new(v))
     }
 }
 
@@ -91,17 +122,59 @@ pub type Filter = Vec<(Match, &'static [u8])>;
 pub fn run_tests(config: Config) -> Result<()> {
     eprintln!("   Compiler: {}", config.program.display());
 
-    let name = config.root_dir.display().to_string();
+    let status_emitter = default_status_emitter(&config)?;
 
-    run_tests_generic(
-        config,
-        default_file_filter,
-        default_per_file_config,
-        (status_emitter::Text, status_emitter::Gha::<true> { name }),
-    )
+    match config.test_kind {
+        TestKind::File => run_tests_generic(
+            config.clone(),
+            |path| config.has_test_extension(path),
+            default_per_file_config,
+            status_emitter,
+        ),
+        TestKind::CargoProject => run_tests_generic(config, |_| true, |_, _| None, status_emitter),
+    }
+}
+
+/// The status emitter used by `run_tests`, as selected by [`Config::format`],
+/// [`Config::json_output`] and [`Config::markdown_report`].
+fn default_status_emitter(config: &Config) -> Result<Box<dyn StatusEmitter + Send>> {
+    let name = config.root_dir.display().to_string();
+    let text: Box<dyn StatusEmitter + Send> = match config.format {
+        Format::Pretty => Box::new(status_emitter::Text),
+        Format::Terse => Box::new(status_emitter::Quiet::default()),
+        Format::Json => Box::new((
+            status_emitter::Text,
+            status_emitter::Libtest::new().root_dir(&config.root_dir),
+        )),
+        Format::Quiet => Box::new(status_emitter::OnlyFailures::default()),
+    };
+    let status_emitter = (text, status_emitter::Gha::<true> { name });
+    let status_emitter: Box<dyn StatusEmitter + Send> = match &config.json_output {
+        None => Box::new(status_emitter),
+        Some(JsonOutput::Stdout) => Box::new((
+            status_emitter,
+            status_emitter::Json::stdout().root_dir(&config.root_dir),
+        )),
+        Some(JsonOutput::File(path)) => Box::new((
+            status_emitter,
+            status_emitter::Json::file(path)
+                .map_err(|err| eyre!("could not create {}: {err}", path.display()))?
+                .root_dir(&config.root_dir),
+        )),
+    };
+    let status_emitter: Box<dyn StatusEmitter + Send> = match &config.markdown_report {
+        None => status_emitter,
+        Some(path) => Box::new((
+            status_emitter,
+            status_emitter::Markdown::file(path, config.markdown_max_diff_len)
+                .map_err(|err| eyre!("could not create {}: {err}", path.display()))?,
+        )),
+    };
+    Ok(status_emitter)
 }
 
-/// The filter used by `run_tests` to only run on `.rs` files.
+/// A filter that only runs `.rs` files, like `run_tests` does with the default
+/// [`Config::test_extensions`].
 pub fn default_file_filter(path: &Path) -> bool {
     path.extension().map(|ext| ext == "rs").unwrap_or(false)
 }
@@ -113,7 +186,10 @@ pub fn default_per_file_config(config: &Config, path: &Path) -> Option<Config> {
     // * if the file contains `#[test]`, automatically pass `--cfg test`.
     // * if the file does not contain `fn main()` or `#[start]`, automatically pass `--crate-type=lib`.
     // This avoids having to spam `fn main() {}` in almost every test.
-    let file_contents = std::fs::read(path).unwrap();
+    // A test that can't be read fails once its comments are parsed.
+    let Ok(file_contents) = std::fs::read(path) else {
+        return Some(config);
+    };
     if file_contents.find(b"#[proc_macro]").is_some()
         || file_contents.find(b"#[proc_macro_attribute]").is_some()
         || file_contents.find(b"#[proc_macro_derive]").is_some()
@@ -134,21 +210,132 @@ pub fn default_per_file_config(config: &Config, path: &Path) -> Option<Config> {
 pub fn test_command(mut config: Config, path: &Path) -> Result<Command> {
     config.build_dependencies_and_link_them()?;
 
-    let comments =
-        Comments::parse_file(path)?.map_err(|errors| color_eyre::eyre::eyre!("{errors:#?}"))?;
+    let comments = parse_test_comments(path, &config).map_err(|(stderr, errors)| {
+        if errors.is_empty() {
+            eyre!("{}", String::from_utf8_lossy(&stderr))
+        } else {
+            eyre!("{errors:#?}")
+        }
+    })?;
     let mut errors = vec![];
-    let result = build_command(path, &config, "", &comments, &mut errors);
+    let result = build_command(
+        path,
+        &config,
+        &compile_out_dir(&config, path, ""),
+        "",
+        &comments,
+        &mut errors,
+    );
     assert!(errors.is_empty(), "{errors:#?}");
     Ok(result)
 }
 
+/// Run all revisions of a single test file with the settings from the `config` argument, and
+/// return their outcomes instead of reporting them to a [`StatusEmitter`].
+/// Like [`test_command`], this ignores the settings from `Config` that relate to finding test
+/// files. Aux files and dependencies are built just like for [`run_tests`].
+///
+/// Failing tests are reported in the outcomes, only problems with the `config` itself, like
+/// dependencies that fail to build, are returned as errors. Unlike [`run_tests`], this never
+/// exits the process, not even on ctrl-c.
+pub fn run_file(mut config: Config, path: &Path) -> Result<Vec<TestOutcome>> {
+    config.program.check_program()?;
+    config.fill_host_and_target()?;
+    config.build_dependencies_and_link_them()?;
+    if let TestKind::File = config.test_kind {
+        config = default_per_file_config(&config, path).unwrap();
+    }
+    let jobs = Jobs::new(config.num_test_threads);
+    let runs = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        parse_and_test_file(path, &config, &TestCache::default(), &jobs)
+    })) {
+        Ok(runs) => runs,
+        Err(err) => {
+            return Ok(vec![TestOutcome {
+                revision: String::new(),
+                result: TestResult::Errored {
+                    command: Command::new("<unknown>"),
+                    errors: vec![Error::Bug(panic_message(err))],
+                    stderr: vec![],
+                },
+                command: None,
+                stdout: vec![],
+                stderr: vec![],
+                warnings: vec![],
+                timings: Timings::default(),
+            }])
+        }
+    };
+    Ok(runs
+        .into_iter()
+        .map(|run| TestOutcome {
+            revision: run.revision,
+            result: run.result,
+            command: run.captured.command,
+            stdout: run.captured.stdout,
+            stderr: run.captured.stderr,
+            warnings: run.warnings,
+            timings: run.timings,
+        })
+        .collect())
+}
+
+/// The outcome of one revision of a test, see [`run_file`].
+pub struct TestOutcome {
+    /// The revision, empty if the test has no revisions.
+    pub revision: String,
+    /// Whether the test passed. [`TestResult::Errored`] contains the errors that made it fail.
+    pub result: TestResult,
+    /// The command of the test's last attempt, `None` if the test did not run.
+    /// For [`Mode::Run`] tests that got to run, this is the test binary.
+    pub command: Option<Command>,
+    /// The full stdout of `command`, unfiltered.
+    pub stdout: Vec<u8>,
+    /// The full stderr of `command`, unfiltered.
+    pub stderr: Vec<u8>,
+    /// Problems that were reported but did not fail the test.
+    pub warnings: Errors,
+    /// How long running the test took, including all attempts.
+    pub timings: Timings,
+}
+
+/// A copy of `cmd` that can be run again, as `Command` is not `Clone`.
+fn copy_command(cmd: &Command) -> Command {
+    let mut copy = Command::new(cmd.get_program());
+    copy.args(cmd.get_args());
+    for (key, value) in cmd.get_envs() {
+        match value {
+            Some(value) => copy.env(key, value),
+            None => copy.env_remove(key),
+        };
+    }
+    if let Some(dir) = cmd.get_current_dir() {
+        copy.current_dir(dir);
+    }
+    copy
+}
+
 #[allow(clippy::large_enum_variant)]
 /// The possible results a single test can have.
 pub enum TestResult {
     /// The test passed
     Ok,
+    /// The test failed, but passed when it was retried because of `//@retry` or
+    /// [`Config::test_attempts`].
+    Flaky {
+        /// The attempt that passed, the ones before it failed.
+        attempts: usize,
+    },
+    /// The test passed in an earlier run, and none of its inputs changed since.
+    /// See [`Config::incremental`].
+    Cached,
     /// The test was ignored due to a rule (`//@only-*` or `//@ignore-*`)
-    Ignored,
+    Ignored {
+        /// The comment that caused the test to be ignored, like `` `//@ignore-target-windows` ``.
+        reason: String,
+        /// The line of that comment.
+        line: usize,
+    },
     /// The test was filtered with the `file_filter` argument.
     Filtered,
     /// The test failed.
@@ -166,121 +353,822 @@ struct TestRun {
     result: TestResult,
     path: PathBuf,
     revision: String,
+    /// Problems that were reported but did not fail the test.
+    warnings: Errors,
+    /// The files the test saved in [`Config::failure_artifacts_dir`] because it failed.
+    artifacts: Vec<PathBuf>,
+    /// The expected output files the test may produce, `None` if they are unknown
+    /// because the test's comments could not be parsed.
+    output_files: Option<Vec<PathBuf>>,
+    /// Output files of revisions or bitwidths the test doesn't have anymore, which blessing removed.
+    removed_files: Vec<PathBuf>,
+    /// How many bytes of the test's artifacts were removed, see [`Config::cleanup`].
+    reclaimed: u64,
+    /// The hash of the test's inputs, if it is tracked by the [`Config::incremental`] cache.
+    inputs_hash: Option<u64>,
+    /// How long running the test took, including all attempts.
+    timings: Timings,
+    /// The command of the last attempt and what it printed.
+    captured: Captured,
+}
+
+/// The command that ran a test and its output, see [`TestOutcome`].
+#[derive(Default)]
+struct Captured {
+    command: Option<Command>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+/// How long a test took, in total and in each phase. All attempts of a test are added up.
+/// Tests that did not run took no time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timings {
+    /// The time from starting the test until it finished.
+    pub total: Duration,
+    /// Building aux files, aux binaries and custom builds, including the time spent waiting for
+    /// other tests that build the same ones.
+    pub aux_builds: Duration,
+    /// Waiting for a free slot to run in, see [`Config::num_test_threads`].
+    pub waiting: Duration,
+    /// Compiling the test, and for `//@run-rustfix` tests also the fixed code.
+    pub compile: Duration,
+    /// Running the compiled test binary.
+    pub run: Duration,
+    /// Applying rustfix suggestions and comparing the output and diagnostics to the expected ones.
+    pub compare: Duration,
+}
+
+impl Timings {
+    /// The phases with their names, in the order they happen in.
+    pub fn phases(&self) -> [(&'static str, Duration); 5] {
+        [
+            ("aux builds", self.aux_builds),
+            ("waiting", self.waiting),
+            ("compile", self.compile),
+            ("run", self.run),
+            ("compare", self.compare),
+        ]
+    }
+}
+
+impl std::ops::AddAssign for Timings {
+    fn add_assign(&mut self, other: Self) {
+        self.total += other.total;
+        self.aux_builds += other.aux_builds;
+        self.waiting += other.waiting;
+        self.compile += other.compile;
+        self.run += other.run;
+        self.compare += other.compare;
+    }
 }
 
 /// A version of `run_tests` that allows more fine-grained control over running tests.
 pub fn run_tests_generic(
+    config: Config,
+    file_filter: impl Fn(&Path) -> bool + Sync,
+    per_file_config: impl Fn(&Config, &Path) -> Option<Config> + Sync,
+    status_emitter: impl StatusEmitter + Send,
+) -> Result<()> {
+    let root = TestRoot::new("");
+    run_test_roots_generic(
+        config,
+        vec![root],
+        file_filter,
+        per_file_config,
+        status_emitter,
+    )
+}
+
+/// A directory of tests in a run of [`run_test_roots`], with the settings that differ from the
+/// config shared by all roots.
+pub struct TestRoot {
+    /// The directory, relative to [`Config::root_dir`] of the shared config.
+    pub dir: PathBuf,
+    /// Changes the shared config for the tests in `dir`. The settings that select the tests
+    /// of the whole run, [`Config::root_dir`], [`Config::filters`], [`Config::skip`],
+    /// [`Config::exact`] and [`Config::shard`], can't be changed per root.
+    pub overrides: Box<dyn FnOnce(&mut Config)>,
+}
+
+impl TestRoot {
+    /// A root that uses the shared config as is.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            overrides: Box::new(|_| {}),
+        }
+    }
+}
+
+/// Run the tests of several directories at once, like `run_tests` does for a single one.
+/// Dependencies are built once, all tests share the threads and the status emitter, and the
+/// summary reports the results of each root.
+pub fn run_test_roots(config: Config, roots: Vec<TestRoot>) -> Result<()> {
+    eprintln!("   Compiler: {}", config.program.display());
+    let status_emitter = default_status_emitter(&config)?;
+    match config.test_kind {
+        TestKind::File => run_test_roots_generic(
+            config.clone(),
+            roots,
+            |path| config.has_test_extension(path),
+            default_per_file_config,
+            status_emitter,
+        ),
+        TestKind::CargoProject => {
+            run_test_roots_generic(config, roots, |_| true, |_, _| None, status_emitter)
+        }
+    }
+}
+
+/// A version of [`run_test_roots`] that allows more fine-grained control over running tests,
+/// like [`run_tests_generic`]. `per_file_config` gets the config of the root of each test.
+pub fn run_test_roots_generic(
     mut config: Config,
+    roots: Vec<TestRoot>,
     file_filter: impl Fn(&Path) -> bool + Sync,
     per_file_config: impl Fn(&Config, &Path) -> Option<Config> + Sync,
     mut status_emitter: impl StatusEmitter + Send,
 ) -> Result<()> {
+    config::check_env_vars(&config)?;
+    config.program.check_program()?;
     config.fill_host_and_target()?;
 
+    if let Some((index, count)) = config.shard {
+        if index >= count {
+            return Err(eyre!(
+                "shard index {index} must be smaller than the number of shards {count}"
+            ));
+        }
+    }
+    if !config.exact {
+        for pattern in config.filters.iter().chain(&config.skip) {
+            TestGlob::parse(pattern).map_err(|err| eyre!("invalid glob `{pattern}`: {err}"))?;
+        }
+    }
+
+    let mut roots: Vec<(PathBuf, Config)> = roots
+        .into_iter()
+        .map(|root| {
+            let mut root_config = config.clone();
+            (root.overrides)(&mut root_config);
+            root_config.root_dir = config.root_dir.clone();
+            root_config.filters = config.filters.clone();
+            root_config.skip = config.skip.clone();
+            root_config.exact = config.exact;
+            root_config.shard = config.shard;
+            let dir = if root.dir.as_os_str().is_empty() {
+                config.root_dir.clone()
+            } else {
+                config.root_dir.join(root.dir)
+            };
+            (dir, root_config)
+        })
+        .collect();
+    for (_, root_config) in &roots {
+        root_config.program.check_program()?;
+    }
+
+    if config.list {
+        list_tests(&roots, file_filter, per_file_config);
+        return Ok(());
+    }
+
+    let mut reclaimed = 0;
+    if let CleanupPolicy::Fresh = config.cleanup {
+        // The hashed directories of the tests that exist, by the `out_dir` they are in.
+        let mut hashed_dirs: HashMap<PathBuf, HashSet<PathBuf>> = HashMap::new();
+        for (dir, root_config) in &roots {
+            if root_config.test_kind != TestKind::File {
+                continue;
+            }
+            let out_dir = root_config.out_dir.join(relative_to(Path::new(""), dir));
+            // Never wipe the whole `out_dir`, it also contains the dependencies.
+            if out_dir != root_config.out_dir {
+                reclaimed += remove_stale_artifacts(root_config, &out_dir, dir);
+            }
+            let existing = hashed_dirs.entry(root_config.out_dir.clone()).or_default();
+            collect_test_out_dirs(root_config, dir, existing);
+        }
+        for (out_dir, existing) in &hashed_dirs {
+            reclaimed += remove_stale_hashed_artifacts(&config, out_dir, existing);
+        }
+    }
+
+    if let Some(dir) = &config.failure_artifacts_dir {
+        // Artifacts of earlier runs may belong to tests that pass by now.
+        match std::fs::remove_dir_all(dir) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(eyre!("could not clean {}: {err}", dir.display())),
+        }
+    }
+
+    let args = config.program.args.len();
     config.build_dependencies_and_link_them()?;
+    // All roots link the dependencies that were built once.
+    for (_, root_config) in &mut roots {
+        root_config
+            .program
+            .args
+            .extend_from_slice(&config.program.args[args..]);
+    }
 
+    let timing_baseline = TimingBaseline::load(&config)?;
+    let cache = TestCache::load(&config);
+    let jobs = Jobs::new(config.num_test_threads);
+    let failed_first = failed_tests::load(&config);
     let mut results = vec![];
+    // Expected output files, and files that are not tests or were filtered out.
+    let mut found_output_files = vec![];
+    let mut skipped_files = vec![];
 
-    run_and_collect(
-        config.num_test_threads.get(),
-        |submit| {
-            let mut todo = VecDeque::new();
-            todo.push_back(config.root_dir.clone());
-            while let Some(path) = todo.pop_front() {
-                if path.is_dir() {
-                    if path.file_name().unwrap() == "auxiliary" {
-                        continue;
+    // On Ctrl-C, the running tests are killed and no more tests are started. The tests that
+    // finished before are summarized as usual.
+    interrupt::watch(|| {
+        run_and_collect(
+            config.num_test_threads.get(),
+            |submit| {
+                // Tests that failed in the last run go first, the others keep their order.
+                let mut submitted = HashSet::new();
+                let mut submit = |path: PathBuf| {
+                    if submitted.insert(path.clone()) {
+                        submit.send(path).unwrap();
                     }
-                    // Enqueue everything inside this directory.
-                    // We want it sorted, to have some control over scheduling of slow tests.
-                    let mut entries = std::fs::read_dir(path)
-                        .unwrap()
-                        .collect::<Result<Vec<_>, _>>()
-                        .unwrap();
-                    entries.sort_by_key(|e| e.file_name());
-                    for entry in entries {
-                        todo.push_back(entry.path());
+                };
+                for path in &failed_first {
+                    let root = match root_of(&roots, path) {
+                        Some(root) => root,
+                        None => continue,
+                    };
+                    let is_test = match roots[root].1.test_kind {
+                        TestKind::File => {
+                            path.is_file()
+                                && !path
+                                    .ancestors()
+                                    .any(|dir| roots[root].1.is_excluded_dir(dir))
+                        }
+                        TestKind::CargoProject => path.ends_with("Cargo.toml"),
+                    };
+                    if is_test && file_filter(path) {
+                        submit(path.clone());
                     }
-                } else if file_filter(&path) {
-                    // Forward .rs files to the test workers.
-                    submit.send(path).unwrap();
                 }
-            }
-        },
-        |receive, finished_files_sender| -> Result<()> {
-            for path in receive {
-                let maybe_config;
-                let config = match per_file_config(&config, &path) {
-                    None => &config,
-                    Some(config) => {
-                        maybe_config = config;
-                        &maybe_config
+                for (dir, root_config) in &roots {
+                    find_tests(
+                        root_config,
+                        dir,
+                        &file_filter,
+                        &mut submit,
+                        &mut found_output_files,
+                        &mut skipped_files,
+                    );
+                }
+            },
+            |receive, finished_files_sender| -> Result<()> {
+                for path in receive {
+                    if interrupt::interrupted() {
+                        break;
                     }
-                };
-                let result = match std::panic::catch_unwind(|| parse_and_test_file(&path, config)) {
-                    Ok(res) => res,
-                    Err(err) => {
-                        finished_files_sender.send(TestRun {
-                            result: TestResult::Errored {
-                                command: Command::new("<unknown>"),
-                                errors: vec![Error::Bug(
-                                    *Box::<dyn std::any::Any + Send + 'static>::downcast::<String>(
-                                        err,
-                                    )
-                                    .unwrap(),
-                                )],
-                                stderr: vec![],
-                            },
-                            path,
-                            revision: String::new(),
-                        })?;
+                    let root_config = &roots[root_of(&roots, &path).unwrap()].1;
+                    // Tests only read the config, so a panicking test does not affect the other tests.
+                    let result =
+                        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                            match per_file_config(root_config, &path) {
+                                None => parse_and_test_file(&path, root_config, &cache, &jobs),
+                                Some(config) => parse_and_test_file(&path, &config, &cache, &jobs),
+                            }
+                        })) {
+                            Ok(res) => res,
+                            Err(err) => {
+                                finished_files_sender.send(TestRun {
+                                    result: TestResult::Errored {
+                                        command: Command::new("<unknown>"),
+                                        errors: vec![Error::Bug(panic_message(err))],
+                                        stderr: vec![],
+                                    },
+                                    path,
+                                    revision: String::new(),
+                                    warnings: vec![],
+                                    artifacts: vec![],
+                                    output_files: None,
+                                    removed_files: vec![],
+                                    reclaimed: 0,
+                                    inputs_hash: None,
+                                    timings: Timings::default(),
+                                    captured: Captured::default(),
+                                })?;
+                                continue;
+                            }
+                        };
+                    for result in result {
+                        finished_files_sender.send(result)?;
+                    }
+                }
+                Ok(())
+            },
+            |finished_files_recv| {
+                for run in finished_files_recv {
+                    // Tests that were still running got killed.
+                    if interrupt::interrupted() {
                         continue;
                     }
-                };
-                for result in result {
-                    finished_files_sender.send(result)?;
+                    status_emitter.test_timings(&run.path, &run.revision, &run.timings);
+                    status_emitter.test_result(&run.path, &run.revision, &run.result);
+                    reclaimed += run.reclaimed;
+                    if !run.removed_files.is_empty() {
+                        status_emitter.removed_outdated_files(&run.path, &run.removed_files);
+                    }
+
+                    results.push(run);
                 }
-            }
-            Ok(())
-        },
-        |finished_files_recv| {
-            for run in finished_files_recv {
-                status_emitter.test_result(&run.path, &run.revision, &run.result);
+            },
+        )
+    })?;
+    let interrupted = interrupt::interrupted();
 
-                results.push(run);
-            }
-        },
-    )?;
+    // Durations can only be compared if all tests ran.
+    if let (Some(baseline), false) = (timing_baseline, interrupted) {
+        check_timings(
+            &config,
+            &roots,
+            baseline,
+            &per_file_config,
+            &mut results,
+            &jobs,
+        )?;
+    }
+    // The tests finished in whatever order the threads ran them in, but the summary must be the
+    // same for every run. The revisions of a test ran in the order they are declared in.
+    results.sort_by(|a, b| a.path.cmp(&b.path));
+
+    cache.save(results.iter().filter_map(|run| {
+        let hash = run.inputs_hash?;
+        Some((run.path.as_path(), run.revision.as_str(), &run.result, hash))
+    }));
+
+    let assigned = results
+        .iter()
+        .filter(|run| in_shard(&config, &run.path, &run.revision))
+        .count();
+
+    let slowest = config.slowest_tests.map(|n| {
+        let mut total = Timings::default();
+        for run in &results {
+            total += run.timings;
+        }
+        let mut slowest: Vec<_> = results
+            .iter()
+            .filter(|run| run.timings.total > Duration::ZERO)
+            .map(|run| (run.path.clone(), run.revision.clone(), run.timings))
+            .collect();
+        slowest.sort_by_key(|(.., timings)| std::cmp::Reverse(timings.total));
+        slowest.truncate(n);
+        (slowest, total)
+    });
+
+    // Keep the failures of the last run if not all tests ran.
+    if !interrupted {
+        failed_tests::save(
+            &config,
+            results
+                .iter()
+                .filter(|run| matches!(run.result, TestResult::Errored { .. }))
+                .map(|run| (run.path.as_path(), run.revision.as_str())),
+        );
+    }
 
     let mut failures = vec![];
+    let mut warnings = vec![];
+    let mut flaky = vec![];
     let mut succeeded = 0;
     let mut ignored = 0;
+    let mut ignored_reasons: HashMap<String, Vec<(PathBuf, String, usize)>> = HashMap::new();
     let mut filtered = 0;
+    let mut excluded_by_filters = 0;
+    let mut excluded_by_skip = 0;
+    // The failed, succeeded, ignored and filtered tests of each root.
+    let mut root_results = vec![(0, 0, 0, 0); roots.len()];
 
+    let expected_output_dirs: Vec<_> = roots
+        .iter()
+        .filter_map(|(_, root_config)| root_config.expected_output_dir.as_ref())
+        .collect();
+    if config.check_stale_output_files && !expected_output_dirs.is_empty() {
+        for dir in expected_output_dirs {
+            collect_output_files(dir, &mut found_output_files);
+        }
+        // The directory may be inside the root directory.
+        found_output_files.sort();
+        found_output_files.dedup();
+    }
+    let root_config = |path: &Path| root_of(&roots, path).map_or(&config, |root| &roots[root].1);
+    // Output files that belong to tests whose expected output files are unknown,
+    // by the path of the test without its extension.
+    let mut unknown_outputs: HashSet<PathBuf> = skipped_files
+        .iter()
+        .map(|path| expected_output_base(root_config(path), path).with_extension(""))
+        .collect();
+    let mut claimed_outputs = HashSet::new();
     for run in results {
+        match run.output_files {
+            Some(files) => claimed_outputs.extend(files),
+            None => {
+                unknown_outputs.insert(
+                    expected_output_base(root_config(&run.path), &run.path).with_extension(""),
+                );
+            }
+        }
+        if !run.warnings.is_empty() {
+            warnings.push((run.path.clone(), run.revision.clone(), run.warnings));
+        }
+        if let Some(root) = root_of(&roots, &run.path) {
+            let (failed, succeeded, ignored, filtered) = &mut root_results[root];
+            match run.result {
+                TestResult::Errored { .. } => *failed += 1,
+                TestResult::Ok | TestResult::Cached | TestResult::Flaky { .. } => *succeeded += 1,
+                TestResult::Ignored { .. } => *ignored += 1,
+                TestResult::Filtered => *filtered += 1,
+            }
+        }
         match run.result {
-            TestResult::Ok => succeeded += 1,
-            TestResult::Ignored => ignored += 1,
-            TestResult::Filtered => filtered += 1,
+            TestResult::Ok | TestResult::Cached => succeeded += 1,
+            TestResult::Flaky { attempts } => {
+                succeeded += 1;
+                flaky.push((run.path, run.revision, attempts));
+            }
+            TestResult::Ignored { reason, line } => {
+                ignored += 1;
+                if config.ignored_summary {
+                    ignored_reasons
+                        .entry(reason)
+                        .or_default()
+                        .push((run.path, run.revision, line));
+                }
+            }
+            TestResult::Filtered => {
+                filtered += 1;
+                match excluded(&config, &run.path, &run.revision) {
+                    Some(Excluded::Filter) => excluded_by_filters += 1,
+                    Some(Excluded::Skip) => excluded_by_skip += 1,
+                    None => {}
+                }
+            }
             TestResult::Errored {
                 command,
                 errors,
                 stderr,
-            } => failures.push((run.path, command, run.revision, errors, stderr)),
+            } => failures.push((
+                run.path,
+                command,
+                run.revision,
+                errors,
+                stderr,
+                run.artifacts,
+            )),
+        }
+    }
+
+    // Output files of tests that didn't run would look stale.
+    if config.check_stale_output_files && !config.check_determinism && !interrupted {
+        for path in stale_output_files(found_output_files, &claimed_outputs, &unknown_outputs) {
+            if let OutputConflictHandling::Bless = config.output_conflict_handling {
+                match std::fs::remove_file(&path) {
+                    // Another process may have removed it already.
+                    Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                        failures.push((
+                            path.clone(),
+                            Command::new("remove stale output files"),
+                            String::new(),
+                            vec![Error::Io {
+                                path,
+                                message: err.to_string(),
+                            }],
+                            vec![],
+                            vec![],
+                        ));
+                    }
+                    _ => {}
+                }
+            } else {
+                failures.push((
+                    path,
+                    Command::new("check stale output files"),
+                    String::new(),
+                    vec![Error::StaleOutputFile],
+                    vec![],
+                    vec![],
+                ));
+            }
+        }
+    }
+
+    if config.ignored_summary && !ignored_reasons.is_empty() {
+        let mut ignored_reasons: Vec<_> = ignored_reasons.into_iter().collect();
+        for (_, tests) in &mut ignored_reasons {
+            tests.sort();
         }
+        // The most common reasons first, the others alphabetically.
+        ignored_reasons
+            .sort_by(|(a, a_tests), (b, b_tests)| b_tests.len().cmp(&a_tests.len()).then(a.cmp(b)));
+        status_emitter.ignored_tests(&ignored_reasons);
+    }
+    if let Some((slowest, total)) = &slowest {
+        status_emitter.slowest_tests(slowest, total);
+    }
+    if let Some((index, count)) = config.shard {
+        status_emitter.shard(index, count, assigned);
+    }
+    if excluded_by_filters > 0 || excluded_by_skip > 0 {
+        status_emitter.excluded_tests(excluded_by_filters, excluded_by_skip);
+    }
+    if config.cleanup != CleanupPolicy::Keep {
+        status_emitter.reclaimed(reclaimed);
+    }
+    if roots.len() > 1 {
+        let root_results: Vec<_> = roots
+            .iter()
+            .zip(root_results)
+            .map(|((dir, _), (failed, succeeded, ignored, filtered))| {
+                (dir.clone(), failed, succeeded, ignored, filtered)
+            })
+            .collect();
+        status_emitter.test_roots(&root_results);
     }
 
     let mut failure_emitter = status_emitter.finalize(failures.len(), succeeded, ignored, filtered);
-    for (path, command, revision, errors, stderr) in &failures {
+    for (path, revision, warnings) in &warnings {
+        failure_emitter.test_warnings(path, revision, warnings);
+    }
+    for (path, revision, attempts) in &flaky {
+        failure_emitter.test_flaky(path, revision, *attempts);
+    }
+    for (path, command, revision, errors, stderr, artifacts) in &failures {
         let _guard = status_emitter.failed_test(revision, path, command, stderr);
+        if !artifacts.is_empty() {
+            status_emitter.failure_artifacts(path, revision, artifacts);
+        }
         failure_emitter.test_failure(path, revision, errors);
     }
+    if let OutputConflictHandling::Error(template) = &config.output_conflict_handling {
+        let mut outdated: Vec<_> = failures
+            .iter()
+            .filter(|(_, _, _, errors, ..)| errors.iter().any(output_differs))
+            .map(|(path, ..)| path.as_path())
+            .collect();
+        outdated.sort();
+        outdated.dedup();
+        // A single test already shows the command that blesses it.
+        if bless_command::has_placeholders(template) && outdated.len() > 1 {
+            failure_emitter.bless_command(&bless_command::render(
+                template,
+                &config,
+                outdated.into_iter(),
+            ));
+        }
+    }
 
-    if failures.is_empty() {
-        Ok(())
-    } else {
+    if let Some(dir) = &config.failure_artifacts_dir {
+        // Passing tests delete their artifacts, but not the directories created for them.
+        remove_empty_dirs(dir);
+    }
+
+    // Filtered tests were never meant to run, so they don't count.
+    let too_many_ignored = config.warn_on_ignored.filter(|&threshold| {
+        ignored > 0 && ignored as f64 > threshold * (succeeded + failures.len() + ignored) as f64
+    });
+
+    if interrupted {
+        Err(eyre!("test run was interrupted"))
+    } else if !failures.is_empty() {
         Err(eyre!("tests failed"))
+    } else if let Some(threshold) = too_many_ignored {
+        Err(eyre!(
+            "{ignored} of {} tests were ignored, more than the {}% allowed by `--warn-on-ignored`",
+            succeeded + ignored,
+            threshold * 100.0
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// The index of the root in `roots` that the test at `path` belongs to. Tests in nested roots
+/// belong to the innermost one.
+fn root_of(roots: &[(PathBuf, Config)], path: &Path) -> Option<usize> {
+    roots
+        .iter()
+        .enumerate()
+        .filter(|(_, (dir, _))| path.starts_with(dir))
+        .max_by_key(|(_, (dir, _))| dir.components().count())
+        .map(|(root, _)| root)
+}
+
+/// Whether blessing fixes (part of) the error.
+fn output_differs(error: &Error) -> bool {
+    match error {
+        Error::OutputDiffers { .. } => true,
+        Error::Aux { errors, .. } => errors.iter().any(output_differs),
+        _ => false,
+    }
+}
+
+/// Walk `dir` and call `submit` for every test, in a deterministic order.
+/// Expected output files and other files that are not tests are collected separately.
+fn find_tests(
+    config: &Config,
+    dir: &Path,
+    file_filter: &dyn Fn(&Path) -> bool,
+    submit: &mut dyn FnMut(PathBuf),
+    found_output_files: &mut Vec<PathBuf>,
+    skipped_files: &mut Vec<PathBuf>,
+) {
+    let mut todo = VecDeque::new();
+    todo.push_back(dir.to_owned());
+    while let Some(path) = todo.pop_front() {
+        if config.test_kind == TestKind::CargoProject && path != dir {
+            // Packages are not searched for further tests.
+            let manifest = path.join("Cargo.toml");
+            if manifest.is_file() && file_filter(&manifest) {
+                submit(manifest);
+            } else {
+                skipped_files.push(manifest);
+            }
+            if config.check_stale_output_files && path.is_dir() {
+                for entry in std::fs::read_dir(path).unwrap() {
+                    let path = entry.unwrap().path();
+                    if is_output_file(&path) {
+                        found_output_files.push(path);
+                    }
+                }
+            }
+        } else if path.is_dir() {
+            if config.is_excluded_dir(&path) {
+                continue;
+            }
+            // Enqueue everything inside this directory.
+            // We want it sorted, to have some control over scheduling of slow tests.
+            let mut entries = std::fs::read_dir(path)
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            entries.sort_by_key(|e| e.file_name());
+            for entry in entries {
+                todo.push_back(entry.path());
+            }
+        } else if file_filter(&path) {
+            // Forward .rs files to the test workers.
+            submit(path);
+        } else if is_output_file(&path) {
+            found_output_files.push(path);
+        } else {
+            skipped_files.push(path);
+        }
+    }
+}
+
+/// Print the id of every test that would run to stdout, like libtest's `--list`, without
+/// building or running anything. See [`Config::list`].
+fn list_tests(
+    roots: &[(PathBuf, Config)],
+    file_filter: impl Fn(&Path) -> bool,
+    per_file_config: impl Fn(&Config, &Path) -> Option<Config>,
+) {
+    let mut tests = vec![];
+    let mut found = HashSet::new();
+    for (dir, config) in roots {
+        // Nested roots find their tests in all enclosing roots, too.
+        find_tests(
+            config,
+            dir,
+            &file_filter,
+            &mut |path| {
+                if found.insert(path.clone()) {
+                    tests.push(path)
+                }
+            },
+            &mut vec![],
+            &mut vec![],
+        );
+    }
+    let mut count = 0;
+    for path in tests {
+        let config = &roots[root_of(roots, &path).unwrap()].1;
+        let maybe_config;
+        let config = match per_file_config(config, &path) {
+            None => config,
+            Some(config) => {
+                maybe_config = config;
+                &maybe_config
+            }
+        };
+        let comments = parse_test_comments(&path, config);
+        // Tests with broken comments are listed, running them reports the problem.
+        let revisions = comments
+            .as_ref()
+            .ok()
+            .and_then(|comments| comments.revisions.clone())
+            .unwrap_or_else(|| vec![String::new()]);
+        for revision in revisions {
+            if excluded(config, &path, &revision).is_some() || !in_shard(config, &path, &revision) {
+                continue;
+            }
+            let id = test_id(config, &path, &revision);
+            match comments
+                .as_ref()
+                .ok()
+                .and_then(|comments| ignore_reason(comments, config, &revision).ok().flatten())
+            {
+                Some((reason, _)) => println!("{id}: test (ignored by {reason})"),
+                None => println!("{id}: test"),
+            }
+            count += 1;
+        }
+    }
+    println!();
+    println!(
+        "{count} {}, 0 benchmarks",
+        if count == 1 { "test" } else { "tests" }
+    );
+}
+
+/// Compare the durations of the tests that passed to the [`Config::timing_baseline`], or
+/// bless them.
+fn check_timings(
+    config: &Config,
+    roots: &[(PathBuf, Config)],
+    baseline: TimingBaseline,
+    per_file_config: impl Fn(&Config, &Path) -> Option<Config>,
+    results: &mut [TestRun],
+    jobs: &Jobs,
+) -> Result<()> {
+    // Flaky tests took several attempts, and other tests did not run at all.
+    let passed = |run: &&mut TestRun| {
+        matches!(run.result, TestResult::Ok) && run.timings.total > Duration::ZERO
+    };
+    if config.bless_timings {
+        return baseline.bless(
+            config,
+            results
+                .iter_mut()
+                .filter(passed)
+                .map(|run| (run.path.as_path(), run.revision.as_str(), run.timings.total)),
+        );
+    }
+    for run in results.iter_mut().filter(passed) {
+        let mut actual = run.timings.total;
+        let mut samples = 1;
+        let expected = match baseline.regressed(config, &run.path, &run.revision, actual) {
+            Some(expected) => expected,
+            None => continue,
+        };
+        // Measure again, in case the test was slow because of something else.
+        if config.timing_samples > 0 {
+            let root_config = &roots[root_of(roots, &run.path).unwrap()].1;
+            let maybe_config;
+            let test_config = match per_file_config(root_config, &run.path) {
+                None => root_config,
+                Some(config) => {
+                    maybe_config = config;
+                    &maybe_config
+                }
+            };
+            let durations: Vec<_> = (0..config.timing_samples)
+                .filter_map(|_| {
+                    parse_and_test_file(&run.path, test_config, &TestCache::default(), jobs)
+                        .into_iter()
+                        .find(|sample| sample.revision == run.revision)
+                        .map(|sample| sample.timings.total)
+                })
+                .collect();
+            if !durations.is_empty() {
+                samples = durations.len();
+                actual = timing_baseline::median(durations);
+            }
+            if baseline
+                .regressed(config, &run.path, &run.revision, actual)
+                .is_none()
+            {
+                continue;
+            }
+        }
+        let error = Error::TimingRegression {
+            baseline: expected,
+            actual,
+            samples,
+        };
+        match config.timing_regressions {
+            Strictness::Warn => run.warnings.push(Error::Warning(Box::new(error))),
+            Strictness::Error => {
+                run.result = TestResult::Errored {
+                    command: Command::new("compare timings to the baseline"),
+                    errors: vec![error],
+                    stderr: vec![],
+                }
+            }
+        }
     }
+    Ok(())
 }
 
 /// A generic multithreaded runner that has a thread for producing work,
@@ -320,9 +1208,29 @@ pub fn run_and_collect<SUBMISSION: Send, RESULT: Send>(
     })
 }
 
-fn parse_and_test_file(path: &Path, config: &Config) -> Vec<TestRun> {
-    let comments = match parse_comments_in_file(path) {
+fn parse_and_test_file(
+    path: &Path,
+    config: &Config,
+    cache: &TestCache,
+    jobs: &Jobs,
+) -> Vec<TestRun> {
+    let comments = match parse_test_comments(path, config) {
         Ok(comments) => comments,
+        Err(_) if excluded(config, path, "").is_some() || !in_shard(config, path, "") => {
+            return vec![TestRun {
+                result: TestResult::Filtered,
+                path: path.into(),
+                revision: "".into(),
+                warnings: vec![],
+                artifacts: vec![],
+                output_files: None,
+                removed_files: vec![],
+                reclaimed: 0,
+                inputs_hash: None,
+                timings: Timings::default(),
+                captured: Captured::default(),
+            }]
+        }
         Err((stderr, errors)) => {
             return vec![TestRun {
                 result: TestResult::Errored {
@@ -332,62 +1240,385 @@ fn parse_and_test_file(path: &Path, config: &Config) -> Vec<TestRun> {
                 },
                 path: path.into(),
                 revision: "".into(),
+                warnings: vec![],
+                artifacts: vec![],
+                output_files: None,
+                removed_files: vec![],
+                reclaimed: 0,
+                inputs_hash: None,
+                timings: Timings::default(),
+                captured: Captured::default(),
             }]
         }
     };
     // Run the test for all revisions
-    comments
+    let unused_revisions = config
+        .unused_revisions
+        .map(|strictness| (strictness, unused_revisions(path, config, &comments)));
+    let mut runs = comments
         .revisions
         .clone()
         .unwrap_or_else(|| vec![String::new()])
         .into_iter()
         .map(|revision| {
-            // Ignore file if only/ignore rules do (not) apply
-            if !test_file_conditions(&comments, config, &revision) {
+            let mut replacement = None;
+            let result = if excluded(config, path, &revision).is_some()
+                || !in_shard(config, path, &revision)
+            {
+                TestResult::Filtered
+            } else {
+                match ignore_reason(&comments, config, &revision) {
+                    // Ignore file if only/ignore rules do (not) apply
+                    Ok(Some((reason, line))) => TestResult::Ignored { reason, line },
+                    Ok(None) => match pre_test_actions(path, config, &revision, &comments) {
+                        Ok(command) => {
+                            replacement = command;
+                            TestResult::Ok
+                        }
+                        Err(result) => result,
+                    },
+                    Err(error) => {
+                        let mut errors = vec![error];
+                        let command = build_command(
+                            path,
+                            config,
+                            &compile_out_dir(config, path, &revision),
+                            &revision,
+                            &comments,
+                            &mut errors,
+                        );
+                        TestResult::Errored {
+                            command,
+                            errors,
+                            stderr: vec![],
+                        }
+                    }
+                }
+            };
+            if !matches!(result, TestResult::Ok) {
                 return TestRun {
-                    result: TestResult::Ignored,
+                    result,
                     path: path.into(),
+                    output_files: Some(output_files(
+                        &expected_output_base(config, path),
+                        &comments,
+                        &revision,
+                    )),
                     revision,
+                    warnings: vec![],
+                    artifacts: vec![],
+                    removed_files: vec![],
+                    reclaimed: 0,
+                    inputs_hash: None,
+                    timings: Timings::default(),
+                    captured: Captured::default(),
                 };
             }
-            let (command, errors, stderr) = run_test(path, config, &revision, &comments);
-            let result = if errors.is_empty() {
-                TestResult::Ok
-            } else {
-                TestResult::Errored {
-                    command,
-                    errors,
-                    stderr,
+            let output_files = Some(output_files(
+                &expected_output_base(config, path),
+                &comments,
+                &revision,
+            ));
+            let inputs_hash = cache.inputs_hash(path, config, &comments, &revision);
+            if let Some(hash) =
+                inputs_hash.filter(|&hash| cache.passed(path, &revision, hash, config))
+            {
+                return TestRun {
+                    result: TestResult::Cached,
+                    path: path.into(),
+                    output_files,
+                    revision,
+                    warnings: vec![],
+                    artifacts: vec![],
+                    removed_files: vec![],
+                    reclaimed: 0,
+                    inputs_hash: Some(hash),
+                    timings: Timings::default(),
+                    captured: Captured::default(),
+                };
+            }
+            let mut retry_errors = vec![];
+            let attempts = comments.attempts(&mut retry_errors, &revision, config);
+            if let Some((strictness, unused)) = &unused_revisions {
+                if unused.contains(&revision) {
+                    let error = Error::InvalidComment {
+                        msg: format!(
+                            "the revision `{revision}` is not used by any comment or output file, \
+                             add `//@allow-unused-revisions` if it only differs in its `--cfg`"
+                        ),
+                        line: comments.revisions_line,
+                    };
+                    retry_errors.push(match strictness {
+                        Strictness::Warn => Error::Warning(Box::new(error)),
+                        Strictness::Error => error,
+                    });
+                }
+            }
+            let out_dir = revision_out_dir(config, path, &revision);
+            let cleanup = match config.test_kind {
+                TestKind::File => config.cleanup,
+                TestKind::CargoProject => CleanupPolicy::Keep,
+            };
+            let mut reclaimed = 0;
+            if let CleanupPolicy::Fresh = cleanup {
+                reclaimed += remove_artifacts(&out_dir);
+            }
+            // Rerun failing tests until they pass or run out of attempts.
+            let mut attempt = 1;
+            let start = Instant::now();
+            let mut timings = Timings::default();
+            let mut captured = Captured::default();
+            let (command, mut errors, stderr) = loop {
+                // A panic must not lose the results of the other revisions.
+                let run = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    run_test_with_hooks(
+                        path,
+                        config,
+                        &revision,
+                        &comments,
+                        replacement.as_ref(),
+                        jobs,
+                        &mut timings,
+                        &mut captured,
+                    )
+                }));
+                let (command, errors, stderr) = run.unwrap_or_else(|err| {
+                    let mut errors = vec![Error::Bug(panic_message(err))];
+                    let command = build_command(
+                        path,
+                        config,
+                        &compile_out_dir(config, path, &revision),
+                        &revision,
+                        &comments,
+                        &mut errors,
+                    );
+                    (command, errors, vec![])
+                });
+                if attempt >= attempts
+                    || errors
+                        .iter()
+                        .all(|error| matches!(error, Error::Warning(_)))
+                {
+                    break (command, errors, stderr);
+                }
+                attempt += 1;
+            };
+            errors.extend(retry_errors);
+            sort_errors(&mut errors);
+            captured.command = Some(copy_command(&command));
+            let (warnings, errors): (Vec<_>, Vec<_>) = errors
+                .into_iter()
+                .partition(|error| matches!(error, Error::Warning(_)));
+            let artifacts =
+                failure_artifacts(path, config, &comments, &revision, errors.is_empty());
+            if errors.is_empty() && cleanup == CleanupPolicy::RemoveOnSuccess {
+                reclaimed += remove_artifacts(&out_dir);
+            }
+            let result = if errors.is_empty() && attempt > 1 {
+                TestResult::Flaky { attempts: attempt }
+            } else if errors.is_empty() {
+                TestResult::Ok
+            } else {
+                TestResult::Errored {
+                    command,
+                    errors,
+                    stderr,
                 }
             };
             TestRun {
                 result,
+                output_files,
                 revision,
                 path: path.into(),
+                warnings,
+                artifacts,
+                removed_files: vec![],
+                reclaimed,
+                inputs_hash,
+                timings: Timings {
+                    total: start.elapsed(),
+                    ..timings
+                },
+                captured,
             }
         })
-        .collect()
+        .collect::<Vec<_>>();
+    let bless = matches!(
+        config.output_conflict_handling,
+        OutputConflictHandling::Bless
+    );
+    // The determinism check doesn't look at the output files.
+    if bless && !config.check_determinism {
+        let removed_files = remove_outdated_output_files(path, config, &comments);
+        runs.last_mut().unwrap().removed_files = removed_files;
+    }
+    runs
+}
+
+/// Put the errors about annotations in the order of their lines, followed by the differing
+/// output files in the order of their names, instead of the order they were found in.
+/// The other errors keep their place.
+fn sort_errors(errors: &mut Errors) {
+    let slots: Vec<usize> = (0..errors.len())
+        .filter(|&i| error_order(&errors[i]).is_some())
+        .collect();
+    let mut sorted: Vec<Error> = slots.iter().rev().map(|&i| errors.remove(i)).collect();
+    sorted.reverse();
+    sorted.sort_by_cached_key(error_order);
+    for (slot, error) in slots.into_iter().zip(sorted) {
+        errors.insert(slot, error);
+    }
+}
+
+/// Where [`sort_errors`] puts `error`: annotation errors by their line, with diagnostics outside
+/// of the test file first and missing annotations last, then differing output files by their
+/// path. `None` for all other errors.
+fn error_order(error: &Error) -> Option<(u8, usize, Option<PathBuf>)> {
+    match error {
+        Error::PatternNotFound {
+            definition_line, ..
+        }
+        | Error::CodeNotFound {
+            definition_line, ..
+        }
+        | Error::ColumnMismatch {
+            definition_line, ..
+        }
+        | Error::PatternCountMismatch {
+            definition_line, ..
+        }
+        | Error::AnnotationOutOfOrder {
+            definition_line, ..
+        } => Some((0, *definition_line, None)),
+        Error::ErrorsWithoutPattern { path, .. } => {
+            Some((0, path.as_ref().map_or(0, |(_, line)| *line), None))
+        }
+        Error::NoPatternsFound | Error::PatternFoundInPassTest => Some((0, usize::MAX, None)),
+        Error::OutputDiffers { path, .. } => Some((1, 0, Some(path.clone()))),
+        Error::FixedOutputDiffers { diff, .. } => error_order(diff),
+        Error::Warning(error) => error_order(error),
+        _ => None,
+    }
+}
+
+/// The message of a panic caught with [`std::panic::catch_unwind`].
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "<unknown panic payload>".to_string(),
+        },
+    }
+}
+
+/// Parse the comments of the test at `path`, including the ones of its defaults files.
+fn parse_test_comments(path: &Path, config: &Config) -> Result<Comments, (Vec<u8>, Vec<Error>)> {
+    let mut comments = parse_comments_in_file(&config.comments_file(path), config)?;
+    let mut defaults = Revisioned::default();
+    for file in defaults_files(config, path) {
+        let mut nearer =
+            parse_defaults_file(&file, config).map_err(|error| (vec![], vec![error]))?;
+        nearer.apply_defaults(defaults);
+        defaults = nearer;
+    }
+    comments
+        .revisioned
+        .entry(vec![])
+        .or_default()
+        .apply_defaults(defaults);
+    Ok(comments)
+}
+
+/// The [`Config::defaults_file_name`] files that apply to the test at `path`, from the one in
+/// [`Config::root_dir`] down to the one in the test's directory.
+pub(crate) fn defaults_files(config: &Config, path: &Path) -> Vec<PathBuf> {
+    let name = match &config.defaults_file_name {
+        Some(name) => name,
+        None => return vec![],
+    };
+    let mut files: Vec<_> = path
+        .parent()
+        .unwrap()
+        .ancestors()
+        .take_while(|dir| dir.starts_with(&config.root_dir))
+        .map(|dir| dir.join(name))
+        .filter(|file| file.is_file())
+        .collect();
+    files.reverse();
+    files
 }
 
-fn parse_comments_in_file(path: &Path) -> Result<Comments, (Vec<u8>, Vec<Error>)> {
-    match Comments::parse_file(path) {
+/// The comments of a defaults file, which apply to all revisions.
+fn parse_defaults_file(path: &Path, config: &Config) -> std::result::Result<Revisioned, Error> {
+    let comment_start = config.comment_start_for(path);
+    let errors = |errors| Error::Defaults {
+        path: path.to_owned(),
+        errors,
+    };
+    let content = std::fs::read(path).map_err(|err| {
+        errors(vec![Error::Bug(format!(
+            "could not read {}: {err}",
+            path.display()
+        ))])
+    })?;
+    let mut comments = Comments::parse_with_custom_comments(
+        &content,
+        comment_start,
+        &config.custom_comments,
+        config.expand_env_vars,
+    )
+    .map_err(errors)?;
+    if comments.revisions.is_some() {
+        let line = content
+            .lines()
+            .position(|line| line.starts_with(format!("{comment_start}@revisions").as_bytes()))
+            .map_or(0, |line| line + 1);
+        return Err(errors(vec![Error::InvalidComment {
+            msg: "defaults files can't declare revisions".into(),
+            line,
+        }]));
+    }
+    Ok(comments.revisioned.remove(&vec![]).unwrap_or_default())
+}
+
+fn parse_comments_in_file(path: &Path, config: &Config) -> Result<Comments, (Vec<u8>, Vec<Error>)> {
+    match Comments::parse_file(path, config) {
         Ok(Ok(comments)) => Ok(comments),
         Ok(Err(errors)) => Err((vec![], errors)),
-        Err(err) => Err((format!("{err:?}").into(), vec![])),
+        Err(err) => Err((
+            vec![],
+            vec![Error::Io {
+                path: path.to_owned(),
+                message: format!("{err:#}"),
+            }],
+        )),
     }
 }
 
+/// The command compiling `path` into `out_dir`.
 fn build_command(
     path: &Path,
     config: &Config,
+    out_dir: &Path,
     revision: &str,
     comments: &Comments,
     errors: &mut Vec<Error>,
 ) -> Command {
-    let mut cmd = config.program.build(&config.out_dir);
-    cmd.arg(path);
+    let mut cmd = if let Some(working_dir) = working_dir(config, path, revision, comments) {
+        // Paths relative to the directory ui_test runs in would be wrong in the working dir.
+        let mut cmd = config.program.build(&absolute(out_dir));
+        cmd.current_dir(working_dir).arg(absolute(path));
+        cmd
+    } else {
+        let mut cmd = config.program.build(out_dir);
+        cmd.arg(path);
+        cmd
+    };
     if !revision.is_empty() {
-        cmd.arg(format!("--cfg={revision}"));
+        for value in comments.revision_values(revision) {
+            cmd.arg(format!("--cfg={value}"));
+        }
     }
     for arg in comments
         .for_revision(revision)
@@ -405,25 +1636,68 @@ fn build_command(
             .flat_map(|r| r.env_vars.iter())
             .map(|(k, v)| (k, v)),
     );
+    for (flag, _) in comments
+        .for_revision(revision)
+        .flat_map(|r| r.custom.values())
+    {
+        flag.apply(&mut cmd);
+    }
 
     cmd
 }
 
+/// The `//@working-dir` of the test at `path`, which the program and the test binary run in.
+/// Without it, the program runs in the directory ui_test runs in, where it finds the sources of
+/// dependencies, whose paths are relative to it. Cargo projects always run there.
+fn working_dir(
+    config: &Config,
+    path: &Path,
+    revision: &str,
+    comments: &Comments,
+) -> Option<PathBuf> {
+    if let TestKind::CargoProject = config.test_kind {
+        return None;
+    }
+    comments
+        .for_revision(revision)
+        .find_map(|r| r.working_dir.as_ref())
+        .map(|(dir, _)| absolute(&path.parent().unwrap().join(dir)))
+}
+
+/// `path` relative to the directory ui_test runs in, if it isn't absolute already.
+fn absolute(path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        path.to_owned()
+    } else {
+        std::env::current_dir().unwrap().join(path)
+    }
+}
+
 fn build_aux(
     aux_file: &Path,
     path: &Path,
     config: &Config,
     revision: &str,
     comments: &Comments,
-    kind: &str,
+    kind: Option<&str>,
+    flags: &[String],
     aux: &Path,
     extra_args: &mut Vec<String>,
+    aux_builds: &AuxBuilds<'_>,
 ) -> std::result::Result<(), (Command, Vec<Error>, Vec<u8>)> {
-    let comments = match parse_comments_in_file(aux_file) {
+    let test_comments = comments;
+    let comments = match parse_comments_in_file(aux_file, config) {
         Ok(comments) => comments,
         Err((msg, mut errors)) => {
             return Err((
-                build_command(path, config, revision, comments, &mut errors),
+                build_command(
+                    path,
+                    config,
+                    &compile_out_dir(config, path, revision),
+                    revision,
+                    test_comments,
+                    &mut errors,
+                ),
                 errors,
                 msg,
             ))
@@ -456,27 +1730,83 @@ fn build_aux(
     // Put aux builds into a separate directory per test so that
     // tests running in parallel but building the same aux build don't conflict.
     // FIXME: put aux builds into the regular build queue.
-    config.out_dir = config.out_dir.join(path.with_extension(""));
+    config.out_dir = absolute(&test_out_dir(&config, path));
 
     let mut errors = vec![];
 
-    let mut aux_cmd = build_command(aux_file, &config, revision, &comments, &mut errors);
+    // The aux file has no revisions of its own, but sees the `--cfg`s of the test's revision.
+    let mut aux_cmd = build_command(
+        aux_file,
+        &config,
+        &config.out_dir,
+        "",
+        &comments,
+        &mut errors,
+    );
+    if !revision.is_empty() {
+        for value in test_comments.revision_values(revision) {
+            aux_cmd.arg(format!("--cfg={value}"));
+        }
+    }
 
     if !errors.is_empty() {
         return Err((aux_cmd, errors, vec![]));
     }
 
-    let current_extra_args =
-        build_aux_files(aux_file, aux_file.parent().unwrap(), &comments, "", &config)?;
+    let current_extra_args = build_aux_files(
+        aux_file,
+        aux_file.parent().unwrap(),
+        &comments,
+        "",
+        &config,
+        aux_builds,
+    )?;
     // Make sure we see our dependencies
     aux_cmd.args(current_extra_args.iter());
-    // Make sure our dependents also see our dependencies.
-    extra_args.extend(current_extra_args);
+    // Make sure our dependents can find our dependencies, without making them available by name,
+    // as they may depend on a different crate of the same name.
+    let search_paths: Vec<_> = current_extra_args
+        .chunks(2)
+        .filter(|pair| pair[0] == "-L")
+        .flatten()
+        .cloned()
+        .collect();
+    extend_extra_args(extra_args, &search_paths);
 
+    // Same-named aux files in different directories must not be mistaken for each other.
+    let disambiguator = path_hash(aux_file);
+    aux_cmd.arg(format!("-Cmetadata={disambiguator:016x}"));
+    aux_cmd.arg(format!("-Cextra-filename=-{disambiguator:016x}"));
+    aux_cmd.args(flags);
+    let kind = kind
+        .or_else(|| {
+            comments
+                .for_revision("")
+                .find_map(|r| r.crate_type.as_ref())
+                .map(|(kind, _)| kind.as_str())
+        })
+        .unwrap_or("lib");
     aux_cmd.arg("--crate-type").arg(kind);
     aux_cmd.arg("--emit=link");
     let filename = aux.file_stem().unwrap().to_str().unwrap();
-    let output = aux_cmd.output().unwrap();
+
+    let cache = config.out_dir.join(format!("{filename}.{kind}.cache"));
+    let hash = aux_build_hash(aux_file, &aux_cmd, extra_args);
+    if !config.force_rebuild {
+        if let Some(cached) = cached_aux_build(&cache, hash) {
+            extend_extra_args(extra_args, &cached);
+            return Ok(());
+        }
+    }
+
+    let _job = aux_builds.jobs.acquire();
+    let output = match command_output(&mut aux_cmd) {
+        Ok(output) => output,
+        Err(err) => {
+            let error = spawn_error(&aux_cmd, err);
+            return Err((aux_cmd, vec![error], vec![]));
+        }
+    };
     if !output.status.success() {
         let error = Error::Command {
             kind: "compilation of aux build failed".to_string(),
@@ -485,224 +1815,1676 @@ fn build_aux(
         return Err((
             aux_cmd,
             vec![error],
-            rustc_stderr::process(path, &output.stderr).rendered,
+            config
+                .diagnostics_parser
+                .parse(path, &output.stderr)
+                .rendered,
         ));
     }
 
+    // Aux files with their own `//~` annotations get checked against the diagnostics
+    // of their compilation. Without any annotations, we only care about the exit status.
+    if comments
+        .for_revision("")
+        .any(|r| !r.error_matches.is_empty() || !r.error_in_other_files.is_empty())
+    {
+        let diagnostics = config.diagnostics_parser.parse(aux_file, &output.stderr);
+        let mut aux_config = config.clone();
+        if !matches!(aux_config.mode, Mode::Yolo) {
+            // The aux build succeeded, so it can't be a fail test, but we still want to see
+            // all unmatched diagnostics.
+            aux_config.mode = Mode::Fail {
+                require_patterns: false,
+            };
+        }
+        check_annotations(
+            diagnostics.messages,
+            diagnostics.messages_from_unknown_file_or_line,
+            aux_file,
+            &mut errors,
+            &aux_config,
+            "",
+            &comments,
+        );
+        if !errors.is_empty() {
+            return Err((aux_cmd, errors, diagnostics.rendered));
+        }
+    }
+
     // Now run the command again to fetch the output filenames
     aux_cmd.arg("--print").arg("file-names");
-    let output = aux_cmd.output().unwrap();
-    assert!(output.status.success());
+    let output = match command_output(&mut aux_cmd) {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            let error = Error::Command {
+                kind: "printing the file names of aux build".to_string(),
+                status: output.status,
+            };
+            return Err((aux_cmd, vec![error], output.stderr));
+        }
+        Err(err) => {
+            let error = spawn_error(&aux_cmd, err);
+            return Err((aux_cmd, vec![error], vec![]));
+        }
+    };
 
+    let mut own_args = vec![];
     for file in output.stdout.lines() {
         let file = std::str::from_utf8(file).unwrap();
         let crate_name = filename.replace('-', "_");
         let path = config.out_dir.join(file);
-        extra_args.push("--extern".into());
-        extra_args.push(format!("{crate_name}={}", path.display()));
-        // Help cargo find the crates added with `--extern`.
-        extra_args.push("-L".into());
-        extra_args.push(config.out_dir.display().to_string());
+        own_args.extend([
+            "--extern".into(),
+            format!("{crate_name}={}", path.display()),
+            // Help cargo find the crates added with `--extern`.
+            "-L".into(),
+            config.out_dir.display().to_string(),
+        ]);
     }
+    extend_extra_args(extra_args, &own_args);
+    cache_aux_build(&cache, hash, &own_args);
     Ok(())
 }
 
-fn run_test(
-    path: &Path,
-    config: &Config,
-    revision: &str,
-    comments: &Comments,
-) -> (Command, Errors, Vec<u8>) {
-    let extra_args = match build_aux_files(
-        path,
-        &path.parent().unwrap().join("auxiliary"),
-        comments,
-        revision,
-        config,
-    ) {
-        Ok(value) => value,
-        Err(value) => return value,
-    };
-
-    let mut errors = vec![];
+lazy_static::lazy_static! {
+    /// The `-vV` output of each program used for aux builds, so it is only queried once per run.
+    static ref PROGRAM_VERSIONS: Mutex<HashMap<OsString, Vec<u8>>> =
+        Default::default();
+}
 
-    let mut cmd = build_command(path, config, revision, comments, &mut errors);
-    cmd.args(&extra_args);
+/// The `-vV` output of `program`, or nothing if it doesn't support `-vV`.
+fn program_version(program: &OsStr) -> Vec<u8> {
+    PROGRAM_VERSIONS
+        .lock()
+        .unwrap()
+        .entry(program.to_owned())
+        .or_insert_with(|| {
+            Command::new(program)
+                .arg("-vV")
+                .output()
+                .map(|output| output.stdout)
+                .unwrap_or_default()
+        })
+        .clone()
+}
 
-    let output = cmd
-        .output()
-        .unwrap_or_else(|err| panic!("could not execute {cmd:?}: {err}"));
-    let mode = config.mode.maybe_override(comments, revision, &mut errors);
-    let status_check = mode.ok(output.status);
-    if status_check.is_empty() && matches!(mode, Mode::Run { .. }) {
-        let cmd = run_test_binary(mode, path, revision, comments, cmd, config, &mut errors);
-        return (cmd, errors, vec![]);
+/// Hash everything that goes into an aux build: the aux file, the command building it, the
+/// version of the program and the artifacts of the aux builds it depends on.
+fn aux_build_hash(aux_file: &Path, cmd: &Command, deps: &[String]) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = StableHasher::default();
+    std::fs::read(aux_file).ok().hash(&mut hasher);
+    cmd.get_program().hash(&mut hasher);
+    for arg in cmd.get_args() {
+        arg.hash(&mut hasher);
     }
-    errors.extend(status_check);
-    if output.status.code() == Some(101) && !matches!(config.mode, Mode::Panic | Mode::Yolo) {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        errors.push(Error::Bug(format!(
-            "test panicked: stderr:\n{stderr}\nstdout:\n{stdout}",
-        )));
-        return (cmd, errors, vec![]);
+    for env in cmd.get_envs() {
+        env.hash(&mut hasher);
     }
-    // Always remove annotation comments from stderr.
-    let diagnostics = rustc_stderr::process(path, &output.stderr);
-    let rustfixed = matches!(mode, Mode::Fix).then(|| {
-        run_rustfix(
-            &output.stderr,
-            path,
-            comments,
-            revision,
-            config,
-            extra_args,
-            &mut errors,
-        )
-    });
-    let stderr = check_test_result(
-        path,
-        config,
-        revision,
-        comments,
-        &mut errors,
-        &output.stdout,
-        diagnostics,
-    );
-    if let Some((mut rustfix, rustfix_path)) = rustfixed {
-        // picking the crate name from the file name is problematic when `.revision_name` is inserted
-        rustfix.arg("--crate-name").arg(
-            path.file_stem()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .replace('-', "_"),
-        );
-        let output = rustfix.output().unwrap();
-        if !output.status.success() {
-            errors.push(Error::Command {
-                kind: "rustfix".into(),
-                status: output.status,
-            });
-            return (
-                rustfix,
-                errors,
-                rustc_stderr::process(&rustfix_path, &output.stderr).rendered,
-            );
+    cmd.get_current_dir().hash(&mut hasher);
+    program_version(cmd.get_program()).hash(&mut hasher);
+    // Rebuilt dependencies keep their path, but not their modification time.
+    for pair in deps.chunks(2) {
+        if let [flag, dep] = pair {
+            if let Some((_, path)) = dep.split_once('=').filter(|_| flag == "--extern") {
+                let metadata = std::fs::metadata(path).ok();
+                metadata.as_ref().map(|m| m.len()).hash(&mut hasher);
+                metadata
+                    .and_then(|m| m.modified().ok())
+                    .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                    .hash(&mut hasher);
+            }
         }
     }
-    (cmd, errors, stderr)
+    hasher.finish()
 }
 
-fn build_aux_files(
-    path: &Path,
-    aux_dir: &Path,
-    comments: &Comments,
-    revision: &str,
-    config: &Config,
-) -> Result<Vec<String>, (Command, Vec<Error>, Vec<u8>)> {
-    let mut extra_args = vec![];
-    for rev in comments.for_revision(revision) {
-        for (aux, kind, line) in &rev.aux_builds {
-            let aux_file = if aux.starts_with("..") {
-                aux_dir.parent().unwrap().join(aux)
-            } else {
-                aux_dir.join(aux)
-            };
-            if let Err((command, errors, msg)) = build_aux(
-                &aux_file,
-                path,
-                config,
-                revision,
-                comments,
-                kind,
-                aux,
-                &mut extra_args,
-            ) {
-                return Err((
-                    command,
-                    vec![Error::Aux {
-                        path: aux_file,
-                        errors,
-                        line: *line,
-                    }],
-                    msg,
-                ));
+/// The flags to link against an aux build that an earlier run cached in `cache`, if its hash is
+/// still `hash` and its artifacts still exist.
+fn cached_aux_build(cache: &Path, hash: u64) -> Option<Vec<String>> {
+    let cached = std::fs::read_to_string(cache).ok()?;
+    let mut lines = cached.lines();
+    if lines.next()? != format!("{hash:016x}") {
+        return None;
+    }
+    let args: Vec<String> = lines.map(String::from).collect();
+    for pair in args.chunks(2) {
+        match pair {
+            [flag, dep] if flag == "--extern" => {
+                let (_, path) = dep.split_once('=')?;
+                if !Path::new(path).exists() {
+                    return None;
+                }
             }
+            [_, _] => {}
+            _ => return None,
         }
     }
-    Ok(extra_args)
+    Some(args)
 }
 
-fn run_test_binary(
-    mode: Mode,
-    path: &Path,
-    revision: &str,
-    comments: &Comments,
-    mut cmd: Command,
-    config: &Config,
-    errors: &mut Vec<Error>,
-) -> Command {
-    cmd.arg("--print").arg("file-names");
-    let output = cmd.output().unwrap();
-    assert!(output.status.success());
+/// Remember the flags to link against an aux build for later runs. Written to a temporary file
+/// first, so that other processes sharing the [`Config::out_dir`] never see a partial cache file.
+fn cache_aux_build(cache: &Path, hash: u64, args: &[String]) {
+    let mut contents = format!("{hash:016x}\n");
+    for arg in args {
+        contents.push_str(arg);
+        contents.push('\n');
+    }
+    let tmp = cache.with_extension(format!("{}.tmp", std::process::id()));
+    // The cache is only an optimization, failing to write it just means rebuilding next time.
+    if std::fs::write(&tmp, contents).is_ok() && std::fs::rename(&tmp, cache).is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+}
 
-    let mut files = output.stdout.lines();
-    let file = files.next().unwrap();
-    assert_eq!(files.next(), None);
-    let file = std::str::from_utf8(file).unwrap();
-    let exe = config.out_dir.join(file);
-    let mut exe = Command::new(exe);
-    let output = exe.output().unwrap();
+/// Which of [`Config::filters`] and [`Config::skip`] excludes a test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Excluded {
+    Filter,
+    Skip,
+}
 
-    check_test_output(
-        path,
-        errors,
-        revision,
-        config,
-        comments,
-        &output.stdout,
-        &output.stderr,
-    );
+/// Whether the test at `path` is excluded by the [`Config::filters`] or [`Config::skip`].
+fn excluded(config: &Config, path: &Path, revision: &str) -> Option<Excluded> {
+    if config.filters.is_empty() && config.skip.is_empty() {
+        return None;
+    }
+    let id = test_id(config, path, revision);
+    let test = test_id(config, path, "");
+    let matches = |patterns: &[String]| {
+        patterns.iter().any(|pattern| {
+            if config.exact {
+                *pattern == id
+            } else {
+                // The globs were checked when the test run started.
+                TestGlob::parse(pattern).map_or(false, |glob| glob.matches(&test, revision))
+            }
+        })
+    };
+    if !config.filters.is_empty() && !matches(&config.filters) {
+        Some(Excluded::Filter)
+    } else if matches(&config.skip) {
+        Some(Excluded::Skip)
+    } else {
+        None
+    }
+}
 
-    errors.extend(mode.ok(output.status));
+/// The path of the test relative to [`Config::root_dir`] with `/` separators, followed by
+/// `::revision` for revisions. This is what [`Config::list`] prints and what
+/// [`Config::exact`] filters compare to.
+fn test_id(config: &Config, path: &Path, revision: &str) -> String {
+    test_id_in(&config.root_dir, path, revision)
+}
 
-    exe
+/// The id of the test, like [`test_id`], for the tests in `root_dir`.
+pub(crate) fn test_id_in(root_dir: &Path, path: &Path, revision: &str) -> String {
+    let mut id = relative_to(root_dir, path)
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    if !revision.is_empty() {
+        id.push_str("::");
+        id.push_str(revision);
+    }
+    id
 }
 
-fn run_rustfix(
-    stderr: &[u8],
-    path: &Path,
-    comments: &Comments,
-    revision: &str,
-    config: &Config,
-    extra_args: Vec<String>,
-    errors: &mut Vec<Error>,
-) -> (Command, PathBuf) {
-    let input = std::str::from_utf8(stderr).unwrap();
-    let suggestions = rustfix::get_suggestions_from_json(
-        input,
-        &HashSet::new(),
-        if let Mode::Yolo = config.mode {
-            rustfix::Filter::Everything
-        } else {
-            rustfix::Filter::MachineApplicableOnly
+/// Whether the test at `path` belongs to the [`Config::shard`] that is running.
+fn in_shard(config: &Config, path: &Path, revision: &str) -> bool {
+    match config.shard {
+        None => true,
+        Some((index, count)) => {
+            let mut test = relative_to_root(config, path).into_os_string();
+            if !revision.is_empty() {
+                test.push(format!("#{revision}"));
+            }
+            path_hash(Path::new(&test)) % count as u64 == index as u64
+        }
+    }
+}
+
+/// A hash of `path` that, unlike the hashers of the standard library, is the same on every
+/// platform and with every Rust version.
+fn path_hash(path: &Path) -> u64 {
+    // 64 bit FNV-1a
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in path.to_string_lossy().replace('\\', "/").bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// The canonical `path`, for comparing it with other canonical paths.
+fn canonicalize(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path)
+        .map(|path| strip_verbatim(&path))
+        .unwrap_or_else(|_| path.to_owned())
+}
+
+/// `path` without a verbatim `\\?\` prefix, like the ones [`std::fs::canonicalize`] returns on
+/// Windows, so that it can be compared with paths that don't have one. Such paths must not be
+/// used to access files, as they may be longer than Windows allows without the prefix.
+pub(crate) fn strip_verbatim(path: &Path) -> PathBuf {
+    let mut components = path.components();
+    let prefix = match components.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::VerbatimDisk(disk) => format!("{}:", disk as char),
+            Prefix::VerbatimUNC(server, share) => format!(
+                r"\\{}\{}",
+                server.to_string_lossy(),
+                share.to_string_lossy()
+            ),
+            _ => return path.to_owned(),
         },
-    )
-    .unwrap_or_else(|err| {
-        panic!("could not deserialize diagnostics json for rustfix {err}:{input}")
-    });
-    let fixed_code =
-        rustfix::apply_suggestions(&std::fs::read_to_string(path).unwrap(), &suggestions)
-            .unwrap_or_else(|e| {
-                panic!(
-                    "failed to apply suggestions for {:?} with rustfix: {e}",
-                    path.display()
-                )
-            });
-    let edition = comments.edition(errors, revision, config);
-    let rustfix_comments = Comments {
+        _ => return path.to_owned(),
+    };
+    Path::new(&prefix).join(components.as_path())
+}
+
+/// The directory in [`Config::out_dir`] for the artifacts of the test at `path`, see
+/// [`Config::max_test_out_dir_len`].
+fn test_out_dir(config: &Config, path: &Path) -> PathBuf {
+    let mirrored = relative_to(Path::new(""), &path.with_extension(""));
+    if mirrored.as_os_str().len() <= config.max_test_out_dir_len {
+        return config.out_dir.join(mirrored);
+    }
+    let stem = path.file_stem().unwrap().to_string_lossy();
+    config
+        .out_dir
+        .join(format!("{stem}-{:016x}", path_hash(&mirrored)))
+}
+
+/// The directory in the [`test_out_dir`] for the files of `revision` of the test at `path`, which
+/// only that revision uses, see [`Config::cleanup`].
+fn revision_out_dir(config: &Config, path: &Path, revision: &str) -> PathBuf {
+    match config.test_kind {
+        TestKind::File => test_out_dir(config, path).join(revised(revision, "artifacts")),
+        // All cargo projects share cargo's build cache.
+        TestKind::CargoProject => config.out_dir.clone(),
+    }
+}
+
+/// The `--out-dir` of `revision` of the test at `path`. Only cleaning up needs the artifacts of
+/// each revision in their own [`revision_out_dir`], with [`CleanupPolicy::Keep`] all tests
+/// compile into the [`Config::out_dir`] itself.
+fn compile_out_dir(config: &Config, path: &Path, revision: &str) -> PathBuf {
+    match config.cleanup {
+        CleanupPolicy::Keep => config.out_dir.clone(),
+        CleanupPolicy::RemoveOnSuccess | CleanupPolicy::Fresh => {
+            revision_out_dir(config, path, revision)
+        }
+    }
+}
+
+/// Remove `path` and everything in it, returning how many bytes that freed. Files that can't be
+/// removed, e.g. because another process has them open on Windows, are left alone.
+fn remove_artifacts(path: &Path) -> u64 {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return if std::fs::remove_file(path).is_ok() {
+            metadata.len()
+        } else {
+            0
+        };
+    }
+    let mut freed = 0;
+    if let Ok(entries) = std::fs::read_dir(path) {
+        for entry in entries.flatten() {
+            freed += remove_artifacts(&entry.path());
+        }
+    }
+    let _ = std::fs::remove_dir(path);
+    freed
+}
+
+/// Remove the artifacts in `dir`, the mirror of `tests` in the [`Config::out_dir`], that
+/// [`CleanupPolicy::Fresh`] doesn't need to leave for other shards: everything of tests that
+/// don't exist anymore, and without [`Config::shard`], the [`revision_out_dir`]s of the others.
+/// The cached aux builds of existing tests stay.
+fn remove_stale_artifacts(config: &Config, dir: &Path, tests: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+    // Whether `dir` belongs to a test file, i.e. `tests` with any extension.
+    let exists = tests
+        .parent()
+        .and_then(|parent| std::fs::read_dir(parent).ok())
+        .map_or(false, |mut siblings| {
+            siblings.any(|sibling| {
+                sibling.map_or(false, |sibling| {
+                    sibling.path().with_extension("") == tests
+                        && sibling.file_type().map_or(false, |kind| kind.is_file())
+                })
+            })
+        });
+    let mut freed = 0;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if !entry.file_type().map_or(false, |kind| kind.is_dir()) {
+            if !exists {
+                freed += remove_artifacts(&path);
+            }
+        } else if name == "artifacts" || name.ends_with(".artifacts") {
+            if !exists || config.shard.is_none() {
+                freed += remove_artifacts(&path);
+            }
+        } else {
+            // The directory of a test in a subdirectory of the tests.
+            freed += remove_stale_artifacts(config, &path, &tests.join(entry.file_name()));
+        }
+    }
+    // Only succeeds if nothing is left.
+    let _ = std::fs::remove_dir(dir);
+    freed
+}
+
+/// Add the [`test_out_dir`]s of all files in `dir` to `out_dirs`.
+fn collect_test_out_dirs(config: &Config, dir: &Path, out_dirs: &mut HashSet<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        match entry.file_type() {
+            Ok(kind) if kind.is_dir() => collect_test_out_dirs(config, &entry.path(), out_dirs),
+            Ok(kind) if kind.is_file() => {
+                out_dirs.insert(test_out_dir(config, &entry.path()));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Whether `name` is the name of a [`test_out_dir`] that [`Config::max_test_out_dir_len`] made
+/// a hash of the test's path, like `foo-0123456789abcdef`.
+fn is_hashed_dir_name(name: &str) -> bool {
+    name.rsplit_once('-').map_or(false, |(stem, hash)| {
+        !stem.is_empty()
+            && hash.len() == 16
+            && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    })
+}
+
+/// Like [`remove_stale_artifacts`], but for the hashed [`test_out_dir`]s directly in `out_dir`,
+/// which don't mirror the path of their test. The ones not in `existing` belong to tests that
+/// don't exist anymore, or at least not in any of the roots of this run.
+fn remove_stale_hashed_artifacts(
+    config: &Config,
+    out_dir: &Path,
+    existing: &HashSet<PathBuf>,
+) -> u64 {
+    let Ok(entries) = std::fs::read_dir(out_dir) else {
+        return 0;
+    };
+    let mut freed = 0;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !entry.file_type().map_or(false, |kind| kind.is_dir())
+            || !is_hashed_dir_name(&entry.file_name().to_string_lossy())
+        {
+            continue;
+        }
+        if !existing.contains(&path) {
+            freed += remove_artifacts(&path);
+        } else if config.shard.is_none() {
+            let Ok(entries) = std::fs::read_dir(&path) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                if entry.file_type().map_or(false, |kind| kind.is_dir())
+                    && (name == "artifacts" || name.ends_with(".artifacts"))
+                {
+                    freed += remove_artifacts(&entry.path());
+                }
+            }
+        }
+    }
+    freed
+}
+
+/// Append pairs of flags like `--extern foo=...` or `-L dir`, unless they were already added by
+/// an earlier aux build.
+fn extend_extra_args(extra_args: &mut Vec<String>, new: &[String]) {
+    for pair in new.chunks(2) {
+        if !extra_args.chunks(2).any(|prev| prev == pair) {
+            extra_args.extend_from_slice(pair);
+        }
+    }
+}
+
+/// An aux file, its crate type and the extra flags to build it with.
+type AuxBuildKey = (PathBuf, Option<String>, Vec<String>);
+
+/// The aux builds of a single test, shared by the threads building them.
+struct AuxBuilds<'a> {
+    /// The flags to link against each aux file, or `None` if it has not been built successfully
+    /// yet. Locked while the aux file is being built, so that aux files depended on by several
+    /// others are only built once.
+    built: Mutex<HashMap<AuxBuildKey, Arc<AuxBuild>>>,
+    /// Each aux build waits for a slot, once the aux builds it depends on are built.
+    jobs: &'a Jobs,
+}
+
+impl<'a> AuxBuilds<'a> {
+    fn new(jobs: &'a Jobs) -> Self {
+        Self {
+            built: Default::default(),
+            jobs,
+        }
+    }
+}
+
+/// The flags to link against an aux file, once it has been built successfully.
+type AuxBuild = Mutex<Option<Vec<String>>>;
+
+/// Run the [`Flag::pre_test_action`]s of the custom comments of the test. Returns the command
+/// that replaces the one compiling the test, if any, or the result of a test that must not run.
+fn pre_test_actions(
+    path: &Path,
+    config: &Config,
+    revision: &str,
+    comments: &Comments,
+) -> std::result::Result<Option<Command>, TestResult> {
+    let mut replacement = None;
+    for (name, (flag, line)) in comments.for_revision(revision).flat_map(|r| &r.custom) {
+        match flag.pre_test_action(config, path, revision) {
+            Ok(PreTest::Continue) => {}
+            Ok(PreTest::Skip { reason }) => {
+                return Err(TestResult::Ignored {
+                    reason: format!("`//@{name}`: {reason}"),
+                    line: *line,
+                })
+            }
+            Ok(PreTest::ReplaceCommand(command)) => replacement = Some(command),
+            Err(message) => {
+                return Err(TestResult::Errored {
+                    command: Command::new("pre_test_action"),
+                    errors: vec![Error::CustomComment {
+                        name: name.clone(),
+                        message,
+                        line: *line,
+                    }],
+                    stderr: vec![],
+                })
+            }
+        }
+    }
+    Ok(replacement)
+}
+
+/// Run the test between the [`Config::before_test`] and [`Config::after_test`] hooks.
+fn run_test_with_hooks(
+    path: &Path,
+    config: &Config,
+    revision: &str,
+    comments: &Comments,
+    replacement: Option<&Command>,
+    jobs: &Jobs,
+    timings: &mut Timings,
+    captured: &mut Captured,
+) -> (Command, Errors, Vec<u8>) {
+    let hook_error = |hook: &str, message| Error::TestHook {
+        hook: hook.into(),
+        message,
+    };
+    if let Some(TestHook(hook)) = &config.before_test {
+        if let Err(message) = hook(config, path, revision) {
+            return (
+                Command::new("before_test hook"),
+                vec![hook_error("before_test", message)],
+                vec![],
+            );
+        }
+    }
+    let (command, mut errors, stderr) = run_test(
+        path,
+        config,
+        revision,
+        comments,
+        replacement,
+        jobs,
+        timings,
+        captured,
+    );
+    if let Some(TestHook(hook)) = &config.after_test {
+        if let Err(message) = hook(config, path, revision) {
+            errors.push(hook_error("after_test", message));
+        }
+    }
+    (command, errors, stderr)
+}
+
+fn run_test(
+    path: &Path,
+    config: &Config,
+    revision: &str,
+    comments: &Comments,
+    replacement: Option<&Command>,
+    jobs: &Jobs,
+    timings: &mut Timings,
+    captured: &mut Captured,
+) -> (Command, Errors, Vec<u8>) {
+    let mut phase = Phase::start();
+    let aux_dir = path.parent().unwrap().join("auxiliary");
+    if let Err(error) = check_aux_cycles(
+        config,
+        &aux_dir,
+        comments,
+        revision,
+        &mut vec![],
+        &mut HashSet::new(),
+    ) {
+        let mut errors = vec![error];
+        let cmd = build_command(
+            path,
+            config,
+            &compile_out_dir(config, path, revision),
+            revision,
+            comments,
+            &mut errors,
+        );
+        return (cmd, errors, vec![]);
+    }
+    let extra_args = match build_aux_files(
+        path,
+        &aux_dir,
+        comments,
+        revision,
+        config,
+        &AuxBuilds::new(jobs),
+    ) {
+        Ok(value) => value,
+        Err(value) => return value,
+    };
+    let aux_bins = match build_aux_bins(path, &aux_dir, comments, revision, config, jobs) {
+        Ok(value) => value,
+        Err(value) => return value,
+    };
+    let mut extra_args: Vec<OsString> = extra_args.into_iter().map(Into::into).collect();
+    for (name, args, line) in comments
+        .for_revision(revision)
+        .flat_map(|r| &r.custom_builds)
+    {
+        match config.custom_builds.build(name, args, config) {
+            Ok(flags) => extra_args.extend(flags),
+            Err(message) => {
+                let mut errors = vec![Error::CustomBuild {
+                    name: name.clone(),
+                    message,
+                    line: *line,
+                }];
+                let cmd = build_command(
+                    path,
+                    config,
+                    &compile_out_dir(config, path, revision),
+                    revision,
+                    comments,
+                    &mut errors,
+                );
+                return (cmd, errors, vec![]);
+            }
+        }
+    }
+
+    timings.aux_builds += phase.next();
+
+    // Only wait for a slot once the aux builds are done, as they need slots, too.
+    let _job = jobs.acquire();
+    timings.waiting += phase.next();
+
+    let mut errors = vec![];
+
+    let mut cmd = match replacement {
+        Some(replacement) => copy_command(replacement),
+        None => {
+            let mut cmd = build_command(
+                path,
+                config,
+                &compile_out_dir(config, path, revision),
+                revision,
+                comments,
+                &mut errors,
+            );
+            cmd.args(&extra_args);
+            cmd
+        }
+    };
+
+    let output = match run_command(&mut cmd, config.max_test_output_bytes, Stdio::null())
+        .unwrap_or_else(|err| Err(spawn_error(&cmd, err)))
+    {
+        Ok(output) => output,
+        Err(error) => {
+            errors.push(error);
+            return (cmd, errors, vec![]);
+        }
+    };
+    timings.compile += phase.next();
+    captured.stdout = output.stdout.clone();
+    captured.stderr = output.stderr.clone();
+    save_command_artifact(
+        path,
+        config,
+        comments,
+        revision,
+        &cmd,
+        output.status,
+        &mut errors,
+    );
+    let mode = config.mode.maybe_override(comments, revision, &mut errors);
+    if config.check_determinism {
+        let second = match run_command(&mut cmd, config.max_test_output_bytes, Stdio::null())
+            .unwrap_or_else(|err| Err(spawn_error(&cmd, err)))
+        {
+            Ok(output) => output,
+            Err(error) => {
+                errors.push(error);
+                return (cmd, errors, vec![]);
+            }
+        };
+        timings.compile += phase.next();
+        let [first, second] = [&output, &second].map(|output| {
+            let (stdout, diagnostics_output) = split_diagnostics_output(config, output)?;
+            let diagnostics = config
+                .diagnostics_parser
+                .parse(&config.diagnostics_file(path), &diagnostics_output);
+            Ok((diagnostics.rendered, stdout))
+        });
+        let (first, second) = match (first, second) {
+            (Ok(first), Ok(second)) => (first, second),
+            (Err(error), _) | (_, Err(error)) => {
+                errors.push(error);
+                return (cmd, errors, vec![]);
+            }
+        };
+        check_determinism(path, config, comments, revision, first, second, &mut errors);
+        if errors.is_empty() && output.status.success() && matches!(mode, Mode::Run { .. }) {
+            let cmd = run_test_binary(
+                mode,
+                path,
+                revision,
+                comments,
+                cmd,
+                config,
+                &aux_bins,
+                &mut errors,
+                captured,
+            );
+            timings.run += phase.next();
+            return (cmd, errors, vec![]);
+        }
+        return (cmd, errors, vec![]);
+    }
+    // The output of a crashed compiler is not worth comparing, nor blessing. This applies to
+    // `Mode::Panic`, too, which expects the exit code of a panic, but not a crash of the compiler.
+    if let Some((message, query_stack)) = rustc_stderr::internal_compiler_error(&output.stderr) {
+        errors.push(Error::InternalCompilerError {
+            message,
+            query_stack,
+        });
+        return (cmd, errors, output.stderr);
+    }
+    for (name, (flag, line)) in comments.for_revision(revision).flat_map(|r| &r.custom) {
+        if let Err(message) = flag.post_test_action(config, path, &output) {
+            errors.push(Error::CustomComment {
+                name: name.clone(),
+                message,
+                line: *line,
+            });
+        }
+    }
+    let status_check = mode.ok(output.status);
+    if status_check.is_empty() && matches!(mode, Mode::Run { .. }) {
+        let cmd = run_test_binary(
+            mode,
+            path,
+            revision,
+            comments,
+            cmd,
+            config,
+            &aux_bins,
+            &mut errors,
+            captured,
+        );
+        timings.run += phase.next();
+        return (cmd, errors, vec![]);
+    }
+    errors.extend(status_check);
+    if output.status.code() == Some(101) && !matches!(config.mode, Mode::Panic | Mode::Yolo) {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        errors.push(Error::Bug(format!(
+            "test panicked: stderr:\n{stderr}\nstdout:\n{stdout}",
+        )));
+        return (cmd, errors, vec![]);
+    }
+    let (stdout, diagnostics_output) = match split_diagnostics_output(config, &output) {
+        Ok(split) => split,
+        Err(error) => {
+            errors.push(error);
+            return (cmd, errors, vec![]);
+        }
+    };
+    // Always remove annotation comments from stderr.
+    let diagnostics = config
+        .diagnostics_parser
+        .parse(&config.diagnostics_file(path), &diagnostics_output);
+    let rustfixed = matches!(mode, Mode::Fix)
+        .then(|| {
+            run_rustfix(
+                &output.stderr,
+                path,
+                comments,
+                revision,
+                config,
+                extra_args,
+                &mut errors,
+            )
+        })
+        .flatten();
+    let stderr = check_test_result(
+        path,
+        config,
+        revision,
+        comments,
+        &mut errors,
+        &stdout,
+        diagnostics,
+    );
+    if let Some((mut rustfix, rustfix_path)) = rustfixed {
+        // picking the crate name from the file name is problematic when `.revision_name` is inserted
+        rustfix.arg("--crate-name").arg(
+            path.file_stem()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .replace('-', "_"),
+        );
+        timings.compare += phase.next();
+        let output = match command_output(&mut rustfix) {
+            Ok(output) => output,
+            Err(err) => {
+                errors.push(spawn_error(&rustfix, err));
+                return (rustfix, errors, vec![]);
+            }
+        };
+        timings.compile += phase.next();
+        save_command_artifact(
+            path,
+            config,
+            comments,
+            revision,
+            &rustfix,
+            output.status,
+            &mut errors,
+        );
+        let check_fixed = comments
+            .find_one_for_revision(
+                revision,
+                |r| r.check_fixed,
+                |_| {
+                    errors.push(Error::InvalidComment {
+                        msg: "`check-fixed` specified twice for same revision".into(),
+                        line: 0,
+                    })
+                },
+            )
+            .or(config.check_fixed.then_some(Level::Warn));
+        let diagnostics = config
+            .diagnostics_parser
+            .parse(&rustfix_path, &output.stderr);
+        if !output.status.success() {
+            errors.push(Error::Command {
+                kind: "rustfix".into(),
+                status: output.status,
+            });
+            return (rustfix, errors, diagnostics.rendered);
+        }
+        if let Some(level) = check_fixed {
+            let msgs: Vec<_> = diagnostics
+                .messages
+                .into_iter()
+                .flatten()
+                .chain(diagnostics.messages_from_unknown_file_or_line)
+                .filter(|msg| msg.level >= level)
+                .collect();
+            if !msgs.is_empty() {
+                errors.push(Error::FixedOutputNotClean { msgs });
+                return (rustfix, errors, diagnostics.rendered);
+            }
+        }
+    }
+    timings.compare += phase.next();
+    (cmd, errors, stderr)
+}
+
+/// Measures the phases of a test one after the other, see [`Timings`].
+struct Phase(Instant);
+
+impl Phase {
+    fn start() -> Self {
+        Self(Instant::now())
+    }
+
+    /// The time since the start of the current phase, which ends it and starts the next one.
+    fn next(&mut self) -> Duration {
+        let now = Instant::now();
+        now.duration_since(std::mem::replace(&mut self.0, now))
+    }
+}
+
+/// Build the aux files requested by `comments` in parallel, and return the flags to link against
+/// them.
+fn build_aux_files(
+    path: &Path,
+    aux_dir: &Path,
+    comments: &Comments,
+    revision: &str,
+    config: &Config,
+    aux_builds: &AuxBuilds<'_>,
+) -> Result<Vec<String>, (Command, Vec<Error>, Vec<u8>)> {
+    let requests: Vec<_> = comments
+        .for_revision(revision)
+        .flat_map(|r| &r.aux_builds)
+        .collect();
+    let results: Vec<_> = thread::scope(|s| {
+        let builds: Vec<_> = requests
+            .iter()
+            .map(|&request| {
+                s.spawn(move || {
+                    build_aux_file(
+                        path, aux_dir, comments, revision, config, aux_builds, request,
+                    )
+                })
+            })
+            .collect();
+        builds
+            .into_iter()
+            .map(|build| {
+                build
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+            })
+            .collect()
+    });
+    let mut extra_args = vec![];
+    for result in results {
+        extend_extra_args(&mut extra_args, &result?);
+    }
+    Ok(extra_args)
+}
+
+fn build_aux_file(
+    path: &Path,
+    aux_dir: &Path,
+    comments: &Comments,
+    revision: &str,
+    config: &Config,
+    aux_builds: &AuxBuilds<'_>,
+    (aux, kind, flags, line): &(PathBuf, Option<String>, Vec<String>, usize),
+) -> Result<Vec<String>, (Command, Vec<Error>, Vec<u8>)> {
+    let aux_file = aux_file(aux_dir, aux);
+    let key = (canonicalize(&aux_file), kind.clone(), flags.clone());
+    let build = aux_builds
+        .built
+        .lock()
+        .unwrap()
+        .entry(key)
+        .or_default()
+        .clone();
+    // Wait for other threads building the same aux file. If their build failed, this build
+    // fails, too, and reports its errors for this test.
+    let mut build = build.lock().unwrap();
+    if let Some(args) = &*build {
+        return Ok(args.clone());
+    }
+    let mut args = vec![];
+    if let Err((command, errors, msg)) = build_aux(
+        &aux_file,
+        path,
+        config,
+        revision,
+        comments,
+        kind.as_deref(),
+        flags,
+        aux,
+        &mut args,
+        aux_builds,
+    ) {
+        return Err((
+            command,
+            vec![Error::Aux {
+                path: aux_file,
+                errors,
+                line: *line,
+            }],
+            msg,
+        ));
+    }
+    *build = Some(args.clone());
+    Ok(args)
+}
+
+fn aux_file(aux_dir: &Path, aux: &Path) -> PathBuf {
+    if aux.starts_with("..") {
+        aux_dir.parent().unwrap().join(aux)
+    } else {
+        aux_dir.join(aux)
+    }
+}
+
+/// Find aux files that are aux builds of themselves, before building them in parallel would wait
+/// forever for the build of one of them to finish.
+fn check_aux_cycles(
+    config: &Config,
+    aux_dir: &Path,
+    comments: &Comments,
+    revision: &str,
+    chain: &mut Vec<PathBuf>,
+    acyclic: &mut HashSet<PathBuf>,
+) -> std::result::Result<(), Error> {
+    for rev in comments.for_revision(revision) {
+        for (aux, _, _, line) in &rev.aux_builds {
+            let aux_file = aux_file(aux_dir, aux);
+            let canonical = canonicalize(&aux_file);
+            if acyclic.contains(&canonical) {
+                continue;
+            }
+            if let Some(start) = chain.iter().position(|p| canonicalize(p) == canonical) {
+                let chain: Vec<_> = chain[start..]
+                    .iter()
+                    .chain([&aux_file])
+                    .map(|p| p.display().to_string())
+                    .collect();
+                return Err(Error::InvalidComment {
+                    msg: format!("aux builds form a cycle: {}", chain.join(" -> ")),
+                    line: *line,
+                });
+            }
+            // Aux files that can't be parsed are reported when building them.
+            if let Ok(aux_comments) = parse_comments_in_file(&aux_file, config) {
+                chain.push(aux_file.clone());
+                let result = check_aux_cycles(
+                    config,
+                    aux_file.parent().unwrap(),
+                    &aux_comments,
+                    "",
+                    chain,
+                    acyclic,
+                );
+                chain.pop();
+                if let Err(error) = result {
+                    return Err(Error::Aux {
+                        path: aux_file,
+                        errors: vec![error],
+                        line: *line,
+                    });
+                }
+            }
+            acyclic.insert(canonical);
+        }
+    }
+    Ok(())
+}
+
+/// How much of the beginning and end of the output is kept when it exceeds
+/// [`Config::max_test_output_bytes`].
+const TRUNCATED_OUTPUT_CONTEXT: usize = 4096;
+
+/// Like [`Command::output`], but kills the process as soon as stdout or stderr get larger
+/// than `max_output_bytes`, so that runaway tests can't use up all memory.
+/// The process is also killed on Ctrl-C.
+fn run_command(
+    cmd: &mut Command,
+    max_output_bytes: Option<usize>,
+    stdin: Stdio,
+) -> std::io::Result<Result<Output, Error>> {
+    let limit = max_output_bytes.unwrap_or(usize::MAX);
+    let mut child = cmd
+        .stdin(stdin)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout: Box<dyn Read + Send> = Box::new(child.stdout.take().unwrap());
+    let stderr: Box<dyn Read + Send> = Box::new(child.stderr.take().unwrap());
+    let child = interrupt::track(child);
+    // The readers are detached and report back through a channel instead of being joined:
+    // processes spawned by the child can keep a pipe open after the child was killed, and
+    // waiting for that pipe to close would hang.
+    let (sender, finished) = unbounded();
+    for (index, mut pipe) in [stdout, stderr].into_iter().enumerate() {
+        let sender = sender.clone();
+        thread::spawn(move || {
+            let mut buf = vec![];
+            let mut chunk = [0; 8192];
+            let exceeded = loop {
+                match pipe.read(&mut chunk) {
+                    Ok(0) => break false,
+                    Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(_) => break false,
+                }
+                if buf.len() > limit {
+                    break true;
+                }
+            };
+            // Dropping the pipe makes processes that still write to it fail instead of
+            // blocking once the pipe is full.
+            drop(pipe);
+            let _ = sender.send((index, buf, exceeded));
+        });
+    }
+    drop(sender);
+    let mut outputs = [None, None];
+    let mut exceeded = None;
+    while let Ok((index, buf, too_large)) = finished.recv() {
+        if too_large {
+            // Stop waiting for the other pipe, it may be held open by a grandchild.
+            exceeded = Some((index, buf));
+            interrupt::kill(child);
+            break;
+        }
+        outputs[index] = Some(buf);
+    }
+    let status = interrupt::untrack(child).wait()?;
+    if let Some((index, buf)) = exceeded {
+        let stream = ["stdout", "stderr"][index];
+        let head = &buf[..TRUNCATED_OUTPUT_CONTEXT.min(buf.len())];
+        let tail = &buf[buf
+            .len()
+            .saturating_sub(TRUNCATED_OUTPUT_CONTEXT)
+            .max(head.len())..];
+        return Ok(Err(Error::OutputTooLarge {
+            stream: stream.to_string(),
+            limit,
+            head: head.to_vec(),
+            tail: tail.to_vec(),
+        }));
+    }
+    let [stdout, stderr] = outputs.map(Option::unwrap_or_default);
+    Ok(Ok(Output {
+        status,
+        stdout,
+        stderr,
+    }))
+}
+
+/// Like [`Command::output`], but the process is killed on Ctrl-C.
+fn command_output(cmd: &mut Command) -> std::io::Result<Output> {
+    run_command(cmd, None, Stdio::null()).map(|output| output.unwrap_or_else(|_| unreachable!()))
+}
+
+/// The error for a `cmd` that could not be run at all, e.g. because its program doesn't exist.
+fn spawn_error(cmd: &Command, err: std::io::Error) -> Error {
+    Error::Io {
+        path: cmd.get_program().into(),
+        message: format!("could not execute `{}`: {err}", display_command(cmd)),
+    }
+}
+
+fn run_test_binary(
+    mode: Mode,
+    path: &Path,
+    revision: &str,
+    comments: &Comments,
+    mut cmd: Command,
+    config: &Config,
+    aux_bins: &[AuxBin],
+    errors: &mut Vec<Error>,
+    captured: &mut Captured,
+) -> Command {
+    cmd.arg("--print").arg("file-names");
+    let output = command_output(&mut cmd).unwrap();
+    assert!(output.status.success());
+
+    let mut files = output.stdout.lines();
+    let file = files.next().unwrap();
+    assert_eq!(files.next(), None);
+    let file = std::str::from_utf8(file).unwrap();
+    let exe = absolute(&compile_out_dir(config, path, revision).join(file));
+    let mut exe = Command::new(exe);
+    let Some((output, aux_errors)) =
+        run_binary_once(&mut exe, path, revision, comments, config, aux_bins, errors)
+    else {
+        return exe;
+    };
+    save_command_artifact(
+        path,
+        config,
+        comments,
+        revision,
+        &exe,
+        output.status,
+        errors,
+    );
+    captured.stdout = output.stdout.clone();
+    captured.stderr = output.stderr.clone();
+
+    if config.check_determinism {
+        if let Some((second, _)) =
+            run_binary_once(&mut exe, path, revision, comments, config, aux_bins, errors)
+        {
+            check_determinism(
+                path,
+                config,
+                comments,
+                revision,
+                (output.stderr, output.stdout),
+                (second.stderr, second.stdout),
+                errors,
+            );
+        }
+        if !errors.is_empty() {
+            errors.extend(aux_errors);
+        }
+        return exe;
+    }
+
+    check_test_output(
+        path,
+        errors,
+        revision,
+        config,
+        comments,
+        &output.stdout,
+        &output.stderr,
+    );
+
+    errors.extend(mode.ok(output.status));
+    // The output of the aux binaries helps debugging a failing test.
+    if !errors.is_empty() {
+        errors.extend(aux_errors);
+    }
+
+    exe
+}
+
+/// Run the test binary `exe` with its `//@run-aux-bin`s running next to it. Returns its output
+/// and the output of the aux binaries, which helps debugging a failing test, or `None` if it
+/// couldn't run.
+fn run_binary_once(
+    exe: &mut Command,
+    path: &Path,
+    revision: &str,
+    comments: &Comments,
+    config: &Config,
+    aux_bins: &[AuxBin],
+    errors: &mut Vec<Error>,
+) -> Option<(Output, Vec<Error>)> {
+    // Without a `//@working-dir`, each run starts in an empty directory, so tests can't see
+    // each other's files.
+    let working_dir = working_dir(config, path, revision, comments).unwrap_or_else(|| {
+        let dir = absolute(&revision_out_dir(config, path, revision).join("cwd"));
+        remove_artifacts(&dir);
+        dir
+    });
+    let result = std::fs::create_dir_all(&working_dir);
+    if result.is_err() {
+        io_error(errors, &working_dir, result);
+        return None;
+    }
+    exe.current_dir(&working_dir);
+    let mut running = vec![];
+    for bin in aux_bins {
+        match RunningAuxBin::spawn(bin, Some(&working_dir)) {
+            Ok(bin) => running.push(bin),
+            Err(err) => {
+                errors.push(Error::Bug(format!(
+                    "could not start aux binary `{}`: {err}",
+                    bin.exe.display()
+                )));
+                return None;
+            }
+        }
+    }
+    let stdin = test_stdin(path, comments, revision, errors)?;
+    let output = run_command(exe, config.max_test_output_bytes, stdin)
+        .unwrap_or_else(|err| Err(spawn_error(exe, err)));
+    let mut aux_errors = vec![];
+    for bin in running {
+        let (status, error) = bin.stop();
+        if status.map_or(false, |status| !status.success()) {
+            errors.push(error);
+        } else {
+            aux_errors.push(error);
+        }
+    }
+    match output {
+        Ok(output) => Some((output, aux_errors)),
+        Err(error) => {
+            errors.push(error);
+            errors.extend(aux_errors);
+            None
+        }
+    }
+}
+
+/// The stdout of the test, and the output to extract its diagnostics from. Cargo emits the
+/// diagnostics of [`TestKind::CargoProject`] tests wrapped in its own json messages on stdout.
+fn split_diagnostics_output(config: &Config, output: &Output) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let mut diagnostics_output = output.stderr.clone();
+    if config.test_kind != TestKind::CargoProject {
+        return Ok((output.stdout.clone(), diagnostics_output));
+    }
+    let (stdout, cargo_messages) =
+        rustc_stderr::split_cargo_messages(&output.stdout).map_err(Error::Bug)?;
+    diagnostics_output.extend(cargo_messages);
+    Ok((stdout, diagnostics_output))
+}
+
+/// Compare the stderr and stdout of two runs of the same command after normalizing them, see
+/// [`Config::check_determinism`].
+fn check_determinism(
+    path: &Path,
+    config: &Config,
+    comments: &Comments,
+    revision: &str,
+    (first_stderr, first_stdout): (Vec<u8>, Vec<u8>),
+    (second_stderr, second_stdout): (Vec<u8>, Vec<u8>),
+    errors: &mut Errors,
+) {
+    for (stream, filters, filter_fns, first, second) in [
+        (
+            "stderr",
+            &config.stderr_filters,
+            &config.stderr_filter_fns,
+            first_stderr,
+            second_stderr,
+        ),
+        (
+            "stdout",
+            &config.stdout_filters,
+            &config.stdout_filter_fns,
+            first_stdout,
+            second_stdout,
+        ),
+    ] {
+        let normalize = |output: &[u8]| {
+            let output = normalize(path, output, filters, config, comments, revision);
+            apply_filter_fns(output, filter_fns, &revised(revision, stream))
+        };
+        match (normalize(&first), normalize(&second)) {
+            (Ok(first), Ok(second)) => {
+                if first != second {
+                    errors.push(Error::Nondeterministic {
+                        stream,
+                        first,
+                        second,
+                        context: config.diff_context,
+                    });
+                }
+            }
+            (Err(error), _) | (_, Err(error)) => errors.push(error),
+        }
+    }
+}
+
+/// An aux file requested with `//@run-aux-bin`, built as a binary.
+struct AuxBin {
+    aux_file: PathBuf,
+    exe: PathBuf,
+    args: Vec<String>,
+}
+
+fn build_aux_bins(
+    path: &Path,
+    aux_dir: &Path,
+    comments: &Comments,
+    revision: &str,
+    config: &Config,
+    jobs: &Jobs,
+) -> Result<Vec<AuxBin>, (Command, Vec<Error>, Vec<u8>)> {
+    let mut bins = vec![];
+    for (aux, args, line) in comments
+        .for_revision(revision)
+        .flat_map(|r| &r.run_aux_bins)
+    {
+        let aux_file = aux_file(aux_dir, aux);
+        let mut link_args = vec![];
+        if let Err((command, errors, msg)) = build_aux(
+            &aux_file,
+            path,
+            config,
+            revision,
+            comments,
+            Some("bin"),
+            &[],
+            aux,
+            &mut link_args,
+            &AuxBuilds::new(jobs),
+        ) {
+            return Err((
+                command,
+                vec![Error::Aux {
+                    path: aux_file,
+                    errors,
+                    line: *line,
+                }],
+                msg,
+            ));
+        }
+        // For a binary, the `--extern` flag that would link against a library names the
+        // executable instead.
+        let exe = link_args
+            .chunks(2)
+            .find(|pair| pair[0] == "--extern")
+            .and_then(|pair| pair[1].split_once('='))
+            .map(|(_, exe)| PathBuf::from(exe))
+            .unwrap();
+        bins.push(AuxBin {
+            aux_file,
+            exe,
+            args: args.clone(),
+        });
+    }
+    Ok(bins)
+}
+
+/// What the test binary reads from its stdin: the `//@stdin` file, the `.stdin` file of the
+/// revision or of the test, or nothing. `None` if the `//@stdin` file can't be opened.
+fn test_stdin(
+    path: &Path,
+    comments: &Comments,
+    revision: &str,
+    errors: &mut Errors,
+) -> Option<Stdio> {
+    let explicit = comments.find_one_for_revision(
+        revision,
+        |r| r.stdin.as_ref(),
+        |&(_, line)| {
+            errors.push(Error::InvalidComment {
+                msg: "`stdin` specified twice".into(),
+                line,
+            })
+        },
+    );
+    if let Some((file, line)) = explicit {
+        let file = path.parent().unwrap().join(file);
+        return match std::fs::File::open(&file) {
+            Ok(file) => Some(file.into()),
+            Err(err) => {
+                errors.push(Error::InvalidComment {
+                    msg: format!("could not open the stdin file `{}`: {err}", file.display()),
+                    line: *line,
+                });
+                None
+            }
+        };
+    }
+    Some(
+        stdin_files(path, revision)
+            .iter()
+            .find_map(|file| std::fs::File::open(file).ok())
+            .map_or_else(Stdio::null, Stdio::from),
+    )
+}
+
+/// The `.stdin` files of the revision and of the test, if there is no `//@stdin` comment.
+fn stdin_files(path: &Path, revision: &str) -> [PathBuf; 2] {
+    [
+        path.with_extension(revised(revision, "stdin")),
+        path.with_extension("stdin"),
+    ]
+}
+
+/// An aux binary running while the test binary runs. It is killed when dropped, so it never
+/// outlives the test, even if the test panics.
+struct RunningAuxBin {
+    path: PathBuf,
+    child: std::process::Child,
+    stdout: Option<thread::JoinHandle<Vec<u8>>>,
+    stderr: Option<thread::JoinHandle<Vec<u8>>>,
+}
+
+impl RunningAuxBin {
+    fn spawn(bin: &AuxBin, working_dir: Option<&Path>) -> std::io::Result<Self> {
+        let mut child = Command::new(&bin.exe);
+        if let Some(dir) = working_dir {
+            child.current_dir(dir);
+        }
+        let mut child = child
+            .args(&bin.args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        // Keep reading the output, so the aux binary never blocks on a full pipe.
+        let read = |mut pipe: Box<dyn Read + Send>| {
+            thread::spawn(move || {
+                let mut buf = vec![];
+                let _ = pipe.read_to_end(&mut buf);
+                buf
+            })
+        };
+        let stdout = child.stdout.take().map(|pipe| read(Box::new(pipe)));
+        let stderr = child.stderr.take().map(|pipe| read(Box::new(pipe)));
+        Ok(Self {
+            path: bin.aux_file.clone(),
+            child,
+            stdout,
+            stderr,
+        })
+    }
+
+    /// Kill the aux binary unless it already exited, and return its exit status if it exited on
+    /// its own, together with its output.
+    fn stop(mut self) -> (Option<ExitStatus>, Error) {
+        let status = self.child.try_wait().ok().flatten();
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let join = |handle: Option<thread::JoinHandle<Vec<u8>>>| {
+            handle
+                .map(|handle| handle.join().unwrap_or_default())
+                .unwrap_or_default()
+        };
+        let error = Error::AuxBin {
+            path: std::mem::take(&mut self.path),
+            status,
+            stdout: join(self.stdout.take()),
+            stderr: join(self.stderr.take()),
+        };
+        (status, error)
+    }
+}
+
+impl Drop for RunningAuxBin {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Drops suggestions that would replace code already replaced by an earlier suggestion, as
+/// rustfix can't apply those. Multi-part suggestions are kept or dropped as a whole.
+/// Unless `allow_conflicts` is set, the earlier suggestion is dropped, too, and each conflict
+/// is reported as an error.
+fn non_overlapping_suggestions(
+    suggestions: Vec<rustfix::Suggestion>,
+    allow_conflicts: bool,
+) -> (Vec<rustfix::Suggestion>, Errors) {
+    let mut replaced: Vec<(&rustfix::Replacement, usize)> = vec![];
+    let mut conflicts = vec![];
+    for (i, suggestion) in suggestions.iter().enumerate() {
+        let replacements: Vec<_> = suggestion
+            .solutions
+            .iter()
+            .flat_map(|solution| &solution.replacements)
+            .collect();
+        let overlaps = |a: &rustfix::Replacement, b: &rustfix::Replacement| {
+            let (a_range, b_range) = (&a.snippet.range, &b.snippet.range);
+            // Applying the exact same replacement twice is fine.
+            a != b
+                && (a_range.start < b_range.end && b_range.start < a_range.end
+                    || a_range.start == b_range.start)
+        };
+        let conflict = replacements.iter().enumerate().find_map(|(j, a)| {
+            replaced
+                .iter()
+                .find(|(b, _)| overlaps(a, b))
+                .map(|&(b, earlier)| (earlier, *a, b))
+                .or_else(|| {
+                    replacements[..j]
+                        .iter()
+                        .find(|b| overlaps(a, b))
+                        .map(|&b| (i, *a, b))
+                })
+        });
+        match conflict {
+            None => replaced.extend(replacements.into_iter().map(|r| (r, i))),
+            Some((earlier, replacement, earlier_replacement)) => conflicts.push((
+                earlier,
+                i,
+                Error::SuggestionConflict {
+                    first: suggestions[earlier].message.clone(),
+                    first_span: earlier_replacement.snippet.line_range.to_string(),
+                    second: suggestion.message.clone(),
+                    second_span: replacement.snippet.line_range.to_string(),
+                },
+            )),
+        }
+    }
+    let dropped: Vec<_> = conflicts
+        .iter()
+        .flat_map(|&(earlier, i, _)| {
+            if allow_conflicts {
+                vec![i]
+            } else {
+                vec![earlier, i]
+            }
+        })
+        .collect();
+    let errors = if allow_conflicts {
+        vec![]
+    } else {
+        conflicts.into_iter().map(|(_, _, error)| error).collect()
+    };
+    // Also drop exact duplicates of dropped suggestions.
+    let dropped: Vec<_> = dropped.iter().map(|&i| suggestions[i].clone()).collect();
+    let suggestions = suggestions
+        .into_iter()
+        .filter(|suggestion| !dropped.contains(suggestion))
+        .collect();
+    (suggestions, errors)
+}
+
+/// Extract the suggestions rustfix should apply from rustc's json output.
+fn rustfix_suggestions(
+    stderr: &[u8],
+    apply_suggestions: ApplySuggestions,
+    allow_conflicts: bool,
+    no_fix_lines: &[usize],
+) -> (Vec<rustfix::Suggestion>, Errors) {
+    let input = std::str::from_utf8(stderr).unwrap();
+    let suggestions = serde_json::Deserializer::from_str(input)
+        .into_iter::<rustfix::diagnostics::Diagnostic>()
+        .map(|diagnostic| {
+            let mut diagnostic = diagnostic?;
+            // Drop the replacements that should not be applied, so rustfix skips them.
+            for child in &mut diagnostic.children {
+                for span in &mut child.spans {
+                    if !apply_suggestions.applies(span.suggestion_applicability) {
+                        span.suggested_replacement = None;
+                    }
+                }
+            }
+            Ok(rustfix::collect_suggestions(
+                &diagnostic,
+                &HashSet::new(),
+                rustfix::Filter::Everything,
+            ))
+        })
+        .filter_map(Result::transpose)
+        .collect::<Result<Vec<_>, serde_json::Error>>()
+        .unwrap_or_else(|err| {
+            panic!("could not deserialize diagnostics json for rustfix {err}:{input}")
+        });
+    // Skip the suggestions of diagnostics annotated with `@no-fix`.
+    let suggestions = suggestions
+        .into_iter()
+        .filter(|suggestion| {
+            !suggestion
+                .snippets
+                .iter()
+                .any(|snippet| no_fix_lines.contains(&snippet.line_range.start.line))
+        })
+        .collect();
+    non_overlapping_suggestions(suggestions, allow_conflicts)
+}
+
+/// Where the given lines of `code` end up after applying `suggestions` to it, so `@no-fix`
+/// annotations keep applying to their diagnostics in later `run-rustfix: loop` rounds.
+fn shift_lines(code: &str, lines: &[usize], suggestions: &[rustfix::Suggestion]) -> Vec<usize> {
+    let replacements: Vec<_> = suggestions
+        .iter()
+        .flat_map(|suggestion| &suggestion.solutions)
+        .flat_map(|solution| &solution.replacements)
+        .collect();
+    lines
+        .iter()
+        .map(|&line| {
+            let line_start: usize = code
+                .split_inclusive('\n')
+                .take(line.saturating_sub(1))
+                .map(str::len)
+                .sum();
+            let shift: isize = replacements
+                .iter()
+                .filter(|replacement| replacement.snippet.range.end <= line_start)
+                .map(|replacement| {
+                    let replaced = code
+                        .get(replacement.snippet.range.clone())
+                        .unwrap_or_default();
+                    replacement.replacement.matches('\n').count() as isize
+                        - replaced.matches('\n').count() as isize
+                })
+                .sum();
+            (line as isize + shift) as usize
+        })
+        .collect()
+}
+
+fn run_rustfix(
+    stderr: &[u8],
+    path: &Path,
+    comments: &Comments,
+    revision: &str,
+    config: &Config,
+    extra_args: Vec<OsString>,
+    errors: &mut Vec<Error>,
+) -> Option<(Command, PathBuf)> {
+    let out_dir = revision_out_dir(config, path, revision);
+    let compile_dir = compile_out_dir(config, path, revision);
+    let apply_suggestions = if let Mode::Yolo = config.mode {
+        ApplySuggestions::Everything
+    } else {
+        comments
+            .find_one_for_revision(
+                revision,
+                |r| r.apply_suggestions,
+                |_| {
+                    errors.push(Error::InvalidComment {
+                        msg: "`apply-suggestions` specified twice for same revision".into(),
+                        line: 0,
+                    })
+                },
+            )
+            .unwrap_or(config.apply_suggestions)
+    };
+    let edition = comments.edition(errors, revision, config);
+    let rustfix_comments = Comments {
         revisions: None,
+        revision_axes: comments.revision_axes.clone(),
+        revisions_line: 0,
+        allow_unused_revisions: false,
         revisioned: std::iter::once((
             vec![],
             Revisioned {
@@ -726,27 +3508,170 @@ fn run_rustfix(
                     .for_revision(revision)
                     .flat_map(|r| r.aux_builds.iter().cloned())
                     .collect(),
+                custom_builds: comments
+                    .for_revision(revision)
+                    .flat_map(|r| r.custom_builds.iter().cloned())
+                    .collect(),
+                run_aux_bins: vec![],
+                crate_type: None,
                 edition,
                 mode: Some((Mode::Pass, 0)),
-                needs_asm_support: false,
+                needs_asm_support: None,
+                needs_debug_assertions: None,
+                check_annotation_order: false,
+                check_fixed: None,
+                apply_suggestions: None,
+                rustfix_loop: false,
+                allow_suggestion_conflicts: false,
+                compare: None,
+                ansi: None,
+                retry: None,
+                stdin: None,
+                // The fixed code is somewhere else, so the test's directory is resolved here.
+                working_dir: comments
+                    .for_revision(revision)
+                    .find_map(|r| r.working_dir.as_ref())
+                    .map(|(dir, line)| (absolute(&path.parent().unwrap().join(dir)), *line)),
+                custom: comments
+                    .for_revision(revision)
+                    .flat_map(|r| r.custom.clone())
+                    .collect(),
             },
         ))
         .collect(),
     };
+    let rustfix_loop = comments.for_revision(revision).any(|r| r.rustfix_loop);
+    let mut fixed_code = match std::fs::read_to_string(path) {
+        Ok(code) => code,
+        Err(error) => {
+            io_error(errors, path, Err(error));
+            return None;
+        }
+    };
+    let allow_conflicts = comments
+        .for_revision(revision)
+        .any(|r| r.allow_suggestion_conflicts);
+    let mut no_fix_lines: Vec<_> = comments
+        .for_revision(revision)
+        .flat_map(|r| &r.error_matches)
+        .filter(|error_match| error_match.no_fix)
+        .map(|error_match| error_match.line)
+        .collect();
+    let (mut suggestions, conflicts) =
+        rustfix_suggestions(stderr, apply_suggestions, allow_conflicts, &no_fix_lines);
+    errors.extend(conflicts);
+    let mut iterations = 0;
+    let mut last_round = vec![];
+    loop {
+        no_fix_lines = shift_lines(&fixed_code, &no_fix_lines, &suggestions);
+        fixed_code = rustfix::apply_suggestions(&fixed_code, &suggestions).unwrap_or_else(|e| {
+            panic!(
+                "failed to apply suggestions for {:?} with rustfix: {e}",
+                path.display()
+            )
+        });
+        iterations += 1;
+        if !rustfix_loop {
+            break;
+        }
+        // Keep the intermediate results around for inspection.
+        let intermediate = out_dir.join(format!(
+            "{}.fixed{iterations}.rs",
+            path.file_stem().unwrap().to_string_lossy(),
+        ));
+        let result = std::fs::create_dir_all(&out_dir)
+            .and_then(|()| std::fs::write(&intermediate, &fixed_code));
+        if result.is_err() {
+            io_error(errors, &intermediate, result);
+            break;
+        }
+        let mut cmd = build_command(
+            &intermediate,
+            config,
+            &compile_dir,
+            revision,
+            &rustfix_comments,
+            errors,
+        );
+        cmd.args(&extra_args);
+        cmd.arg("--crate-name").arg(
+            path.file_stem()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .replace('-', "_"),
+        );
+        let output = match command_output(&mut cmd) {
+            Ok(output) => output,
+            Err(err) => {
+                errors.push(spawn_error(&cmd, err));
+                break;
+            }
+        };
+        let conflicts;
+        (suggestions, conflicts) = rustfix_suggestions(
+            &output.stderr,
+            apply_suggestions,
+            allow_conflicts,
+            &no_fix_lines,
+        );
+        let diagnostics = config
+            .diagnostics_parser
+            .parse(&intermediate, &output.stderr);
+        last_round = diagnostics
+            .messages
+            .into_iter()
+            .flatten()
+            .chain(diagnostics.messages_from_unknown_file_or_line)
+            .collect();
+        if !conflicts.is_empty() {
+            // The conflicting suggestions would stay around forever.
+            errors.extend(conflicts);
+            break;
+        }
+        if suggestions.is_empty() {
+            break;
+        }
+        if iterations >= config.rustfix_max_iterations {
+            errors.push(Error::RustfixIterationLimit {
+                iterations,
+                msgs: last_round.clone(),
+            });
+            break;
+        }
+    }
+    let mut fixed_errors = vec![];
     let path = check_output(
         fixed_code.as_bytes(),
         path,
-        errors,
+        &mut fixed_errors,
         revised(revision, "fixed"),
         &Filter::default(),
+        &config.fixed_filter_fns,
         config,
         &rustfix_comments,
         revision,
     );
+    errors.extend(fixed_errors.into_iter().map(|error| match error {
+        Error::OutputDiffers { .. } => Error::FixedOutputDiffers {
+            applied: apply_suggestions,
+            iterations,
+            msgs: last_round.clone(),
+            diff: Box::new(error),
+        },
+        error => error,
+    }));
 
-    let mut cmd = build_command(&path, config, revision, &rustfix_comments, errors);
+    let mut cmd = build_command(
+        &path,
+        config,
+        &compile_dir,
+        revision,
+        &rustfix_comments,
+        errors,
+    );
     cmd.args(extra_args);
-    (cmd, path)
+    Some((cmd, path))
 }
 
 fn revised(revision: &str, extension: &str) -> String {
@@ -805,6 +3730,7 @@ fn check_test_output(
         errors,
         revised(revision, "stderr"),
         &config.stderr_filters,
+        &config.stderr_filter_fns,
         config,
         comments,
         revision,
@@ -815,12 +3741,33 @@ fn check_test_output(
         errors,
         revised(revision, "stdout"),
         &config.stdout_filters,
+        &config.stdout_filter_fns,
         config,
         comments,
         revision,
     );
 }
 
+/// The messages on `line`, or if there are none, on the closest lines that have messages.
+fn nearby_messages(messages: &[Vec<Message>], line: usize) -> Vec<(usize, Vec<Message>)> {
+    let at = |line: usize| {
+        messages
+            .get(line)
+            .filter(|msgs| !msgs.is_empty())
+            .map(|msgs| (line, msgs.clone()))
+    };
+    (0..messages.len().max(line + 1))
+        .map(|distance| {
+            line.checked_sub(distance)
+                .and_then(at)
+                .into_iter()
+                .chain((distance > 0).then(|| at(line + distance)).flatten())
+                .collect::<Vec<_>>()
+        })
+        .find(|nearby| !nearby.is_empty())
+        .unwrap_or_default()
+}
+
 fn check_annotations(
     mut messages: Vec<Vec<Message>>,
     mut messages_from_unknown_file_or_line: Vec<Message>,
@@ -830,25 +3777,36 @@ fn check_annotations(
     revision: &str,
     comments: &Comments,
 ) {
-    let error_patterns = comments
+    let annotation_errors = errors.len();
+    let mut error_patterns: Vec<_> = comments
         .for_revision(revision)
-        .flat_map(|r| r.error_in_other_files.iter());
+        .flat_map(|r| r.error_in_other_files.iter())
+        .collect();
+    // Patterns for a specific position go first, so that unqualified patterns can't
+    // take away their diagnostics.
+    error_patterns.sort_by_key(|(_, _, location)| location.is_none());
 
     let mut seen_error_match = false;
-    for (error_pattern, definition_line) in error_patterns {
+    for (error_pattern, definition_line, location) in error_patterns {
         seen_error_match = true;
         // first check the diagnostics messages outside of our file. We check this first, so that
         // you can mix in-file annotations with //@error-in-other-file annotations, even if there is overlap
         // in the messages.
-        if let Some(i) = messages_from_unknown_file_or_line
-            .iter()
-            .position(|msg| error_pattern.matches(&msg.message))
-        {
+        if let Some(i) = messages_from_unknown_file_or_line.iter().position(|msg| {
+            error_pattern.matches(msg)
+                && location.as_ref().map_or(true, |location| {
+                    msg.location.as_ref().map_or(false, |loc| {
+                        loc.file.ends_with(&location.file) && loc.line == location.line
+                    })
+                })
+        }) {
             messages_from_unknown_file_or_line.remove(i);
         } else {
             errors.push(Error::PatternNotFound {
                 pattern: error_pattern.clone(),
                 definition_line: *definition_line,
+                line: None,
+                nearby: vec![],
             });
         }
     }
@@ -857,35 +3815,172 @@ fn check_annotations(
     // We will ensure that *all* diagnostics of level at least `lowest_annotation_level`
     // are matched.
     let mut lowest_annotation_level = Level::Error;
-    for &ErrorMatch {
-        ref pattern,
-        definition_line,
-        line,
-        level,
-    } in comments
+    let check_order = comments
+        .for_revision(revision)
+        .any(|r| r.check_annotation_order);
+    let mut error_matches: Vec<_> = comments
         .for_revision(revision)
         .flat_map(|r| r.error_matches.iter())
-    {
+        .collect();
+    // The order of the annotations is only meaningful if they are sorted the way they appear in the file.
+    if check_order {
+        error_matches.sort_by_key(|error_match| error_match.definition_line);
+    }
+    // The index each message had in the original list of messages of its line, so we can
+    // figure out the emission order even after removing some of the messages.
+    let mut emission_order: Vec<Vec<usize>> = messages
+        .iter()
+        .map(|msgs| (0..msgs.len()).collect())
+        .collect();
+    // Per line, the emission index of the last message matched and the annotation that matched it.
+    let mut last_matched: HashMap<usize, (usize, &ErrorMatch)> = HashMap::new();
+    // The line and emission index of the messages that were matched via a label on another line.
+    // They are not reported as unmatched, but can still be matched by annotations on their own line.
+    let mut label_matched: HashSet<(usize, usize)> = HashSet::new();
+    for error_match in error_matches {
+        let &ErrorMatch {
+            ref pattern,
+            definition_line,
+            line,
+            level,
+            count,
+            consume_all,
+            column,
+            no_fix: _,
+        } = error_match;
         seen_error_match = true;
         // If we found a diagnostic with a level annotation, make sure that all
         // diagnostics of that level have annotations, even if we don't end up finding a matching diagnostic
         // for this pattern.
         lowest_annotation_level = std::cmp::min(lowest_annotation_level, level);
 
-        if let Some(msgs) = messages.get_mut(line) {
-            let found = msgs
-                .iter()
-                .position(|msg| pattern.matches(&msg.message) && msg.level == level);
-            if let Some(found) = found {
-                msgs.remove(found);
-                continue;
+        // Without a count, a pattern consumes a single diagnostic, unless it is an `ERROR+` pattern,
+        // which consumes all matching diagnostics. In strict mode, counted patterns
+        // consume all matching diagnostics so that surplus ones can be reported as a count mismatch.
+        let limit = match count {
+            None if consume_all => usize::MAX,
+            None => 1,
+            Some(_) if config.strict_annotation_counts => usize::MAX,
+            Some(count) => count,
+        };
+        let mut found = 0;
+        if let Some(msgs) = messages.get_mut(line) {
+            while found < limit {
+                match msgs.iter().position(|msg| {
+                    pattern.matches(msg)
+                        && msg.level == level
+                        && column.map_or(true, |column| msg.column == Some(column))
+                }) {
+                    Some(i) => {
+                        msgs.remove(i);
+                        let emitted = emission_order[line].remove(i);
+                        found += 1;
+                        if !check_order {
+                            continue;
+                        }
+                        match last_matched.get(&line) {
+                            Some(&(prev_emitted, prev)) if prev_emitted > emitted => {
+                                errors.push(Error::AnnotationOutOfOrder {
+                                    pattern: pattern.clone(),
+                                    definition_line,
+                                    preceding_pattern: prev.pattern.clone(),
+                                    preceding_definition_line: prev.definition_line,
+                                });
+                            }
+                            _ => {
+                                last_matched.insert(line, (emitted, error_match));
+                            }
+                        }
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        // A diagnostic whose primary span is on another line may point to this line with a label.
+        if found == 0 {
+            'lines: for (msg_line, msgs) in messages.iter_mut().enumerate() {
+                for (i, msg) in msgs.iter_mut().enumerate() {
+                    if msg.level != level {
+                        continue;
+                    }
+                    let label = msg.labels.iter().position(|label| {
+                        label.line == line
+                            && column.map_or(true, |column| label.column == column)
+                            && pattern.matches(&Message {
+                                message: label.label.clone(),
+                                ..msg.clone()
+                            })
+                    });
+                    if let Some(label) = label {
+                        // Each label can only be matched once.
+                        msg.labels.remove(label);
+                        label_matched.insert((msg_line, emission_order[msg_line][i]));
+                        found += 1;
+                        if found == limit {
+                            break 'lines;
+                        }
+                    }
+                }
+            }
+        }
+
+        // Diagnostics that would have matched if it weren't for the column.
+        let columns: Vec<usize> = match column {
+            Some(_) if found == 0 => messages
+                .get(line)
+                .into_iter()
+                .flatten()
+                .filter(|msg| pattern.matches(msg) && msg.level == level)
+                .filter_map(|msg| msg.column)
+                .collect(),
+            _ => vec![],
+        };
+        if let (Some(expected), false) = (column, columns.is_empty()) {
+            errors.push(Error::ColumnMismatch {
+                pattern: pattern.clone(),
+                definition_line,
+                expected,
+                found: columns,
+            });
+        } else if found == 0 {
+            if let Pattern::Code(_) = pattern {
+                let codes = messages
+                    .get(line)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|msg| msg.code.clone())
+                    .collect();
+                errors.push(Error::CodeNotFound {
+                    pattern: pattern.clone(),
+                    definition_line,
+                    codes,
+                });
+            } else {
+                errors.push(Error::PatternNotFound {
+                    pattern: pattern.clone(),
+                    definition_line,
+                    line: Some(line),
+                    nearby: nearby_messages(&messages, line),
+                });
             }
+        } else if let Some(expected) = count.filter(|&count| count != found) {
+            errors.push(Error::PatternCountMismatch {
+                pattern: pattern.clone(),
+                definition_line,
+                expected,
+                found,
+            });
         }
+    }
 
-        errors.push(Error::PatternNotFound {
-            pattern: pattern.clone(),
-            definition_line,
-        });
+    // Yolo mode may be used precisely to avoid maintaining annotations, so unmatched
+    // annotations can be downgraded to warnings.
+    if let (Mode::Yolo, Strictness::Warn) = (&config.mode, config.check_annotations_in_yolo) {
+        for error in &mut errors[annotation_errors..] {
+            let err = std::mem::replace(error, Error::NoPatternsFound);
+            *error = Error::Warning(Box::new(err));
+        }
     }
 
     let required_annotation_level = comments
@@ -916,7 +4011,13 @@ fn check_annotations(
             });
         }
 
-        for (line, msgs) in messages.into_iter().enumerate() {
+        for (line, (msgs, emission_order)) in messages.into_iter().zip(emission_order).enumerate() {
+            let msgs = msgs
+                .into_iter()
+                .zip(emission_order)
+                .filter(|&(_, emitted)| !label_matched.contains(&(line, emitted)))
+                .map(|(msg, _)| msg)
+                .collect();
             let msgs = filter(msgs);
             if !msgs.is_empty() {
                 errors.push(Error::ErrorsWithoutPattern {
@@ -945,37 +4046,164 @@ fn check_output(
     errors: &mut Errors,
     kind: String,
     filters: &Filter,
+    filter_fns: &[FilterFn],
     config: &Config,
     comments: &Comments,
     revision: &str,
 ) -> PathBuf {
     let target = config.target.as_ref().unwrap();
-    let output = normalize(path, output, filters, comments, revision);
-    let path = output_path(path, comments, kind, target, revision);
+    let output = normalize(path, output, filters, config, comments, revision);
+    let output = match apply_filter_fns(output, filter_fns, &kind) {
+        Ok(output) => output,
+        Err(error) => {
+            // Neither compare nor bless output that didn't get normalized.
+            errors.push(error);
+            return output_path(
+                &expected_output_base(config, path),
+                comments,
+                kind,
+                target,
+                revision,
+            );
+        }
+    };
+    let compare = comments
+        .find_one_for_revision(
+            revision,
+            |r| r.compare,
+            |_| {
+                errors.push(Error::InvalidComment {
+                    msg: "`compare` specified twice for same revision".into(),
+                    line: 0,
+                })
+            },
+        )
+        .unwrap_or(config.compare);
+    let output = match compare {
+        Compare::Exact => output,
+        Compare::LenientWhitespace => strip_trailing_whitespace(&output),
+    };
+    let test_path = path;
+    let path = output_path(
+        &expected_output_base(config, path),
+        comments,
+        kind,
+        target,
+        revision,
+    );
+    save_artifact(config, &path, &output, errors);
     match &config.output_conflict_handling {
         OutputConflictHandling::Bless => {
-            if output.is_empty() {
-                let _ = std::fs::remove_file(&path);
-            } else {
-                std::fs::write(&path, &output).unwrap();
+            let output = placeholders::bless(
+                &output,
+                &expected_output(&path, config, compare),
+                &config.placeholders,
+            );
+            if let Err(err) = bless(&path, &output) {
+                errors.push(Error::BlessFailed {
+                    path: path.clone(),
+                    message: err.to_string(),
+                });
             }
         }
         OutputConflictHandling::Error(bless_command) => {
-            let expected_output = std::fs::read(&path).unwrap_or_default();
-            if output != expected_output {
+            let expected_output = expected_output(&path, config, compare);
+            if !placeholders::matches(&output, &expected_output, &config.placeholders) {
                 errors.push(Error::OutputDiffers {
                     path: path.clone(),
                     actual: output,
                     expected: expected_output,
-                    bless_command: bless_command.clone(),
+                    bless_command: bless_command::render(
+                        bless_command,
+                        config,
+                        std::iter::once(test_path),
+                    ),
+                    context: config.diff_context,
                 });
             }
         }
         OutputConflictHandling::Ignore => {}
+        OutputConflictHandling::Custom(handle_conflict) => {
+            let expected_output = expected_output(&path, config, compare);
+            if !placeholders::matches(&output, &expected_output, &config.placeholders) {
+                if let Err(error) = handle_conflict(&path, &output, &expected_output) {
+                    errors.push(error);
+                }
+            }
+        }
     }
     path
 }
 
+/// Replace the expected output file at `path` with `output`, or remove it if `output` is empty.
+/// The file is replaced by renaming a temporary file, so that an interrupted run never leaves a
+/// truncated file behind.
+fn bless(path: &Path, output: &[u8]) -> std::io::Result<()> {
+    let permissions = match std::fs::metadata(path) {
+        Ok(metadata) => Some(metadata.permissions()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err),
+    };
+    if permissions.as_ref().map_or(false, |p| p.readonly()) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "the file is read-only",
+        ));
+    }
+    if output.is_empty() {
+        return match permissions {
+            Some(_) => std::fs::remove_file(path),
+            None => Ok(()),
+        };
+    }
+    let dir = path.parent().unwrap();
+    std::fs::create_dir_all(dir)?;
+    let tmp = dir.join(format!(
+        ".{}.{}.tmp",
+        path.file_name().unwrap().to_string_lossy(),
+        std::process::id()
+    ));
+    let result = std::fs::write(&tmp, output)
+        .and_then(|()| match permissions {
+            Some(permissions) => std::fs::set_permissions(&tmp, permissions),
+            None => Ok(()),
+        })
+        .and_then(|()| std::fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// The contents of the expected output file at `path`, normalized like the actual output.
+fn expected_output(path: &Path, config: &Config, compare: Compare) -> Vec<u8> {
+    let mut expected_output = std::fs::read(path).unwrap_or_default();
+    // Files checked out by git on windows may use CRLF line endings.
+    if config.builtin_normalizations.line_endings {
+        expected_output = expected_output.replace(b"\r\n", b"\n");
+    }
+    if compare == Compare::LenientWhitespace {
+        expected_output = strip_trailing_whitespace(&expected_output);
+    }
+    expected_output
+}
+
+/// Remove the whitespace at the end of every line and the blank lines at the end.
+pub(crate) fn strip_trailing_whitespace(text: &[u8]) -> Vec<u8> {
+    let mut stripped = Vec::with_capacity(text.len());
+    for line in text.lines() {
+        stripped.extend_from_slice(line.trim_end_with(|c| c == ' ' || c == '\t'));
+        stripped.push(b'\n');
+    }
+    while stripped.ends_with(b"\n\n") {
+        stripped.pop();
+    }
+    if stripped == b"\n" {
+        stripped.clear();
+    }
+    stripped
+}
+
 fn output_path(
     path: &Path,
     comments: &Comments,
@@ -992,35 +4220,546 @@ fn output_path(
     path.with_extension(kind)
 }
 
-fn test_condition(condition: &Condition, config: &Config) -> bool {
+/// The expected output files that a test may produce for `revision`.
+fn output_files(path: &Path, comments: &Comments, revision: &str) -> Vec<PathBuf> {
+    let per_bitwidth = comments
+        .for_revision(revision)
+        .any(|r| r.stderr_per_bitwidth);
+    let mut files = vec![path.with_extension(revised(revision, "fixed"))];
+    for kind in ["stderr", "stdout"] {
+        let kind = revised(revision, kind);
+        if per_bitwidth {
+            // Other targets produce the files of the other bitwidths.
+            for bits in [16, 32, 64] {
+                files.push(path.with_extension(format!("{bits}bit.{kind}")));
+            }
+        } else {
+            files.push(path.with_extension(kind));
+        }
+    }
+    files
+}
+
+/// The revisions of the test at `path` that no `//@[rev]` or `//~[rev]` comment and no expected
+/// output file refers to, see [`Config::unused_revisions`].
+fn unused_revisions(path: &Path, config: &Config, comments: &Comments) -> Vec<String> {
+    let revisions = match &comments.revisions {
+        Some(revisions) if !comments.allow_unused_revisions => revisions,
+        _ => return vec![],
+    };
+    let base = expected_output_base(config, path);
+    revisions
+        .iter()
+        .filter(|revision| {
+            let values = comments.revision_values(revision);
+            let in_comments = comments
+                .revisioned
+                .keys()
+                .flatten()
+                .any(|rev| rev == *revision || values.contains(&rev.as_str()));
+            !in_comments
+                && !output_files(&base, comments, revision)
+                    .iter()
+                    .any(|file| file.exists())
+        })
+        .cloned()
+        .collect()
+}
+
+/// Remove the output files of `path` for revisions and bitwidths it doesn't have anymore.
+/// Only files named like ui_test names output files are removed, and none that may belong
+/// to another test, like `foo.rev.stderr` of a `foo.rev.rs` next to `foo.rs`.
+fn remove_outdated_output_files(path: &Path, config: &Config, comments: &Comments) -> Vec<PathBuf> {
+    let base = expected_output_base(config, path);
+    let current: HashSet<PathBuf> = comments
+        .revisions
+        .clone()
+        .unwrap_or_else(|| vec![String::new()])
+        .iter()
+        .flat_map(|revision| output_files(&base, comments, revision))
+        .collect();
+    let read_dir = |dir: &Path| -> Vec<PathBuf> {
+        match std::fs::read_dir(dir) {
+            Ok(entries) => entries.flatten().map(|entry| entry.path()).collect(),
+            Err(_) => vec![],
+        }
+    };
+    let other_tests: HashSet<PathBuf> = read_dir(path.parent().unwrap())
+        .iter()
+        .filter(|sibling| !is_output_file(sibling) && sibling.as_path() != path)
+        .map(|sibling| expected_output_base(config, sibling).with_extension(""))
+        .collect();
+    let siblings = read_dir(base.parent().unwrap());
+    let stem = path.file_stem().unwrap().to_string_lossy();
+    let generated = Regex::new(r"^(?:(?:[0-9]+bit)(?:\.[^.]+)?|[^.]+)?$").unwrap();
+    let mut removed = vec![];
+    for sibling in siblings {
+        if !is_output_file(&sibling) || current.contains(&sibling) {
+            continue;
+        }
+        let name = sibling.file_stem().unwrap().to_string_lossy();
+        let middle = match name.strip_prefix(&*stem) {
+            Some("") => "",
+            Some(middle) => match middle.strip_prefix('.') {
+                Some(middle) => middle,
+                None => continue,
+            },
+            None => continue,
+        };
+        let is_fixed = sibling.extension().unwrap() == "fixed";
+        if !generated.is_match(middle.as_bytes()) || (is_fixed && middle.contains('.')) {
+            continue;
+        }
+        // `foo.a.64bit.stderr` may belong to `foo.a.rs`.
+        let parts: Vec<_> = name.split('.').collect();
+        if (stem.split('.').count() + 1..=parts.len())
+            .any(|n| other_tests.contains(&sibling.with_file_name(parts[..n].join("."))))
+        {
+            continue;
+        }
+        if std::fs::remove_file(&sibling).is_ok() {
+            removed.push(sibling);
+        }
+    }
+    removed.sort();
+    removed
+}
+
+/// The path of the test relative to [`Config::root_dir`], without any `..` or `.`.
+fn relative_to_root(config: &Config, path: &Path) -> PathBuf {
+    relative_to(&config.root_dir, path)
+}
+
+/// `path` relative to `root_dir`, without any `.` or `..` components. If `path` is not spelled
+/// as being inside `root_dir`, e.g. because one of them goes through a symlink, they are
+/// compared after resolving symlinks.
+fn relative_to(root_dir: &Path, path: &Path) -> PathBuf {
+    let path = strip_verbatim(path);
+    let root_dir = strip_verbatim(root_dir);
+    let canonical;
+    let relative = match path.strip_prefix(&root_dir) {
+        Ok(relative) => relative,
+        Err(_) => {
+            canonical = canonicalize(&path);
+            canonical
+                .strip_prefix(canonicalize(&root_dir))
+                .unwrap_or(&path)
+        }
+    };
+    relative
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect()
+}
+
+/// The path that the expected output files of the test at `path` are named after,
+/// which is in [`Config::expected_output_dir`] if that is set.
+fn expected_output_base(config: &Config, path: &Path) -> PathBuf {
+    match &config.expected_output_dir {
+        Some(dir) => dir.join(relative_to_root(config, path)),
+        None => path.to_owned(),
+    }
+}
+
+/// Collect all expected output files in `dir` and its subdirectories.
+fn collect_output_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_output_files(&path, files);
+        } else if is_output_file(&path) {
+            files.push(path);
+        }
+    }
+}
+
+fn is_output_file(path: &Path) -> bool {
+    path.extension().map_or(false, |ext| {
+        ext == "stderr" || ext == "stdout" || ext == "fixed"
+    })
+}
+
+/// The output files that were `found` but that no test claimed. Files that may belong to a test
+/// whose output files are unknown, because it was filtered out or its comments could not be
+/// parsed, are never stale.
+fn stale_output_files(
+    found: Vec<PathBuf>,
+    claimed: &HashSet<PathBuf>,
+    unknown: &HashSet<PathBuf>,
+) -> Vec<PathBuf> {
+    found
+        .into_iter()
+        .filter(|path| !claimed.contains(path))
+        .filter(|path| {
+            // `foo.rev.stderr` may belong to `foo.rs` or `foo.rev.rs`.
+            let name = path.file_name().unwrap().to_string_lossy();
+            let parts: Vec<_> = name.split('.').collect();
+            (1..parts.len()).all(|n| !unknown.contains(&path.with_file_name(parts[..n].join("."))))
+        })
+        .collect()
+}
+
+/// Where the actual output that belongs in `output_path` is saved, if
+/// [`Config::failure_artifacts_dir`] is set.
+fn artifact_path(config: &Config, output_path: &Path) -> Option<PathBuf> {
+    let dir = config.failure_artifacts_dir.as_ref()?;
+    let relative = match config
+        .expected_output_dir
+        .as_ref()
+        .and_then(|expected_dir| output_path.strip_prefix(expected_dir).ok())
+    {
+        Some(relative) => relative.to_owned(),
+        None => relative_to_root(config, output_path),
+    };
+    Some(dir.join(relative))
+}
+
+fn save_artifact(config: &Config, output_path: &Path, contents: &[u8], errors: &mut Errors) {
+    if let Some(artifact) = artifact_path(config, output_path) {
+        let result = std::fs::create_dir_all(artifact.parent().unwrap())
+            .and_then(|()| std::fs::write(&artifact, contents));
+        io_error(errors, &artifact, result);
+    }
+}
+
+/// Append a command and its exit status to the `.command` artifact of the test.
+fn save_command_artifact(
+    path: &Path,
+    config: &Config,
+    comments: &Comments,
+    revision: &str,
+    cmd: &Command,
+    status: ExitStatus,
+    errors: &mut Errors,
+) {
+    let target = config.target.as_ref().unwrap();
+    let command_path = output_path(
+        path,
+        comments,
+        revised(revision, "command"),
+        target,
+        revision,
+    );
+    if let Some(artifact) = artifact_path(config, &command_path) {
+        let result = std::fs::create_dir_all(artifact.parent().unwrap()).and_then(|()| {
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&artifact)?;
+            writeln!(file, "command: {}\nstatus: {status}", display_command(cmd))
+        });
+        io_error(errors, &artifact, result);
+    }
+}
+
+/// Report the error of an I/O operation on `path`, if it failed.
+fn io_error(errors: &mut Errors, path: &Path, result: std::io::Result<()>) {
+    if let Err(error) = result {
+        errors.push(Error::Io {
+            path: path.to_owned(),
+            message: error.to_string(),
+        });
+    }
+}
+
+/// Delete the artifacts of a test that passed, or return the ones of a test that failed.
+fn failure_artifacts(
+    path: &Path,
+    config: &Config,
+    comments: &Comments,
+    revision: &str,
+    passed: bool,
+) -> Vec<PathBuf> {
+    let target = config.target.as_ref().unwrap();
+    let mut artifacts = vec![];
+    for kind in ["stderr", "stdout", "command"] {
+        artifacts.push(output_path(
+            path,
+            comments,
+            revised(revision, kind),
+            target,
+            revision,
+        ));
+    }
+    // The fixed code is never per bitwidth.
+    artifacts.push(path.with_extension(revised(revision, "fixed")));
+    let artifacts = artifacts
+        .iter()
+        .filter_map(|output_path| artifact_path(config, output_path));
+    if passed {
+        for artifact in artifacts {
+            let _ = std::fs::remove_file(artifact);
+        }
+        vec![]
+    } else {
+        artifacts.filter(|artifact| artifact.exists()).collect()
+    }
+}
+
+/// Remove all empty directories in `dir`, including `dir` itself.
+fn remove_empty_dirs(dir: &Path) {
+    if let Ok(entries) = std::fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            if entry.file_type().map_or(false, |ty| ty.is_dir()) {
+                remove_empty_dirs(&entry.path());
+            }
+        }
+    }
+    // Fails if the directory is not empty.
+    let _ = std::fs::remove_dir(dir);
+}
+
+/// Bare cfgs that `--print cfg` only prints if they are set.
+const OPTIONAL_CFGS: &[&str] = &[
+    "debug_assertions",
+    "miri",
+    "overflow_checks",
+    "proc_macro",
+    "target_thread_local",
+    "test",
+    "ub_checks",
+    "unix",
+    "windows",
+];
+
+/// Names of `name="value"` cfgs that `--print cfg` doesn't print for all targets.
+const OPTIONAL_CFG_NAMES: &[&str] = &[
+    "relocation_model",
+    "sanitize",
+    "target_abi",
+    "target_feature",
+    "target_has_atomic",
+    "target_has_atomic_equal_alignment",
+    "target_has_atomic_load_store",
+];
+
+/// Whether the condition holds. Fails for cfgs that the program doesn't know and for names that
+/// are not in [`Config::custom_conditions`], as a typo would silently skip or run the test, and
+/// for values that ui_test can't determine.
+fn test_condition(
+    condition: &Condition,
+    config: &Config,
+    comments: &Comments,
+    revision: &str,
+) -> Result<bool, String> {
     let target = config.target.as_ref().unwrap();
-    match condition {
+    Ok(match condition {
         Condition::Bitwidth(bits) => get_pointer_width(target) == *bits,
         Condition::Target(t) => target.contains(t),
         Condition::Host(t) => config.host.as_ref().unwrap().contains(t),
         Condition::OnHost => target == config.host.as_ref().unwrap(),
+        Condition::Cfg { name, value } => {
+            let cfgs = config
+                .target_cfgs(cfg_flags(config, comments, revision))
+                .map_err(|err| format!("failed to get the cfgs of `{target}`: {err}"))?;
+            let has_value = cfgs.iter().any(|(n, v)| n == name && v.is_some());
+            match value {
+                Some(value) => {
+                    if !has_value && !OPTIONAL_CFG_NAMES.contains(&name.as_str()) {
+                        return Err(unknown_cfg(name, &cfgs));
+                    }
+                    cfgs.iter()
+                        .any(|(n, v)| n == name && v.as_ref() == Some(value))
+                }
+                None => {
+                    if has_value {
+                        return Err(format!(
+                            "the cfg `{name}` has values, like `{name}=\"{}\"`",
+                            cfgs.iter()
+                                .find_map(|(n, v)| v.as_ref().filter(|_| n == name))
+                                .unwrap()
+                        ));
+                    }
+                    let set = cfgs.iter().any(|(n, v)| n == name && v.is_none());
+                    // Each revision only sets its own cfg.
+                    let is_revision = comments.revisions.iter().flatten().any(|revision| {
+                        comments.revision_values(revision).contains(&name.as_str())
+                    });
+                    if !set && !is_revision && !OPTIONAL_CFGS.contains(&name.as_str()) {
+                        return Err(unknown_cfg(name, &cfgs));
+                    }
+                    set
+                }
+            }
+        }
+        Condition::When(name) => match config.custom_conditions.get(name) {
+            Some(&holds) => holds,
+            None => {
+                let mut names: Vec<_> = config.custom_conditions.keys().collect();
+                names.sort_unstable();
+                return Err(if names.is_empty() {
+                    format!("unknown condition `{name}`, `Config::custom_conditions` is empty")
+                } else {
+                    format!(
+                        "unknown condition `{name}`, expected one of {}",
+                        names
+                            .iter()
+                            .map(|name| format!("`{name}`"))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )
+                });
+            }
+        },
+        Condition::DebugAssertions => program_debug_assertions(config)?,
+        Condition::OptLevel(level) => opt_level(config, comments, revision)? == *level,
+    })
+}
+
+/// The arguments of the [`Config::program`], `//@compile-flags` and `--cfg`s of the revision that
+/// change what `--print cfg` prints, like `-C debug-assertions`, `-O` or `--cfg foo`.
+fn cfg_flags(config: &Config, comments: &Comments, revision: &str) -> Vec<String> {
+    let mut flags = config
+        .program
+        .args
+        .iter()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .chain(
+            comments
+                .for_revision(revision)
+                .flat_map(|r| r.compile_flags.iter().cloned()),
+        );
+    let mut cfg_flags = vec![];
+    while let Some(flag) = flags.next() {
+        match flag.as_str() {
+            "-C" | "--codegen" | "--cfg" | "-Z" => {
+                if let Some(value) = flags.next() {
+                    cfg_flags.extend([flag, value]);
+                }
+            }
+            "-O" => cfg_flags.push(flag),
+            _ if ["-C", "--codegen=", "--cfg=", "-Z"]
+                .iter()
+                .any(|prefix| flag.starts_with(prefix)) =>
+            {
+                cfg_flags.push(flag)
+            }
+            _ => {}
+        }
     }
+    if !revision.is_empty() {
+        for value in comments.revision_values(revision) {
+            cfg_flags.push(format!("--cfg={value}"));
+        }
+    }
+    cfg_flags
 }
 
-/// Returns whether according to the in-file conditions, this file should be run.
-fn test_file_conditions(comments: &Comments, config: &Config, revision: &str) -> bool {
-    if comments
+fn program_debug_assertions(config: &Config) -> Result<bool, String> {
+    config.program_debug_assertions.ok_or_else(|| {
+        format!(
+            "whether `{}` was built with debug assertions is unknown, set `Config::program_debug_assertions`",
+            config.program.program.display()
+        )
+    })
+}
+
+/// The `-C opt-level` that the test is compiled with, according to the arguments of the
+/// [`Config::program`] and the `//@compile-flags` of the test. Like for rustc, the last one wins
+/// and `-O` means `2`.
+fn opt_level(config: &Config, comments: &Comments, revision: &str) -> Result<String, String> {
+    if config.test_kind == TestKind::CargoProject {
+        return Err(
+            "the opt-level of cargo projects depends on their profile, which ui_test doesn't know"
+                .into(),
+        );
+    }
+    let flags = config
+        .program
+        .args
+        .iter()
+        .map(|arg| arg.to_string_lossy())
+        .chain(
+            comments
+                .for_revision(revision)
+                .flat_map(|r| r.compile_flags.iter())
+                .map(|flag| Cow::Borrowed(flag.as_str())),
+        );
+    let mut level = "0".to_owned();
+    let mut codegen_option = false;
+    for flag in flags {
+        let option = if std::mem::take(&mut codegen_option) {
+            Some(&*flag)
+        } else if flag == "-C" || flag == "--codegen" {
+            codegen_option = true;
+            None
+        } else {
+            flag.strip_prefix("-C")
+                .or_else(|| flag.strip_prefix("--codegen="))
+        };
+        if flag == "-O" {
+            level = "2".into();
+        } else if let Some(opt_level) = option.and_then(|option| option.strip_prefix("opt-level="))
+        {
+            level = opt_level.into();
+        }
+    }
+    Ok(level)
+}
+
+fn unknown_cfg(name: &str, cfgs: &Cfgs) -> String {
+    let mut names: Vec<_> = cfgs
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .chain(OPTIONAL_CFGS.iter().copied())
+        .chain(OPTIONAL_CFG_NAMES.iter().copied())
+        .collect();
+    names.sort_unstable();
+    names.dedup();
+    format!(
+        "unknown cfg `{name}`, expected one of {}",
+        names
+            .iter()
+            .map(|name| format!("`{name}`"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
+/// Returns the comment that makes the in-file conditions skip this file, if any, or the error
+/// of a condition that can't be evaluated.
+fn ignore_reason(
+    comments: &Comments,
+    config: &Config,
+    revision: &str,
+) -> Result<Option<(String, usize)>, Error> {
+    let test = |condition: &Condition, line: usize| {
+        test_condition(condition, config, comments, revision)
+            .map_err(|msg| Error::InvalidComment { msg, line })
+    };
+    for (condition, line) in comments
         .for_revision(revision)
         .flat_map(|r| r.ignore.iter())
-        .any(|c| test_condition(c, config))
     {
-        return false;
+        if test(condition, *line)? {
+            return Ok(Some((format!("`//@ignore-{condition}`"), *line)));
+        }
     }
-    if comments
+    if let Some(line) = comments
         .for_revision(revision)
-        .any(|r| r.needs_asm_support && !config.has_asm_support())
+        .find_map(|r| r.needs_asm_support)
+        .filter(|_| !config.has_asm_support())
     {
-        return false;
+        return Ok(Some(("`//@needs-asm-support`".into(), line)));
     }
-    comments
+    if let Some(line) = comments
         .for_revision(revision)
-        .flat_map(|r| r.only.iter())
-        .all(|c| test_condition(c, config))
+        .find_map(|r| r.needs_debug_assertions)
+    {
+        if !program_debug_assertions(config).map_err(|msg| Error::InvalidComment { msg, line })? {
+            return Ok(Some(("`//@needs-debug-assertions`".into(), line)));
+        }
+    }
+    for (condition, line) in comments.for_revision(revision).flat_map(|r| r.only.iter()) {
+        if !test(condition, *line)? {
+            return Ok(Some((format!("`//@only-{condition}`"), *line)));
+        }
+    }
+    Ok(None)
 }
 
 // Taken 1:1 from compiletest-rs
@@ -1036,17 +4775,170 @@ fn get_pointer_width(triple: &str) -> u8 {
     }
 }
 
+/// Run the filter functions in order, turning panics into errors.
+fn apply_filter_fns(
+    mut output: Vec<u8>,
+    filter_fns: &[FilterFn],
+    kind: &str,
+) -> Result<Vec<u8>, Error> {
+    for (index, FilterFn(f)) in filter_fns.iter().enumerate() {
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(&mut output))).map_err(
+            |payload| Error::FilterFnPanicked {
+                kind: kind.to_string(),
+                index,
+                message: panic_message(payload),
+            },
+        )?;
+    }
+    Ok(output)
+}
+
+/// Apply the [`BuiltinNormalizations`] that are enabled in the config.
+fn builtin_normalize(path: &Path, mut text: Vec<u8>, config: &Config) -> Vec<u8> {
+    let normalizations = config.builtin_normalizations;
+    if normalizations.line_endings {
+        text = text.replace(b"\r\n", b"\n");
+    }
+    if normalizations.backslashes {
+        // Relative paths are printed as they were passed to the program, and on windows the
+        // files in a directory are joined to it with a backslash.
+        let dirs = if cfg!(windows) {
+            vec![path.parent().unwrap(), config.out_dir.as_path()]
+        } else {
+            vec![]
+        };
+        text = forward_slashes(&text, &dirs);
+    }
+    let mut dirs = vec![];
+    if normalizations.out_dir {
+        dirs.push((config.out_dir.as_path(), b"$OUT_DIR" as &[u8]));
+    }
+    let expected_output_dir = expected_output_base(config, path);
+    if normalizations.test_dir {
+        // Messages about the `.fixed` file mention the directory of the expected output.
+        if config.expected_output_dir.is_some() {
+            dirs.push((expected_output_dir.parent().unwrap(), b"$DIR"));
+        }
+        dirs.push((path.parent().unwrap(), b"$DIR"));
+    }
+    let temp_dir = std::env::temp_dir();
+    if normalizations.temp_dir {
+        dirs.push((&temp_dir, b"$TMP"));
+    }
+    for (dir, replacement) in dirs {
+        text = replace_dir(text, dir, replacement);
+    }
+    text
+}
+
+lazy_static::lazy_static! {
+    /// An absolute windows path like `C:\foo\bar.rs` or `\\?\C:\foo`.
+    static ref WINDOWS_PATH: Regex =
+        Regex::new(r"(\\\\\?\\)?(?-u:\b)([A-Z]:(?:\\[A-Za-z0-9_.$-]+)+)").unwrap();
+}
+
+/// Whether `byte` can be part of a file name in the paths that are normalized.
+fn is_file_name_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"_.$-".contains(&byte)
+}
+
+/// Replace the backslashes in absolute windows paths like `C:\foo\bar.rs` or `\\?\C:\foo`,
+/// and in paths that start with one of `dirs` like `tests/ui\foo.rs`, with forward slashes.
+/// All other backslashes, like the one in `"\n"`, are kept.
+fn forward_slashes(text: &[u8], dirs: &[&Path]) -> Vec<u8> {
+    let mut text = WINDOWS_PATH
+        .replace_all(text, |captures: &regex::bytes::Captures<'_>| {
+            captures[2].replace(b"\\", b"/")
+        })
+        .into_owned();
+    for dir in dirs {
+        let dir = dir.display().to_string();
+        if dir.is_empty() {
+            continue;
+        }
+        let mut normalized = Vec::with_capacity(text.len());
+        let mut done = 0;
+        for start in text.find_iter(&dir) {
+            if start < done || start > 0 && is_file_name_byte(text[start - 1]) {
+                continue;
+            }
+            // Extend the path over all the `\file_name`s that follow the directory.
+            let mut end = start + dir.len();
+            while text.get(end) == Some(&b'\\')
+                && text.get(end + 1).copied().map_or(false, is_file_name_byte)
+            {
+                end += 1;
+                while text.get(end).copied().map_or(false, is_file_name_byte) {
+                    end += 1;
+                }
+            }
+            normalized.extend_from_slice(&text[done..start]);
+            normalized.extend(text[start..end].iter().map(|&b| match b {
+                b'\\' => b'/',
+                b => b,
+            }));
+            done = end;
+        }
+        normalized.extend_from_slice(&text[done..]);
+        text = normalized;
+    }
+    text
+}
+
+/// Replace `dir` in `text`, both as it was given and canonicalized. Only whole directories
+/// are replaced, so `/tmp` is neither replaced in `/tmpfoo` nor in `/var/tmp`.
+fn replace_dir(mut text: Vec<u8>, dir: &Path, replacement: &[u8]) -> Vec<u8> {
+    // The canonical path is usually absolute, so it needs to be replaced before the path
+    // as it was given, which may be a suffix of it.
+    let canonical = dir.canonicalize().ok().map(|dir| strip_verbatim(&dir));
+    for dir in canonical.as_deref().into_iter().chain([dir]) {
+        if dir.as_os_str().is_empty() {
+            continue;
+        }
+        let dir = path_bytes(dir);
+        let mut replaced = Vec::with_capacity(text.len());
+        let mut done = 0;
+        for start in text.find_iter(&dir) {
+            let end = start + dir.len();
+            if start < done
+                || start > 0 && is_file_name_byte(text[start - 1])
+                || text.get(end).copied().map_or(false, is_file_name_byte)
+            {
+                continue;
+            }
+            replaced.extend_from_slice(&text[done..start]);
+            replaced.extend_from_slice(replacement);
+            done = end;
+        }
+        replaced.extend_from_slice(&text[done..]);
+        text = replaced;
+    }
+    text
+}
+
 fn normalize(
     path: &Path,
     text: &[u8],
     filters: &Filter,
+    config: &Config,
     comments: &Comments,
     revision: &str,
 ) -> Vec<u8> {
-    // Useless paths
-    let path_filter = (Match::from(path.parent().unwrap()), b"$DIR" as &[u8]);
-    let filters = filters.iter().chain(std::iter::once(&path_filter));
-    let mut text = text.to_owned();
+    let ansi = comments
+        .for_revision(revision)
+        .find_map(|r| r.ansi)
+        .unwrap_or(config.ansi);
+    let text = ansi::apply(text.to_owned(), ansi);
+    let mut text = builtin_normalize(path, text, config);
+    // A `//@working-dir` inside the directory of the test was already replaced with `$DIR`.
+    if config.builtin_normalizations.test_dir {
+        if let Some((dir, _)) = comments
+            .for_revision(revision)
+            .find_map(|r| r.working_dir.as_ref())
+        {
+            text = replace_dir(text, &path.parent().unwrap().join(dir), b"$CWD");
+        }
+    }
     if let Some(lib_path) = option_env!("RUSTC_LIB_PATH") {
         text = text.replace(lib_path, "RUSTLIB");
     }
@@ -1,12 +1,17 @@
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use bstr::{ByteSlice, Utf8Error};
 use regex::bytes::Regex;
 
-use crate::{rustc_stderr::Level, Error, Mode};
+use crate::{
+    rustc_stderr::{Level, Location, Message},
+    AnsiEscapes, ApplySuggestions, Compare, Config, CustomComment, Error, Flag, Mode, Shared,
+};
 
 use color_eyre::eyre::{Context, Result};
 
@@ -20,6 +25,14 @@ mod tests;
 pub(crate) struct Comments {
     /// List of revision names to execute. Can only be specified once
     pub revisions: Option<Vec<String>>,
+    /// The line of the `revisions` comment.
+    pub revisions_line: usize,
+    /// Don't report revisions that nothing refers to, see
+    /// [`Config::unused_revisions`](crate::Config::unused_revisions).
+    pub allow_unused_revisions: bool,
+    /// The values of each axis of a revision matrix, like `//@revisions: {a b} x {c d}`, whose
+    /// revisions are named like `a.c`. Empty if the revisions are not a matrix.
+    pub revision_axes: Vec<Vec<String>>,
     /// Comments that are only available under specific revisions.
     /// The defaults are in key `vec![]`
     pub revisioned: HashMap<Vec<String>, Revisioned>,
@@ -43,10 +56,25 @@ impl Comments {
         result
     }
 
-    /// Returns an iterator over all revisioned comments that match the revision.
+    /// The revision itself, or the axis values of a revision of a matrix, like `a` and `c` for
+    /// `a.c`.
+    pub fn revision_values<'a>(&self, revision: &'a str) -> Vec<&'a str> {
+        if self.revision_axes.is_empty() {
+            vec![revision]
+        } else {
+            revision.split('.').collect()
+        }
+    }
+
+    /// Returns an iterator over all revisioned comments that match the revision, or one of its
+    /// axis values if the revisions are a matrix.
     pub fn for_revision<'a>(&'a self, revision: &'a str) -> impl Iterator<Item = &'a Revisioned> {
+        let values = self.revision_values(revision);
         self.revisioned.iter().filter_map(move |(k, v)| {
-            if k.is_empty() || k.iter().any(|rev| rev == revision) {
+            if k.is_empty()
+                || k.iter()
+                    .any(|rev| rev == revision || values.contains(&&**rev))
+            {
                 Some(v)
             } else {
                 None
@@ -73,6 +101,26 @@ impl Comments {
         .cloned()
         .or(config.edition.clone().map(|e| (e, 0)))
     }
+
+    /// How often to run the test before reporting it as failed.
+    pub(crate) fn attempts(
+        &self,
+        errors: &mut Vec<Error>,
+        revision: &str,
+        config: &crate::Config,
+    ) -> usize {
+        self.find_one_for_revision(
+            revision,
+            |r| r.retry,
+            |(_, line)| {
+                errors.push(Error::InvalidComment {
+                    msg: "`retry` specified twice".into(),
+                    line,
+                })
+            },
+        )
+        .map_or(config.test_attempts, |(attempts, _)| attempts)
+    }
 }
 
 #[derive(Default, Debug)]
@@ -81,10 +129,10 @@ pub(crate) struct Revisioned {
     /// The line in which this revisioned item was first added.
     /// Used for reporting errors on unknown revisions.
     pub line: usize,
-    /// Don't run this test if any of these filters apply
-    pub ignore: Vec<Condition>,
-    /// Only run this test if all of these filters apply
-    pub only: Vec<Condition>,
+    /// Don't run this test if any of these filters apply, with the lines they were declared in.
+    pub ignore: Vec<(Condition, usize)>,
+    /// Only run this test if all of these filters apply, with the lines they were declared in.
+    pub only: Vec<(Condition, usize)>,
     /// Generate one .stderr file per bit width, by prepending with `.64bit` and similar
     pub stderr_per_bitwidth: bool,
     /// Additional flags to pass to the executable
@@ -96,16 +144,131 @@ pub(crate) struct Revisioned {
     /// Arbitrary patterns to look for in the stderr.
     /// The error must be from another file, as errors from the current file must be
     /// checked via `error_matches`.
-    pub error_in_other_files: Vec<(Pattern, usize)>,
+    /// The optional file and line restrict the pattern to diagnostics reported at that position.
+    pub error_in_other_files: Vec<(Pattern, usize, Option<Location>)>,
     pub error_matches: Vec<ErrorMatch>,
     /// Ignore diagnostics below this level.
     /// `None` means pick the lowest level from the `error_pattern`s.
     pub require_annotations_for_level: Option<Level>,
-    pub aux_builds: Vec<(PathBuf, String, usize)>,
+    /// Aux files to build, with their crate type if specified and the extra flags to build them
+    /// with.
+    pub aux_builds: Vec<(PathBuf, Option<String>, Vec<String>, usize)>,
+    /// Custom builds to run before building this file, by the name they were registered under,
+    /// with their arguments.
+    pub custom_builds: Vec<(String, String, usize)>,
+    /// Aux files to build as binaries and run with the given arguments while the test binary runs.
+    pub run_aux_bins: Vec<(PathBuf, Vec<String>, usize)>,
+    /// The crate type to build this file as when it is an aux file, unless the `aux-build`
+    /// requesting it specifies one.
+    pub crate_type: Option<(String, usize)>,
     pub edition: Option<(String, usize)>,
     /// Overwrites the mode from `Config`.
     pub mode: Option<(Mode, usize)>,
-    pub needs_asm_support: bool,
+    /// The line of the `//@needs-asm-support` comment, if any.
+    pub needs_asm_support: Option<usize>,
+    /// The line of the `//@needs-debug-assertions` comment, if any.
+    pub needs_debug_assertions: Option<usize>,
+    /// Require the annotations of a line to match the diagnostics in the order they were emitted.
+    pub check_annotation_order: bool,
+    /// Fail if recompiling the rustfix output emits diagnostics of this level or higher.
+    pub check_fixed: Option<Level>,
+    /// Overwrites which rustfix suggestions get applied from `Config`.
+    pub apply_suggestions: Option<ApplySuggestions>,
+    /// Reapply rustfix suggestions to the fixed code until no suggestions remain.
+    pub rustfix_loop: bool,
+    /// Apply the first of two overlapping rustfix suggestions instead of failing the test.
+    pub allow_suggestion_conflicts: bool,
+    /// Overwrites how the output is compared from `Config`.
+    pub compare: Option<Compare>,
+    /// Overwrites what happens to ANSI escape sequences in the output from `Config`.
+    pub ansi: Option<AnsiEscapes>,
+    /// Overwrites how often a failing test is run before it is reported as failed from `Config`.
+    pub retry: Option<(usize, usize)>,
+    /// The file the test binary reads its stdin from, relative to the directory of the test.
+    pub stdin: Option<(PathBuf, usize)>,
+    /// The directory the program and the test binary run in, relative to the directory of the
+    /// test. Without it, the program runs in the directory ui_test runs in, and the test binary
+    /// in a fresh directory in [`Config::out_dir`](crate::Config::out_dir).
+    pub working_dir: Option<(PathBuf, usize)>,
+    /// The flags of custom comments, see [`Config::custom_comments`](crate::Config::custom_comments),
+    /// with the lines they were declared in.
+    pub custom: BTreeMap<String, (Arc<dyn Flag>, usize)>,
+}
+
+impl Revisioned {
+    /// Add the comments of a defaults file, see [`Config::defaults_file_name`]. Lists are
+    /// prepended, and single values only apply if these comments don't set them.
+    ///
+    /// [`Config::defaults_file_name`]: crate::Config::defaults_file_name
+    pub(crate) fn apply_defaults(&mut self, defaults: Revisioned) {
+        let Revisioned {
+            line: _,
+            ignore,
+            only,
+            stderr_per_bitwidth,
+            compile_flags,
+            env_vars,
+            normalize_stderr,
+            error_in_other_files,
+            error_matches,
+            require_annotations_for_level,
+            aux_builds,
+            custom_builds,
+            run_aux_bins,
+            crate_type,
+            edition,
+            mode,
+            needs_asm_support,
+            needs_debug_assertions,
+            check_annotation_order,
+            check_fixed,
+            apply_suggestions,
+            rustfix_loop,
+            allow_suggestion_conflicts,
+            compare,
+            ansi,
+            retry,
+            stdin,
+            working_dir,
+            custom,
+        } = defaults;
+        fn prepend<T>(list: &mut Vec<T>, mut defaults: Vec<T>) {
+            defaults.append(list);
+            *list = defaults;
+        }
+        prepend(&mut self.ignore, ignore);
+        prepend(&mut self.only, only);
+        self.stderr_per_bitwidth |= stderr_per_bitwidth;
+        prepend(&mut self.compile_flags, compile_flags);
+        prepend(&mut self.env_vars, env_vars);
+        prepend(&mut self.normalize_stderr, normalize_stderr);
+        prepend(&mut self.error_in_other_files, error_in_other_files);
+        prepend(&mut self.error_matches, error_matches);
+        self.require_annotations_for_level = self
+            .require_annotations_for_level
+            .or(require_annotations_for_level);
+        prepend(&mut self.aux_builds, aux_builds);
+        prepend(&mut self.custom_builds, custom_builds);
+        prepend(&mut self.run_aux_bins, run_aux_bins);
+        self.crate_type = self.crate_type.take().or(crate_type);
+        self.edition = self.edition.take().or(edition);
+        self.mode = self.mode.or(mode);
+        self.needs_asm_support = self.needs_asm_support.or(needs_asm_support);
+        self.needs_debug_assertions = self.needs_debug_assertions.or(needs_debug_assertions);
+        self.check_annotation_order |= check_annotation_order;
+        self.check_fixed = self.check_fixed.or(check_fixed);
+        self.apply_suggestions = self.apply_suggestions.or(apply_suggestions);
+        self.rustfix_loop |= rustfix_loop;
+        self.allow_suggestion_conflicts |= allow_suggestion_conflicts;
+        self.compare = self.compare.or(compare);
+        self.ansi = self.ansi.or(ansi);
+        self.retry = self.retry.or(retry);
+        self.stdin = self.stdin.take().or(stdin);
+        self.working_dir = self.working_dir.take().or(working_dir);
+        for (name, flag) in custom {
+            self.custom.entry(name).or_insert(flag);
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -118,6 +281,10 @@ struct CommentParser<T> {
     line: usize,
     /// The available commands and their parsing logic
     commands: HashMap<&'static str, CommandParserFunc>,
+    /// The custom comments registered in [`Config::custom_comments`](crate::Config::custom_comments).
+    custom_comments: Shared<BTreeMap<String, CustomComment>>,
+    /// See [`Config::expand_env_vars`](crate::Config::expand_env_vars).
+    expand_env_vars: bool,
 }
 
 type CommandParserFunc = fn(&mut CommentParser<&mut Revisioned>, args: &str);
@@ -147,6 +314,14 @@ pub(crate) enum Condition {
     Bitwidth(u8),
     /// Tests that the target is the host.
     OnHost,
+    /// Tests that `--print cfg` of the program prints this bare cfg or `name="value"` pair.
+    Cfg { name: String, value: Option<String> },
+    /// Looks up the condition in [`Config::custom_conditions`].
+    When(String),
+    /// Tests [`Config::program_debug_assertions`].
+    DebugAssertions,
+    /// Tests that the test is compiled with this `-C opt-level`.
+    OptLevel(String),
 }
 
 #[derive(Debug, Clone)]
@@ -154,12 +329,27 @@ pub(crate) enum Condition {
 pub enum Pattern {
     SubString(String),
     Regex(Regex),
+    /// Matches if any of the substrings match, as produced by `(foo|bar) baz`.
+    Alternatives(Vec<String>),
+    /// Matches the error code or lint name of a diagnostic instead of its message.
+    /// Supports `*` wildcards and `|` separated alternatives.
+    Code(String),
 }
 
 #[derive(Debug)]
 pub(crate) struct ErrorMatch {
     pub pattern: Pattern,
     pub level: Level,
+    /// How many identical diagnostics this pattern is expected to match, as set by `ERROR*3`.
+    /// `None` means the pattern matches exactly one diagnostic.
+    pub count: Option<usize>,
+    /// Whether this pattern matches all matching diagnostics on its line, as set by `ERROR+`.
+    pub consume_all: bool,
+    /// The column the diagnostic must start at, as set by `ERROR@col:17`.
+    /// `None` matches diagnostics at any column.
+    pub column: Option<usize>,
+    /// Whether rustfix skips the suggestions of diagnostics on this line, as set by `ERROR@no-fix`.
+    pub no_fix: bool,
     /// The line where the message was defined, for reporting issues with it (e.g. in case it wasn't found).
     pub definition_line: usize,
     /// The line this pattern is expecting to find a message in.
@@ -167,8 +357,28 @@ pub(crate) struct ErrorMatch {
 }
 
 impl Condition {
-    fn parse(c: &str) -> std::result::Result<Self, String> {
-        if c == "on-host" {
+    /// Parse the condition `c` after `//@ignore-` or `//@only-`. Only `cfg`, `when` and
+    /// `opt-level` use the `args` after the colon, the others leave them for comments.
+    fn parse(c: &str, args: &str) -> std::result::Result<Self, String> {
+        if c == "cfg" {
+            Self::parse_cfg(args.trim())
+        } else if c == "when" {
+            match args.trim() {
+                "" => Err(
+                    "`when` needs the name of a condition in `Config::custom_conditions`".into(),
+                ),
+                name => Ok(Condition::When(name.to_owned())),
+            }
+        } else if c == "opt-level" {
+            match args.trim() {
+                level @ ("0" | "1" | "2" | "3" | "s" | "z") => Ok(Condition::OptLevel(level.into())),
+                level => Err(format!(
+                    "`{level}` is not a valid opt-level, expected one of `0`, `1`, `2`, `3`, `s` or `z`"
+                )),
+            }
+        } else if c == "debug-assertions" {
+            Ok(Condition::DebugAssertions)
+        } else if c == "on-host" {
             Ok(Condition::OnHost)
         } else if let Some(bits) = c.strip_suffix("bit") {
             let bits: u8 = bits.parse().map_err(|_err| {
@@ -181,36 +391,115 @@ impl Condition {
             Ok(Condition::Host(triple_substr.to_owned()))
         } else {
             Err(format!(
-                "`{c}` is not a valid condition, expected `on-host`, /[0-9]+bit/, /host-.*/, or /target-.*/"
+                "`{c}` is not a valid condition, expected `on-host`, `cfg`, `when`, `debug-assertions`, `opt-level`, /[0-9]+bit/, /host-.*/, or /target-.*/"
             ))
         }
     }
+
+    /// Parse a bare cfg like `unix` or a pair like `target_env="musl"`.
+    fn parse_cfg(cfg: &str) -> std::result::Result<Self, String> {
+        if cfg.is_empty() {
+            return Err(
+                "`cfg` needs a name like `unix` or a pair like `target_env=\"musl\"`".into(),
+            );
+        }
+        let (name, value) = match cfg.split_once('=') {
+            Some((name, value)) => {
+                let value = value.trim();
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|value| value.strip_suffix('"'))
+                    .filter(|value| !value.contains('"'))
+                    .ok_or_else(|| {
+                        format!(
+                            "the value of the cfg `{cfg}` must be quoted, like `{}=\"{}\"`",
+                            name.trim(),
+                            value.trim_matches('"')
+                        )
+                    })?;
+                (name.trim(), Some(value.to_owned()))
+            }
+            None => (cfg, None),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return Err(format!(
+                "`{cfg}` is not a valid cfg, expected a name like `unix` or a pair like `target_env=\"musl\"`"
+            ));
+        }
+        Ok(Condition::Cfg {
+            name: name.to_owned(),
+            value,
+        })
+    }
+}
+
+impl std::fmt::Display for Condition {
+    /// The condition as written after `//@ignore-` or `//@only-`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Condition::Host(triple_substr) => write!(f, "host-{triple_substr}"),
+            Condition::Target(triple_substr) => write!(f, "target-{triple_substr}"),
+            Condition::Bitwidth(bits) => write!(f, "{bits}bit"),
+            Condition::OnHost => write!(f, "on-host"),
+            Condition::Cfg { name, value: None } => write!(f, "cfg: {name}"),
+            Condition::Cfg {
+                name,
+                value: Some(value),
+            } => write!(f, "cfg: {name}=\"{value}\""),
+            Condition::When(name) => write!(f, "when: {name}"),
+            Condition::DebugAssertions => write!(f, "debug-assertions"),
+            Condition::OptLevel(level) => write!(f, "opt-level: {level}"),
+        }
+    }
 }
 
 impl Comments {
-    pub(crate) fn parse_file(path: &Path) -> Result<std::result::Result<Self, Vec<Error>>> {
+    pub(crate) fn parse_file(
+        path: &Path,
+        config: &Config,
+    ) -> Result<std::result::Result<Self, Vec<Error>>> {
         let content =
             std::fs::read(path).wrap_err_with(|| format!("failed to read {}", path.display()))?;
-        Ok(Self::parse(&content))
+        Ok(Self::parse_with_custom_comments(
+            &content,
+            config.comment_start_for(path),
+            &config.custom_comments,
+            config.expand_env_vars,
+        ))
     }
 
-    /// Parse comments in `content`.
-    /// `path` is only used to emit diagnostics if parsing fails.
+    /// Parse comments in `content`, whose comments start with `comment_start`, like `//`.
+    /// Custom comments are unknown, and environment variables are not expanded, like by default.
+    #[cfg(test)]
     pub(crate) fn parse(
         content: &(impl AsRef<[u8]> + ?Sized),
+        comment_start: &str,
+    ) -> std::result::Result<Self, Vec<Error>> {
+        Self::parse_with_custom_comments(content, comment_start, &Shared::default(), false)
+    }
+
+    /// Parse comments in `content`, like [`Comments::parse`], with the `custom_comments`, and
+    /// expanding environment variables if `expand_env_vars` is set.
+    pub(crate) fn parse_with_custom_comments(
+        content: &(impl AsRef<[u8]> + ?Sized),
+        comment_start: &str,
+        custom_comments: &Shared<BTreeMap<String, CustomComment>>,
+        expand_env_vars: bool,
     ) -> std::result::Result<Self, Vec<Error>> {
         let mut parser = CommentParser {
             comments: Comments::default(),
             errors: vec![],
             line: 0,
             commands: CommentParser::<_>::commands(),
+            custom_comments: custom_comments.clone(),
+            expand_env_vars,
         };
 
         let mut fallthrough_to = None; // The line that a `|` will refer to.
         for (l, line) in content.as_ref().lines().enumerate() {
             let l = l + 1; // enumerate starts at 0, but line numbers start at 1
             parser.line = l;
-            match parser.parse_checked_line(&mut fallthrough_to, line) {
+            match parser.parse_checked_line(&mut fallthrough_to, line, comment_start) {
                 Ok(()) => {}
                 Err(e) => parser.errors.push(Error::InvalidComment {
                     msg: format!("Comment is not utf8: {e:?}"),
@@ -221,7 +510,14 @@ impl Comments {
         if let Some(revisions) = &parser.comments.revisions {
             for (key, revisioned) in &parser.comments.revisioned {
                 for rev in key {
-                    if !revisions.contains(rev) {
+                    if !revisions.contains(rev)
+                        && !parser
+                            .comments
+                            .revision_axes
+                            .iter()
+                            .flatten()
+                            .any(|v| v == rev)
+                    {
                         parser.errors.push(Error::InvalidComment {
                             msg: format!("the revision `{rev}` is not known"),
                             line: revisioned.line,
@@ -239,6 +535,33 @@ impl Comments {
                 }
             }
         }
+        // All aux builds of a test share a directory, so an aux file can only be built with one
+        // set of flags.
+        let mut aux_builds: Vec<_> = parser
+            .comments
+            .revisioned
+            .values()
+            .flat_map(|r| &r.aux_builds)
+            .collect();
+        aux_builds.sort_by_key(|(_, _, _, line)| *line);
+        let mut aux_flags: HashMap<&Path, &[String]> = HashMap::new();
+        for (aux, _, flags, line) in aux_builds {
+            match aux_flags.get(aux.as_path()) {
+                Some(prev) if prev != flags => parser.errors.push(Error::InvalidComment {
+                    msg: format!(
+                        "`{}` is built with `{}` here, but with `{}` elsewhere",
+                        aux.display(),
+                        flags.join(" "),
+                        prev.join(" ")
+                    ),
+                    line: *line,
+                }),
+                Some(_) => {}
+                None => {
+                    aux_flags.insert(aux, flags);
+                }
+            }
+        }
         if parser.errors.is_empty() {
             Ok(parser.comments)
         } else {
@@ -252,25 +575,27 @@ impl CommentParser<Comments> {
         &mut self,
         fallthrough_to: &mut Option<usize>,
         line: &[u8],
+        comment_start: &str,
     ) -> std::result::Result<(), Utf8Error> {
-        if let Some(command) = line.strip_prefix(b"//@") {
+        let command_start = format!("{comment_start}@");
+        if let Some(command) = line.strip_prefix(command_start.as_bytes()) {
             self.parse_command(command.trim().to_str()?)
-        } else if let Some((_, pattern)) = line.split_once_str("//~") {
+        } else if let Some((_, pattern)) = line.split_once_str(&format!("{comment_start}~")) {
             let (revisions, pattern) = self.parse_revisions(pattern.to_str()?);
             self.revisioned(revisions, |this| {
                 this.parse_pattern(pattern, fallthrough_to)
             })
         } else {
             *fallthrough_to = None;
-            for pos in line.find_iter("//") {
-                let rest = &line[pos + 2..];
+            for pos in line.find_iter(comment_start) {
+                let rest = &line[pos + comment_start.len()..];
                 for rest in std::iter::once(rest).chain(rest.strip_prefix(b" ")) {
                     if let Some('@' | '~' | '[' | ']' | '^' | '|') = rest.chars().next() {
                         self.errors.push(Error::InvalidComment {
                             msg: format!(
                                 "comment looks suspiciously like a test suite command: `{}`\n\
-                             All `//@` test suite commands must be at the start of the line.\n\
-                             The `//` must be directly followed by `@` or `~`.",
+                             All `{comment_start}@` test suite commands must be at the start of the line.\n\
+                             The `{comment_start}` must be directly followed by `@` or `~`.",
                                 rest.to_str()?,
                             ),
                             line: self.line,
@@ -281,16 +606,19 @@ impl CommentParser<Comments> {
                             errors: vec![],
                             comments: Comments::default(),
                             commands: std::mem::take(&mut self.commands),
+                            custom_comments: std::mem::take(&mut self.custom_comments),
+                            expand_env_vars: self.expand_env_vars,
                         };
                         parser.parse_command(rest.to_str()?);
                         if parser.errors.is_empty() {
-                            self.error(
+                            self.error(format!(
                                 "a compiletest-rs style comment was detected.\n\
                                 Please use text that could not also be interpreted as a command,\n\
-                                and prefix all actual commands with `//@`",
-                            );
+                                and prefix all actual commands with `{comment_start}@`",
+                            ));
                         }
                         self.commands = parser.commands;
+                        self.custom_comments = parser.custom_comments;
                     }
                 }
             }
@@ -317,6 +645,48 @@ impl<CommentsType> CommentParser<CommentsType> {
         self.check(opt.is_some(), s);
         opt
     }
+
+    /// Replace `${VAR}` in `args` with the value of the environment variable `VAR` and `$$` with
+    /// `$`, if [`Config::expand_env_vars`](crate::Config::expand_env_vars) is set. This happens
+    /// before the arguments are split, so values with whitespace need to be quoted, like
+    /// `"${VAR}"`, to stay a single argument.
+    fn expand_env_vars<'a>(&mut self, args: &'a str) -> Option<Cow<'a, str>> {
+        if !self.expand_env_vars || !args.contains('$') {
+            return Some(Cow::Borrowed(args));
+        }
+        let mut expanded = String::new();
+        let mut rest = args;
+        while let Some(pos) = rest.find('$') {
+            expanded.push_str(&rest[..pos]);
+            rest = &rest[pos + 1..];
+            if let Some(after) = rest.strip_prefix('$') {
+                expanded.push('$');
+                rest = after;
+            } else if let Some(after) = rest.strip_prefix('{') {
+                let Some((name, after)) = after.split_once('}') else {
+                    self.error(format!(
+                        "`${{` without a matching `}}` in `{args}`, use `$$` for a literal `$`"
+                    ));
+                    return None;
+                };
+                match std::env::var(name) {
+                    Ok(value) => expanded.push_str(&value),
+                    Err(_) => {
+                        self.error(format!(
+                            "the environment variable `{name}` in `{args}` is not set, \
+                             use `$$` for a literal `$`"
+                        ));
+                        return None;
+                    }
+                }
+                rest = after;
+            } else {
+                expanded.push('$');
+            }
+        }
+        expanded.push_str(rest);
+        Some(Cow::Owned(expanded))
+    }
 }
 
 impl CommentParser<Comments> {
@@ -350,12 +720,128 @@ impl CommentParser<Comments> {
                 "revisions cannot be declared under a revision",
             );
             self.check(self.revisions.is_none(), "cannot specify `revisions` twice");
-            self.revisions = Some(args.split_whitespace().map(|s| s.to_string()).collect());
+            self.revisions_line = self.line;
+            if args.starts_with('{') {
+                self.parse_revision_matrix(args);
+                let values: Vec<_> = self.revision_axes.iter().flatten().cloned().collect();
+                self.check_revision_names(&values);
+            } else {
+                let revisions: Vec<_> = args.split_whitespace().map(|s| s.to_string()).collect();
+                self.check_revision_names(&revisions);
+                self.revisions = Some(revisions);
+            }
+            return;
+        }
+        if command == "allow-unused-revisions" {
+            self.check(
+                revisions.is_empty(),
+                "`allow-unused-revisions` cannot be declared under a revision",
+            );
+            self.allow_unused_revisions = true;
             return;
         }
         self.revisioned(revisions, |this| this.parse_command(command, args));
     }
 
+    /// Check that the revisions can be passed as `--cfg` and don't share their output files on
+    /// case-insensitive file systems.
+    fn check_revision_names(&mut self, names: &[String]) {
+        /// The cfgs that rustc sets by itself, which revisions would silently turn on.
+        const BUILTIN_CFGS: &[&str] = &[
+            "debug_assertions",
+            "doc",
+            "doctest",
+            "miri",
+            "overflow_checks",
+            "proc_macro",
+            "test",
+            "unix",
+            "windows",
+        ];
+        for (i, name) in names.iter().enumerate() {
+            let mut chars = name.chars();
+            let is_ident = chars
+                .next()
+                .map_or(false, |c| c.is_alphabetic() || c == '_')
+                && chars.all(|c| c.is_alphanumeric() || c == '_');
+            if !is_ident {
+                self.error(format!(
+                    "the revision `{name}` is not a valid cfg name, only use letters, digits and `_`"
+                ));
+            } else if BUILTIN_CFGS.contains(&name.as_str()) {
+                self.error(format!(
+                    "the revision `{name}` collides with the built-in cfg `{name}`"
+                ));
+            }
+            if let Some(other) = names[..i]
+                .iter()
+                .find(|other| other.to_lowercase() == name.to_lowercase())
+            {
+                if other == name {
+                    self.error(format!("the revision `{name}` is declared twice"));
+                } else {
+                    self.error(format!(
+                        "the revisions `{other}` and `{name}` only differ in case, so their output \
+                         files collide on case-insensitive file systems"
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Parse a revision matrix like `{a b} x {c d}` into its axes and the revisions `a.c`, `a.d`,
+    /// `b.c` and `b.d`.
+    fn parse_revision_matrix(&mut self, mut args: &str) {
+        let mut axes: Vec<Vec<String>> = vec![];
+        loop {
+            let axis = match args.strip_prefix('{').and_then(|rest| rest.split_once('}')) {
+                Some((axis, rest)) => {
+                    args = rest.trim_start();
+                    axis
+                }
+                None => return self.error("expected an axis like `{a b}` in the revision matrix"),
+            };
+            let axis: Vec<String> = axis.split_whitespace().map(|s| s.to_string()).collect();
+            if axis.is_empty() {
+                return self.error("the axes of a revision matrix can't be empty");
+            }
+            for value in &axis {
+                if value.contains('.') {
+                    return self.error(format!(
+                        "the revision `{value}` of a matrix can't contain `.`"
+                    ));
+                }
+                if axes.iter().flatten().any(|other| other == value) {
+                    return self.error(format!(
+                        "the revision `{value}` is in more than one axis of the matrix"
+                    ));
+                }
+            }
+            axes.push(axis);
+            if args.is_empty() {
+                break;
+            }
+            match args.strip_prefix('x') {
+                Some(rest) => args = rest.trim_start(),
+                None => return self.error("expected `x` between the axes of the revision matrix"),
+            }
+        }
+        let mut revisions = vec![String::new()];
+        for axis in &axes {
+            revisions = revisions
+                .iter()
+                .flat_map(|prefix| {
+                    axis.iter().map(move |value| match prefix.as_str() {
+                        "" => value.clone(),
+                        prefix => format!("{prefix}.{value}"),
+                    })
+                })
+                .collect();
+        }
+        self.revisions = Some(revisions);
+        self.revision_axes = axes;
+    }
+
     fn revisioned(
         &mut self,
         revisions: Vec<String>,
@@ -365,6 +851,8 @@ impl CommentParser<Comments> {
         let mut this = CommentParser {
             errors: std::mem::take(&mut self.errors),
             commands: std::mem::take(&mut self.commands),
+            custom_comments: std::mem::take(&mut self.custom_comments),
+            expand_env_vars: self.expand_env_vars,
             line,
             comments: self
                 .revisioned
@@ -376,9 +864,13 @@ impl CommentParser<Comments> {
         };
         f(&mut this);
         let CommentParser {
-            errors, commands, ..
+            errors,
+            commands,
+            custom_comments,
+            ..
         } = this;
         self.commands = commands;
+        self.custom_comments = custom_comments;
         self.errors = errors;
     }
 }
@@ -395,14 +887,26 @@ impl CommentParser<&mut Revisioned> {
         }
         commands! {
             "compile-flags" => (this, args){
-                if let Some(parsed) = comma::parse_command(args) {
+                let Some(args) = this.expand_env_vars(args) else { return };
+                if let Some(parsed) = comma::parse_command(&args) {
                     this.compile_flags.extend(parsed);
                 } else {
                     this.error(format!("`{args}` contains an unclosed quotation mark"));
                 }
             }
             "rustc-env" => (this, args){
-                for env in args.split_whitespace() {
+                let Some(args) = this.expand_env_vars(args) else { return };
+                // Without quotes, backslashes are kept as they are, e.g. in Windows paths.
+                let envs = if args.contains(['"', '\'']) {
+                    comma::parse_command(&args)
+                } else {
+                    Some(args.split_whitespace().map(str::to_owned).collect())
+                };
+                let Some(envs) = envs else {
+                    this.error(format!("`{args}` contains an unclosed quotation mark"));
+                    return;
+                };
+                for env in envs {
                     if let Some((k, v)) = this.check_some(
                         env.split_once('='),
                         "environment variables must be key/value pairs separated by a `=`",
@@ -437,9 +941,13 @@ impl CommentParser<&mut Revisioned> {
                 this.error("`error-pattern` has been renamed to `error-in-other-file`");
             }
             "error-in-other-file" => (this, args){
-                let pat = this.parse_error_pattern(args.trim());
+                let (location, args) = match this.parse_location_qualifier(args.trim()) {
+                    Some((location, args)) => (Some(location), args),
+                    None => (None, args.trim()),
+                };
+                let pat = this.parse_error_pattern(args);
                 let line = this.line;
-                this.error_in_other_files.push((pat, line));
+                this.error_in_other_files.push((pat, line, location));
             }
             "stderr-per-bitwidth" => (this, _args){
                 // args are ignored (can be used as comment)
@@ -449,26 +957,109 @@ impl CommentParser<&mut Revisioned> {
                 );
                 this.stderr_per_bitwidth = true;
             }
-            "run-rustfix" => (this, _args){
-                // args are ignored (can be used as comment)
+            "run-rustfix" => (this, args){
+                // args other than `loop` are ignored (can be used as comment)
                 this.check(
                     this.mode.is_none(),
                     "cannot specify test mode changes twice",
                 );
-                this.mode = Some((Mode::Fix, this.line))
+                this.mode = Some((Mode::Fix, this.line));
+                this.rustfix_loop = args.trim() == "loop";
             }
             "needs-asm-support" => (this, _args){
                 // args are ignored (can be used as comment)
                 this.check(
-                    !this.needs_asm_support,
+                    this.needs_asm_support.is_none(),
                     "cannot specify `needs-asm-support` twice",
                 );
-                this.needs_asm_support = true;
+                this.needs_asm_support = Some(this.line);
+            }
+            "needs-debug-assertions" => (this, _args){
+                // args are ignored (can be used as comment)
+                this.check(
+                    this.needs_debug_assertions.is_none(),
+                    "cannot specify `needs-debug-assertions` twice",
+                );
+                this.needs_debug_assertions = Some(this.line);
+            }
+            "check-annotation-order" => (this, _args){
+                // args are ignored (can be used as comment)
+                this.check(
+                    !this.check_annotation_order,
+                    "cannot specify `check-annotation-order` twice",
+                );
+                this.check_annotation_order = true;
+            }
+            "allow-suggestion-conflicts" => (this, _args){
+                // args are ignored (can be used as comment)
+                this.check(
+                    !this.allow_suggestion_conflicts,
+                    "cannot specify `allow-suggestion-conflicts` twice",
+                );
+                this.allow_suggestion_conflicts = true;
             }
             "aux-build" => (this, args){
-                let (name, kind) = args.split_once(':').unwrap_or((args, "lib"));
+                let Some(args) = this.expand_env_vars(args) else { return };
+                let (args, flags) = match args.split_once(" --") {
+                    Some((args, flags)) => (args.trim(), flags),
+                    None => (&*args, ""),
+                };
+                let (name, kind) = match args.split_once(':') {
+                    Some((name, kind)) => (name, Some(kind.into())),
+                    None => (args, None),
+                };
                 let line = this.line;
-                this.aux_builds.push((name.into(), kind.into(), line));
+                if let Some(flags) = comma::parse_command(flags) {
+                    this.aux_builds.push((name.into(), kind, flags, line));
+                } else {
+                    this.error(format!("`{flags}` contains an unclosed quotation mark"));
+                }
+            }
+            "custom-build" => (this, args){
+                let (name, args) = args.split_once(':').unwrap_or((args, ""));
+                let line = this.line;
+                this.custom_builds.push((name.trim().into(), args.trim().into(), line));
+            }
+            "run-aux-bin" => (this, args){
+                let Some(args) = this.expand_env_vars(args) else { return };
+                if let Some(mut args) = comma::parse_command(&args) {
+                    if args.is_empty() {
+                        this.error("`run-aux-bin` needs the file name of an aux binary");
+                    } else {
+                        let name = args.remove(0);
+                        let line = this.line;
+                        this.run_aux_bins.push((name.into(), args, line));
+                    }
+                } else {
+                    this.error(format!("`{args}` contains an unclosed quotation mark"));
+                }
+            }
+            "crate-type" => (this, args){
+                this.check(this.crate_type.is_none(), "cannot specify `crate-type` twice");
+                this.crate_type = Some((args.into(), this.line))
+            }
+            "retry" => (this, args){
+                this.check(this.retry.is_none(), "cannot specify `retry` twice");
+                match args.trim().parse() {
+                    Ok(0) => this.error("`retry` needs at least one attempt"),
+                    Ok(attempts) => this.retry = Some((attempts, this.line)),
+                    Err(err) => this.error(format!("invalid number of attempts for `retry`: {err}")),
+                }
+            }
+            "stdin" => (this, args){
+                this.check(this.stdin.is_none(), "cannot specify `stdin` twice");
+                let Some(args) = this.expand_env_vars(args) else { return };
+                this.stdin = Some((args.as_ref().into(), this.line))
+            }
+            "working-dir" => (this, args){
+                this.check(this.working_dir.is_none(), "cannot specify `working-dir` twice");
+                let Some(args) = this.expand_env_vars(args.trim()) else { return };
+                let dir = PathBuf::from(args.as_ref());
+                if dir.as_os_str().is_empty() || dir.has_root() {
+                    this.error("`working-dir` needs a path relative to the directory of the test");
+                } else {
+                    this.working_dir = Some((dir, this.line))
+                }
             }
             "edition" => (this, args){
                 this.check(this.edition.is_none(), "cannot specify `edition` twice");
@@ -497,6 +1088,45 @@ impl CommentParser<&mut Revisioned> {
                     }
                 }
             }
+            "check-fixed" => (this, args){
+                this.check(
+                    this.check_fixed.is_none(),
+                    "cannot specify `check-fixed` twice",
+                );
+                let args = args.trim();
+                if args.is_empty() {
+                    this.check_fixed = Some(Level::Warn);
+                } else {
+                    match args.parse() {
+                        Ok(it) => this.check_fixed = Some(it),
+                        Err(msg) => this.error(msg),
+                    }
+                }
+            }
+            "apply-suggestions" => (this, args){
+                this.check(
+                    this.apply_suggestions.is_none(),
+                    "cannot specify `apply-suggestions` twice",
+                );
+                match args.trim().parse() {
+                    Ok(it) => this.apply_suggestions = Some(it),
+                    Err(msg) => this.error(msg),
+                }
+            }
+            "compare" => (this, args){
+                this.check(this.compare.is_none(), "cannot specify `compare` twice");
+                match args.trim().parse() {
+                    Ok(it) => this.compare = Some(it),
+                    Err(msg) => this.error(msg),
+                }
+            }
+            "ansi" => (this, args){
+                this.check(this.ansi.is_none(), "cannot specify `ansi` twice");
+                match args.trim().parse() {
+                    Ok(it) => this.ansi = Some(it),
+                    Err(msg) => this.error(msg),
+                }
+            }
             "require-annotations-for-level" => (this, args){
                 this.check(
                     this.require_annotations_for_level.is_none(),
@@ -515,21 +1145,37 @@ impl CommentParser<&mut Revisioned> {
         if let Some(command) = self.commands.get(command) {
             command(self, args);
         } else if let Some(s) = command.strip_prefix("ignore-") {
-            // args are ignored (can be used as comment)
-            match Condition::parse(s) {
-                Ok(cond) => self.ignore.push(cond),
+            // args are ignored (can be used as comment), except by some conditions
+            let line = self.line;
+            match Condition::parse(s, args) {
+                Ok(cond) => self.ignore.push((cond, line)),
                 Err(msg) => self.error(msg),
             }
         } else if let Some(s) = command.strip_prefix("only-") {
-            // args are ignored (can be used as comment)
-            match Condition::parse(s) {
-                Ok(cond) => self.only.push(cond),
+            // args are ignored (can be used as comment), except by some conditions
+            let line = self.line;
+            match Condition::parse(s, args) {
+                Ok(cond) => self.only.push((cond, line)),
+                Err(msg) => self.error(msg),
+            }
+        } else if let Some(CustomComment(parse)) = self.custom_comments.get(command).cloned() {
+            self.check(
+                !self.custom.contains_key(command),
+                format!("cannot specify `{command}` twice"),
+            );
+            match parse(args) {
+                Ok(flag) => {
+                    let line = self.line;
+                    self.custom.insert(command.to_owned(), (flag.into(), line));
+                }
                 Err(msg) => self.error(msg),
             }
         } else {
             let best_match = self
                 .commands
                 .keys()
+                .copied()
+                .chain(self.custom_comments.keys().map(String::as_str))
                 .min_by_key(|key| distance::damerau_levenshtein(key, command))
                 .unwrap();
             self.error(format!(
@@ -583,6 +1229,29 @@ impl<CommentsType> CommentParser<CommentsType> {
         }
     }
 
+    /// Parses an optional `@path/to/file.rs:12:` prefix of an `error-in-other-file` pattern.
+    /// The `@` keeps messages like `x:12: expected ...` from being taken for a location.
+    fn parse_location_qualifier<'a>(&mut self, s: &'a str) -> Option<(Location, &'a str)> {
+        let s = s.strip_prefix('@')?;
+        let location = s.split_once(':').and_then(|(file, rest)| {
+            let (line, rest) = rest.split_once(':')?;
+            let line: usize = line.parse().ok()?;
+            (!file.is_empty() && !file.contains(char::is_whitespace)).then_some((file, line, rest))
+        });
+        let Some((file, line, rest)) = location else {
+            self.error("expected a location like `@path/to/file.rs:12:` before the pattern");
+            return None;
+        };
+        self.check(line > 0, "line numbers start at 1");
+        Some((
+            Location {
+                file: file.into(),
+                line,
+            },
+            rest.trim(),
+        ))
+    }
+
     // parse something like \[[a-z]+(,[a-z]+)*\]
     fn parse_revisions<'a>(&mut self, pattern: &'a str) -> (Vec<String>, &'a str) {
         match pattern.chars().next() {
@@ -610,7 +1279,7 @@ impl<CommentsType> CommentParser<CommentsType> {
 }
 
 impl CommentParser<&mut Revisioned> {
-    // parse something like (\[[a-z]+(,[a-z]+)*\])?(?P<offset>\||[\^]+)? *(?P<level>ERROR|HELP|WARN|NOTE): (?P<text>.*)
+    // parse something like (\[[a-z]+(,[a-z]+)*\])?(?P<offset>\||[\^]+)? *(?P<level>ERROR|HELP|WARN|NOTE)(\*(?P<count>[0-9]+)|\+)?: (?P<text>.*)
     fn parse_pattern(&mut self, pattern: &str, fallthrough_to: &mut Option<usize>) {
         let (match_line, pattern) = match pattern.chars().next() {
             Some('|') => (
@@ -662,19 +1331,98 @@ impl CommentParser<&mut Revisioned> {
             }
         };
         let pattern = &pattern[offset..];
-        let pattern = match pattern.strip_prefix(':') {
-            Some(offset) => offset,
-            None => {
-                self.error("no `:` after level found");
+        let (count, pattern) = match pattern.strip_prefix('*') {
+            Some(rest) => {
+                let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+                match rest[..digits].parse() {
+                    Ok(0) => {
+                        self.error("an annotation cannot expect 0 occurrences");
+                        return;
+                    }
+                    Ok(count) => (Some(count), &rest[digits..]),
+                    Err(_) => {
+                        self.error("expected a number of occurrences after `*`");
+                        return;
+                    }
+                }
+            }
+            None => (None, pattern),
+        };
+        let (consume_all, pattern) = match pattern.strip_prefix('+') {
+            Some(_) if count.is_some() => {
+                self.error("an annotation cannot both have a count and match all occurrences");
                 return;
             }
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        let (column, pattern) = match pattern.strip_prefix("@col:") {
+            Some(rest) => {
+                let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+                match rest[..digits].parse() {
+                    Ok(0) => {
+                        self.error("columns start at 1");
+                        return;
+                    }
+                    Ok(column) => {
+                        let rest = &rest[digits..];
+                        // The `:` before the pattern is optional after a column.
+                        let rest = match rest.strip_prefix(':') {
+                            Some(rest) => rest,
+                            None if rest.starts_with(char::is_whitespace)
+                                || rest.starts_with("@no-fix") =>
+                            {
+                                rest
+                            }
+                            None => {
+                                self.error("expected whitespace, `:` or `@no-fix` after column");
+                                return;
+                            }
+                        };
+                        (Some(column), rest)
+                    }
+                    Err(_) => {
+                        self.error("expected a column number after `@col:`");
+                        return;
+                    }
+                }
+            }
+            None => (None, pattern),
         };
+        let (no_fix, pattern) = match pattern.strip_prefix("@no-fix") {
+            // The `:` before the pattern is optional after `@no-fix`.
+            Some(rest) => (true, rest.strip_prefix(':').unwrap_or(rest)),
+            None => (false, pattern),
+        };
+        let pattern = if let Some(code) = pattern.trim_start().strip_prefix('[') {
+            match code.trim_end().strip_suffix(']') {
+                Some(code) if !code.trim().is_empty() => Pattern::Code(code.trim().to_string()),
+                Some(_) => {
+                    self.error("no diagnostic code specified");
+                    return;
+                }
+                None => {
+                    self.error("expected `]` after diagnostic code");
+                    return;
+                }
+            }
+        } else {
+            let pattern = match pattern.strip_prefix(':') {
+                Some(offset) => offset,
+                // The `:` is optional after a column, `+` or `@no-fix`.
+                None if column.is_some() || consume_all || no_fix => pattern,
+                None => {
+                    self.error("no `:` after level found");
+                    return;
+                }
+            };
 
-        let pattern = pattern.trim();
+            let pattern = pattern.trim();
 
-        self.check(!pattern.is_empty(), "no pattern specified");
+            self.check(!pattern.is_empty(), "no pattern specified");
 
-        let pattern = self.parse_error_pattern(pattern);
+            self.parse_error_pattern(pattern)
+        };
 
         *fallthrough_to = Some(match_line);
 
@@ -682,27 +1430,75 @@ impl CommentParser<&mut Revisioned> {
         self.error_matches.push(ErrorMatch {
             pattern,
             level,
+            count,
+            consume_all,
+            column,
+            no_fix,
             definition_line,
             line: match_line,
         });
     }
 }
 
+impl std::fmt::Display for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Pattern::SubString(s) => write!(f, "{s}"),
+            Pattern::Regex(r) => write!(f, "/{r}/"),
+            Pattern::Alternatives(alternatives) => write!(f, "{}", alternatives.join(" | ")),
+            Pattern::Code(code) => write!(f, "[{code}]"),
+        }
+    }
+}
+
 impl Pattern {
-    pub(crate) fn matches(&self, message: &str) -> bool {
+    pub(crate) fn matches(&self, message: &Message) -> bool {
         match self {
-            Pattern::SubString(s) => message.contains(s),
-            Pattern::Regex(r) => r.is_match(message.as_bytes()),
+            Pattern::SubString(s) => message.message.contains(s),
+            Pattern::Regex(r) => r.is_match(message.message.as_bytes()),
+            Pattern::Alternatives(alternatives) => alternatives
+                .iter()
+                .any(|s| message.message.contains(s.as_str())),
+            Pattern::Code(code) => message
+                .code
+                .as_deref()
+                .map_or(false, |actual| code_matches(code, actual)),
         }
     }
 }
 
+/// Checks a code pattern like `E0277|E0599` or `E0*` against an actual code.
+fn code_matches(pattern: &str, code: &str) -> bool {
+    pattern.split('|').any(|alternative| {
+        let mut parts = alternative.trim().split('*');
+        let first = parts.next().unwrap();
+        let Some(mut rest) = code.strip_prefix(first) else {
+            return false;
+        };
+        let mut parts: Vec<_> = parts.collect();
+        let Some(last) = parts.pop() else {
+            // No wildcard, so the code must match exactly.
+            return rest.is_empty();
+        };
+        for part in parts {
+            match rest.find(part) {
+                Some(i) => rest = &rest[i + part.len()..],
+                None => return false,
+            }
+        }
+        rest.ends_with(last)
+    })
+}
+
 impl<CommentsType> CommentParser<CommentsType> {
     fn parse_error_pattern(&mut self, pattern: &str) -> Pattern {
         if let Some(regex) = pattern.strip_prefix('/') {
             match regex.strip_suffix('/') {
-                Some(regex) => match self.parse_regex(regex) {
-                    Some(regex) => Pattern::Regex(regex),
+                Some(source) => match self.parse_regex(source) {
+                    Some(regex) => match literal_alternatives(source) {
+                        Some(alternatives) => Pattern::Alternatives(alternatives),
+                        None => Pattern::Regex(regex),
+                    },
                     None => Pattern::SubString(pattern.to_string()),
                 },
                 None => {
@@ -717,3 +1513,53 @@ impl<CommentsType> CommentParser<CommentsType> {
         }
     }
 }
+
+/// The substrings that a regex like `(cannot find|unresolved) type` matches, if it consists of
+/// nothing but literal text and `(foo|bar)` groups, so that errors can list them.
+fn literal_alternatives(regex: &str) -> Option<Vec<String>> {
+    if regex.contains(|c| "\\.^$*+?[]{}".contains(c)) {
+        return None;
+    }
+    let alternatives = expand_alternatives(regex);
+    (alternatives.len() > 1
+        && alternatives
+            .iter()
+            .all(|alternative| !alternative.contains(['(', ')', '|'])))
+    .then_some(alternatives)
+}
+
+/// Expands every `(foo|bar)` group in the pattern into all the substrings it can stand for.
+/// Parentheses without a `|` in them are kept as they are.
+fn expand_alternatives(pattern: &str) -> Vec<String> {
+    let group = pattern.find('(').and_then(|start| {
+        let end = start + pattern[start..].find(')')?;
+        pattern[start + 1..end]
+            .contains('|')
+            .then_some((start, end))
+    });
+    match group {
+        Some((start, end)) => {
+            let (prefix, suffix) = (&pattern[..start], &pattern[end + 1..]);
+            let suffixes = expand_alternatives(suffix);
+            pattern[start + 1..end]
+                .split('|')
+                .flat_map(|alternative| {
+                    suffixes
+                        .iter()
+                        .map(move |suffix| format!("{prefix}{alternative}{suffix}"))
+                })
+                .collect()
+        }
+        None => match pattern.find('(') {
+            // Skip over a group without alternatives and look for more groups after it.
+            Some(start) if pattern[start..].contains(')') => {
+                let end = start + pattern[start..].find(')').unwrap();
+                expand_alternatives(&pattern[end + 1..])
+                    .into_iter()
+                    .map(|suffix| format!("{}{suffix}", &pattern[..=end]))
+                    .collect()
+            }
+            _ => vec![pattern.to_string()],
+        },
+    }
+}
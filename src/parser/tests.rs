@@ -1,6 +1,9 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
 use crate::{
     parser::{Condition, Pattern},
-    Error,
+    rustc_stderr::Level,
+    AnsiEscapes, ApplySuggestions, Compare, CustomComment, Error, Flag, Shared,
 };
 
 use super::Comments;
@@ -14,7 +17,7 @@ fn main() {
     let _x: &i32 = unsafe { mem::transmute(16usize) }; //~ ERROR: encountered a dangling reference (address $HEX is unallocated)
 }
     ";
-    let comments = Comments::parse(s).unwrap();
+    let comments = Comments::parse(s, "//").unwrap();
     println!("parsed comments: {:#?}", comments);
     assert_eq!(comments.revisioned.len(), 1);
     let revisioned = &comments.revisioned[&vec![]];
@@ -39,11 +42,14 @@ fn main() {
     let _x: &i32 = unsafe { mem::transmute(16usize) }; //~ encountered a dangling reference (address $HEX is unallocated)
 }
     ";
-    let errors = Comments::parse(s).unwrap_err();
+    let errors = Comments::parse(s, "//").unwrap_err();
     println!("parsed comments: {:#?}", errors);
     assert_eq!(errors.len(), 1);
     match &errors[0] {
-        Error::InvalidComment { msg, line: 5 } => assert_eq!(msg, "unknown level `encountered`"),
+        Error::InvalidComment { msg, line: 5 } => assert_eq!(
+            msg,
+            "unknown level `encountered`, expected one of `ERROR`, `WARN`, `WARNING`, `HELP`, `NOTE` or `ICE` (in any case)"
+        ),
         _ => unreachable!(),
     }
 }
@@ -55,7 +61,7 @@ fn parse_slash_slash_at() {
 use std::mem;
 
     ";
-    let comments = Comments::parse(s).unwrap();
+    let comments = Comments::parse(s, "//").unwrap();
     println!("parsed comments: {:#?}", comments);
     assert_eq!(comments.revisioned.len(), 1);
     let revisioned = &comments.revisioned[&vec![]];
@@ -71,7 +77,7 @@ fn parse_regex_error_pattern() {
 use std::mem;
 
     ";
-    let comments = Comments::parse(s).unwrap();
+    let comments = Comments::parse(s, "//").unwrap();
     println!("parsed comments: {:#?}", comments);
     assert_eq!(comments.revisioned.len(), 1);
     let revisioned = &comments.revisioned[&vec![]];
@@ -80,6 +86,41 @@ use std::mem;
     assert_eq!(pat.1, 2);
 }
 
+#[test]
+fn parse_located_error_pattern() {
+    let s = r"
+//@error-in-other-file: @aux/helper.rs:12: cannot find type
+//@error-in-other-file: x:12: expected `u32`
+use std::mem;
+
+    ";
+    let comments = Comments::parse(s, "//").unwrap();
+    println!("parsed comments: {:#?}", comments);
+    let revisioned = &comments.revisioned[&vec![]];
+    let pat = &revisioned.error_in_other_files[0];
+    assert_eq!(format!("{:?}", pat.0), r#"SubString("cannot find type")"#);
+    assert_eq!(pat.1, 2);
+    let location = pat.2.as_ref().unwrap();
+    assert_eq!(location.file, PathBuf::from("aux/helper.rs"));
+    assert_eq!(location.line, 12);
+    // Without the `@`, text that looks like a location is part of the pattern.
+    let pat = &revisioned.error_in_other_files[1];
+    assert_eq!(
+        format!("{:?}", pat.0),
+        r#"SubString("x:12: expected `u32`")"#
+    );
+    assert!(pat.2.is_none());
+
+    let errors = Comments::parse("//@error-in-other-file: @aux/helper.rs: oops", "//").unwrap_err();
+    match &errors[..] {
+        [Error::InvalidComment { msg, line: 1 }] => assert_eq!(
+            msg,
+            "expected a location like `@path/to/file.rs:12:` before the pattern"
+        ),
+        _ => panic!("{errors:?}"),
+    }
+}
+
 #[test]
 fn parse_slash_slash_at_fail() {
     let s = r"
@@ -87,7 +128,7 @@ fn parse_slash_slash_at_fail() {
 use std::mem;
 
     ";
-    let errors = Comments::parse(s).unwrap_err();
+    let errors = Comments::parse(s, "//").unwrap_err();
     println!("parsed comments: {:#?}", errors);
     assert_eq!(errors.len(), 2);
     match &errors[0] {
@@ -111,7 +152,7 @@ fn missing_colon_fail() {
 use std::mem;
 
     ";
-    let errors = Comments::parse(s).unwrap_err();
+    let errors = Comments::parse(s, "//").unwrap_err();
     println!("parsed comments: {:#?}", errors);
     assert_eq!(errors.len(), 1);
     match &errors[0] {
@@ -125,13 +166,877 @@ use std::mem;
 #[test]
 fn parse_x86_64() {
     let s = r"//@ only-target-x86_64-unknown-linux";
-    let comments = Comments::parse(s).unwrap();
+    let comments = Comments::parse(s, "//").unwrap();
     println!("parsed comments: {:#?}", comments);
     assert_eq!(comments.revisioned.len(), 1);
     let revisioned = &comments.revisioned[&vec![]];
     assert_eq!(revisioned.only.len(), 1);
     match &revisioned.only[0] {
-        Condition::Target(t) => assert_eq!(t, "x86_64-unknown-linux"),
+        (Condition::Target(t), 1) => assert_eq!(t, "x86_64-unknown-linux"),
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn parse_count() {
+    let s = r"
+fn main() {
+    foo!(); //~ ERROR*3: mismatched types
+}
+    ";
+    let comments = Comments::parse(s, "//").unwrap();
+    println!("parsed comments: {:#?}", comments);
+    let revisioned = &comments.revisioned[&vec![]];
+    assert_eq!(revisioned.error_matches[0].count, Some(3));
+    match &revisioned.error_matches[0].pattern {
+        Pattern::SubString(s) => assert_eq!(s, "mismatched types"),
+        other => panic!("expected substring, got {other:?}"),
+    }
+}
+
+#[test]
+fn parse_zero_count() {
+    let s = r"
+fn main() {
+    foo!(); //~ ERROR*0: mismatched types
+}
+    ";
+    let errors = Comments::parse(s, "//").unwrap_err();
+    println!("parsed comments: {:#?}", errors);
+    assert_eq!(errors.len(), 1);
+    match &errors[0] {
+        Error::InvalidComment { msg, line: 3 } => {
+            assert_eq!(msg, "an annotation cannot expect 0 occurrences")
+        }
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn parse_level_aliases() {
+    let s = r"
+fn main() {
+    foo!(); //~ WARNING: unused
+    foo!(); //~ Error: WARN about this
+    foo!(); //~ note: Help me
+    foo!(); //~ ice: HELP
+}
+    ";
+    let comments = Comments::parse(s, "//").unwrap();
+    println!("parsed comments: {:#?}", comments);
+    let revisioned = &comments.revisioned[&vec![]];
+    let matches: Vec<_> = revisioned
+        .error_matches
+        .iter()
+        .map(|m| (m.level, m.pattern.to_string()))
+        .collect();
+    assert_eq!(
+        matches,
+        [
+            (Level::Warn, "unused".to_string()),
+            (Level::Error, "WARN about this".to_string()),
+            (Level::Note, "Help me".to_string()),
+            (Level::Ice, "HELP".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn parse_unknown_level() {
+    let s = r"
+fn main() {
+    foo!(); //~ WARNIG: unused
+}
+    ";
+    let errors = Comments::parse(s, "//").unwrap_err();
+    println!("parsed comments: {:#?}", errors);
+    assert_eq!(errors.len(), 1);
+    match &errors[0] {
+        Error::InvalidComment { msg, line: 3 } => {
+            assert!(msg.starts_with("unknown level `WARNIG`, expected one of"))
+        }
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn parse_code_pattern() {
+    let s = r"
+fn main() {
+    foo.clone(); //~ WARN*2 [clippy::needless_clone]
+    foo.clone(); //~ ERROR: [not a code]
+}
+    ";
+    let comments = Comments::parse(s, "//").unwrap();
+    println!("parsed comments: {:#?}", comments);
+    let revisioned = &comments.revisioned[&vec![]];
+    assert_eq!(revisioned.error_matches[0].count, Some(2));
+    match &revisioned.error_matches[0].pattern {
+        Pattern::Code(code) => assert_eq!(code, "clippy::needless_clone"),
+        other => panic!("expected code, got {other:?}"),
+    }
+    match &revisioned.error_matches[1].pattern {
+        Pattern::SubString(s) => assert_eq!(s, "[not a code]"),
+        other => panic!("expected substring, got {other:?}"),
+    }
+}
+
+#[test]
+fn code_wildcards() {
+    use super::code_matches;
+    assert!(code_matches("E0308", "E0308"));
+    assert!(!code_matches("E0308", "E03080"));
+    assert!(code_matches("E0*", "E0308"));
+    assert!(!code_matches("E0*", "clippy::needless_clone"));
+    assert!(code_matches("clippy::*_clone", "clippy::needless_clone"));
+    assert!(code_matches("*", "dead_code"));
+    assert!(code_matches("E*3*8", "E0308"));
+    assert!(!code_matches("E*8*3", "E0308"));
+    assert!(code_matches("E0277|E0599", "E0599"));
+    assert!(code_matches("E0277 | E05*", "E0599"));
+    assert!(!code_matches("E0277|E0599", "E0308"));
+}
+
+#[test]
+fn parse_column() {
+    let s = r"
+fn main() {
+    foo!(); //~ ERROR@col:17 mismatched types
+    foo!(); //~ ERROR*2@col:3: mismatched types
+    foo!(); //~ ERROR: mismatched types
+}
+    ";
+    let comments = Comments::parse(s, "//").unwrap();
+    println!("parsed comments: {:#?}", comments);
+    let revisioned = &comments.revisioned[&vec![]];
+    let matches: Vec<_> = revisioned
+        .error_matches
+        .iter()
+        .map(|m| (m.count, m.column, m.pattern.to_string()))
+        .collect();
+    assert_eq!(
+        matches,
+        [
+            (None, Some(17), "mismatched types".to_string()),
+            (Some(2), Some(3), "mismatched types".to_string()),
+            (None, None, "mismatched types".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn parse_bad_column() {
+    let s = r"
+fn main() {
+    foo!(); //~ ERROR@col:x mismatched types
+}
+    ";
+    let errors = Comments::parse(s, "//").unwrap_err();
+    println!("parsed comments: {:#?}", errors);
+    assert_eq!(errors.len(), 1);
+    match &errors[0] {
+        Error::InvalidComment { msg, line: 3 } => {
+            assert_eq!(msg, "expected a column number after `@col:`")
+        }
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn parse_consume_all() {
+    let s = r"
+fn main() {
+    foo!(); //~ ERROR+ mismatched types
+    foo!(); //~ ERROR+: mismatched types
+    foo!(); //~ ERROR*2+: mismatched types
+}
+    ";
+    let errors = Comments::parse(s, "//").unwrap_err();
+    println!("parsed comments: {:#?}", errors);
+    assert_eq!(errors.len(), 1);
+    match &errors[0] {
+        Error::InvalidComment { msg, line: 5 } => assert_eq!(
+            msg,
+            "an annotation cannot both have a count and match all occurrences"
+        ),
+        _ => unreachable!(),
+    }
+
+    let comments = Comments::parse(&s.replace("*2+", ""), "//").unwrap();
+    let revisioned = &comments.revisioned[&vec![]];
+    let matches: Vec<_> = revisioned
+        .error_matches
+        .iter()
+        .map(|m| (m.consume_all, m.pattern.to_string()))
+        .collect();
+    assert_eq!(
+        matches,
+        [
+            (true, "mismatched types".to_string()),
+            (true, "mismatched types".to_string()),
+            (false, "mismatched types".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn parse_alternatives() {
+    let s = r"
+fn main() {
+    foo!(); //~ ERROR: /(cannot find|unresolved) type/
+    //~| ERROR: expected (u32, u32)
+    //~| ERROR: expected one of `(a|b)`
+    //~| ERROR: /(cannot find|unresolved) type.*/
+}
+    ";
+    let comments = Comments::parse(s, "//").unwrap();
+    println!("parsed comments: {:#?}", comments);
+    let revisioned = &comments.revisioned[&vec![]];
+    match &revisioned.error_matches[0].pattern {
+        Pattern::Alternatives(alternatives) => {
+            assert_eq!(alternatives, &["cannot find type", "unresolved type"])
+        }
+        other => panic!("expected alternatives, got {other:?}"),
+    }
+    assert_eq!(revisioned.error_matches[1].line, 3);
+    match &revisioned.error_matches[1].pattern {
+        Pattern::SubString(s) => assert_eq!(s, "expected (u32, u32)"),
+        other => panic!("expected substring, got {other:?}"),
+    }
+    // Only regexes have alternatives.
+    match &revisioned.error_matches[2].pattern {
+        Pattern::SubString(s) => assert_eq!(s, "expected one of `(a|b)`"),
+        other => panic!("expected substring, got {other:?}"),
+    }
+    match &revisioned.error_matches[3].pattern {
+        Pattern::Regex(regex) => assert_eq!(regex.as_str(), "(cannot find|unresolved) type.*"),
+        other => panic!("expected regex, got {other:?}"),
+    }
+}
+
+#[test]
+fn expand_alternatives() {
+    use super::expand_alternatives;
+    assert_eq!(expand_alternatives("no groups"), ["no groups"]);
+    assert_eq!(
+        expand_alternatives("fn(u32) (a|b)"),
+        ["fn(u32) a", "fn(u32) b"]
+    );
+    assert_eq!(
+        expand_alternatives("(a|b) and (c|d)"),
+        ["a and c", "a and d", "b and c", "b and d"]
+    );
+    assert_eq!(expand_alternatives("unclosed (a|b"), ["unclosed (a|b"]);
+}
+
+#[test]
+fn parse_check_fixed() {
+    let s = r"
+//@revisions: foo bar
+//@check-fixed
+//@[foo]check-fixed: ERROR
+//@[bar]check-fixed: nope
+    ";
+    let errors = Comments::parse(s, "//").unwrap_err();
+    println!("parsed comments: {:#?}", errors);
+    assert_eq!(errors.len(), 1);
+    match &errors[0] {
+        Error::InvalidComment { msg, line: 5 } => assert!(msg.starts_with("unknown level `nope`")),
+        _ => unreachable!(),
+    }
+
+    let comments = Comments::parse(&s.replace("nope", "HELP"), "//").unwrap();
+    assert_eq!(comments.revisioned[&vec![]].check_fixed, Some(Level::Warn));
+    assert_eq!(
+        comments.revisioned[&vec!["foo".to_string()]].check_fixed,
+        Some(Level::Error)
+    );
+    assert_eq!(
+        comments.revisioned[&vec!["bar".to_string()]].check_fixed,
+        Some(Level::Help)
+    );
+}
+
+#[test]
+fn parse_apply_suggestions() {
+    let s = r"
+//@apply-suggestions: maybe-incorrect
+//@apply-suggestions: sometimes
+    ";
+    let errors = Comments::parse(s, "//").unwrap_err();
+    println!("parsed comments: {:#?}", errors);
+    assert_eq!(errors.len(), 2);
+    match &errors[0] {
+        Error::InvalidComment { msg, line: 3 } => {
+            assert_eq!(msg, "cannot specify `apply-suggestions` twice")
+        }
+        _ => unreachable!(),
+    }
+    match &errors[1] {
+        Error::InvalidComment { msg, line: 3 } => {
+            assert!(msg.starts_with("unknown applicability `sometimes`"))
+        }
+        _ => unreachable!(),
+    }
+
+    let comments =
+        Comments::parse(&s.replace("//@apply-suggestions: sometimes", ""), "//").unwrap();
+    assert_eq!(
+        comments.revisioned[&vec![]].apply_suggestions,
+        Some(ApplySuggestions::MaybeIncorrect)
+    );
+}
+
+#[test]
+fn parse_rustfix_loop() {
+    let s = r"
+//@revisions: foo bar
+//@[foo]run-rustfix: loop
+//@[bar]run-rustfix: because why not
+    ";
+    let comments = Comments::parse(s, "//").unwrap();
+    assert!(comments.revisioned[&vec!["foo".to_string()]].rustfix_loop);
+    assert!(!comments.revisioned[&vec!["bar".to_string()]].rustfix_loop);
+}
+
+#[test]
+fn parse_no_fix() {
+    let s = r"
+fn main() {
+    let _a = (1); //~ WARN@no-fix unnecessary parentheses
+    let _b = (2); //~ WARN@col:14@no-fix: unnecessary parentheses
+    let _c = (3); //~ WARN: unnecessary parentheses
+}
+    ";
+    let comments = Comments::parse(s, "//").unwrap();
+    println!("parsed comments: {:#?}", comments);
+    let matches: Vec<_> = comments.revisioned[&vec![]]
+        .error_matches
+        .iter()
+        .map(|m| (m.no_fix, m.column, m.pattern.to_string()))
+        .collect();
+    assert_eq!(
+        matches,
+        [
+            (true, None, "unnecessary parentheses".to_string()),
+            (true, Some(14), "unnecessary parentheses".to_string()),
+            (false, None, "unnecessary parentheses".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn parse_compare() {
+    let s = r"
+//@revisions: a b
+//@compare: lenient-whitespace
+//@[a]compare: exact
+//@[b]compare: loose
+    ";
+    let errors = Comments::parse(s, "//").unwrap_err();
+    println!("parsed comments: {:#?}", errors);
+    assert_eq!(errors.len(), 1);
+    match &errors[0] {
+        Error::InvalidComment { msg, line: 5 } => {
+            assert!(msg.starts_with("unknown comparison `loose`"))
+        }
         _ => unreachable!(),
     }
+
+    let comments = Comments::parse(&s.replace("//@[b]compare: loose", ""), "//").unwrap();
+    assert_eq!(
+        comments.revisioned[&vec![]].compare,
+        Some(Compare::LenientWhitespace)
+    );
+    assert_eq!(
+        comments.revisioned[&vec!["a".to_string()]].compare,
+        Some(Compare::Exact)
+    );
+}
+
+#[test]
+fn parse_cfg_conditions() {
+    let s = r#"
+//@only-cfg: target_env = "musl"
+//@ignore-cfg: unix
+//@ignore-cfg: target_os=linux
+//@only-cfg: target-os
+//@only-cfg:
+    "#;
+    let errors = Comments::parse(s, "//").unwrap_err();
+    let msgs: Vec<_> = errors
+        .iter()
+        .map(|error| match error {
+            Error::InvalidComment { msg, line } => (msg.as_str(), *line),
+            _ => unreachable!(),
+        })
+        .collect();
+    assert_eq!(
+        msgs,
+        [
+            (
+                "the value of the cfg `target_os=linux` must be quoted, like `target_os=\"linux\"`",
+                4
+            ),
+            (
+                "`target-os` is not a valid cfg, expected a name like `unix` or a pair like `target_env=\"musl\"`",
+                5
+            ),
+            (
+                "`cfg` needs a name like `unix` or a pair like `target_env=\"musl\"`",
+                6
+            ),
+        ]
+    );
+
+    let comments =
+        Comments::parse(s.split("//@ignore-cfg: target_os").next().unwrap(), "//").unwrap();
+    let revisioned = &comments.revisioned[&vec![]];
+    let conditions: Vec<_> = revisioned
+        .only
+        .iter()
+        .chain(&revisioned.ignore)
+        .map(|(condition, _)| condition.to_string())
+        .collect();
+    assert_eq!(conditions, [r#"cfg: target_env="musl""#, "cfg: unix"]);
+}
+
+#[test]
+fn parse_ansi() {
+    let s = r"
+//@revisions: a b
+//@ansi: render
+//@[a]ansi: preserve
+//@[b]ansi: keep
+    ";
+    let errors = Comments::parse(s, "//").unwrap_err();
+    assert_eq!(errors.len(), 1);
+    match &errors[0] {
+        Error::InvalidComment { msg, line: 5 } => {
+            assert!(msg.starts_with("unknown ANSI escape handling `keep`"))
+        }
+        _ => unreachable!(),
+    }
+
+    let comments = Comments::parse(&s.replace("//@[b]ansi: keep", ""), "//").unwrap();
+    assert_eq!(comments.revisioned[&vec![]].ansi, Some(AnsiEscapes::Render));
+    assert_eq!(
+        comments.revisioned[&vec!["a".to_string()]].ansi,
+        Some(AnsiEscapes::Preserve)
+    );
+}
+
+#[test]
+fn parse_crlf() {
+    let s = "//@revisions: a b\r\nfn main() {\r\n    let x = 5; //~ ERROR: unused variable\r\n    //~^ WARN: /x$/\r\n}\r\n";
+    let comments = Comments::parse(s, "//").unwrap();
+    assert_eq!(comments.revisions, Some(vec!["a".into(), "b".into()]));
+    let revisioned = &comments.revisioned[&vec![]];
+    assert_eq!(revisioned.error_matches.len(), 2);
+    assert_eq!(revisioned.error_matches[0].line, 3);
+    match &revisioned.error_matches[0].pattern {
+        Pattern::SubString(s) => assert_eq!(s, "unused variable"),
+        other => panic!("expected substring, got {other:?}"),
+    }
+    assert_eq!(revisioned.error_matches[1].definition_line, 4);
+    assert_eq!(revisioned.error_matches[1].line, 3);
+    match &revisioned.error_matches[1].pattern {
+        Pattern::Regex(r) => assert_eq!(r.as_str(), "x$"),
+        other => panic!("expected regex, got {other:?}"),
+    }
+}
+
+#[test]
+fn parse_crlf_commands() {
+    let s = "//@revisions: a b\r\n\
+             //@compile-flags: --cfg foo\r\n\
+             //@[a] edition: 2018\r\n\
+             //@aux-build: helper.rs:proc-macro\r\n\
+             //@normalize-stderr-test: \"foo\" -> \"bar\"\r\n\
+             //@error-in-other-file: no main\r\n\
+             //@[b] ignore-on-host\r\n\
+             fn main() {} //~[a] ERROR: unused\r";
+    let comments = Comments::parse(s, "//").unwrap();
+    assert_eq!(comments.revisions, Some(vec!["a".into(), "b".into()]));
+    let revisioned = &comments.revisioned[&vec![]];
+    assert_eq!(revisioned.compile_flags, ["--cfg", "foo"]);
+    let (aux, kind, flags, line) = &revisioned.aux_builds[0];
+    assert_eq!(aux, &PathBuf::from("helper.rs"));
+    assert_eq!(kind.as_deref(), Some("proc-macro"));
+    assert!(flags.is_empty());
+    assert_eq!(*line, 4);
+    assert_eq!(revisioned.normalize_stderr[0].1, b"bar");
+    match &revisioned.error_in_other_files[0].0 {
+        Pattern::SubString(s) => assert_eq!(s, "no main"),
+        other => panic!("expected substring, got {other:?}"),
+    }
+    let a = &comments.revisioned[&vec!["a".to_string()]];
+    assert_eq!(a.edition.as_ref().unwrap().0, "2018");
+    // The last line ends in a `\r` without a `\n`.
+    assert_eq!(a.error_matches[0].line, 8);
+    match &a.error_matches[0].pattern {
+        Pattern::SubString(s) => assert_eq!(s, "unused"),
+        other => panic!("expected substring, got {other:?}"),
+    }
+    let b = &comments.revisioned[&vec!["b".to_string()]];
+    assert_eq!(b.ignore.len(), 1);
+}
+
+#[test]
+fn parse_aux_build_flags() {
+    let s = r#"
+//@revisions: a b
+//@aux-build: helper.rs:proc-macro -- --cfg special --cfg 'feature="x"'
+//@[a] aux-build: other.rs
+//@[b] aux-build: other.rs -- --cfg special
+"#;
+    let errors = Comments::parse(s, "//").unwrap_err();
+    assert_eq!(errors.len(), 1);
+    match &errors[0] {
+        Error::InvalidComment { msg, line: 5 } => assert_eq!(
+            msg,
+            "`other.rs` is built with `--cfg special` here, but with `` elsewhere"
+        ),
+        _ => unreachable!(),
+    }
+
+    let comments = Comments::parse(&s[..s.rfind("//@[b]").unwrap()], "//").unwrap();
+    let (aux, kind, flags, line) = &comments.revisioned[&vec![]].aux_builds[0];
+    assert_eq!(aux, &PathBuf::from("helper.rs"));
+    assert_eq!(kind.as_deref(), Some("proc-macro"));
+    assert_eq!(flags, &["--cfg", "special", "--cfg", "feature=\"x\""]);
+    assert_eq!(*line, 3);
+    let (aux, kind, flags, _) = &comments.revisioned[&vec!["a".to_string()]].aux_builds[0];
+    assert_eq!(aux, &PathBuf::from("other.rs"));
+    assert_eq!(*kind, None);
+    assert!(flags.is_empty());
+}
+
+#[test]
+fn parse_custom_build() {
+    let s = "//@custom-build: wasm: module.wat --opt\n//@custom-build: assets\n";
+    let comments = Comments::parse(s, "//").unwrap();
+    assert_eq!(
+        comments.revisioned[&vec![]].custom_builds,
+        [
+            ("wasm".to_string(), "module.wat --opt".to_string(), 1),
+            ("assets".to_string(), String::new(), 2)
+        ]
+    );
+}
+
+#[test]
+fn parse_retry() {
+    let comments = Comments::parse("//@retry: 3\n", "//").unwrap();
+    assert_eq!(comments.revisioned[&vec![]].retry, Some((3, 1)));
+
+    let errors = Comments::parse("//@retry: 0\n", "//").unwrap_err();
+    assert!(
+        matches!(&errors[..], [Error::InvalidComment { msg, line: 1 }] if msg.contains("at least one")),
+        "{errors:?}"
+    );
+}
+
+#[test]
+fn parse_custom_comment_start() {
+    let s = r"
+#@revisions: a b
+#@[a] error-in-other-file: foomp
+let x = y; #~ ERROR: cannot find value
+#~^^ WARN: unused
+// not a comment in this language: //@check-pass //~ ERROR: bar
+    ";
+    let comments = Comments::parse(s, "#").unwrap();
+    assert_eq!(
+        comments.revisions,
+        Some(vec!["a".to_string(), "b".to_string()])
+    );
+    let (pattern, line, _) = &comments.revisioned[&vec!["a".to_string()]].error_in_other_files[0];
+    assert!(matches!(pattern, Pattern::SubString(s) if s == "foomp"));
+    assert_eq!(*line, 3);
+    let matches = &comments.revisioned[&vec![]].error_matches;
+    assert_eq!(matches.len(), 2);
+    assert_eq!((matches[0].level, matches[0].line), (Level::Error, 4));
+    assert_eq!(
+        (
+            matches[1].level,
+            matches[1].line,
+            matches[1].definition_line
+        ),
+        (Level::Warn, 3, 5)
+    );
+
+    // Comment starts can be longer than one character.
+    let comments = Comments::parse("--@check-pass\nfoo --~ ERROR: bar\n", "--").unwrap();
+    assert_eq!(comments.revisioned[&vec![]].error_matches[0].line, 2);
+
+    let errors = Comments::parse("# @check-pass\n", "#").unwrap_err();
+    assert!(
+        matches!(&errors[..], [Error::InvalidComment { msg, line: 1 }] if msg.contains("All `#@` test suite commands")),
+        "{errors:?}"
+    );
+}
+
+#[test]
+fn parse_custom_comments() {
+    #[derive(Debug)]
+    struct Feature(String);
+    impl Flag for Feature {
+        fn apply(&self, cmd: &mut std::process::Command) {
+            cmd.arg("--cfg").arg(format!("feature=\"{}\"", self.0));
+        }
+    }
+
+    let mut custom_comments = Shared::<BTreeMap<_, _>>::default();
+    custom_comments.insert(
+        "feature".to_string(),
+        CustomComment::new(|args| {
+            if args.is_empty() {
+                Err("`feature` needs the name of a feature".into())
+            } else {
+                Ok(Box::new(Feature(args.to_owned())))
+            }
+        }),
+    );
+    let s = "//@revisions: a b\n//@[a]feature: foo\n//@feature: bar\n";
+    let comments = Comments::parse_with_custom_comments(s, "//", &custom_comments, true).unwrap();
+    let (flag, line) = &comments.revisioned[&vec!["a".to_string()]].custom["feature"];
+    assert_eq!(format!("{flag:?}"), r#"Feature("foo")"#);
+    assert_eq!(*line, 2);
+    assert_eq!(comments.revisioned[&vec![]].custom["feature"].1, 3);
+
+    let errors = Comments::parse_with_custom_comments(
+        "//@feature\n//@feature: a\n//@feature: b\n",
+        "//",
+        &custom_comments,
+        true,
+    )
+    .unwrap_err();
+    assert!(
+        matches!(
+            &errors[..],
+            [
+                Error::InvalidComment { msg: missing, line: 1 },
+                Error::InvalidComment { msg: twice, line: 3 },
+            ] if missing.contains("needs the name") && twice.contains("twice")
+        ),
+        "{errors:?}"
+    );
+
+    // Without registering the comment, it is unknown.
+    let errors = Comments::parse("//@feature: foo\n", "//").unwrap_err();
+    assert!(
+        matches!(&errors[..], [Error::InvalidComment { msg, line: 1 }] if msg.contains("not a command known")),
+        "{errors:?}"
+    );
+}
+
+#[test]
+fn parse_revision_matrix() {
+    let s = "//@revisions: {e2015 e2021} x {opt0 opt3}\n//@[e2021] edition: 2021\n//@[opt3, e2015] compile-flags: -O\n";
+    let comments = Comments::parse(s, "//").unwrap();
+    assert_eq!(
+        comments.revisions.as_deref().unwrap(),
+        ["e2015.opt0", "e2015.opt3", "e2021.opt0", "e2021.opt3"]
+    );
+    let edition = |revision| {
+        comments
+            .for_revision(revision)
+            .find_map(|r| r.edition.clone())
+            .map(|(edition, _)| edition)
+    };
+    assert_eq!(edition("e2021.opt3"), Some("2021".to_string()));
+    assert_eq!(edition("e2015.opt3"), None);
+    let flags = |revision| {
+        comments
+            .for_revision(revision)
+            .flat_map(|r| r.compile_flags.clone())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(flags("e2015.opt0"), ["-O"]);
+    assert_eq!(flags("e2021.opt0"), Vec::<String>::new());
+    assert_eq!(flags("e2021.opt3"), ["-O"]);
+    assert_eq!(comments.revision_values("e2021.opt3"), ["e2021", "opt3"]);
+
+    for (matrix, error) in [
+        ("{a b} x {c a}", "more than one axis"),
+        ("{a b} {c d}", "expected `x`"),
+        ("{a b} x {}", "can't be empty"),
+        ("{a b} x {c", "expected an axis"),
+        ("{a.b c}", "can't contain `.`"),
+    ] {
+        let errors = Comments::parse(&format!("//@revisions: {matrix}\n"), "//").unwrap_err();
+        assert!(
+            matches!(&errors[..], [Error::InvalidComment { msg, line: 1 }] if msg.contains(error)),
+            "{matrix}: {errors:?}"
+        );
+    }
+    let errors =
+        Comments::parse("//@revisions: {a b} x {c d}\n//@[e] check-pass\n", "//").unwrap_err();
+    assert!(
+        matches!(&errors[..], [Error::InvalidComment { msg, line: 2 }] if msg.contains("`e` is not known")),
+        "{errors:?}"
+    );
+}
+
+#[test]
+fn invalid_revision_names() {
+    for (revisions, error) in [
+        ("a no-std", "`no-std` is not a valid cfg name"),
+        ("1st", "`1st` is not a valid cfg name"),
+        ("a test", "collides with the built-in cfg `test`"),
+        ("a b a", "`a` is declared twice"),
+        ("foo Foo", "`foo` and `Foo` only differ in case"),
+        (
+            "{a b} x {c debug_assertions}",
+            "built-in cfg `debug_assertions`",
+        ),
+        ("{a b} x {c c}", "`c` is declared twice"),
+    ] {
+        let errors = Comments::parse(&format!("//@check-pass\n//@revisions: {revisions}\n"), "//")
+            .unwrap_err();
+        assert!(
+            matches!(&errors[..], [Error::InvalidComment { msg, line: 2 }] if msg.contains(error)),
+            "{revisions}: {errors:?}"
+        );
+    }
+    Comments::parse("//@revisions: a _b edition2021 Ünïcode\n", "//").unwrap();
+}
+
+#[test]
+fn parse_stdin() {
+    let comments = Comments::parse(
+        "//@revisions: a b\n//@[a] stdin: ../fixtures/input.txt\n",
+        "//",
+    )
+    .unwrap();
+    let (file, line) = comments.revisioned[&vec!["a".to_string()]]
+        .stdin
+        .clone()
+        .unwrap();
+    assert_eq!(file, PathBuf::from("../fixtures/input.txt"));
+    assert_eq!(line, 2);
+
+    let errors = Comments::parse("//@stdin: a.txt\n//@stdin: b.txt\n", "//").unwrap_err();
+    assert!(
+        matches!(&errors[..], [Error::InvalidComment { msg, line: 2 }] if msg.contains("`stdin` twice")),
+        "{errors:?}"
+    );
+}
+
+#[test]
+fn parse_quoted_args() {
+    let comments = Comments::parse(
+        "//@compile-flags: --cfg foo=\"bar baz\" -C 'link-arg=-Wl,a b' --cfg x\\ y\n\
+         //@rustc-env: A=\"x y\" B=z\n\
+         //@rustc-env: PATH=C:\\tmp\n",
+        "//",
+    )
+    .unwrap();
+    let revisioned = &comments.revisioned[&vec![]];
+    assert_eq!(
+        revisioned.compile_flags,
+        [
+            "--cfg",
+            "foo=bar baz",
+            "-C",
+            "link-arg=-Wl,a b",
+            "--cfg",
+            "x y"
+        ]
+    );
+    assert_eq!(
+        revisioned.env_vars,
+        [
+            ("A".to_string(), "x y".to_string()),
+            ("B".to_string(), "z".to_string()),
+            ("PATH".to_string(), "C:\\tmp".to_string()),
+        ]
+    );
+
+    for comment in ["//@compile-flags: --cfg 'foo", "//@rustc-env: A=\"x"] {
+        let errors = Comments::parse(&format!("fn main() {{}}\n{comment}\n"), "//").unwrap_err();
+        assert!(
+            matches!(&errors[..], [Error::InvalidComment { msg, line: 2 }] if msg.contains("unclosed quotation mark")),
+            "{errors:?}"
+        );
+    }
+}
+
+#[test]
+fn parse_env_vars() {
+    let dir = env!("CARGO_MANIFEST_DIR");
+    let parse = |content: &str| {
+        Comments::parse_with_custom_comments(content, "//", &Shared::default(), true)
+    };
+    let comments = parse(
+        "//@compile-flags: -L ${CARGO_MANIFEST_DIR}/prebuilt --cfg 'a=$$b' --cfg $c\n\
+         //@rustc-env: DIR=\"${CARGO_MANIFEST_DIR}\"\n\
+         //@aux-build: ${CARGO_MANIFEST_DIR}/aux.rs\n\
+         //@stdin: ${CARGO_MANIFEST_DIR}/input.txt\n",
+    )
+    .unwrap();
+    let revisioned = &comments.revisioned[&vec![]];
+    assert_eq!(
+        revisioned.compile_flags,
+        [
+            "-L".to_string(),
+            format!("{dir}/prebuilt"),
+            "--cfg".into(),
+            "a=$b".into(),
+            "--cfg".into(),
+            "$c".into()
+        ]
+    );
+    assert_eq!(revisioned.env_vars, [("DIR".to_string(), dir.to_string())]);
+    assert_eq!(
+        revisioned.aux_builds[0].0,
+        PathBuf::from(format!("{dir}/aux.rs"))
+    );
+    assert_eq!(
+        revisioned.stdin.as_ref().unwrap().0,
+        PathBuf::from(format!("{dir}/input.txt"))
+    );
+
+    for (comment, expected) in [
+        (
+            "//@compile-flags: -L ${UI_TEST_SURELY_NOT_SET}",
+            "`UI_TEST_SURELY_NOT_SET` in `-L ${UI_TEST_SURELY_NOT_SET}` is not set",
+        ),
+        ("//@stdin: ${CARGO_MANIFEST_DIR", "without a matching `}`"),
+    ] {
+        let errors = parse(&format!("fn main() {{}}\n{comment}\n")).unwrap_err();
+        assert!(
+            matches!(&errors[..], [Error::InvalidComment { msg, line: 2 }] if msg.contains(expected)),
+            "{errors:#?}"
+        );
+    }
+
+    // Disabled by default.
+    let comments = Comments::parse(
+        "//@compile-flags: --cfg ${UI_TEST_SURELY_NOT_SET} --cfg $$\n",
+        "//",
+    )
+    .unwrap();
+    assert_eq!(
+        comments.revisioned[&vec![]].compile_flags,
+        ["--cfg", "${UI_TEST_SURELY_NOT_SET}", "--cfg", "$$"]
+    );
+}
+
+#[test]
+fn parse_working_dir() {
+    let comments = Comments::parse("//@working-dir: ../data\n", "//").unwrap();
+    assert_eq!(
+        comments.revisioned[&vec![]].working_dir,
+        Some((PathBuf::from("../data"), 1))
+    );
+
+    for comment in ["//@working-dir:", "//@working-dir: /tmp"] {
+        let errors = Comments::parse(&format!("fn main() {{}}\n{comment}\n"), "//").unwrap_err();
+        assert!(
+            matches!(&errors[..], [Error::InvalidComment { msg, line: 2 }] if msg.contains("relative to the directory of the test")),
+            "{errors:#?}"
+        );
+    }
 }
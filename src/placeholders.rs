@@ -0,0 +1,140 @@
+//! `[..]` and named placeholders like `[ROOT]` in expected output files.
+
+use bstr::ByteSlice;
+use diff::{slice, Result::*};
+use regex::bytes::Regex;
+
+use crate::Match;
+
+/// A line of the expected or the actual output.
+enum Line<'a> {
+    /// A line of the expected output, and the regex it stands for if it contains placeholders.
+    Expected(&'a [u8], Option<Regex>),
+    Actual(&'a [u8]),
+}
+
+impl<'a> Line<'a> {
+    fn text(&self) -> &'a [u8] {
+        match *self {
+            Line::Expected(text, _) | Line::Actual(text) => text,
+        }
+    }
+}
+
+impl PartialEq for Line<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Line::Expected(_, Some(regex)), Line::Actual(actual))
+            | (Line::Actual(actual), Line::Expected(_, Some(regex))) => regex.is_match(actual),
+            _ => self.text() == other.text(),
+        }
+    }
+}
+
+/// The regex a line of the expected output stands for, if it contains placeholders.
+fn line_regex(line: &[u8], placeholders: &[(String, Match)]) -> Option<Regex> {
+    let line = line.to_str().ok()?;
+    let mut regex = String::from("^");
+    let mut found = false;
+    let mut rest = line;
+    'search: while let Some(start) = rest.find('[') {
+        let (before, bracket) = rest.split_at(start);
+        if let Some(after) = bracket.strip_prefix("[..]") {
+            regex.push_str(&regex::escape(before));
+            // Any substring, including non-UTF-8 bytes.
+            regex.push_str("(?-u:.)*?");
+            rest = after;
+            found = true;
+            continue;
+        }
+        for (name, pattern) in placeholders {
+            if let Some(after) = bracket
+                .strip_prefix('[')
+                .and_then(|bracket| bracket.strip_prefix(name.as_str()))
+                .and_then(|bracket| bracket.strip_prefix(']'))
+            {
+                regex.push_str(&regex::escape(before));
+                match pattern {
+                    Match::Regex(pattern) => {
+                        regex.push_str("(?:");
+                        regex.push_str(pattern.as_str());
+                        regex.push(')');
+                    }
+                    Match::Exact(text) => regex.push_str(&regex::escape(&text.to_str_lossy())),
+                }
+                rest = after;
+                found = true;
+                continue 'search;
+            }
+        }
+        // Not a placeholder, like the `[E0308]` in `error[E0308]`.
+        regex.push_str(&regex::escape(&rest[..=start]));
+        rest = &rest[start + 1..];
+    }
+    if !found {
+        return None;
+    }
+    regex.push_str(&regex::escape(rest));
+    regex.push('$');
+    Regex::new(&regex).ok()
+}
+
+/// Pair up the lines of the actual output with the lines of the expected output that match them,
+/// or `None` if the expected output contains no placeholders.
+fn align<'a>(
+    actual: &'a [u8],
+    expected: &'a [u8],
+    placeholders: &[(String, Match)],
+) -> Option<Vec<diff::Result<&'a [u8]>>> {
+    let expected: Vec<_> = expected
+        .split_str("\n")
+        .map(|line| Line::Expected(line, line_regex(line, placeholders)))
+        .collect();
+    if expected
+        .iter()
+        .all(|line| matches!(line, Line::Expected(_, None)))
+    {
+        return None;
+    }
+    let actual: Vec<_> = actual.split_str("\n").map(Line::Actual).collect();
+    Some(
+        slice(&expected, &actual)
+            .into_iter()
+            .map(|row| match row {
+                Left(l) => Left(l.text()),
+                Both(l, r) => Both(l.text(), r.text()),
+                Right(r) => Right(r.text()),
+            })
+            .collect(),
+    )
+}
+
+/// Whether the actual output matches the expected output, in which `[..]` matches any part of a
+/// line and `[NAME]` matches the pattern of the placeholder called `NAME`.
+pub(crate) fn matches(actual: &[u8], expected: &[u8], placeholders: &[(String, Match)]) -> bool {
+    if actual == expected {
+        return true;
+    }
+    match align(actual, expected, placeholders) {
+        Some(rows) => rows.iter().all(|row| matches!(row, Both(..))),
+        None => false,
+    }
+}
+
+/// The actual output to bless, with the lines that match a line of the expected output that
+/// contains placeholders replaced by that line.
+pub(crate) fn bless(actual: &[u8], expected: &[u8], placeholders: &[(String, Match)]) -> Vec<u8> {
+    let rows = match align(actual, expected, placeholders) {
+        Some(rows) => rows,
+        None => return actual.to_vec(),
+    };
+    let lines: Vec<&[u8]> = rows
+        .into_iter()
+        .filter_map(|row| match row {
+            Both(expected, _) => Some(expected),
+            Right(actual) => Some(actual),
+            Left(_) => None,
+        })
+        .collect();
+    lines.join(&b'\n')
+}
@@ -3,64 +3,230 @@ use std::path::{Path, PathBuf};
 use bstr::ByteSlice;
 use regex::Regex;
 
+use crate::strip_verbatim;
+
 #[derive(serde::Deserialize, Debug)]
 struct RustcMessage {
     rendered: Option<String>,
     spans: Vec<Span>,
     level: String,
     message: String,
+    code: Option<RustcCode>,
     children: Vec<RustcMessage>,
 }
 
+/// A line of cargo's `--message-format=json` output.
+#[derive(serde::Deserialize, Debug)]
+struct CargoMessage {
+    /// The kind of message, only `compiler-message` contains a diagnostic.
+    reason: String,
+    /// The diagnostic as rustc emitted it.
+    message: Option<serde_json::Value>,
+}
+
+/// The error code or lint name of a diagnostic.
+#[derive(serde::Deserialize, Debug)]
+struct RustcCode {
+    code: String,
+}
+
 #[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq)]
-pub(crate) enum Level {
+/// The severity of a diagnostic.
+pub enum Level {
+    /// An internal compiler error.
     Ice = 5,
+    /// An error.
     Error = 4,
+    /// A warning.
     Warn = 3,
+    /// A help message, usually a child of another diagnostic.
     Help = 2,
+    /// A note, usually a child of another diagnostic.
     Note = 1,
     /// Only used for "For more information about this error, try `rustc --explain EXXXX`".
     FailureNote = 0,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 /// A diagnostic message.
 pub struct Message {
-    pub(crate) level: Level,
-    pub(crate) message: String,
+    /// The severity of the message.
+    pub level: Level,
+    /// The text of the message.
+    pub message: String,
+    /// The file and line of the primary span of the message, if it had any.
+    pub location: Option<Location>,
+    /// The error code (`E0308`) or lint name (`clippy::needless_clone`) of the message.
+    pub code: Option<String>,
+    /// The column of the message on its line in the test file, if it has one.
+    pub column: Option<usize>,
+    /// The labels of the secondary spans of the message in the test file.
+    pub labels: Vec<SpanLabel>,
+    /// The macro expansions the primary span of the message is in, innermost first.
+    pub expansions: Vec<MacroExpansion>,
+}
+
+/// A macro invocation that a diagnostic was emitted from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MacroExpansion {
+    /// The name of the macro, like `foo!` or `#[derive(Debug)]`.
+    pub macro_name: String,
+    /// Where the macro was invoked.
+    pub location: Location,
+}
+
+/// A label of a secondary span of a diagnostic, like "borrow later used here".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpanLabel {
+    /// The line of the span in the test file.
+    pub line: usize,
+    /// The column the span starts at.
+    pub column: usize,
+    /// The text of the label.
+    pub label: String,
+}
+
+/// A position in a source file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    /// The file as reported by the compiler.
+    pub file: PathBuf,
+    /// The line within `file`.
+    pub line: usize,
 }
 
 /// Information about macro expansion.
 #[derive(serde::Deserialize, Debug)]
 struct Expansion {
+    /// The call site of the macro.
     span: Span,
+    macro_decl_name: String,
 }
 
 #[derive(serde::Deserialize, Debug)]
 struct Span {
     line_start: usize,
+    column_start: usize,
     file_name: PathBuf,
     is_primary: bool,
+    label: Option<String>,
     expansion: Option<Box<Expansion>>,
 }
 
+/// Put the message into the line-indexed list, or the list of messages without a line.
+fn insert(
+    msg: Message,
+    position: Option<(usize, usize)>,
+    messages: &mut Vec<Vec<Message>>,
+    messages_from_unknown_file_or_line: &mut Vec<Message>,
+) {
+    if let Some((line, _)) = position {
+        if messages.len() <= line {
+            messages.resize_with(line + 1, Vec::new);
+        }
+        messages[line].push(msg);
+    // All other messages go into the general bin, unless they are specifically of the
+    // "aborting due to X previous errors" or "X warnings emitted" variety, as we never want
+    // to match those. They only count the number of diagnostics and provide no useful
+    // information about the tests.
+    } else if !(msg.message.starts_with("aborting due to")
+        && msg.message.contains("previous error"))
+        && !(msg.level == Level::Warn
+            && (msg.message.ends_with("warning emitted")
+                || msg.message.ends_with("warnings emitted")))
+    {
+        messages_from_unknown_file_or_line.push(msg);
+    }
+}
+
+impl Span {
+    fn location(&self) -> Location {
+        Location {
+            file: self.file_name.clone(),
+            line: self.line_start,
+        }
+    }
+}
+
 impl std::str::FromStr for Level {
     type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "ERROR" | "error" => Ok(Self::Error),
-            "WARN" | "warning" => Ok(Self::Warn),
-            "HELP" | "help" => Ok(Self::Help),
-            "NOTE" | "note" => Ok(Self::Note),
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(Self::Error),
+            "warn" | "warning" => Ok(Self::Warn),
+            "help" => Ok(Self::Help),
+            "note" => Ok(Self::Note),
             "failure-note" => Ok(Self::FailureNote),
-            "error: internal compiler error" => Ok(Self::Ice),
-            _ => Err(format!("unknown level `{s}`")),
+            "ice" | "error: internal compiler error" => Ok(Self::Ice),
+            _ => Err(format!(
+                "unknown level `{s}`, expected one of `ERROR`, `WARN`, `WARNING`, `HELP`, `NOTE` or `ICE` (in any case)"
+            )),
+        }
+    }
+}
+
+/// Turns the output of the program under test into diagnostics that `//~` annotations
+/// can be checked against.
+pub trait DiagnosticsParser: std::fmt::Debug + Send + Sync {
+    /// Extract the diagnostics of the program's output. Only diagnostics in `file` end up
+    /// in [`Diagnostics::messages`].
+    fn parse(&self, file: &Path, stderr: &[u8]) -> Diagnostics;
+}
+
+/// The default [`DiagnosticsParser`], for rustc's `--error-format=json` output and the
+/// diagnostics in cargo's `--message-format=json` output.
+#[derive(Debug, Clone, Copy)]
+pub struct RustcDiagnostics {
+    /// Use [`HumanDiagnostics`] if the output contains no json at all. Disabled by default.
+    pub human_fallback: bool,
+    /// Put diagnostics from within macro expansions on the line of the outermost macro
+    /// invocation in the test file. If disabled, diagnostics whose span is in the test file
+    /// itself (e.g. in a `macro_rules!` defined there) stay on that line, and only the ones
+    /// from other files are put on the line of the invocation. Enabled by default.
+    pub expansion_call_site: bool,
+    /// Put messages whose primary span is not in the test file on the line of their first
+    /// secondary span that is. If disabled, such messages are not on any line. Enabled by default.
+    pub secondary_span_fallback: bool,
+}
+
+impl Default for RustcDiagnostics {
+    fn default() -> Self {
+        Self {
+            human_fallback: false,
+            expansion_call_site: true,
+            secondary_span_fallback: true,
         }
     }
 }
 
-#[derive(Debug)]
-pub(crate) struct Diagnostics {
+impl DiagnosticsParser for RustcDiagnostics {
+    fn parse(&self, file: &Path, stderr: &[u8]) -> Diagnostics {
+        if self.human_fallback && !stderr.lines().any(|line| line.starts_with_str(b"{")) {
+            process_human(file, stderr)
+        } else {
+            process(file, stderr, self)
+        }
+    }
+}
+
+/// A best-effort [`DiagnosticsParser`] for rustc's human readable output.
+///
+/// This is lossy: only diagnostics of the shape `error[E0308]: message` followed by a
+/// `--> file:line:col` line are found, the lint names of warnings are not known, and
+/// notes and helps are only found if they have a span or start with `= note:`/`= help:`.
+/// Lines that don't look like a diagnostic are kept in the rendered output as they are.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HumanDiagnostics;
+
+impl DiagnosticsParser for HumanDiagnostics {
+    fn parse(&self, file: &Path, stderr: &[u8]) -> Diagnostics {
+        process_human(file, stderr)
+    }
+}
+
+#[derive(Debug, Default)]
+/// The diagnostics emitted by a single run of the program under test.
+pub struct Diagnostics {
     /// Rendered and concatenated version of all diagnostics.
     /// This is equivalent to non-json diagnostics.
     pub rendered: Vec<u8>,
@@ -70,53 +236,145 @@ pub(crate) struct Diagnostics {
     pub messages_from_unknown_file_or_line: Vec<Message>,
 }
 
+/// The file whose diagnostics are extracted from the output, see [`TestFile::is`].
+struct TestFile<'a> {
+    path: &'a Path,
+    normalized: String,
+    /// Resolved once per output instead of once per span, as it hits the file system.
+    canonical: Option<PathBuf>,
+}
+
+impl<'a> TestFile<'a> {
+    fn new(path: &'a Path) -> Self {
+        Self {
+            path,
+            normalized: normalize_path(path),
+            canonical: path.canonicalize().ok(),
+        }
+    }
+
+    /// Whether a path reported by the compiler refers to the test file.
+    /// Paths are compared after normalizing away `\\?\` prefixes, backslashes and leading `./`,
+    /// and if that doesn't make them equal, after resolving symlinks.
+    fn is(&self, reported: &Path) -> bool {
+        if reported == self.path || normalize_path(reported) == self.normalized {
+            return true;
+        }
+        match (&self.canonical, reported.canonicalize()) {
+            (Some(canonical), Ok(reported)) => reported == *canonical,
+            _ => false,
+        }
+    }
+}
+
+fn normalize_path(path: &Path) -> String {
+    let path = strip_verbatim(path).display().to_string();
+    let path = path
+        .strip_prefix(r"\\?\")
+        .unwrap_or(&path)
+        .replace('\\', "/");
+    path.strip_prefix("./").unwrap_or(&path).to_owned()
+}
+
 impl RustcMessage {
-    fn line(&self, file: &Path) -> Option<usize> {
-        let span = |primary| self.spans.iter().find_map(|span| span.line(file, primary));
-        span(true).or_else(|| span(false))
+    /// The line and column of the message in the given file. Messages are on the line
+    /// of their primary span if it is in the file, otherwise on the line of their first
+    /// secondary span in the file, if enabled. Line 0 is not a line of the file.
+    fn position(&self, file: &TestFile<'_>, options: &RustcDiagnostics) -> Option<(usize, usize)> {
+        let span = |primary| {
+            self.spans
+                .iter()
+                .find_map(|span| span.position(file, primary, options.expansion_call_site))
+        };
+        span(true).or_else(|| {
+            if options.secondary_span_fallback {
+                span(false)
+            } else {
+                None
+            }
+        })
     }
 
     /// Put the message and its children into the line-indexed list.
     fn insert_recursive(
         self,
-        file: &Path,
+        file: &TestFile<'_>,
         messages: &mut Vec<Vec<Message>>,
         messages_from_unknown_file_or_line: &mut Vec<Message>,
-        line: Option<usize>,
+        options: &RustcDiagnostics,
+        position: Option<(usize, usize)>,
+        location: Option<Location>,
     ) {
-        let line = self.line(file).or(line);
+        let position = self.position(file, options).or(position);
+        let primary = self.spans.iter().find(|span| span.is_primary);
+        let location = primary.map(Span::location).or(location);
+        let mut expansions = vec![];
+        let mut expansion = primary.and_then(|span| span.expansion.as_deref());
+        while let Some(exp) = expansion {
+            expansions.push(MacroExpansion {
+                macro_name: exp.macro_decl_name.clone(),
+                location: exp.span.location(),
+            });
+            expansion = exp.span.expansion.as_deref();
+        }
         let msg = Message {
             level: self.level.parse().unwrap(),
             message: self.message,
+            location: location.clone(),
+            code: self.code.map(|code| code.code),
+            column: position.map(|(_, column)| column),
+            labels: self
+                .spans
+                .iter()
+                .filter(|span| !span.is_primary && file.is(&span.file_name))
+                .filter_map(|span| {
+                    Some(SpanLabel {
+                        line: span.line_start,
+                        column: span.column_start,
+                        label: span.label.clone()?,
+                    })
+                })
+                .collect(),
+            expansions,
         };
-        if let Some(line) = line {
-            if messages.len() <= line {
-                messages.resize_with(line + 1, Vec::new);
-            }
-            messages[line].push(msg);
-        // All other messages go into the general bin, unless they are specifically of the
-        // "aborting due to X previous errors" variety, as we never want to match those. They
-        // only count the number of errors and provide no useful information about the tests.
-        } else if !(msg.message.starts_with("aborting due to")
-            && msg.message.contains("previous error"))
-        {
-            messages_from_unknown_file_or_line.push(msg);
-        }
+        insert(msg, position, messages, messages_from_unknown_file_or_line);
         for child in self.children {
-            child.insert_recursive(file, messages, messages_from_unknown_file_or_line, line)
+            child.insert_recursive(
+                file,
+                messages,
+                messages_from_unknown_file_or_line,
+                options,
+                position,
+                location.clone(),
+            )
         }
     }
 }
 
 impl Span {
-    /// Returns the most expanded line number *in the given file*, if possible.
-    fn line(&self, file: &Path, primary: bool) -> Option<usize> {
+    /// Returns the most expanded line and column number *in the given file*, if possible.
+    /// Without `call_site`, the span itself is preferred over its expansions if it is in the file.
+    fn position(
+        &self,
+        file: &TestFile<'_>,
+        primary: bool,
+        call_site: bool,
+    ) -> Option<(usize, usize)> {
+        let own =
+            ((!primary || self.is_primary) && self.line_start > 0 && file.is(&self.file_name))
+                .then_some((self.line_start, self.column_start));
+        if !call_site && own.is_some() {
+            return own;
+        }
         if let Some(exp) = &self.expansion {
-            if let Some(line) = exp.span.line(file, primary && !self.is_primary) {
-                return Some(line);
+            if let Some(position) = exp
+                .span
+                .position(file, primary && !self.is_primary, call_site)
+            {
+                return Some(position);
             }
         }
-        ((!primary || self.is_primary) && self.file_name == file).then_some(self.line_start)
+        own
     }
 }
 
@@ -125,24 +383,91 @@ pub(crate) fn filter_annotations_from_rendered(rendered: &str) -> std::borrow::C
     annotations.replace_all(rendered, "")
 }
 
-pub(crate) fn process(file: &Path, stderr: &[u8]) -> Diagnostics {
+/// Find out whether the compiler crashed, and if so, extract the panic message and the
+/// query stack from its output. Only the banner the compiler prints when it crashes counts,
+/// so that an ordinary panic (of the test's own program, for example) is not mistaken for
+/// an internal compiler error.
+pub(crate) fn internal_compiler_error(stderr: &[u8]) -> Option<(String, Vec<String>)> {
+    let mut crashed = false;
+    let mut message = None;
+    let mut panic_message = None;
+    let mut query_stack = vec![];
+    let mut lines = stderr.lines().map(|line| line.to_str_lossy());
+    while let Some(line) = lines.next() {
+        let line = line.trim_end();
+        if line.starts_with('{') {
+            if let Ok(msg) = serde_json::from_str::<RustcMessage>(line) {
+                if msg.level == "error: internal compiler error" {
+                    crashed = true;
+                    message.get_or_insert(msg.message);
+                }
+            }
+        } else if line == "error: internal compiler error: unexpected panic"
+            || line.starts_with("error: the compiler unexpectedly panicked")
+        {
+            // The banner of a panicking compiler, the message is in the panic itself.
+            crashed = true;
+        } else if let Some(msg) = line.strip_prefix("error: internal compiler error: ") {
+            crashed = true;
+            message.get_or_insert_with(|| msg.to_string());
+        } else if line.starts_with("thread 'rustc' panicked at") {
+            // Newer compilers put the panic message on the next line.
+            let msg = match line.strip_suffix(':') {
+                Some(_) => lines.next().map(|msg| msg.into_owned()).unwrap_or_default(),
+                None => line.to_string(),
+            };
+            panic_message.get_or_insert(msg);
+        } else if line.starts_with("error: rustc interrupted by SIG") {
+            crashed = true;
+            message.get_or_insert_with(|| line["error: ".len()..].to_string());
+        } else if line == "query stack during panic:" {
+            query_stack.extend(
+                lines
+                    .by_ref()
+                    .take_while(|line| line.trim_end() != "end of query stack")
+                    .map(|line| line.trim_end().to_string()),
+            );
+        }
+    }
+    if !crashed {
+        return None;
+    }
+    let message = message.or(panic_message).unwrap_or_default();
+    Some((message, query_stack))
+}
+
+/// Split the json messages of cargo's `--message-format=json` off the rest of its stdout.
+/// Returns the remaining stdout and the diagnostics unwrapped from cargo's messages, which can
+/// be passed to [`process`]. All other messages of cargo are dropped, as they contain absolute
+/// paths and don't tell us anything about the test.
+pub(crate) fn split_cargo_messages(stdout: &[u8]) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let mut rest = vec![];
+    let mut diagnostics = vec![];
+    for (line_number, line) in stdout.lines_with_terminator().enumerate() {
+        if !line.starts_with_str(br#"{"reason":"#) {
+            rest.extend(line);
+            continue;
+        }
+        let CargoMessage { reason, message } = serde_json::from_slice(line).map_err(|err| {
+            format!("failed to parse cargo JSON output at line {line_number}: {err}")
+        })?;
+        if let Some(message) = message.filter(|_| reason == "compiler-message") {
+            diagnostics.extend(message.to_string().as_bytes());
+            diagnostics.push(b'\n');
+        }
+    }
+    Ok((rest, diagnostics))
+}
+
+pub(crate) fn process(file: &Path, stderr: &[u8], options: &RustcDiagnostics) -> Diagnostics {
+    let file = TestFile::new(file);
     let mut rendered = Vec::new();
     let mut messages = vec![];
     let mut messages_from_unknown_file_or_line = vec![];
     for (line_number, line) in stderr.lines_with_terminator().enumerate() {
-        if line.starts_with_str(b"{") {
+        let msg = if line.starts_with_str(b"{") {
             match serde_json::from_slice::<RustcMessage>(line) {
-                Ok(msg) => {
-                    rendered.extend(
-                        filter_annotations_from_rendered(msg.rendered.as_ref().unwrap()).as_bytes(),
-                    );
-                    msg.insert_recursive(
-                        file,
-                        &mut messages,
-                        &mut messages_from_unknown_file_or_line,
-                        None,
-                    );
-                }
+                Ok(msg) => Some(msg),
                 Err(err) => {
                     panic!("failed to parse rustc JSON output at line {line_number}: {err}")
                 }
@@ -150,8 +475,130 @@ pub(crate) fn process(file: &Path, stderr: &[u8]) -> Diagnostics {
         } else {
             // FIXME: do we want to throw interpreter stderr into a separate file?
             rendered.extend(line);
+            None
+        };
+        if let Some(msg) = msg {
+            rendered.extend(
+                filter_annotations_from_rendered(msg.rendered.as_ref().unwrap()).as_bytes(),
+            );
+            msg.insert_recursive(
+                &file,
+                &mut messages,
+                &mut messages_from_unknown_file_or_line,
+                options,
+                None,
+                None,
+            );
+        }
+    }
+    Diagnostics {
+        rendered,
+        messages,
+        messages_from_unknown_file_or_line,
+    }
+}
+
+lazy_static::lazy_static! {
+    /// The first line of a diagnostic in rustc's human readable output, like `error[E0308]: ...`.
+    static ref HUMAN_HEADER: Regex = Regex::new(
+        r"^(error: internal compiler error|error|warning|help|note)(?:\[([^\]]+)\])?: (.*)$",
+    )
+    .unwrap();
+    /// The location of a span, like `  --> src/main.rs:2:5`.
+    static ref HUMAN_SPAN: Regex = Regex::new(r"^ *--> (.+):([0-9]+):([0-9]+)$").unwrap();
+    /// A note or help without a span of its own, like `  = note: ...`.
+    static ref HUMAN_SUB: Regex = Regex::new(r"^ *= (help|note): (.*)$").unwrap();
+}
+
+/// Extract the diagnostics from rustc's human readable output, see [`HumanDiagnostics`].
+pub(crate) fn process_human(file: &Path, stderr: &[u8]) -> Diagnostics {
+    let file = TestFile::new(file);
+    let mut rendered = Vec::new();
+    let mut messages = vec![];
+    let mut messages_from_unknown_file_or_line = vec![];
+    // The messages of the current diagnostic with their position, the first one is the main message.
+    let mut current: Vec<(Message, Option<(usize, usize)>)> = vec![];
+    let mut flush = |current: &mut Vec<(Message, Option<(usize, usize)>)>| {
+        let Some((parent, parent_position)) = current.first() else {
+            return;
+        };
+        let (parent_location, parent_position) = (parent.location.clone(), *parent_position);
+        for (mut msg, mut position) in current.drain(..) {
+            // Children without a span of their own are reported at their parent.
+            if msg.location.is_none() {
+                msg.location = parent_location.clone();
+                msg.column = parent_position.map(|(_, column)| column);
+                position = parent_position;
+            }
+            insert(
+                msg,
+                position,
+                &mut messages,
+                &mut messages_from_unknown_file_or_line,
+            );
+        }
+    };
+    for line in stderr.lines_with_terminator() {
+        let Ok(text) = line.to_str() else {
+            rendered.extend(line);
+            continue;
+        };
+        rendered.extend(filter_annotations_from_rendered(text).as_bytes());
+        let text = text.trim_end();
+        if let Some(captures) = HUMAN_HEADER.captures(text) {
+            let level: Level = captures[1].parse().unwrap();
+            // Helps and notes without an indentation belong to the previous diagnostic.
+            if !matches!(level, Level::Help | Level::Note) {
+                flush(&mut current);
+            }
+            current.push((
+                Message {
+                    level,
+                    message: captures[3].to_string(),
+                    location: None,
+                    code: captures.get(2).map(|code| code.as_str().to_string()),
+                    column: None,
+                    labels: vec![],
+                    expansions: vec![],
+                },
+                None,
+            ));
+        } else if let Some(captures) = HUMAN_SPAN.captures(text) {
+            let (Ok(line), Ok(column)) = (captures[2].parse(), captures[3].parse()) else {
+                continue;
+            };
+            // Only the first span after a header is its primary span.
+            if let Some((msg, position)) =
+                current.last_mut().filter(|(msg, _)| msg.location.is_none())
+            {
+                let location = Location {
+                    file: PathBuf::from(&captures[1]),
+                    line,
+                };
+                if line > 0 && file.is(&location.file) {
+                    *position = Some((line, column));
+                    msg.column = Some(column);
+                }
+                msg.location = Some(location);
+            }
+        } else if let Some(captures) = HUMAN_SUB.captures(text) {
+            if !current.is_empty() {
+                current.push((
+                    Message {
+                        level: captures[1].parse().unwrap(),
+                        message: captures[2].to_string(),
+                        location: None,
+                        code: None,
+                        column: None,
+                        labels: vec![],
+                        expansions: vec![],
+                    },
+                    None,
+                ));
+            }
         }
     }
+    flush(&mut current);
     Diagnostics {
         rendered,
         messages,
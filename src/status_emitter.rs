@@ -1,14 +1,23 @@
 //! Variaous schemes for reporting messages during testing or after testing is done.
 
-use bstr::ByteSlice;
 use colored::Colorize;
 
-use crate::{github_actions, parser::Pattern, rustc_stderr::Message, Error, Errors, TestResult};
+use crate::{
+    cmd::display_command,
+    diff::{escape_bytes, render_diff},
+    github_actions,
+    parser::Pattern,
+    rustc_stderr::{Location, Message},
+    strip_trailing_whitespace, Error, Errors, TestResult, Timings,
+};
 use std::{
+    collections::{HashMap, HashSet},
     fmt::{Debug, Write as _},
     io::Write as _,
-    path::Path,
+    path::{Path, PathBuf},
     process::Command,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 /// A generic way to handle the output of this crate.
@@ -26,6 +35,50 @@ pub trait StatusEmitter: Sync {
     /// A test has finished, handle the result immediately.
     fn test_result(&mut self, _path: &Path, _revision: &str, _result: &TestResult) {}
 
+    /// Invoked right before [`StatusEmitter::test_result`] with how long the test took to run,
+    /// including all of its attempts.
+    fn test_timings(&mut self, _path: &Path, _revision: &str, _timings: &Timings) {}
+
+    /// Invoked after [`StatusEmitter::failed_test`] with the files in
+    /// [`Config::failure_artifacts_dir`](crate::Config::failure_artifacts_dir) that the failed test saved.
+    fn failure_artifacts(&self, _path: &Path, _revision: &str, _artifacts: &[PathBuf]) {}
+
+    /// Blessing removed output files of revisions or bitwidths that the test at `path` doesn't have anymore.
+    fn removed_outdated_files(&mut self, _path: &Path, _files: &[PathBuf]) {}
+
+    /// Only the tests of shard `index` of `count` shards ran, see
+    /// [`Config::shard`](crate::Config::shard). Invoked right before [`StatusEmitter::finalize`].
+    fn shard(&mut self, _index: usize, _count: usize, _assigned: usize) {}
+
+    /// How many tests were filtered out because they did not match any of the
+    /// [`Config::filters`](crate::Config::filters), and how many because they matched
+    /// [`Config::skip`](crate::Config::skip). Invoked right before [`StatusEmitter::finalize`].
+    fn excluded_tests(&mut self, _by_filters: usize, _by_skip: usize) {}
+
+    /// How many bytes of artifacts were removed from [`Config::out_dir`](crate::Config::out_dir)
+    /// because of the [`Config::cleanup`](crate::Config::cleanup) policy. Only invoked if that
+    /// isn't [`CleanupPolicy::Keep`](crate::CleanupPolicy::Keep), right before
+    /// [`StatusEmitter::finalize`].
+    fn reclaimed(&mut self, _bytes: u64) {}
+
+    /// The directory of each root of [`run_test_roots`](crate::run_test_roots) with how many of
+    /// its tests failed, succeeded, were ignored and were filtered out, in the order of
+    /// [`StatusEmitter::finalize`]. Only invoked if there are several roots, right before
+    /// [`StatusEmitter::finalize`].
+    fn test_roots(&mut self, _roots: &[(PathBuf, usize, usize, usize, usize)]) {}
+
+    /// The slowest tests with their paths and revisions, slowest first, and how long all tests
+    /// spent in each phase, see [`Config::slowest_tests`](crate::Config::slowest_tests).
+    /// Invoked right before [`StatusEmitter::finalize`].
+    fn slowest_tests(&mut self, _slowest: &[(PathBuf, String, Timings)], _total: &Timings) {}
+
+    /// The ignored tests grouped by the comment that caused them to be ignored, with their
+    /// paths, revisions and the lines of these comments. The most common reasons come first,
+    /// see [`Config::ignored_summary`](crate::Config::ignored_summary).
+    /// Invoked right before [`StatusEmitter::slowest_tests`].
+    #[allow(clippy::type_complexity)]
+    fn ignored_tests(&mut self, _ignored: &[(String, Vec<(PathBuf, String, usize)>)]) {}
+
     /// Create a report about the entire test run at the end.
     #[allow(clippy::type_complexity)]
     fn finalize(
@@ -41,6 +94,17 @@ pub trait StatusEmitter: Sync {
 pub trait Summary {
     /// A test has finished, handle the result.
     fn test_failure(&mut self, _path: &Path, _revision: &str, _errors: &Errors) {}
+
+    /// A test reported problems that did not cause it to fail. Invoked for all tests with
+    /// warnings before any of the other methods.
+    fn test_warnings(&mut self, _path: &Path, _revision: &str, _warnings: &Errors) {}
+
+    /// A test failed, but passed when it was retried. `attempts` is the attempt that passed.
+    fn test_flaky(&mut self, _path: &Path, _revision: &str, _attempts: usize) {}
+
+    /// Invoked after all failures if the output of more than one test differed, with a command
+    /// that blesses all of them, see [`OutputConflictHandling::Error`](crate::OutputConflictHandling::Error).
+    fn bless_command(&mut self, _command: &str) {}
 }
 
 impl Summary for () {}
@@ -66,7 +130,7 @@ impl StatusEmitter for Text {
         eprint!("{revision}");
         eprint!(" {}", "FAILED:".red().bold());
         eprintln!();
-        eprintln!("command: {cmd:?}");
+        eprintln!("command: {}", display_command(cmd));
         eprintln!();
 
         #[derive(Debug)]
@@ -82,11 +146,21 @@ impl StatusEmitter for Text {
         Box::new(Guard(stderr))
     }
 
+    fn failure_artifacts(&self, _path: &Path, _revision: &str, artifacts: &[PathBuf]) {
+        eprintln!("actual output saved to:");
+        for artifact in artifacts {
+            eprintln!("    {}", artifact.display());
+        }
+        eprintln!();
+    }
+
     fn test_result(&mut self, path: &Path, revision: &str, result: &TestResult) {
         let result = match result {
             TestResult::Ok => "ok".green(),
+            TestResult::Flaky { attempts } => format!("ok (passed on attempt {attempts})").yellow(),
+            TestResult::Cached => "ok (cached)".green(),
             TestResult::Errored { .. } => "FAILED".red().bold(),
-            TestResult::Ignored => "ignored (in-test comment)".yellow(),
+            TestResult::Ignored { .. } => "ignored (in-test comment)".yellow(),
             TestResult::Filtered => return,
         };
         eprint!(
@@ -101,56 +175,192 @@ impl StatusEmitter for Text {
         eprintln!("{result}");
     }
 
+    fn removed_outdated_files(&mut self, _path: &Path, files: &[PathBuf]) {
+        for file in files {
+            eprintln!("    removed outdated {}", file.display());
+        }
+    }
+
+    fn shard(&mut self, index: usize, count: usize, assigned: usize) {
+        eprintln!();
+        eprintln!("ran shard {index} of {count}, {assigned} tests were assigned to it");
+    }
+
+    fn excluded_tests(&mut self, by_filters: usize, by_skip: usize) {
+        eprintln!();
+        eprintln!("{by_filters} tests did not match `--filter`, {by_skip} tests matched `--skip`");
+    }
+
+    fn reclaimed(&mut self, bytes: u64) {
+        eprintln!();
+        eprintln!("removed {} of test artifacts", format_bytes(bytes));
+    }
+
+    fn test_roots(&mut self, roots: &[(PathBuf, usize, usize, usize, usize)]) {
+        eprintln!();
+        eprintln!("{}", "results per test root:".underline().bold());
+        for (dir, failed, succeeded, ignored, filtered) in roots {
+            let result = if *failed == 0 {
+                "ok".green()
+            } else {
+                "FAILED".red()
+            };
+            eprintln!(
+                "    {}: {result}. {succeeded} passed; {failed} failed; {ignored} ignored; {filtered} filtered out",
+                dir.display()
+            );
+        }
+    }
+
+    fn slowest_tests(&mut self, slowest: &[(PathBuf, String, Timings)], total: &Timings) {
+        let phases = |timings: &Timings| {
+            timings
+                .phases()
+                .iter()
+                .map(|(phase, time)| format!("{:.2}s {phase}", time.as_secs_f64()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        eprintln!();
+        eprintln!("{}", "slowest tests:".underline().bold());
+        for (path, revision, timings) in slowest {
+            let revision = if revision.is_empty() {
+                String::new()
+            } else {
+                format!(" (revision {revision})")
+            };
+            eprintln!(
+                "    {:>7.2}s {}{revision}: {}",
+                timings.total.as_secs_f64(),
+                path.display(),
+                phases(timings)
+            );
+        }
+        eprintln!("time per phase: {}", phases(total));
+    }
+
+    fn ignored_tests(&mut self, ignored: &[(String, Vec<(PathBuf, String, usize)>)]) {
+        eprintln!();
+        eprintln!("{}", "ignored tests:".underline().bold());
+        for (reason, tests) in ignored {
+            eprintln!("    {:>5} {reason}", tests.len().to_string().yellow());
+            for (path, revision, line) in tests {
+                let revision = if revision.is_empty() {
+                    String::new()
+                } else {
+                    format!(" (revision {revision})")
+                };
+                eprintln!("          {}:{line}{revision}", path.display());
+            }
+        }
+    }
+
     fn finalize(
         &self,
-        failures: usize,
+        _failures: usize,
         succeeded: usize,
         ignored: usize,
         filtered: usize,
     ) -> Box<dyn Summary> {
-        // Print all errors in a single thread to show reliable output
-        if failures == 0 {
-            eprintln!();
-            eprintln!(
-                "test result: {}. {} tests passed, {} ignored, {} filtered out",
-                "ok".green(),
-                succeeded.to_string().green(),
-                ignored.to_string().yellow(),
-                filtered.to_string().yellow(),
-            );
-            eprintln!();
-            Box::new(())
-        } else {
-            struct Summarizer {
-                failures: Vec<String>,
-                succeeded: usize,
-                ignored: usize,
-                filtered: usize,
-            }
+        struct Summarizer {
+            failures: Vec<String>,
+            flaky: Vec<String>,
+            bless_command: Option<String>,
+            succeeded: usize,
+            ignored: usize,
+            filtered: usize,
+            warnings: usize,
+        }
 
-            impl Summary for Summarizer {
-                fn test_failure(&mut self, path: &Path, revision: &str, errors: &Errors) {
-                    for error in errors {
-                        print_error(error, &path.display().to_string());
-                    }
+        impl Summary for Summarizer {
+            fn test_failure(&mut self, path: &Path, revision: &str, errors: &Errors) {
+                for error in errors {
+                    print_error(error, &path.display().to_string());
+                }
+
+                self.failures.push(if revision.is_empty() {
+                    format!("    {}", path.display())
+                } else {
+                    format!("    {} (revision {revision})", path.display())
+                });
+            }
 
-                    self.failures.push(if revision.is_empty() {
-                        format!("    {}", path.display())
-                    } else {
-                        format!("    {} (revision {revision})", path.display())
-                    });
+            fn test_warnings(&mut self, path: &Path, revision: &str, warnings: &Errors) {
+                self.warnings += warnings.len();
+                eprintln!();
+                let revision = if revision.is_empty() {
+                    String::new()
+                } else {
+                    format!(" (revision `{revision}`)")
+                };
+                eprintln!(
+                    "{}{revision} {}",
+                    path.display().to_string().underline().bold(),
+                    "warnings:".yellow().bold()
+                );
+                for warning in warnings {
+                    print_error(warning, &path.display().to_string());
                 }
             }
 
-            impl Drop for Summarizer {
-                fn drop(&mut self) {
+            fn test_flaky(&mut self, path: &Path, revision: &str, attempts: usize) {
+                let revision = if revision.is_empty() {
+                    String::new()
+                } else {
+                    format!(" (revision {revision})")
+                };
+                self.flaky.push(format!(
+                    "    {}{revision} passed on attempt {attempts}",
+                    path.display()
+                ));
+            }
+
+            fn bless_command(&mut self, command: &str) {
+                self.bless_command = Some(command.to_owned());
+            }
+        }
+
+        impl Drop for Summarizer {
+            fn drop(&mut self) {
+                // Only mention warnings and flaky tests if there were any, to keep the summary short.
+                let mut extra = if self.warnings == 0 {
+                    String::new()
+                } else {
+                    format!(", {} warnings", self.warnings.to_string().yellow())
+                };
+                if !self.flaky.is_empty() {
+                    eprintln!();
+                    eprintln!("{}", "FLAKY:".yellow().underline().bold());
+                    for line in &self.flaky {
+                        eprintln!("{line}");
+                    }
+                    extra.push_str(&format!(
+                        ", {} passed on retry",
+                        self.flaky.len().to_string().yellow()
+                    ));
+                }
+                if self.failures.is_empty() {
+                    eprintln!();
+                    eprintln!(
+                        "test result: {}. {} tests passed, {} ignored, {} filtered out{extra}",
+                        "ok".green(),
+                        self.succeeded.to_string().green(),
+                        self.ignored.to_string().yellow(),
+                        self.filtered.to_string().yellow(),
+                    );
+                    eprintln!();
+                } else {
                     eprintln!("{}", "FAILURES:".red().underline().bold());
                     for line in &self.failures {
                         eprintln!("{line}");
                     }
+                    if let Some(command) = &self.bless_command {
+                        eprintln!();
+                        eprintln!("Execute `{command}` to update the output of all of these tests");
+                    }
                     eprintln!();
                     eprintln!(
-                        "test result: {}. {} tests failed, {} tests passed, {} ignored, {} filtered out",
+                        "test result: {}. {} tests failed, {} tests passed, {} ignored, {} filtered out{extra}",
                         "FAIL".red(),
                         self.failures.len().to_string().red().bold(),
                         self.succeeded.to_string().green(),
@@ -159,14 +369,66 @@ impl StatusEmitter for Text {
                     );
                 }
             }
-            Box::new(Summarizer {
-                failures: vec![],
-                succeeded,
-                ignored,
-                filtered,
-            })
         }
+        Box::new(Summarizer {
+            failures: vec![],
+            flaky: vec![],
+            bless_command: None,
+            succeeded,
+            ignored,
+            filtered,
+            warnings: 0,
+        })
+    }
+}
+
+const WHITESPACE_ONLY: &str =
+    "Only trailing whitespace differs, which `//@compare: lenient-whitespace` ignores.";
+
+/// Renders an amount of bytes in the largest binary unit it fills, like `1.5 MiB`.
+fn format_bytes(bytes: u64) -> String {
+    if bytes < 1024 {
+        return format!("{bytes} bytes");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    for unit in ["KiB", "MiB", "GiB"] {
+        if size < 1024.0 {
+            return format!("{size:.1} {unit}");
+        }
+        size /= 1024.0;
     }
+    format!("{size:.1} TiB")
+}
+
+/// Renders diagnostics as a comma separated list of their messages.
+fn format_messages(msgs: &[Message]) -> String {
+    msgs.iter()
+        .map(|msg| format!("`{}`", msg.message))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Renders the macro invocations a diagnostic was emitted from, innermost first.
+/// Invocations in `file` are only referred to by their line.
+fn expansion_trail<'a>(
+    msg: &'a Message,
+    file: Option<&'a Path>,
+) -> impl Iterator<Item = String> + 'a {
+    msg.expansions.iter().map(move |expansion| {
+        let Location {
+            file: exp_file,
+            line,
+        } = &expansion.location;
+        if Some(exp_file.as_path()) == file {
+            format!("in expansion of `{}` at line {line}", expansion.macro_name)
+        } else {
+            format!(
+                "in expansion of `{}` at {}:{line}",
+                expansion.macro_name,
+                exp_file.display()
+            )
+        }
+    })
 }
 
 fn print_error(error: &Error, path: &str) {
@@ -184,6 +446,8 @@ fn print_error(error: &Error, path: &str) {
         Error::PatternNotFound {
             pattern,
             definition_line,
+            line,
+            nearby,
         } => {
             match pattern {
                 Pattern::SubString(s) => {
@@ -192,15 +456,137 @@ fn print_error(error: &Error, path: &str) {
                 Pattern::Regex(r) => {
                     eprintln!("`/{r}/` does {} stderr output", "not match".red())
                 }
+                Pattern::Alternatives(alternatives) => {
+                    let alternatives = alternatives
+                        .iter()
+                        .map(|s| format!("`{s}`"))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    eprintln!(
+                        "none of the substrings {alternatives} {} in stderr output",
+                        "were found".red()
+                    )
+                }
+                Pattern::Code(code) => {
+                    eprintln!(
+                        "diagnostic code `{code}` {} in stderr output",
+                        "not found".red()
+                    )
+                }
+            }
+            eprintln!(
+                "expected because of pattern here: {}",
+                format!("{path}:{definition_line}").bold()
+            );
+            if let Some(line) = line {
+                for (found_line, msgs) in nearby {
+                    eprintln!(
+                        "expected `{pattern}` on line {line}, but line {found_line} had: {}",
+                        format_messages(msgs)
+                    );
+                }
+            }
+        }
+        Error::CodeNotFound {
+            pattern,
+            definition_line,
+            codes,
+        } => {
+            eprintln!(
+                "code pattern `{pattern}` {} in stderr output",
+                "not found".red()
+            );
+            if codes.is_empty() {
+                eprintln!("there were no diagnostics with a code on that line");
+            } else {
+                eprintln!("codes found on that line: {}", codes.join(", "));
+            }
+            eprintln!(
+                "expected because of pattern here: {}",
+                format!("{path}:{definition_line}").bold()
+            );
+        }
+        Error::ColumnMismatch {
+            pattern,
+            definition_line,
+            expected,
+            found,
+        } => {
+            let found = found
+                .iter()
+                .map(|column| column.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            eprintln!(
+                "`{pattern}`: {} in stderr output",
+                format!("expected column {expected}, found column {found}").red()
+            );
+            eprintln!(
+                "expected because of pattern here: {}",
+                format!("{path}:{definition_line}").bold()
+            );
+        }
+        Error::PatternCountMismatch {
+            pattern,
+            definition_line,
+            expected,
+            found,
+        } => {
+            let mismatch = format!("expected {expected} occurrences, found {found}");
+            match pattern {
+                Pattern::SubString(s) => {
+                    eprintln!("substring `{s}`: {} in stderr output", mismatch.red())
+                }
+                Pattern::Regex(r) => {
+                    eprintln!("`/{r}/`: {} in stderr output", mismatch.red())
+                }
+                Pattern::Alternatives(alternatives) => {
+                    eprintln!(
+                        "substrings `{}`: {} in stderr output",
+                        alternatives.join("` | `"),
+                        mismatch.red()
+                    )
+                }
+                Pattern::Code(code) => {
+                    eprintln!(
+                        "diagnostic code `{code}`: {} in stderr output",
+                        mismatch.red()
+                    )
+                }
             }
             eprintln!(
                 "expected because of pattern here: {}",
                 format!("{path}:{definition_line}").bold()
             );
         }
+        Error::AnnotationOutOfOrder {
+            pattern,
+            definition_line,
+            preceding_pattern,
+            preceding_definition_line,
+        } => {
+            eprintln!(
+                "`{pattern}` matched a diagnostic that was emitted {} the one matched by `{preceding_pattern}`",
+                "before".red()
+            );
+            eprintln!(
+                "expected because of pattern order here: {} and {}",
+                format!("{path}:{preceding_definition_line}").bold(),
+                format!("{path}:{definition_line}").bold()
+            );
+        }
         Error::NoPatternsFound => {
             eprintln!("{}", "no error patterns found in fail test".red());
         }
+        Error::FixedOutputNotClean { msgs } => {
+            eprintln!(
+                "{}",
+                "the code fixed by rustfix still emitted diagnostics".red()
+            );
+            for Message { level, message, .. } in msgs {
+                eprintln!("    {level:?}: {message}")
+            }
+        }
         Error::PatternFoundInPassTest => {
             eprintln!("{}", "error pattern found in pass test".red())
         }
@@ -209,6 +595,7 @@ fn print_error(error: &Error, path: &str) {
             actual,
             expected,
             bless_command,
+            context,
         } => {
             eprintln!("{}", "actual output differed from expected".underline());
             eprintln!(
@@ -218,28 +605,63 @@ fn print_error(error: &Error, path: &str) {
             );
             eprintln!("{}", format!("--- {}", output_path.display()).red());
             eprintln!("{}", "+++ <stderr output>".green());
-            crate::diff::print_diff(expected, actual);
+            crate::diff::print_diff(expected, actual, *context);
+            if strip_trailing_whitespace(expected) == strip_trailing_whitespace(actual) {
+                eprintln!("{WHITESPACE_ONLY}");
+                eprintln!();
+            }
+        }
+        Error::Nondeterministic {
+            stream,
+            first,
+            second,
+            context,
+        } => {
+            eprintln!(
+                "{}",
+                format!("{stream} differed between two runs").underline()
+            );
+            eprintln!("{}", "--- first run".red());
+            eprintln!("{}", "+++ second run".green());
+            crate::diff::print_diff(first, second, *context);
         }
         Error::ErrorsWithoutPattern { path: None, msgs } => {
             eprintln!(
                 "There were {} unmatched diagnostics that occurred outside the testfile and had no pattern",
                 msgs.len(),
             );
-            for Message { level, message } in msgs {
-                eprintln!("    {level:?}: {message}")
+            for msg in msgs {
+                let Message {
+                    level,
+                    message,
+                    location,
+                    ..
+                } = msg;
+                match location {
+                    Some(Location { file, line }) => {
+                        eprintln!("    {level:?}: {message} (at {}:{line})", file.display())
+                    }
+                    None => eprintln!("    {level:?}: {message}"),
+                }
+                for trail in expansion_trail(msg, None) {
+                    eprintln!("        {trail}");
+                }
             }
         }
         Error::ErrorsWithoutPattern {
             path: Some((path, line)),
             msgs,
         } => {
-            let path = path.display();
             eprintln!(
-                "There were {} unmatched diagnostics at {path}:{line}",
+                "There were {} unmatched diagnostics at {}:{line}",
                 msgs.len(),
+                path.display(),
             );
-            for Message { level, message } in msgs {
-                eprintln!("    {level:?}: {message}")
+            for msg in msgs {
+                eprintln!("    {:?}: {}", msg.level, msg.message);
+                for trail in expansion_trail(msg, Some(path)) {
+                    eprintln!("        {trail}");
+                }
             }
         }
         Error::InvalidComment { msg, line } => {
@@ -248,6 +670,149 @@ fn print_error(error: &Error, path: &str) {
         Error::Bug(msg) => {
             eprintln!("A bug in `ui_test` occurred: {msg}");
         }
+        Error::FilterFnPanicked {
+            kind,
+            index,
+            message,
+        } => {
+            eprintln!("filter function #{index} of the `{kind}` output panicked: {message}");
+        }
+        Error::OutputTooLarge {
+            stream,
+            limit,
+            head,
+            tail,
+        } => {
+            eprintln!("the {stream} of the test exceeded the limit of {limit} bytes, so the test was killed");
+            eprintln!("the first {} bytes were:", head.len());
+            eprintln!("{}", escape_bytes(head));
+            eprintln!("the last {} bytes were:", tail.len());
+            eprintln!("{}", escape_bytes(tail));
+        }
+        Error::InternalCompilerError {
+            message,
+            query_stack,
+        } => {
+            eprintln!("{}: {message}", "internal compiler error".red().bold());
+            if !query_stack.is_empty() {
+                eprintln!("query stack during panic:");
+                for query in query_stack {
+                    eprintln!("    {query}");
+                }
+            }
+        }
+        Error::StaleOutputFile => {
+            eprintln!("no test produces this output file, it belongs to a test that was removed or renamed");
+        }
+        Error::TimingRegression {
+            baseline,
+            actual,
+            samples,
+        } => {
+            let samples = if *samples > 1 {
+                format!(" (the median of {samples} runs)")
+            } else {
+                String::new()
+            };
+            eprintln!(
+                "the test took {:.2}s{samples}, but only {:.2}s in the timing baseline",
+                actual.as_secs_f64(),
+                baseline.as_secs_f64()
+            );
+            eprintln!("`--bless-timings` updates the timing baseline");
+        }
+        Error::BlessFailed {
+            path: output_path,
+            message,
+        } => {
+            eprintln!("could not bless `{}`: {message}", output_path.display());
+        }
+        Error::Io {
+            path: file,
+            message,
+        } => {
+            eprintln!("could not access `{}`: {message}", file.display());
+        }
+        Error::Warning(error) => {
+            eprint!("{} ", "warning:".yellow().bold());
+            // Already ends with an empty line.
+            return print_error(error, path);
+        }
+        Error::FixedOutputDiffers {
+            applied,
+            iterations,
+            msgs,
+            diff,
+        } => {
+            if *iterations > 1 {
+                eprintln!("rustfix applied `{applied}` suggestions in {iterations} iterations");
+            } else {
+                eprintln!("rustfix applied `{applied}` suggestions");
+            }
+            if !msgs.is_empty() {
+                eprintln!("the last iteration emitted these diagnostics:");
+                for Message { level, message, .. } in msgs {
+                    eprintln!("    {level:?}: {message}")
+                }
+            }
+            // Already ends with an empty line.
+            return print_error(diff, path);
+        }
+        Error::SuggestionConflict {
+            first,
+            first_span,
+            second,
+            second_span,
+        } => {
+            eprintln!("{}", "rustfix suggestions overlap".red());
+            eprintln!("    {first} (replacing {first_span})");
+            eprintln!("    {second} (replacing {second_span})");
+        }
+        Error::RustfixIterationLimit { iterations, msgs } => {
+            eprintln!(
+                "{}",
+                format!("rustfix still had suggestions to apply after {iterations} iterations")
+                    .red()
+            );
+            for Message { level, message, .. } in msgs {
+                eprintln!("    {level:?}: {message}")
+            }
+        }
+        Error::AuxBin {
+            path: aux_path,
+            status,
+            stdout,
+            stderr,
+        } => {
+            match status {
+                Some(status) => eprintln!(
+                    "aux binary `{}` exited with {status} while the test was running",
+                    aux_path.display()
+                ),
+                None => eprintln!("aux binary `{}` was running", aux_path.display()),
+            }
+            eprintln!("its stdout:");
+            eprintln!("{}", String::from_utf8_lossy(stdout));
+            eprintln!("its stderr:");
+            eprintln!("{}", String::from_utf8_lossy(stderr));
+        }
+        Error::TestHook { hook, message } => {
+            eprintln!("The `{hook}` hook failed: {message}");
+        }
+        Error::CustomComment {
+            name,
+            message,
+            line,
+        } => {
+            eprintln!("Custom comment `{name}` from {path}:{line} failed: {message}");
+        }
+        Error::CustomBuild {
+            name,
+            message,
+            line,
+        } => {
+            eprintln!("Custom build `{name}` from {path}:{line} failed: {message}");
+        }
         Error::Aux {
             path: aux_path,
             errors,
@@ -258,6 +823,15 @@ fn print_error(error: &Error, path: &str) {
                 print_error(error, &aux_path.display().to_string());
             }
         }
+        Error::Defaults {
+            path: defaults_path,
+            errors,
+        } => {
+            eprintln!("Defaults file {} is invalid", defaults_path.display());
+            for error in errors {
+                print_error(error, &defaults_path.display().to_string());
+            }
+        }
     }
     eprintln!();
 }
@@ -278,11 +852,96 @@ fn gha_error(error: &Error, path: &str, revision: &str) {
             github_actions::error(path, format!("{kind}{revision} failed with {status}"));
         }
         Error::PatternNotFound {
+            pattern,
+            definition_line,
+            line,
+            nearby,
+        } => {
+            let mut err = github_actions::error(path, format!("Pattern not found{revision}"))
+                .line(*definition_line);
+            if let Some(line) = line {
+                if nearby.is_empty() {
+                    writeln!(
+                        err,
+                        "expected `{pattern}` on line {line}, but there were no diagnostics nearby"
+                    )
+                    .unwrap();
+                }
+                for (found_line, msgs) in nearby {
+                    writeln!(
+                        err,
+                        "expected `{pattern}` on line {line}, but line {found_line} had: {}",
+                        format_messages(msgs)
+                    )
+                    .unwrap();
+                }
+            }
+        }
+        Error::CodeNotFound {
+            pattern,
+            definition_line,
+            codes,
+        } => {
+            let mut err = github_actions::error(path, format!("Code not found{revision}"))
+                .line(*definition_line);
+            write!(err, "`{pattern}` did not match").unwrap();
+            if codes.is_empty() {
+                writeln!(err, ", there were no diagnostics with a code on that line").unwrap();
+            } else {
+                writeln!(err, " any of the codes on that line: {}", codes.join(", ")).unwrap();
+            }
+        }
+        Error::ColumnMismatch {
+            pattern,
+            definition_line,
+            expected,
+            found,
+        } => {
+            let mut err = github_actions::error(path, format!("Column mismatch{revision}"))
+                .line(*definition_line);
+            writeln!(
+                err,
+                "`{pattern}` expected column {expected}, found column {}",
+                found
+                    .iter()
+                    .map(|column| column.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+            .unwrap();
+        }
+        Error::PatternCountMismatch {
             pattern: _,
             definition_line,
+            expected,
+            found,
+        } => {
+            let mut err = github_actions::error(path, format!("Pattern count mismatch{revision}"))
+                .line(*definition_line);
+            writeln!(err, "expected {expected} occurrences, found {found}").unwrap();
+        }
+        Error::AnnotationOutOfOrder {
+            pattern,
+            definition_line,
+            preceding_pattern,
+            preceding_definition_line,
         } => {
-            github_actions::error(path, format!("Pattern not found{revision}"))
+            let mut err = github_actions::error(path, format!("Annotation out of order{revision}"))
                 .line(*definition_line);
+            writeln!(
+                err,
+                "`{pattern}` matched a diagnostic that was emitted before the one matched by `{preceding_pattern}` on line {preceding_definition_line}"
+            )
+            .unwrap();
+        }
+        Error::FixedOutputNotClean { msgs } => {
+            let mut err = github_actions::error(
+                path,
+                format!("the code fixed by rustfix still emitted diagnostics{revision}"),
+            );
+            for Message { level, message, .. } in msgs {
+                writeln!(err, "{level:?}: {message}").unwrap();
+            }
         }
         Error::NoPatternsFound => {
             github_actions::error(
@@ -298,18 +957,24 @@ fn gha_error(error: &Error, path: &str, revision: &str) {
             actual,
             expected,
             bless_command: _,
+            context: _,
         } => {
+            // New output files don't exist yet, so the error is shown on the test instead.
             let mut err = github_actions::error(
                 if expected.is_empty() {
                     path.to_owned()
                 } else {
                     output_path.display().to_string()
                 },
-                "actual output differs from expected",
+                format!("actual output differs from expected{revision}"),
             );
+            if strip_trailing_whitespace(expected) == strip_trailing_whitespace(actual) {
+                writeln!(err, "{WHITESPACE_ONLY}").unwrap();
+            }
             writeln!(err, "```diff").unwrap();
             let mut seen_diff_line = Some(0);
-            for r in ::diff::lines(expected.to_str().unwrap(), actual.to_str().unwrap()) {
+            let (expected, actual) = (escape_bytes(expected), escape_bytes(actual));
+            for r in ::diff::lines(&expected, &actual) {
                 if let Some(line) = &mut seen_diff_line {
                     *line += 1;
                 }
@@ -340,24 +1005,42 @@ fn gha_error(error: &Error, path: &str, revision: &str) {
             }
             writeln!(err, "```").unwrap();
         }
+        Error::Nondeterministic {
+            stream,
+            first,
+            second,
+            context,
+        } => {
+            let mut err =
+                github_actions::error(path, format!("{stream} differs between two runs{revision}"));
+            writeln!(err, "```diff").unwrap();
+            write!(err, "{}", render_diff(first, second, *context, false)).unwrap();
+            writeln!(err, "```").unwrap();
+        }
         Error::ErrorsWithoutPattern { path: None, msgs } => {
             let mut err = github_actions::error(
                 path,
                 format!("Unmatched diagnostics outside the testfile{revision}"),
             );
-            for Message { level, message } in msgs {
-                writeln!(err, "{level:?}: {message}").unwrap();
+            for msg in msgs {
+                writeln!(err, "{:?}: {}", msg.level, msg.message).unwrap();
+                for trail in expansion_trail(msg, None) {
+                    writeln!(err, "    {trail}").unwrap();
+                }
             }
         }
         Error::ErrorsWithoutPattern {
             path: Some((path, line)),
             msgs,
         } => {
-            let path = path.display();
-            let mut err = github_actions::error(&path, format!("Unmatched diagnostics{revision}"))
-                .line(*line);
-            for Message { level, message } in msgs {
-                writeln!(err, "{level:?}: {message}").unwrap();
+            let mut err =
+                github_actions::error(path.display(), format!("Unmatched diagnostics{revision}"))
+                    .line(*line);
+            for msg in msgs {
+                writeln!(err, "{:?}: {}", msg.level, msg.message).unwrap();
+                for trail in expansion_trail(msg, Some(path)) {
+                    writeln!(err, "    {trail}").unwrap();
+                }
             }
         }
         Error::InvalidComment { msg, line } => {
@@ -365,17 +1048,189 @@ fn gha_error(error: &Error, path: &str, revision: &str) {
                 github_actions::error(path, format!("Could not parse comment")).line(*line);
             writeln!(err, "{msg}").unwrap();
         }
-        Error::Bug(_) => {}
+        Error::Bug(msg) => {
+            let mut err =
+                github_actions::error(path, format!("A bug in `ui_test` occurred{revision}"));
+            writeln!(err, "{msg}").unwrap();
+        }
+        Error::FilterFnPanicked {
+            kind,
+            index,
+            message,
+        } => {
+            let mut err = github_actions::error(
+                path,
+                format!("Filter function #{index} of the `{kind}` output panicked{revision}"),
+            );
+            writeln!(err, "{message}").unwrap();
+        }
+        Error::OutputTooLarge {
+            stream,
+            limit,
+            head,
+            tail,
+        } => {
+            let mut err = github_actions::error(
+                path,
+                format!("The {stream} exceeded the limit of {limit} bytes{revision}"),
+            );
+            writeln!(err, "first {} bytes:", head.len()).unwrap();
+            writeln!(err, "{}", escape_bytes(head)).unwrap();
+            writeln!(err, "last {} bytes:", tail.len()).unwrap();
+            writeln!(err, "{}", escape_bytes(tail)).unwrap();
+        }
+        Error::InternalCompilerError {
+            message,
+            query_stack,
+        } => {
+            let mut err = github_actions::error(path, format!("Internal compiler error{revision}"));
+            writeln!(err, "{message}").unwrap();
+            for query in query_stack {
+                writeln!(err, "{query}").unwrap();
+            }
+        }
+        Error::StaleOutputFile => {
+            github_actions::error(path, "No test produces this output file");
+        }
+        Error::TimingRegression {
+            baseline,
+            actual,
+            samples: _,
+        } => {
+            github_actions::error(
+                path,
+                format!(
+                    "The test took {:.2}s, but only {:.2}s in the timing baseline{revision}",
+                    actual.as_secs_f64(),
+                    baseline.as_secs_f64()
+                ),
+            );
+        }
+        Error::BlessFailed {
+            path: output_path,
+            message,
+        } => {
+            let mut err = github_actions::error(
+                output_path.display(),
+                format!("Could not bless the output{revision}"),
+            );
+            writeln!(err, "{message}").unwrap();
+        }
+        Error::Io {
+            path: file,
+            message,
+        } => {
+            let mut err = github_actions::error(
+                file.display(),
+                format!("Could not access the file{revision}"),
+            );
+            writeln!(err, "{message}").unwrap();
+        }
+        Error::Warning(error) => gha_error(error, path, &format!("{revision} (warning)")),
+        Error::FixedOutputDiffers {
+            applied,
+            iterations,
+            msgs: _,
+            diff,
+        } => {
+            let applied = if *iterations > 1 {
+                format!("rustfix applied `{applied}` suggestions in {iterations} iterations")
+            } else {
+                format!("rustfix applied `{applied}` suggestions")
+            };
+            gha_error(diff, path, &format!("{revision} ({applied})"))
+        }
+        Error::SuggestionConflict {
+            first,
+            first_span,
+            second,
+            second_span,
+        } => {
+            let mut err =
+                github_actions::error(path, format!("rustfix suggestions overlap{revision}"));
+            // The spans are `line:column-line:column`.
+            if let Some(line) = first_span.split(':').next().and_then(|l| l.parse().ok()) {
+                err = err.line(line);
+            }
+            writeln!(err, "{first} (replacing {first_span})").unwrap();
+            writeln!(err, "{second} (replacing {second_span})").unwrap();
+        }
+        Error::RustfixIterationLimit { iterations, msgs } => {
+            let mut err = github_actions::error(
+                path,
+                format!("rustfix still had suggestions after {iterations} iterations{revision}"),
+            );
+            for Message { level, message, .. } in msgs {
+                writeln!(err, "{level:?}: {message}").unwrap();
+            }
+        }
+        Error::AuxBin {
+            path: aux_path,
+            status,
+            stdout,
+            stderr,
+        } => {
+            let mut err = match status {
+                Some(status) => github_actions::error(
+                    path,
+                    format!(
+                        "aux binary `{}` exited with {status}{revision}",
+                        aux_path.display()
+                    ),
+                ),
+                None => github_actions::error(
+                    path,
+                    format!("output of aux binary `{}`{revision}", aux_path.display()),
+                ),
+            };
+            writeln!(err, "stdout:").unwrap();
+            writeln!(err, "{}", String::from_utf8_lossy(stdout)).unwrap();
+            writeln!(err, "stderr:").unwrap();
+            writeln!(err, "{}", String::from_utf8_lossy(stderr)).unwrap();
+        }
+        Error::TestHook { hook, message } => {
+            github_actions::error(path, format!("The `{hook}` hook failed{revision}"))
+                .write_str(message)
+                .unwrap();
+        }
+        Error::CustomComment {
+            name,
+            message,
+            line,
+        } => {
+            github_actions::error(path, format!("Custom comment `{name}` failed{revision}"))
+                .line(*line)
+                .write_str(message)
+                .unwrap();
+        }
+        Error::CustomBuild {
+            name,
+            message,
+            line,
+        } => {
+            github_actions::error(path, format!("Custom build `{name}` failed{revision}"))
+                .line(*line)
+                .write_str(message)
+                .unwrap();
+        }
         Error::Aux {
             path: aux_path,
             errors,
             line,
         } => {
-            github_actions::error(path, format!("Aux build failed")).line(*line);
+            github_actions::error(path, format!("Aux build failed{revision}")).line(*line);
             for error in errors {
                 gha_error(error, &aux_path.display().to_string(), "")
             }
         }
+        Error::Defaults {
+            path: defaults_path,
+            errors,
+        } => {
+            for error in errors {
+                gha_error(error, &defaults_path.display().to_string(), revision)
+            }
+        }
     }
     eprintln!();
 }
@@ -391,9 +1246,10 @@ impl StatusEmitter for Quiet {
         // Humans start counting at 1
         self.n += 1;
         match result {
-            TestResult::Ok => eprint!("{}", ".".green()),
+            TestResult::Ok | TestResult::Cached => eprint!("{}", ".".green()),
+            TestResult::Flaky { .. } => eprint!("{}", ".".yellow()),
             TestResult::Errored { .. } => eprint!("{}", "F".red().bold()),
-            TestResult::Ignored => eprint!("{}", "i".yellow()),
+            TestResult::Ignored { .. } => eprint!("{}", "i".yellow()),
             TestResult::Filtered => {}
         }
         if self.n % 100 == 0 {
@@ -411,6 +1267,34 @@ impl StatusEmitter for Quiet {
         Text.failed_test(revision, path, cmd, stderr)
     }
 
+    fn failure_artifacts(&self, path: &Path, revision: &str, artifacts: &[PathBuf]) {
+        Text.failure_artifacts(path, revision, artifacts)
+    }
+
+    fn shard(&mut self, index: usize, count: usize, assigned: usize) {
+        Text.shard(index, count, assigned)
+    }
+
+    fn excluded_tests(&mut self, by_filters: usize, by_skip: usize) {
+        Text.excluded_tests(by_filters, by_skip)
+    }
+
+    fn reclaimed(&mut self, bytes: u64) {
+        Text.reclaimed(bytes)
+    }
+
+    fn test_roots(&mut self, roots: &[(PathBuf, usize, usize, usize, usize)]) {
+        Text.test_roots(roots)
+    }
+
+    fn slowest_tests(&mut self, slowest: &[(PathBuf, String, Timings)], total: &Timings) {
+        Text.slowest_tests(slowest, total)
+    }
+
+    fn ignored_tests(&mut self, ignored: &[(String, Vec<(PathBuf, String, usize)>)]) {
+        Text.ignored_tests(ignored)
+    }
+
     fn finalize(
         &self,
         failed: usize,
@@ -422,6 +1306,198 @@ impl StatusEmitter for Quiet {
     }
 }
 
+/// Print nothing for tests that pass, and the errors of failed tests as soon as they fail
+/// instead of at the end. Only the final counts follow at the end of the test run.
+#[derive(Default)]
+pub struct OnlyFailures {
+    /// The tests whose errors were already printed, so that they are not printed again at the end.
+    reported: Arc<Mutex<HashSet<(PathBuf, String)>>>,
+}
+
+impl OnlyFailures {
+    fn reported(&self, path: &Path, revision: &str) -> bool {
+        self.reported
+            .lock()
+            .unwrap()
+            .contains(&(path.to_owned(), revision.to_owned()))
+    }
+}
+
+impl StatusEmitter for OnlyFailures {
+    fn test_result(&mut self, path: &Path, revision: &str, result: &TestResult) {
+        if let TestResult::Errored {
+            command,
+            errors,
+            stderr,
+        } = result
+        {
+            let _guard = Text.failed_test(revision, path, command, stderr);
+            for error in errors {
+                print_error(error, &path.display().to_string());
+            }
+            self.reported
+                .lock()
+                .unwrap()
+                .insert((path.to_owned(), revision.to_owned()));
+        }
+    }
+
+    fn failed_test<'a>(
+        &'a self,
+        revision: &'a str,
+        path: &'a Path,
+        cmd: &'a Command,
+        stderr: &'a [u8],
+    ) -> Box<dyn Debug + 'a> {
+        // Failures that are only noticed at the end, like stale output files, still need a header.
+        if self.reported(path, revision) {
+            Box::new(())
+        } else {
+            Text.failed_test(revision, path, cmd, stderr)
+        }
+    }
+
+    fn failure_artifacts(&self, path: &Path, revision: &str, artifacts: &[PathBuf]) {
+        if self.reported(path, revision) {
+            let revision = if revision.is_empty() {
+                String::new()
+            } else {
+                format!(" (revision {revision})")
+            };
+            eprintln!("actual output of {}{revision} saved to:", path.display());
+            for artifact in artifacts {
+                eprintln!("    {}", artifact.display());
+            }
+            eprintln!();
+        } else {
+            Text.failure_artifacts(path, revision, artifacts)
+        }
+    }
+
+    fn shard(&mut self, index: usize, count: usize, assigned: usize) {
+        Text.shard(index, count, assigned)
+    }
+
+    fn excluded_tests(&mut self, by_filters: usize, by_skip: usize) {
+        Text.excluded_tests(by_filters, by_skip)
+    }
+
+    fn reclaimed(&mut self, bytes: u64) {
+        Text.reclaimed(bytes)
+    }
+
+    fn test_roots(&mut self, roots: &[(PathBuf, usize, usize, usize, usize)]) {
+        Text.test_roots(roots)
+    }
+
+    fn slowest_tests(&mut self, slowest: &[(PathBuf, String, Timings)], total: &Timings) {
+        Text.slowest_tests(slowest, total)
+    }
+
+    fn ignored_tests(&mut self, ignored: &[(String, Vec<(PathBuf, String, usize)>)]) {
+        Text.ignored_tests(ignored)
+    }
+
+    fn finalize(
+        &self,
+        _failures: usize,
+        succeeded: usize,
+        ignored: usize,
+        filtered: usize,
+    ) -> Box<dyn Summary> {
+        struct Summarizer {
+            reported: Arc<Mutex<HashSet<(PathBuf, String)>>>,
+            failures: usize,
+            flaky: usize,
+            bless_command: Option<String>,
+            succeeded: usize,
+            ignored: usize,
+            filtered: usize,
+            warnings: usize,
+        }
+
+        impl Summary for Summarizer {
+            fn test_failure(&mut self, path: &Path, revision: &str, errors: &Errors) {
+                self.failures += 1;
+                if !self
+                    .reported
+                    .lock()
+                    .unwrap()
+                    .contains(&(path.to_owned(), revision.to_owned()))
+                {
+                    for error in errors {
+                        print_error(error, &path.display().to_string());
+                    }
+                }
+            }
+
+            fn test_warnings(&mut self, _path: &Path, _revision: &str, warnings: &Errors) {
+                self.warnings += warnings.len();
+            }
+
+            fn test_flaky(&mut self, _path: &Path, _revision: &str, _attempts: usize) {
+                self.flaky += 1;
+            }
+
+            fn bless_command(&mut self, command: &str) {
+                self.bless_command = Some(command.to_owned());
+            }
+        }
+
+        impl Drop for Summarizer {
+            fn drop(&mut self) {
+                let mut extra = String::new();
+                if self.warnings > 0 {
+                    extra.push_str(&format!(
+                        ", {} warnings",
+                        self.warnings.to_string().yellow()
+                    ));
+                }
+                if self.flaky > 0 {
+                    extra.push_str(&format!(
+                        ", {} passed on retry",
+                        self.flaky.to_string().yellow()
+                    ));
+                }
+                if let Some(command) = &self.bless_command {
+                    eprintln!();
+                    eprintln!("Execute `{command}` to update the output of all failed tests");
+                }
+                eprintln!();
+                if self.failures == 0 {
+                    eprintln!(
+                        "test result: {}. {} tests passed, {} ignored, {} filtered out{extra}",
+                        "ok".green(),
+                        self.succeeded.to_string().green(),
+                        self.ignored.to_string().yellow(),
+                        self.filtered.to_string().yellow(),
+                    );
+                } else {
+                    eprintln!(
+                        "test result: {}. {} tests failed, {} tests passed, {} ignored, {} filtered out{extra}",
+                        "FAIL".red(),
+                        self.failures.to_string().red().bold(),
+                        self.succeeded.to_string().green(),
+                        self.ignored.to_string().yellow(),
+                        self.filtered.to_string().yellow(),
+                    );
+                }
+            }
+        }
+
+        Box::new(Summarizer {
+            reported: self.reported.clone(),
+            failures: 0,
+            flaky: 0,
+            bless_command: None,
+            succeeded,
+            ignored,
+            filtered,
+            warnings: 0,
+        })
+    }
+}
+
 /// Emits Github Actions Workspace commands to show the failures directly in the github diff view.
 /// If the const generic `GROUP` boolean is `true`, also emit `::group` commands.
 pub struct Gha<const GROUP: bool> {
@@ -458,6 +1534,7 @@ impl<const GROUP: bool> StatusEmitter for Gha<GROUP> {
     ) -> Box<dyn Summary> {
         struct Summarizer<const GROUP: bool> {
             failures: Vec<String>,
+            flaky: Vec<String>,
             succeeded: usize,
             ignored: usize,
             filtered: usize,
@@ -476,6 +1553,29 @@ impl<const GROUP: bool> StatusEmitter for Gha<GROUP> {
                 }
                 self.failures.push(format!("{}{revision}", path.display()));
             }
+
+            fn test_warnings(&mut self, path: &Path, revision: &str, warnings: &Errors) {
+                let revision = if revision.is_empty() {
+                    "".to_string()
+                } else {
+                    format!(" (revision: {revision})")
+                };
+                for warning in warnings {
+                    gha_error(warning, &path.display().to_string(), &revision);
+                }
+            }
+
+            fn test_flaky(&mut self, path: &Path, revision: &str, attempts: usize) {
+                let revision = if revision.is_empty() {
+                    "".to_string()
+                } else {
+                    format!(" (revision: {revision})")
+                };
+                self.flaky.push(format!(
+                    "{}{revision} passed on attempt {attempts}",
+                    path.display()
+                ));
+            }
         }
         impl<const GROUP: bool> Drop for Summarizer<GROUP> {
             fn drop(&mut self) {
@@ -484,6 +1584,13 @@ impl<const GROUP: bool> StatusEmitter for Gha<GROUP> {
                     for line in &self.failures {
                         writeln!(file, "* {line}").unwrap();
                     }
+                    if !self.flaky.is_empty() {
+                        writeln!(file).unwrap();
+                        writeln!(file, "Flaky tests:").unwrap();
+                        for line in &self.flaky {
+                            writeln!(file, "* {line}").unwrap();
+                        }
+                    }
                     writeln!(file).unwrap();
                     writeln!(file, "| failed | passed | ignored | filtered out |").unwrap();
                     writeln!(file, "| --- | --- | --- | --- |").unwrap();
@@ -502,6 +1609,7 @@ impl<const GROUP: bool> StatusEmitter for Gha<GROUP> {
 
         Box::new(Summarizer::<GROUP> {
             failures: vec![],
+            flaky: vec![],
             succeeded,
             ignored,
             filtered,
@@ -510,25 +1618,1092 @@ impl<const GROUP: bool> StatusEmitter for Gha<GROUP> {
     }
 }
 
-impl<T: StatusEmitter, U: StatusEmitter> StatusEmitter for (T, U) {
-    fn failed_test<'a>(
-        &'a self,
-        revision: &'a str,
-        path: &'a Path,
-        cmd: &'a Command,
-        stderr: &'a [u8],
-    ) -> Box<dyn Debug + 'a> {
-        Box::new((
-            self.0.failed_test(revision, path, cmd, stderr),
-            self.1.failed_test(revision, path, cmd, stderr),
-        ))
-    }
+/// Writes the results as JSON, one object per line: one for every test and revision when it
+/// finishes, and one with the totals at the end of the test run. Tests that were filtered out
+/// are left out. The tests are in the order they finished in, which can differ between runs,
+/// so consumers that compare runs should sort them by their `id`.
+///
+/// Every object has a `format_version` field, which is [`Json::FORMAT_VERSION`], and a `type`
+/// field, which is `test` or `summary`. Test objects have these fields:
+/// * `path` and `revision` of the test, the revision is empty for tests without revisions.
+/// * `id`: the path relative to the [`Json::root_dir`] followed by `::revision` for
+///   revisions, as [`Config::list`](crate::Config::list) prints it and filters match it.
+/// * `outcome`: `passed`, `flaky`, `cached`, `failed` or `ignored`.
+/// * `attempts`: how many attempts a flaky test needed, otherwise `null`.
+/// * `duration_secs`: how long the test took, zero for tests that did not run.
+/// * `timings`: how many seconds the test spent in each phase, see [`Timings`], with the names
+///   of the phases as keys and spaces replaced by underscores, like `aux_builds`.
+/// * `command`: the command that failed, otherwise `null`.
+/// * `bless_command`: the command that blesses the differing output files of the test,
+///   otherwise `null`.
+/// * `expected_files`: the expected output files that differed from the actual output.
+/// * `errors`: objects with the `kind` of the error, like `output_differs`, a `message`, and
+///   further fields depending on the kind. `output_differs` errors contain the `expected_file`,
+///   the `expected_len` and `actual_len` in bytes, and the rendered `diff`.
+/// * `ignore_reason` and `ignore_line`: the comment that caused an ignored test to be ignored,
+///   and its line, otherwise `null`.
+///
+/// The summary object has the fields `outcome`, `passed`, `failed`, `ignored`, `filtered`,
+/// `excluded_by_filters`, `excluded_by_skip`, `warnings` and `timings`. The `filtered` tests
+/// include the ones excluded by [`Config::filters`](crate::Config::filters) and
+/// [`Config::skip`](crate::Config::skip). The `timings` add up the timings of all tests, but only if
+/// [`Config::slowest_tests`](crate::Config::slowest_tests) is set, otherwise they are `null`.
+/// `ignored_reasons` lists the ignored tests grouped by their `reason` if
+/// [`Config::ignored_summary`](crate::Config::ignored_summary) is set, otherwise it is `null`.
+/// `roots` has the `dir`, `passed`, `failed`, `ignored` and `filtered` counts of each root of
+/// [`run_test_roots`](crate::run_test_roots) if there are several, otherwise it is `null`.
+/// `bless_command` blesses the output of all failed tests if there is more than one of them,
+/// otherwise it is `null`. `reclaimed_bytes` is how many bytes of artifacts the
+/// [`Config::cleanup`](crate::Config::cleanup) policy removed, `null` if it keeps them.
+pub struct Json {
+    out: Arc<Mutex<Box<dyn std::io::Write + Send>>>,
+    timings: Timings,
+    total: Option<Timings>,
+    ignored: Option<serde_json::Value>,
+    excluded: (usize, usize),
+    reclaimed: Option<u64>,
+    roots: Option<serde_json::Value>,
+    root_dir: PathBuf,
+}
+
+impl Json {
+    /// Changes whenever fields are removed or change their meaning. Adding fields does not
+    /// change the version.
+    pub const FORMAT_VERSION: u32 = 1;
+
+    /// Write the results to stdout.
+    pub fn stdout() -> Self {
+        Self::new(Box::new(std::io::stdout()))
+    }
+
+    /// Write the results to a file, replacing it.
+    pub fn file(path: &Path) -> std::io::Result<Self> {
+        Ok(Self::new(Box::new(std::fs::File::create(path)?)))
+    }
+
+    /// Write the results to `out`.
+    pub fn new(out: Box<dyn std::io::Write + Send>) -> Self {
+        Self {
+            out: Arc::new(Mutex::new(out)),
+            timings: Timings::default(),
+            total: None,
+            ignored: None,
+            excluded: (0, 0),
+            reclaimed: None,
+            roots: None,
+            root_dir: PathBuf::new(),
+        }
+    }
+
+    /// Make the `id`s of the tests relative to `root_dir`, which should be the
+    /// [`Config::root_dir`](crate::Config::root_dir). By default, the `id`s are the paths.
+    pub fn root_dir(mut self, root_dir: impl Into<PathBuf>) -> Self {
+        self.root_dir = root_dir.into();
+        self
+    }
+}
+
+fn write_json_line(out: &Mutex<Box<dyn std::io::Write + Send>>, mut json: serde_json::Value) {
+    json["format_version"] = Json::FORMAT_VERSION.into();
+    let mut out = out.lock().unwrap();
+    writeln!(out, "{json}").unwrap();
+    // Consumers may follow the results while the tests run.
+    out.flush().unwrap();
+}
+
+impl StatusEmitter for Json {
+    fn failed_test<'a>(
+        &'a self,
+        _revision: &'a str,
+        _path: &'a Path,
+        _cmd: &'a Command,
+        _stderr: &'a [u8],
+    ) -> Box<dyn Debug + 'a> {
+        Box::new(())
+    }
+
+    fn test_timings(&mut self, _path: &Path, _revision: &str, timings: &Timings) {
+        self.timings = *timings;
+    }
+
+    fn slowest_tests(&mut self, _slowest: &[(PathBuf, String, Timings)], total: &Timings) {
+        self.total = Some(*total);
+    }
+
+    fn excluded_tests(&mut self, by_filters: usize, by_skip: usize) {
+        self.excluded = (by_filters, by_skip);
+    }
+
+    fn reclaimed(&mut self, bytes: u64) {
+        self.reclaimed = Some(bytes);
+    }
+
+    fn test_roots(&mut self, roots: &[(PathBuf, usize, usize, usize, usize)]) {
+        self.roots = Some(
+            roots
+                .iter()
+                .map(|(dir, failed, succeeded, ignored, filtered)| {
+                    serde_json::json!({
+                        "dir": dir.display().to_string(),
+                        "passed": succeeded,
+                        "failed": failed,
+                        "ignored": ignored,
+                        "filtered": filtered,
+                    })
+                })
+                .collect(),
+        );
+    }
+
+    fn ignored_tests(&mut self, ignored: &[(String, Vec<(PathBuf, String, usize)>)]) {
+        self.ignored = Some(
+            ignored
+                .iter()
+                .map(|(reason, tests)| {
+                    serde_json::json!({
+                        "reason": reason,
+                        "tests": tests
+                            .iter()
+                            .map(|(path, revision, line)| serde_json::json!({
+                                "path": path.display().to_string(),
+                                "revision": revision,
+                                "line": line,
+                            }))
+                            .collect::<Vec<_>>(),
+                    })
+                })
+                .collect(),
+        );
+    }
+
+    fn test_result(&mut self, path: &Path, revision: &str, result: &TestResult) {
+        let (outcome, attempts, command, errors): (_, _, _, &[Error]) = match result {
+            TestResult::Ok => ("passed", None, None, &[]),
+            TestResult::Flaky { attempts } => ("flaky", Some(*attempts), None, &[]),
+            TestResult::Cached => ("cached", None, None, &[]),
+            TestResult::Ignored { .. } => ("ignored", None, None, &[]),
+            TestResult::Filtered => return,
+            TestResult::Errored {
+                command, errors, ..
+            } => ("failed", None, Some(display_command(command)), errors),
+        };
+        let (ignore_reason, ignore_line) = match result {
+            TestResult::Ignored { reason, line } => (Some(reason), Some(line)),
+            _ => (None, None),
+        };
+        let mut bless_command = None;
+        let mut expected_files = vec![];
+        for error in errors {
+            collect_output_differs(error, &mut |path, command| {
+                bless_command.get_or_insert_with(|| command.to_owned());
+                expected_files.push(path.display().to_string());
+            });
+        }
+        write_json_line(
+            &self.out,
+            serde_json::json!({
+                "type": "test",
+                "path": path.display().to_string(),
+                "revision": revision,
+                "id": crate::test_id_in(&self.root_dir, path, revision),
+                "outcome": outcome,
+                "attempts": attempts,
+                "duration_secs": self.timings.total.as_secs_f64(),
+                "timings": json_timings(&self.timings),
+                "command": command,
+                "bless_command": bless_command,
+                "expected_files": expected_files,
+                "errors": errors.iter().map(json_error).collect::<Vec<_>>(),
+                "ignore_reason": ignore_reason,
+                "ignore_line": ignore_line,
+            }),
+        );
+    }
+
+    fn finalize(
+        &self,
+        failed: usize,
+        succeeded: usize,
+        ignored: usize,
+        filtered: usize,
+    ) -> Box<dyn Summary> {
+        struct Summarizer {
+            out: Arc<Mutex<Box<dyn std::io::Write + Send>>>,
+            summary: serde_json::Value,
+        }
+
+        impl Summary for Summarizer {
+            fn test_warnings(&mut self, _path: &Path, _revision: &str, warnings: &Errors) {
+                let count = self.summary["warnings"].as_u64().unwrap() + warnings.len() as u64;
+                self.summary["warnings"] = count.into();
+            }
+
+            fn bless_command(&mut self, command: &str) {
+                self.summary["bless_command"] = command.into();
+            }
+        }
+
+        impl Drop for Summarizer {
+            fn drop(&mut self) {
+                write_json_line(&self.out, self.summary.take());
+            }
+        }
+
+        Box::new(Summarizer {
+            out: self.out.clone(),
+            summary: serde_json::json!({
+                "type": "summary",
+                "outcome": if failed == 0 { "passed" } else { "failed" },
+                "passed": succeeded,
+                "failed": failed,
+                "ignored": ignored,
+                "filtered": filtered,
+                "excluded_by_filters": self.excluded.0,
+                "excluded_by_skip": self.excluded.1,
+                "warnings": 0,
+                "timings": self.total.as_ref().map(json_timings),
+                "ignored_reasons": self.ignored,
+                "roots": self.roots,
+                "bless_command": null,
+                "reclaimed_bytes": self.reclaimed,
+            }),
+        })
+    }
+}
+
+/// Writes the events of libtest's unstable JSON format to stdout, so that tools which parse the
+/// output of `cargo test -- --format json` can consume the results. Tests are named like
+/// [`Config::list`](crate::Config::list) prints them and filters match them: by their path
+/// relative to the [`Libtest::root_dir`], followed by `::revision` for tests with revisions.
+///
+/// Tests are discovered while they run, so the `test_count` of the `suite` `started` event is
+/// always 0, and the `started` event of every test is only written when the test has finished.
+pub struct Libtest {
+    start: Instant,
+    duration: Duration,
+    root_dir: PathBuf,
+}
+
+impl Libtest {
+    /// Writes the `suite` `started` event, so create this right before running the tests.
+    pub fn new() -> Self {
+        Self::event(serde_json::json!({
+            "type": "suite",
+            "event": "started",
+            "test_count": 0,
+        }));
+        Self {
+            start: Instant::now(),
+            duration: Duration::ZERO,
+            root_dir: PathBuf::new(),
+        }
+    }
+
+    /// Name the tests relative to `root_dir`, which should be the
+    /// [`Config::root_dir`](crate::Config::root_dir). By default, the names are the paths.
+    pub fn root_dir(mut self, root_dir: impl Into<PathBuf>) -> Self {
+        self.root_dir = root_dir.into();
+        self
+    }
+
+    fn event(event: serde_json::Value) {
+        let mut stdout = std::io::stdout().lock();
+        writeln!(stdout, "{event}").unwrap();
+        stdout.flush().unwrap();
+    }
+}
+
+impl Default for Libtest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StatusEmitter for Libtest {
+    fn failed_test<'a>(
+        &'a self,
+        _revision: &'a str,
+        _path: &'a Path,
+        _cmd: &'a Command,
+        _stderr: &'a [u8],
+    ) -> Box<dyn Debug + 'a> {
+        Box::new(())
+    }
+
+    fn test_timings(&mut self, _path: &Path, _revision: &str, timings: &Timings) {
+        self.duration = timings.total;
+    }
+
+    fn test_result(&mut self, path: &Path, revision: &str, result: &TestResult) {
+        let name = crate::test_id_in(&self.root_dir, path, revision);
+        let mut event = serde_json::json!({
+            "type": "test",
+            "name": name,
+            "exec_time": self.duration.as_secs_f64(),
+        });
+        match result {
+            TestResult::Ok | TestResult::Flaky { .. } | TestResult::Cached => {
+                event["event"] = "ok".into();
+            }
+            TestResult::Errored {
+                command, errors, ..
+            } => {
+                event["event"] = "failed".into();
+                // The details of the failure are reported with the summary.
+                let mut stdout = format!("command: {}\n", display_command(command));
+                for error in errors {
+                    writeln!(stdout, "{}", json_error(error)["message"].as_str().unwrap()).unwrap();
+                }
+                event["stdout"] = stdout.into();
+            }
+            TestResult::Ignored { reason, .. } => {
+                event["event"] = "ignored".into();
+                event["message"] = reason.as_str().into();
+            }
+            TestResult::Filtered => return,
+        }
+        Self::event(serde_json::json!({
+            "type": "test",
+            "event": "started",
+            "name": name,
+        }));
+        Self::event(event);
+    }
+
+    fn finalize(
+        &self,
+        failed: usize,
+        succeeded: usize,
+        ignored: usize,
+        filtered: usize,
+    ) -> Box<dyn Summary> {
+        Self::event(serde_json::json!({
+            "type": "suite",
+            "event": if failed == 0 { "ok" } else { "failed" },
+            "passed": succeeded,
+            "failed": failed,
+            "ignored": ignored,
+            "measured": 0,
+            "filtered_out": filtered,
+            "exec_time": self.start.elapsed().as_secs_f64(),
+        }));
+        Box::new(())
+    }
+}
+
+/// How long each phase took in seconds, see [`Timings::phases`].
+fn json_timings(timings: &Timings) -> serde_json::Value {
+    timings
+        .phases()
+        .iter()
+        .map(|(phase, time)| (phase.replace(' ', "_"), time.as_secs_f64().into()))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+/// Writes a Markdown report of the test run to a file at the end of the run, e.g. to post it as
+/// a comment on a pull request. The report starts with a table of how many tests passed,
+/// failed or were ignored. Every failed test follows in a collapsible `<details>` block with
+/// the command that failed, the diffs of its output files and the diagnostics that no
+/// annotation matched.
+///
+/// Diffs longer than `max_diff_len` bytes are truncated, with a note pointing at the actual
+/// output saved in [`Config::failure_artifacts_dir`](crate::Config::failure_artifacts_dir).
+pub struct Markdown {
+    out: Arc<Mutex<std::fs::File>>,
+    max_diff_len: usize,
+    /// The failed tests by their path and revision.
+    failures: Arc<Mutex<HashMap<(PathBuf, String), MarkdownFailure>>>,
+}
+
+/// What [`Markdown`] learns about a failed test before its errors are reported.
+struct MarkdownFailure {
+    command: String,
+    artifacts: Vec<PathBuf>,
+}
+
+impl Markdown {
+    /// Write the report to the file at `path`, truncating diffs after `max_diff_len` bytes.
+    pub fn file(path: &Path, max_diff_len: usize) -> std::io::Result<Self> {
+        Ok(Self {
+            out: Arc::new(Mutex::new(std::fs::File::create(path)?)),
+            max_diff_len,
+            failures: Default::default(),
+        })
+    }
+}
+
+impl StatusEmitter for Markdown {
+    fn failed_test<'a>(
+        &'a self,
+        revision: &'a str,
+        path: &'a Path,
+        cmd: &'a Command,
+        _stderr: &'a [u8],
+    ) -> Box<dyn Debug + 'a> {
+        self.failures.lock().unwrap().insert(
+            (path.to_owned(), revision.to_owned()),
+            MarkdownFailure {
+                command: display_command(cmd),
+                artifacts: vec![],
+            },
+        );
+        Box::new(())
+    }
+
+    fn failure_artifacts(&self, path: &Path, revision: &str, artifacts: &[PathBuf]) {
+        if let Some(failure) = self
+            .failures
+            .lock()
+            .unwrap()
+            .get_mut(&(path.to_owned(), revision.to_owned()))
+        {
+            failure.artifacts = artifacts.to_vec();
+        }
+    }
+
+    fn finalize(
+        &self,
+        failed: usize,
+        succeeded: usize,
+        ignored: usize,
+        filtered: usize,
+    ) -> Box<dyn Summary> {
+        struct Summarizer {
+            out: Arc<Mutex<std::fs::File>>,
+            max_diff_len: usize,
+            failures: Arc<Mutex<HashMap<(PathBuf, String), MarkdownFailure>>>,
+            report: String,
+            flaky: Vec<String>,
+            bless_command: Option<String>,
+            counts: [(&'static str, usize); 5],
+        }
+
+        impl Summary for Summarizer {
+            fn test_failure(&mut self, path: &Path, revision: &str, errors: &Errors) {
+                let failure = self
+                    .failures
+                    .lock()
+                    .unwrap()
+                    .remove(&(path.to_owned(), revision.to_owned()));
+                let out = &mut self.report;
+                write!(
+                    out,
+                    "<details>\n<summary><code>{}</code>",
+                    escape_html(&path.display().to_string())
+                )
+                .unwrap();
+                if !revision.is_empty() {
+                    write!(out, " (revision <code>{}</code>)", escape_html(revision)).unwrap();
+                }
+                writeln!(out, "</summary>\n").unwrap();
+                let artifacts = match failure {
+                    Some(MarkdownFailure { command, artifacts }) => {
+                        writeln!(out, "Command:\n\n{}", markdown_fence(&command, "")).unwrap();
+                        artifacts
+                    }
+                    None => vec![],
+                };
+                for error in errors {
+                    markdown_error(error, &artifacts, self.max_diff_len, out);
+                }
+                writeln!(out, "</details>\n").unwrap();
+            }
+
+            fn test_warnings(&mut self, _path: &Path, _revision: &str, warnings: &Errors) {
+                // The last count is the one of the warnings.
+                self.counts[4].1 += warnings.len();
+            }
+
+            fn test_flaky(&mut self, path: &Path, revision: &str, attempts: usize) {
+                let revision = if revision.is_empty() {
+                    String::new()
+                } else {
+                    format!(" (revision `{revision}`)")
+                };
+                self.flaky.push(format!(
+                    "* `{}`{revision} passed on attempt {attempts}",
+                    path.display()
+                ));
+            }
+
+            fn bless_command(&mut self, command: &str) {
+                self.bless_command = Some(command.to_owned());
+            }
+        }
+
+        impl Drop for Summarizer {
+            fn drop(&mut self) {
+                let failed = self.counts[1].1;
+                let mut report = format!(
+                    "## Test results: {}\n\n| Outcome | Tests |\n| --- | ---: |\n",
+                    if failed == 0 { "ok" } else { "FAILED" }
+                );
+                for (outcome, count) in self.counts {
+                    writeln!(report, "| {outcome} | {count} |").unwrap();
+                }
+                if !self.flaky.is_empty() {
+                    writeln!(report, "\n### Flaky tests\n\n{}", self.flaky.join("\n")).unwrap();
+                }
+                if !self.report.is_empty() {
+                    write!(report, "\n### Failures\n\n{}", self.report).unwrap();
+                }
+                if let Some(command) = &self.bless_command {
+                    writeln!(
+                        report,
+                        "Run `{command}` to update the output of all failed tests."
+                    )
+                    .unwrap();
+                }
+                if let Err(err) = self.out.lock().unwrap().write_all(report.as_bytes()) {
+                    eprintln!("could not write the Markdown report: {err}");
+                }
+            }
+        }
+
+        Box::new(Summarizer {
+            out: self.out.clone(),
+            max_diff_len: self.max_diff_len,
+            failures: self.failures.clone(),
+            report: String::new(),
+            flaky: vec![],
+            bless_command: None,
+            counts: [
+                ("Passed", succeeded),
+                ("Failed", failed),
+                ("Ignored", ignored),
+                ("Filtered out", filtered),
+                ("Warnings", 0),
+            ],
+        })
+    }
+}
+
+/// Render an error as Markdown paragraphs, for [`Markdown`].
+fn markdown_error(error: &Error, artifacts: &[PathBuf], max_diff_len: usize, out: &mut String) {
+    let diagnostics = |out: &mut String, msgs: &[Message]| {
+        for msg in msgs {
+            let line = match &msg.location {
+                Some(location) => format!(" (line {})", location.line),
+                None => String::new(),
+            };
+            writeln!(out, "* {:?}{line}: {}", msg.level, msg.message).unwrap();
+        }
+        writeln!(out).unwrap();
+    };
+    match error {
+        Error::OutputDiffers {
+            path,
+            actual,
+            expected,
+            bless_command,
+            context,
+        } => {
+            writeln!(
+                out,
+                "`{}` differs from the actual output, run `{bless_command}` to update it:\n",
+                path.display()
+            )
+            .unwrap();
+            let diff = render_diff(expected, actual, *context, false);
+            let shown = truncate_at_line(&diff, max_diff_len);
+            writeln!(out, "{}", markdown_fence(shown, "diff")).unwrap();
+            if shown.len() < diff.len() {
+                // Only the artifact of this output file, if the test saved any.
+                let artifacts: Vec<_> = artifacts
+                    .iter()
+                    .filter(|artifact| artifact.file_name() == path.file_name())
+                    .map(|artifact| format!("`{}`", artifact.display()))
+                    .collect();
+                if artifacts.is_empty() {
+                    writeln!(
+                        out,
+                        "The diff was truncated after {} bytes. Set `Config::failure_artifacts_dir` to keep the actual output of failed tests.\n",
+                        shown.len()
+                    )
+                    .unwrap();
+                } else {
+                    writeln!(
+                        out,
+                        "The diff was truncated after {} bytes, the actual output is saved to {}.\n",
+                        shown.len(),
+                        artifacts.join(", ")
+                    )
+                    .unwrap();
+                }
+            }
+        }
+        Error::Nondeterministic {
+            stream,
+            first,
+            second,
+            context,
+        } => {
+            writeln!(out, "`{stream}` differed between two runs:\n").unwrap();
+            let diff = render_diff(first, second, *context, false);
+            let shown = truncate_at_line(&diff, max_diff_len);
+            writeln!(out, "{}", markdown_fence(shown, "diff")).unwrap();
+            if shown.len() < diff.len() {
+                writeln!(out, "The diff was truncated after {} bytes.\n", shown.len()).unwrap();
+            }
+        }
+        Error::FixedOutputDiffers { msgs, diff, .. } => {
+            writeln!(out, "{}:\n", json_error(error)["message"].as_str().unwrap()).unwrap();
+            diagnostics(out, msgs);
+            markdown_error(diff, artifacts, max_diff_len, out);
+        }
+        Error::Warning(warning) => markdown_error(warning, artifacts, max_diff_len, out),
+        Error::Aux { path, errors, .. } => {
+            writeln!(out, "Building the aux file `{}` failed:\n", path.display()).unwrap();
+            for error in errors {
+                markdown_error(error, artifacts, max_diff_len, out);
+            }
+        }
+        Error::Defaults { path, errors } => {
+            writeln!(out, "The defaults file `{}` is invalid:\n", path.display()).unwrap();
+            for error in errors {
+                markdown_error(error, artifacts, max_diff_len, out);
+            }
+        }
+        Error::PatternNotFound { nearby, .. } => {
+            let message = json_error(error)["message"].as_str().unwrap().to_owned();
+            if nearby.is_empty() {
+                writeln!(out, "{message}\n").unwrap();
+            } else {
+                writeln!(out, "{message}, unmatched diagnostics nearby:\n").unwrap();
+                for (_, msgs) in nearby {
+                    diagnostics(out, msgs);
+                }
+            }
+        }
+        Error::ErrorsWithoutPattern { msgs, .. }
+        | Error::FixedOutputNotClean { msgs }
+        | Error::RustfixIterationLimit { msgs, .. } => {
+            writeln!(out, "{}:\n", json_error(error)["message"].as_str().unwrap()).unwrap();
+            diagnostics(out, msgs);
+        }
+        _ => writeln!(out, "{}\n", json_error(error)["message"].as_str().unwrap()).unwrap(),
+    }
+}
+
+/// The start of `s` up to the last line break within its first `max_len` bytes, or all of `s`
+/// if it is short enough.
+fn truncate_at_line(s: &str, max_len: usize) -> &str {
+    if s.len() <= max_len {
+        return s;
+    }
+    let mut end = max_len;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    let end = s[..end].rfind('\n').map_or(end, |newline| newline + 1);
+    &s[..end]
+}
+
+/// A fenced code block with `content`, using more backticks than any run of backticks in it.
+fn markdown_fence(content: &str, lang: &str) -> String {
+    let longest = content.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest.max(2) + 1);
+    let newline = if content.ends_with('\n') { "" } else { "\n" };
+    format!("{fence}{lang}\n{content}{newline}{fence}\n")
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Calls `f` with the expected output file and bless command of every
+/// [`Error::OutputDiffers`] in `error`.
+fn collect_output_differs(error: &Error, f: &mut dyn FnMut(&Path, &str)) {
+    match error {
+        Error::OutputDiffers {
+            path,
+            bless_command,
+            ..
+        } => f(path, bless_command),
+        Error::FixedOutputDiffers { diff, .. } => collect_output_differs(diff, f),
+        Error::Warning(error) => collect_output_differs(error, f),
+        _ => {}
+    }
+}
+
+/// The diagnostics of an error, as JSON.
+fn json_messages(msgs: &[Message]) -> serde_json::Value {
+    msgs.iter()
+        .map(|msg| {
+            serde_json::json!({
+                "level": format!("{:?}", msg.level),
+                "message": msg.message,
+                "code": msg.code,
+                "line": msg.location.as_ref().map(|location| location.line),
+                "column": msg.column,
+            })
+        })
+        .collect()
+}
+
+/// An error as JSON, see [`Json`].
+fn json_error(error: &Error) -> serde_json::Value {
+    use serde_json::json;
+    match error {
+        Error::ExitStatus {
+            mode,
+            status,
+            expected,
+        } => json!({
+            "kind": "exit_status",
+            "message": format!("{mode} test got {status}, but expected {expected}"),
+            "status": status.code(),
+            "expected": expected,
+        }),
+        Error::PatternNotFound {
+            pattern,
+            definition_line,
+            line,
+            nearby,
+        } => json!({
+            "kind": "pattern_not_found",
+            "message": format!("`{pattern}` not found in diagnostics"),
+            "pattern": pattern.to_string(),
+            "definition_line": definition_line,
+            "line": line,
+            "nearby": nearby
+                .iter()
+                .map(|(line, msgs)| json!({ "line": line, "diagnostics": json_messages(msgs) }))
+                .collect::<Vec<_>>(),
+        }),
+        Error::CodeNotFound {
+            pattern,
+            definition_line,
+            codes,
+        } => json!({
+            "kind": "code_not_found",
+            "message": format!("`{pattern}` did not match any diagnostic code"),
+            "pattern": pattern.to_string(),
+            "definition_line": definition_line,
+            "codes": codes,
+        }),
+        Error::ColumnMismatch {
+            pattern,
+            definition_line,
+            expected,
+            found,
+        } => json!({
+            "kind": "column_mismatch",
+            "message": format!("`{pattern}` expected column {expected}"),
+            "pattern": pattern.to_string(),
+            "definition_line": definition_line,
+            "expected": expected,
+            "found": found,
+        }),
+        Error::PatternCountMismatch {
+            pattern,
+            definition_line,
+            expected,
+            found,
+        } => json!({
+            "kind": "pattern_count_mismatch",
+            "message": format!("`{pattern}` expected {expected} occurrences, found {found}"),
+            "pattern": pattern.to_string(),
+            "definition_line": definition_line,
+            "expected": expected,
+            "found": found,
+        }),
+        Error::AnnotationOutOfOrder {
+            pattern,
+            definition_line,
+            preceding_pattern,
+            preceding_definition_line,
+        } => json!({
+            "kind": "annotation_out_of_order",
+            "message": format!("`{pattern}` matched a diagnostic that was emitted before the one matched by `{preceding_pattern}`"),
+            "pattern": pattern.to_string(),
+            "definition_line": definition_line,
+            "preceding_pattern": preceding_pattern.to_string(),
+            "preceding_definition_line": preceding_definition_line,
+        }),
+        Error::Warning(error) => json!({
+            "kind": "warning",
+            "message": "a problem that did not fail the test",
+            "error": json_error(error),
+        }),
+        Error::FixedOutputNotClean { msgs } => json!({
+            "kind": "fixed_output_not_clean",
+            "message": "the code fixed by rustfix still emitted diagnostics",
+            "diagnostics": json_messages(msgs),
+        }),
+        Error::FixedOutputDiffers {
+            applied,
+            iterations,
+            msgs,
+            diff,
+        } => json!({
+            "kind": "fixed_output_differs",
+            "message": format!("rustfix applied `{applied}` suggestions in {iterations} iterations"),
+            "applied": applied.to_string(),
+            "iterations": iterations,
+            "diagnostics": json_messages(msgs),
+            "diff": json_error(diff),
+        }),
+        Error::RustfixIterationLimit { iterations, msgs } => json!({
+            "kind": "rustfix_iteration_limit",
+            "message": format!("rustfix still had suggestions after {iterations} iterations"),
+            "iterations": iterations,
+            "diagnostics": json_messages(msgs),
+        }),
+        Error::SuggestionConflict {
+            first,
+            first_span,
+            second,
+            second_span,
+        } => json!({
+            "kind": "suggestion_conflict",
+            "message": "rustfix suggestions overlap",
+            "first": first,
+            "first_span": first_span,
+            "second": second,
+            "second_span": second_span,
+        }),
+        Error::NoPatternsFound => json!({
+            "kind": "no_patterns_found",
+            "message": "no error patterns found in fail test",
+        }),
+        Error::PatternFoundInPassTest => json!({
+            "kind": "pattern_found_in_pass_test",
+            "message": "error pattern found in pass test",
+        }),
+        Error::OutputDiffers {
+            path,
+            actual,
+            expected,
+            bless_command,
+            context,
+        } => json!({
+            "kind": "output_differs",
+            "message": "actual output differed from expected",
+            "expected_file": path.display().to_string(),
+            "bless_command": bless_command,
+            "expected_len": expected.len(),
+            "actual_len": actual.len(),
+            "whitespace_only": strip_trailing_whitespace(expected) == strip_trailing_whitespace(actual),
+            "diff": render_diff(expected, actual, *context, false),
+        }),
+        Error::Nondeterministic {
+            stream,
+            first,
+            second,
+            context,
+        } => json!({
+            "kind": "nondeterministic",
+            "message": format!("{stream} differed between two runs"),
+            "stream": stream,
+            "diff": render_diff(first, second, *context, false),
+        }),
+        Error::ErrorsWithoutPattern { msgs, path } => json!({
+            "kind": "errors_without_pattern",
+            "message": "there were unmatched diagnostics",
+            "file": path.as_ref().map(|(path, _)| path.display().to_string()),
+            "line": path.as_ref().map(|(_, line)| line),
+            "diagnostics": json_messages(msgs),
+        }),
+        Error::InvalidComment { msg, line } => json!({
+            "kind": "invalid_comment",
+            "message": msg,
+            "line": line,
+        }),
+        Error::Command { kind, status } => json!({
+            "kind": "command",
+            "message": format!("{kind} failed with {status}"),
+            "command": kind,
+            "status": status.code(),
+        }),
+        Error::Bug(msg) => json!({
+            "kind": "bug",
+            "message": msg,
+        }),
+        Error::FilterFnPanicked {
+            kind,
+            index,
+            message,
+        } => json!({
+            "kind": "filter_fn_panicked",
+            "message": message,
+            "output": kind,
+            "index": index,
+        }),
+        Error::OutputTooLarge {
+            stream,
+            limit,
+            head,
+            tail,
+        } => json!({
+            "kind": "output_too_large",
+            "message": format!("the {stream} exceeded the limit of {limit} bytes"),
+            "stream": stream,
+            "limit": limit,
+            "head": escape_bytes(head),
+            "tail": escape_bytes(tail),
+        }),
+        Error::InternalCompilerError {
+            message,
+            query_stack,
+        } => json!({
+            "kind": "internal_compiler_error",
+            "message": message,
+            "query_stack": query_stack,
+        }),
+        Error::StaleOutputFile => json!({
+            "kind": "stale_output_file",
+            "message": "no test produces this output file",
+        }),
+        Error::TimingRegression {
+            baseline,
+            actual,
+            samples,
+        } => json!({
+            "kind": "timing_regression",
+            "message": format!(
+                "the test took {:.2}s, but only {:.2}s in the timing baseline",
+                actual.as_secs_f64(),
+                baseline.as_secs_f64()
+            ),
+            "baseline_secs": baseline.as_secs_f64(),
+            "actual_secs": actual.as_secs_f64(),
+            "samples": samples,
+        }),
+        Error::BlessFailed { path, message } => json!({
+            "kind": "bless_failed",
+            "message": message,
+            "expected_file": path.display().to_string(),
+        }),
+        Error::Io { path, message } => json!({
+            "kind": "io",
+            "message": message,
+            "file": path.display().to_string(),
+        }),
+        Error::AuxBin {
+            path,
+            status,
+            stdout,
+            stderr,
+        } => json!({
+            "kind": "aux_bin",
+            "message": match status {
+                Some(status) => format!("aux binary `{}` exited with {status}", path.display()),
+                None => format!("output of aux binary `{}`", path.display()),
+            },
+            "aux_file": path.display().to_string(),
+            "status": status.and_then(|status| status.code()),
+            "stdout": escape_bytes(stdout),
+            "stderr": escape_bytes(stderr),
+        }),
+        Error::TestHook { hook, message } => json!({
+            "kind": "test_hook",
+            "message": format!("the `{hook}` hook failed: {message}"),
+            "hook": hook,
+        }),
+        Error::CustomComment {
+            name,
+            message,
+            line,
+        } => json!({
+            "kind": "custom_comment",
+            "message": format!("custom comment `{name}` failed: {message}"),
+            "name": name,
+            "line": line,
+        }),
+        Error::CustomBuild {
+            name,
+            message,
+            line,
+        } => json!({
+            "kind": "custom_build",
+            "message": message,
+            "name": name,
+            "line": line,
+        }),
+        Error::Aux { path, errors, line } => json!({
+            "kind": "aux",
+            "message": format!("aux build of `{}` failed", path.display()),
+            "aux_file": path.display().to_string(),
+            "line": line,
+            "errors": errors.iter().map(json_error).collect::<Vec<_>>(),
+        }),
+        Error::Defaults { path, errors } => json!({
+            "kind": "defaults",
+            "message": format!("the defaults file `{}` is invalid", path.display()),
+            "defaults_file": path.display().to_string(),
+            "errors": errors.iter().map(json_error).collect::<Vec<_>>(),
+        }),
+    }
+}
+
+impl<T: StatusEmitter, U: StatusEmitter> StatusEmitter for (T, U) {
+    fn failed_test<'a>(
+        &'a self,
+        revision: &'a str,
+        path: &'a Path,
+        cmd: &'a Command,
+        stderr: &'a [u8],
+    ) -> Box<dyn Debug + 'a> {
+        Box::new((
+            self.0.failed_test(revision, path, cmd, stderr),
+            self.1.failed_test(revision, path, cmd, stderr),
+        ))
+    }
 
     fn test_result(&mut self, path: &Path, revision: &str, result: &TestResult) {
         self.0.test_result(path, revision, result);
         self.1.test_result(path, revision, result);
     }
 
+    fn test_timings(&mut self, path: &Path, revision: &str, timings: &Timings) {
+        self.0.test_timings(path, revision, timings);
+        self.1.test_timings(path, revision, timings);
+    }
+
+    fn failure_artifacts(&self, path: &Path, revision: &str, artifacts: &[PathBuf]) {
+        self.0.failure_artifacts(path, revision, artifacts);
+        self.1.failure_artifacts(path, revision, artifacts);
+    }
+
+    fn removed_outdated_files(&mut self, path: &Path, files: &[PathBuf]) {
+        self.0.removed_outdated_files(path, files);
+        self.1.removed_outdated_files(path, files);
+    }
+
+    fn shard(&mut self, index: usize, count: usize, assigned: usize) {
+        self.0.shard(index, count, assigned);
+        self.1.shard(index, count, assigned);
+    }
+
+    fn excluded_tests(&mut self, by_filters: usize, by_skip: usize) {
+        self.0.excluded_tests(by_filters, by_skip);
+        self.1.excluded_tests(by_filters, by_skip);
+    }
+
+    fn reclaimed(&mut self, bytes: u64) {
+        self.0.reclaimed(bytes);
+        self.1.reclaimed(bytes);
+    }
+
+    fn test_roots(&mut self, roots: &[(PathBuf, usize, usize, usize, usize)]) {
+        self.0.test_roots(roots);
+        self.1.test_roots(roots);
+    }
+
+    fn slowest_tests(&mut self, slowest: &[(PathBuf, String, Timings)], total: &Timings) {
+        self.0.slowest_tests(slowest, total);
+        self.1.slowest_tests(slowest, total);
+    }
+
+    fn ignored_tests(&mut self, ignored: &[(String, Vec<(PathBuf, String, usize)>)]) {
+        self.0.ignored_tests(ignored);
+        self.1.ignored_tests(ignored);
+    }
+
     fn finalize(
         &self,
         failures: usize,
@@ -558,6 +2733,42 @@ impl<T: StatusEmitter + ?Sized> StatusEmitter for Box<T> {
         (**self).test_result(path, revision, result);
     }
 
+    fn test_timings(&mut self, path: &Path, revision: &str, timings: &Timings) {
+        (**self).test_timings(path, revision, timings)
+    }
+
+    fn failure_artifacts(&self, path: &Path, revision: &str, artifacts: &[PathBuf]) {
+        (**self).failure_artifacts(path, revision, artifacts)
+    }
+
+    fn removed_outdated_files(&mut self, path: &Path, files: &[PathBuf]) {
+        (**self).removed_outdated_files(path, files)
+    }
+
+    fn shard(&mut self, index: usize, count: usize, assigned: usize) {
+        (**self).shard(index, count, assigned)
+    }
+
+    fn excluded_tests(&mut self, by_filters: usize, by_skip: usize) {
+        (**self).excluded_tests(by_filters, by_skip)
+    }
+
+    fn reclaimed(&mut self, bytes: u64) {
+        (**self).reclaimed(bytes)
+    }
+
+    fn test_roots(&mut self, roots: &[(PathBuf, usize, usize, usize, usize)]) {
+        (**self).test_roots(roots)
+    }
+
+    fn slowest_tests(&mut self, slowest: &[(PathBuf, String, Timings)], total: &Timings) {
+        (**self).slowest_tests(slowest, total)
+    }
+
+    fn ignored_tests(&mut self, ignored: &[(String, Vec<(PathBuf, String, usize)>)]) {
+        (**self).ignored_tests(ignored)
+    }
+
     fn finalize(
         &self,
         failures: usize,
@@ -574,4 +2785,19 @@ impl Summary for (Box<dyn Summary>, Box<dyn Summary>) {
         self.0.test_failure(path, revision, errors);
         self.1.test_failure(path, revision, errors);
     }
+
+    fn test_warnings(&mut self, path: &Path, revision: &str, warnings: &Errors) {
+        self.0.test_warnings(path, revision, warnings);
+        self.1.test_warnings(path, revision, warnings);
+    }
+
+    fn test_flaky(&mut self, path: &Path, revision: &str, attempts: usize) {
+        self.0.test_flaky(path, revision, attempts);
+        self.1.test_flaky(path, revision, attempts);
+    }
+
+    fn bless_command(&mut self, command: &str) {
+        self.0.bless_command(command);
+        self.1.bless_command(command);
+    }
 }
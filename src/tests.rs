@@ -1,7 +1,10 @@
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 use crate::rustc_stderr::Level;
+use crate::rustc_stderr::Location;
 use crate::rustc_stderr::Message;
+use crate::rustc_stderr::SpanLabel;
 
 use super::*;
 
@@ -22,7 +25,7 @@ fn main() {
     let _x: &i32 = unsafe { mem::transmute(16usize) }; //~ ERROR: encountered a dangling reference (address $HEX is unallocated)
 }
     ";
-    let comments = Comments::parse(s).unwrap();
+    let comments = Comments::parse(s, "//").unwrap();
     let mut errors = vec![];
     let config = config();
     let messages = vec![
@@ -31,6 +34,11 @@ fn main() {
             Message {
                 message:"Undefined Behavior: type validation failed: encountered a dangling reference (address 0x10 is unallocated)".to_string(),
                 level: Level::Error,
+                column: None,
+                code: None,
+                location: None,
+                labels: vec![],
+                expansions: vec![],
             }
         ]
     ];
@@ -62,13 +70,18 @@ fn main() {
     let _x: &i32 = unsafe { mem::transmute(16usize) }; //~ ERROR: encountered a dangling reference (address 0x10 is unallocated)
 }
     ";
-    let comments = Comments::parse(s).unwrap();
+    let comments = Comments::parse(s, "//").unwrap();
     let config = config();
     {
         let messages = vec![vec![], vec![], vec![], vec![], vec![], vec![
                 Message {
                     message: "Undefined Behavior: type validation failed: encountered a dangling reference (address 0x10 is unallocated)".to_string(),
                     level: Level::Error,
+                    column: None,
+                    code: None,
+                    location: None,
+                    labels: vec![],
+                    expansions: vec![],
                 }
             ]
         ];
@@ -94,6 +107,11 @@ fn main() {
                 Message {
                     message: "Undefined Behavior: type validation failed: encountered a dangling reference (address 0x10 is unallocated)".to_string(),
                     level: Level::Error,
+                    column: None,
+                    code: None,
+                    location: None,
+                    labels: vec![],
+                    expansions: vec![],
                 }
             ]
         ];
@@ -125,6 +143,11 @@ fn main() {
                 Message {
                     message: "Undefined Behavior: type validation failed: encountered a dangling reference (address 0x10 is unallocated)".to_string(),
                     level: Level::Note,
+                    column: None,
+                    code: None,
+                    location: None,
+                    labels: vec![],
+                    expansions: vec![],
                 }
             ]
         ];
@@ -158,7 +181,7 @@ fn main() {
     //~^ ERROR: encountered a dangling reference (address 0x10 is unallocated)
 }
     ";
-    let comments = Comments::parse(s).unwrap();
+    let comments = Comments::parse(s, "//").unwrap();
     let config = config();
     let messages = vec![
         vec![], vec![], vec![], vec![], vec![],
@@ -166,6 +189,11 @@ fn main() {
             Message {
                 message: "Undefined Behavior: type validation failed: encountered a dangling reference (address 0x10 is unallocated)".to_string(),
                 level: Level::Error,
+                column: None,
+                code: None,
+                location: None,
+                labels: vec![],
+                expansions: vec![],
             }
         ]
     ];
@@ -196,7 +224,7 @@ fn main() {
     let _x: &i32 = unsafe { mem::transmute(16usize) }; //~ ERROR: encountered a dangling reference (address 0x10 is unallocated)
 }
     ";
-    let comments = Comments::parse(s).unwrap();
+    let comments = Comments::parse(s, "//").unwrap();
     let config = config();
     let messages = vec![
         vec![], vec![], vec![], vec![], vec![],
@@ -204,10 +232,20 @@ fn main() {
             Message {
                 message: "Undefined Behavior: type validation failed: encountered a dangling reference (address 0x10 is unallocated)".to_string(),
                 level: Level::Error,
+                column: None,
+                code: None,
+                location: None,
+                labels: vec![],
+                expansions: vec![],
             },
             Message {
                 message: "Undefined Behavior: type validation failed: encountered a dangling reference (address 0x10 is unallocated)".to_string(),
                 level: Level::Error,
+                column: None,
+                code: None,
+                location: None,
+                labels: vec![],
+                expansions: vec![],
             }
         ]
     ];
@@ -239,7 +277,7 @@ fn main() {
     //~^ WARN: cake
 }
     ";
-    let comments = Comments::parse(s).unwrap();
+    let comments = Comments::parse(s, "//").unwrap();
     let config = config();
     let messages= vec![
         vec![],
@@ -251,14 +289,29 @@ fn main() {
             Message {
                 message: "Undefined Behavior: type validation failed: encountered a dangling reference (address 0x10 is unallocated)".to_string(),
                 level: Level::Error,
+                column: None,
+                code: None,
+                location: None,
+                labels: vec![],
+                expansions: vec![],
             },
             Message {
                 message: "kaboom".to_string(),
                 level: Level::Warn,
+                column: None,
+                code: None,
+                location: None,
+                labels: vec![],
+                expansions: vec![],
             },
             Message {
                 message: "cake".to_string(),
                 level: Level::Warn,
+                column: None,
+                code: None,
+                location: None,
+                labels: vec![],
+                expansions: vec![],
             },
         ],
     ];
@@ -281,7 +334,12 @@ fn main() {
             [Message {
                 message,
                 level: Level::Warn,
-            }] if message == "kaboom" => {}
+                column: None,
+                code: None,
+                location: None,
+                labels,
+                ..
+            }] if message == "kaboom" && labels.is_empty() => {}
             _ => panic!("{:#?}", msgs),
         },
         _ => panic!("{:#?}", errors),
@@ -298,7 +356,7 @@ fn main() {
     //~^ WARN: cake
 }
     ";
-    let comments = Comments::parse(s).unwrap();
+    let comments = Comments::parse(s, "//").unwrap();
     let config = config();
     let messages = vec![
         vec![],
@@ -310,14 +368,29 @@ fn main() {
             Message {
                 message: "Undefined Behavior: type validation failed: encountered a dangling reference (address 0x10 is unallocated)".to_string(),
                 level: Level::Error,
+                column: None,
+                code: None,
+                location: None,
+                labels: vec![],
+                expansions: vec![],
             },
             Message {
                 message: "kaboom".to_string(),
                 level: Level::Warn,
+                column: None,
+                code: None,
+                location: None,
+                labels: vec![],
+                expansions: vec![],
             },
             Message {
                 message: "cake".to_string(),
                 level: Level::Warn,
+                column: None,
+                code: None,
+                location: None,
+                labels: vec![],
+                expansions: vec![],
             },
         ],
     ];
@@ -336,3 +409,4298 @@ fn main() {
         _ => panic!("{:#?}", errors),
     }
 }
+
+#[test]
+fn counted_pattern() {
+    let s = r"
+fn main() {
+    foo!(); //~ ERROR*3: mismatched types
+}
+    ";
+    let comments = Comments::parse(s, "//").unwrap();
+    let msg = || Message {
+        message: "mismatched types".to_string(),
+        level: Level::Error,
+        column: None,
+        code: None,
+        location: None,
+        labels: vec![],
+        expansions: vec![],
+    };
+    let mut config = config();
+    {
+        let messages = vec![vec![], vec![], vec![], vec![msg(), msg(), msg()]];
+        let mut errors = vec![];
+        check_annotations(
+            messages,
+            vec![],
+            Path::new("moobar"),
+            &mut errors,
+            &config,
+            "",
+            &comments,
+        );
+        match &errors[..] {
+            [] => {}
+            _ => panic!("{:#?}", errors),
+        }
+    }
+
+    // too few diagnostics
+    {
+        let messages = vec![vec![], vec![], vec![], vec![msg()]];
+        let mut errors = vec![];
+        check_annotations(
+            messages,
+            vec![],
+            Path::new("moobar"),
+            &mut errors,
+            &config,
+            "",
+            &comments,
+        );
+        match &errors[..] {
+            [Error::PatternCountMismatch {
+                definition_line: 3,
+                expected: 3,
+                found: 1,
+                ..
+            }] => {}
+            _ => panic!("{:#?}", errors),
+        }
+    }
+
+    // too many diagnostics, the surplus is reported as unmatched
+    {
+        let messages = vec![vec![], vec![], vec![], vec![msg(), msg(), msg(), msg()]];
+        let mut errors = vec![];
+        check_annotations(
+            messages,
+            vec![],
+            Path::new("moobar"),
+            &mut errors,
+            &config,
+            "",
+            &comments,
+        );
+        match &errors[..] {
+            [Error::ErrorsWithoutPattern {
+                path: Some((_, 3)),
+                msgs,
+            }] if msgs.len() == 1 => {}
+            _ => panic!("{:#?}", errors),
+        }
+    }
+
+    // too many diagnostics in strict mode
+    config.strict_annotation_counts = true;
+    {
+        let messages = vec![vec![], vec![], vec![], vec![msg(), msg(), msg(), msg()]];
+        let mut errors = vec![];
+        check_annotations(
+            messages,
+            vec![],
+            Path::new("moobar"),
+            &mut errors,
+            &config,
+            "",
+            &comments,
+        );
+        match &errors[..] {
+            [Error::PatternCountMismatch {
+                expected: 3,
+                found: 4,
+                ..
+            }] => {}
+            _ => panic!("{:#?}", errors),
+        }
+    }
+}
+
+#[test]
+fn annotation_order() {
+    let s = r"
+//@check-annotation-order
+fn main() {
+    foo!(); //~ ERROR: first
+    //~^ ERROR: second
+}
+    ";
+    let comments = Comments::parse(s, "//").unwrap();
+    let config = config();
+    let msg = |message: &str| Message {
+        message: message.to_string(),
+        level: Level::Error,
+        column: None,
+        code: None,
+        location: None,
+        labels: vec![],
+        expansions: vec![],
+    };
+    {
+        let messages = vec![
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+            vec![msg("first"), msg("second")],
+        ];
+        let mut errors = vec![];
+        check_annotations(
+            messages,
+            vec![],
+            Path::new("moobar"),
+            &mut errors,
+            &config,
+            "",
+            &comments,
+        );
+        match &errors[..] {
+            [] => {}
+            _ => panic!("{:#?}", errors),
+        }
+    }
+
+    {
+        let messages = vec![
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+            vec![msg("second"), msg("first")],
+        ];
+        let mut errors = vec![];
+        check_annotations(
+            messages,
+            vec![],
+            Path::new("moobar"),
+            &mut errors,
+            &config,
+            "",
+            &comments,
+        );
+        match &errors[..] {
+            [Error::AnnotationOutOfOrder {
+                definition_line: 5,
+                preceding_definition_line: 4,
+                ..
+            }] => {}
+            _ => panic!("{:#?}", errors),
+        }
+    }
+}
+
+#[test]
+fn located_error_in_other_file() {
+    let s = r"
+//@error-in-other-file: @aux/helper.rs:12: cannot find type
+//@error-in-other-file: cannot find type
+use std::mem;
+    ";
+    let comments = Comments::parse(s, "//").unwrap();
+    let config = config();
+    let msg = |file: &str, line: usize| Message {
+        message: "cannot find type `Foo` in this scope".to_string(),
+        level: Level::Error,
+        column: None,
+        code: None,
+        location: Some(Location {
+            file: file.into(),
+            line,
+        }),
+        labels: vec![],
+        expansions: vec![],
+    };
+    {
+        let messages_from_unknown_file_or_line =
+            vec![msg("other.rs", 3), msg("tests/aux/helper.rs", 12)];
+        let mut errors = vec![];
+        check_annotations(
+            vec![],
+            messages_from_unknown_file_or_line,
+            Path::new("moobar"),
+            &mut errors,
+            &config,
+            "",
+            &comments,
+        );
+        match &errors[..] {
+            [] => {}
+            _ => panic!("{:#?}", errors),
+        }
+    }
+
+    {
+        let messages_from_unknown_file_or_line =
+            vec![msg("tests/aux/helper.rs", 11), msg("other.rs", 3)];
+        let mut errors = vec![];
+        check_annotations(
+            vec![],
+            messages_from_unknown_file_or_line,
+            Path::new("moobar"),
+            &mut errors,
+            &config,
+            "",
+            &comments,
+        );
+        match &errors[..] {
+            [Error::PatternNotFound {
+                definition_line: 2, ..
+            }, Error::ErrorsWithoutPattern { path: None, msgs }]
+                if msgs.len() == 1 => {}
+            _ => panic!("{:#?}", errors),
+        }
+    }
+}
+
+#[test]
+fn code_pattern() {
+    let s = r"
+fn main() {
+    foo.clone(); //~ WARN [clippy::needless_clone]
+}
+    ";
+    let comments = Comments::parse(s, "//").unwrap();
+    let config = config();
+    let msg = |code: &str| Message {
+        message: "redundant clone".to_string(),
+        level: Level::Warn,
+        column: None,
+        code: Some(code.to_string()),
+        location: None,
+        labels: vec![],
+        expansions: vec![],
+    };
+    {
+        let messages = vec![vec![], vec![], vec![], vec![msg("clippy::needless_clone")]];
+        let mut errors = vec![];
+        check_annotations(
+            messages,
+            vec![],
+            Path::new("moobar"),
+            &mut errors,
+            &config,
+            "",
+            &comments,
+        );
+        match &errors[..] {
+            [] => {}
+            _ => panic!("{:#?}", errors),
+        }
+    }
+
+    {
+        let messages = vec![vec![], vec![], vec![], vec![msg("clippy::redundant_clone")]];
+        let mut errors = vec![];
+        check_annotations(
+            messages,
+            vec![],
+            Path::new("moobar"),
+            &mut errors,
+            &config,
+            "",
+            &comments,
+        );
+        match &errors[..] {
+            [Error::CodeNotFound {
+                definition_line: 3,
+                codes,
+                ..
+            }, Error::ErrorsWithoutPattern { path: Some(_), .. }]
+                if codes == &["clippy::redundant_clone"] => {}
+            _ => panic!("{:#?}", errors),
+        }
+    }
+}
+
+#[test]
+fn column_pattern() {
+    let s = r#"
+fn main() {
+    let _x: (u32, u32) = ("", ""); //~ ERROR@col:31 mismatched types
+}
+    "#;
+    let comments = Comments::parse(s, "//").unwrap();
+    let config = config();
+    let msg = |column: usize| Message {
+        message: "mismatched types".to_string(),
+        level: Level::Error,
+        column: Some(column),
+        code: None,
+        location: None,
+        labels: vec![],
+        expansions: vec![],
+    };
+    {
+        let messages = vec![vec![], vec![], vec![], vec![msg(27), msg(31)]];
+        let mut errors = vec![];
+        check_annotations(
+            messages,
+            vec![],
+            Path::new("moobar"),
+            &mut errors,
+            &config,
+            "",
+            &comments,
+        );
+        match &errors[..] {
+            [Error::ErrorsWithoutPattern {
+                path: Some(_),
+                msgs,
+            }] if msgs[0].column == Some(27) => {}
+            _ => panic!("{:#?}", errors),
+        }
+    }
+
+    {
+        let messages = vec![vec![], vec![], vec![], vec![msg(27)]];
+        let mut errors = vec![];
+        check_annotations(
+            messages,
+            vec![],
+            Path::new("moobar"),
+            &mut errors,
+            &config,
+            "",
+            &comments,
+        );
+        match &errors[..] {
+            [Error::ColumnMismatch {
+                definition_line: 3,
+                expected: 31,
+                found,
+                ..
+            }, Error::ErrorsWithoutPattern { path: Some(_), .. }]
+                if found == &[27] => {}
+            _ => panic!("{:#?}", errors),
+        }
+    }
+}
+
+#[test]
+fn nearby_diagnostics() {
+    let s = r"
+fn main() {
+    let x = 5;
+    let y = x;
+    foo(); //~ ERROR: cannot find function
+}
+    ";
+    let comments = Comments::parse(s, "//").unwrap();
+    let config = config();
+    let msg = |message: &str| Message {
+        message: message.to_string(),
+        level: Level::Error,
+        column: None,
+        code: None,
+        location: None,
+        labels: vec![],
+        expansions: vec![],
+    };
+    {
+        let messages = vec![
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+            vec![msg("mismatched types")],
+            vec![],
+            vec![msg("cannot find value"), msg("expected `;`")],
+        ];
+        let mut errors = vec![];
+        check_annotations(
+            messages,
+            vec![],
+            Path::new("moobar"),
+            &mut errors,
+            &config,
+            "",
+            &comments,
+        );
+        match &errors[..] {
+            [Error::PatternNotFound {
+                definition_line: 5,
+                line: Some(5),
+                nearby,
+                ..
+            }, ..] => {
+                let nearby: Vec<_> = nearby
+                    .iter()
+                    .map(|(line, msgs)| (*line, msgs.len()))
+                    .collect();
+                assert_eq!(nearby, [(4, 1), (6, 2)]);
+            }
+            _ => panic!("{:#?}", errors),
+        }
+    }
+
+    {
+        let messages = vec![
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+            vec![msg("mismatched types")],
+            vec![msg("cannot find value")],
+        ];
+        let mut errors = vec![];
+        check_annotations(
+            messages,
+            vec![],
+            Path::new("moobar"),
+            &mut errors,
+            &config,
+            "",
+            &comments,
+        );
+        match &errors[..] {
+            [Error::PatternNotFound {
+                definition_line: 5,
+                line: Some(5),
+                nearby,
+                ..
+            }, ..] => {
+                assert_eq!(nearby.len(), 1);
+                assert_eq!(nearby[0].0, 5);
+                assert_eq!(nearby[0].1[0].message, "cannot find value");
+            }
+            _ => panic!("{:#?}", errors),
+        }
+    }
+}
+
+#[test]
+fn consume_all_pattern() {
+    let s = r"
+fn main() {
+    foo!(); //~ ERROR+ mismatched types
+}
+    ";
+    let comments = Comments::parse(s, "//").unwrap();
+    let config = config();
+    let msg = |message: &str| Message {
+        message: message.to_string(),
+        level: Level::Error,
+        column: None,
+        code: None,
+        location: None,
+        labels: vec![],
+        expansions: vec![],
+    };
+    {
+        let messages = vec![
+            vec![],
+            vec![],
+            vec![],
+            vec![
+                msg("mismatched types"),
+                msg("mismatched types"),
+                msg("mismatched types"),
+            ],
+        ];
+        let mut errors = vec![];
+        check_annotations(
+            messages,
+            vec![],
+            Path::new("moobar"),
+            &mut errors,
+            &config,
+            "",
+            &comments,
+        );
+        match &errors[..] {
+            [] => {}
+            _ => panic!("{:#?}", errors),
+        }
+    }
+
+    // Only some of the diagnostics on the line are duplicates
+    {
+        let messages = vec![
+            vec![],
+            vec![],
+            vec![],
+            vec![
+                msg("mismatched types"),
+                msg("cannot find value"),
+                msg("mismatched types"),
+            ],
+        ];
+        let mut errors = vec![];
+        check_annotations(
+            messages,
+            vec![],
+            Path::new("moobar"),
+            &mut errors,
+            &config,
+            "",
+            &comments,
+        );
+        match &errors[..] {
+            [Error::ErrorsWithoutPattern {
+                path: Some((_, 3)),
+                msgs,
+            }] if msgs.len() == 1 && msgs[0].message == "cannot find value" => {}
+            _ => panic!("{:#?}", errors),
+        }
+    }
+}
+
+#[test]
+fn alternatives_pattern() {
+    let s = r"
+fn main() {
+    foo!(); //~ ERROR: /(cannot find|unresolved) type/
+}
+    ";
+    let comments = Comments::parse(s, "//").unwrap();
+    let config = config();
+    for (message, expected_errors) in [
+        ("cannot find type `Foo` in this scope", 0),
+        ("unresolved type `Foo`", 0),
+        ("failed to resolve type `Foo`", 2),
+    ] {
+        let messages = vec![
+            vec![],
+            vec![],
+            vec![],
+            vec![Message {
+                message: message.to_string(),
+                level: Level::Error,
+                column: None,
+                code: None,
+                location: None,
+                labels: vec![],
+                expansions: vec![],
+            }],
+        ];
+        let mut errors = vec![];
+        check_annotations(
+            messages,
+            vec![],
+            Path::new("moobar"),
+            &mut errors,
+            &config,
+            "",
+            &comments,
+        );
+        assert_eq!(errors.len(), expected_errors, "{errors:#?}");
+    }
+}
+
+#[test]
+fn literal_alternatives_in_substrings() {
+    let s = r"
+fn main() {
+    foo!(); //~ ERROR: expected one of `(a|b)`
+}
+    ";
+    let comments = Comments::parse(s, "//").unwrap();
+    let config = config();
+    for (message, expected_errors) in [
+        ("expected one of `(a|b)`, found `c`", 0),
+        ("expected one of `a`, found `c`", 2),
+    ] {
+        let messages = vec![
+            vec![],
+            vec![],
+            vec![],
+            vec![Message {
+                message: message.to_string(),
+                level: Level::Error,
+                column: None,
+                code: None,
+                location: None,
+                labels: vec![],
+                expansions: vec![],
+            }],
+        ];
+        let mut errors = vec![];
+        check_annotations(
+            messages,
+            vec![],
+            Path::new("moobar"),
+            &mut errors,
+            &config,
+            "",
+            &comments,
+        );
+        assert_eq!(errors.len(), expected_errors, "{errors:#?}");
+    }
+}
+
+#[test]
+fn overlapping_suggestions() {
+    let suggestion = |ranges: &[(usize, usize)], replacement: &str| rustfix::Suggestion {
+        message: String::new(),
+        snippets: vec![],
+        solutions: vec![rustfix::Solution {
+            message: String::new(),
+            replacements: ranges
+                .iter()
+                .map(|&(start, end)| rustfix::Replacement {
+                    snippet: rustfix::Snippet {
+                        file_name: "moobar".into(),
+                        line_range: rustfix::LineRange {
+                            start: rustfix::LinePosition { line: 1, column: 1 },
+                            end: rustfix::LinePosition { line: 1, column: 1 },
+                        },
+                        range: start..end,
+                        text: Default::default(),
+                    },
+                    replacement: replacement.into(),
+                })
+                .collect(),
+        }],
+    };
+    let suggestions = vec![
+        suggestion(&[(0, 5)], "a"),
+        // overlaps with the first suggestion
+        suggestion(&[(3, 8)], "b"),
+        // multi-part, the second part overlaps with the first suggestion
+        suggestion(&[(10, 12), (4, 6)], "c"),
+        // exact duplicate of the first suggestion
+        suggestion(&[(0, 5)], "a"),
+        suggestion(&[(10, 12), (20, 22)], "d"),
+    ];
+    let (applied, errors) = non_overlapping_suggestions(suggestions.clone(), true);
+    assert!(errors.is_empty());
+    let applied: Vec<_> = applied
+        .into_iter()
+        .map(|s| s.solutions[0].replacements[0].replacement.clone())
+        .collect();
+    assert_eq!(applied, ["a", "a", "d"]);
+    let code = "0123456789abcdefghijklmnopqrstuvwxyz";
+    let fixed = rustfix::apply_suggestions(
+        code,
+        &non_overlapping_suggestions(
+            vec![
+                suggestion(&[(0, 5)], "a"),
+                suggestion(&[(3, 8)], "b"),
+                suggestion(&[(0, 5)], "a"),
+            ],
+            true,
+        )
+        .0,
+    )
+    .unwrap();
+    assert_eq!(fixed, "a56789abcdefghijklmnopqrstuvwxyz");
+
+    // Without allowing conflicts, neither of the conflicting suggestions gets applied.
+    let (applied, errors) = non_overlapping_suggestions(suggestions, false);
+    let applied: Vec<_> = applied
+        .into_iter()
+        .map(|s| s.solutions[0].replacements[0].replacement.clone())
+        .collect();
+    assert_eq!(applied, ["d"]);
+    assert_eq!(errors.len(), 2);
+}
+
+#[test]
+fn no_fix_lines_follow_fixes() {
+    let code = "a\nb\nc\nd\ne\n";
+    let suggestion = |start: usize, end: usize, replacement: &str| rustfix::Suggestion {
+        message: String::new(),
+        snippets: vec![],
+        solutions: vec![rustfix::Solution {
+            message: String::new(),
+            replacements: vec![rustfix::Replacement {
+                snippet: rustfix::Snippet {
+                    file_name: "moobar".into(),
+                    line_range: rustfix::LineRange {
+                        start: rustfix::LinePosition { line: 1, column: 1 },
+                        end: rustfix::LinePosition { line: 1, column: 1 },
+                    },
+                    range: start..end,
+                    text: Default::default(),
+                },
+                replacement: replacement.into(),
+            }],
+        }],
+    };
+    // Line 1 becomes three lines, lines 3 and 4 get joined.
+    let suggestions = vec![suggestion(0, 1, "x\ny\nz"), suggestion(4, 7, "c d")];
+    let fixed = rustfix::apply_suggestions(code, &suggestions).unwrap();
+    assert_eq!(fixed, "x\ny\nz\nb\nc d\ne\n");
+    assert_eq!(shift_lines(code, &[2, 3, 5], &suggestions), [4, 5, 6]);
+}
+
+#[test]
+fn cargo_messages() {
+    let stdout = br#"hello
+{"reason":"compiler-message","package_id":"path+file:///tmp/foo#0.1.0","manifest_path":"/tmp/foo/Cargo.toml","message":{"rendered":"warning: unused variable: `x`\n","spans":[{"file_name":"src/main.rs","line_start":2,"column_start":9,"is_primary":true,"expansion":null}],"level":"warning","message":"unused variable: `x`","code":{"code":"unused_variables","explanation":null},"children":[]}}
+{"reason":"compiler-artifact","package_id":"path+file:///tmp/foo#0.1.0","manifest_path":"/tmp/foo/Cargo.toml","filenames":["/tmp/foo/target/debug/foo"]}
+{"reason":"build-finished","success":true}
+world
+"#;
+    let (rest, cargo_messages) = rustc_stderr::split_cargo_messages(stdout).unwrap();
+    assert_eq!(rest, b"hello\nworld\n");
+    let diagnostics = rustc_stderr::process(
+        Path::new("src/main.rs"),
+        &cargo_messages,
+        &RustcDiagnostics::default(),
+    );
+    assert_eq!(diagnostics.rendered, b"warning: unused variable: `x`\n");
+    assert_eq!(diagnostics.messages.len(), 3);
+    let msg = &diagnostics.messages[2][0];
+    assert_eq!(msg.message, "unused variable: `x`");
+    assert_eq!(msg.code.as_deref(), Some("unused_variables"));
+    assert!(diagnostics.messages_from_unknown_file_or_line.is_empty());
+
+    // Only the output of cargo packages is split.
+    let output = Output {
+        status: ExitStatus::default(),
+        stdout: stdout.to_vec(),
+        stderr: vec![],
+    };
+    let mut config = config();
+    let (rest, diagnostics_output) = split_diagnostics_output(&config, &output).unwrap();
+    assert_eq!(rest, stdout);
+    assert!(diagnostics_output.is_empty());
+    config.test_kind = TestKind::CargoProject;
+    let (rest, diagnostics_output) = split_diagnostics_output(&config, &output).unwrap();
+    assert_eq!(rest, b"hello\nworld\n");
+    assert_eq!(diagnostics_output, cargo_messages);
+
+    let err = rustc_stderr::split_cargo_messages(b"hello\n{\"reason\":\"compiler-message\"\n")
+        .unwrap_err();
+    assert!(
+        err.starts_with("failed to parse cargo JSON output at line 1: "),
+        "{err}"
+    );
+}
+
+#[test]
+fn custom_diagnostics_parser() {
+    /// Parses `line: severity: message` lines.
+    #[derive(Debug)]
+    struct LineParser;
+    impl DiagnosticsParser for LineParser {
+        fn parse(&self, _file: &Path, stderr: &[u8]) -> Diagnostics {
+            let mut diagnostics = Diagnostics::default();
+            for line in std::str::from_utf8(stderr).unwrap().lines() {
+                let mut parts = line.splitn(3, ": ");
+                let line: usize = parts.next().unwrap().parse().unwrap();
+                let level = parts.next().unwrap().parse().unwrap();
+                let message = parts.next().unwrap().to_string();
+                if diagnostics.messages.len() <= line {
+                    diagnostics.messages.resize_with(line + 1, Vec::new);
+                }
+                diagnostics.messages[line].push(Message {
+                    level,
+                    message,
+                    location: None,
+                    labels: vec![],
+                    expansions: vec![],
+                    code: None,
+                    column: None,
+                });
+            }
+            diagnostics
+        }
+    }
+
+    let s = r"
+fn main() {
+    let x = 42; //~ WARN: unused variable
+}
+    ";
+    let comments = Comments::parse(s, "//").unwrap();
+    let config = Config {
+        diagnostics_parser: std::sync::Arc::new(LineParser),
+        ..config()
+    };
+    let diagnostics = config
+        .diagnostics_parser
+        .parse(Path::new("moobar"), b"3: warning: unused variable: `x`\n");
+    let mut errors = vec![];
+    check_annotations(
+        diagnostics.messages,
+        diagnostics.messages_from_unknown_file_or_line,
+        Path::new("moobar"),
+        &mut errors,
+        &config,
+        "",
+        &comments,
+    );
+    assert!(errors.is_empty(), "{errors:#?}");
+}
+
+#[test]
+fn human_diagnostics() {
+    let stderr = br#"warning: unused variable: `y`
+ --> foo.rs:3:9
+  |
+3 |     let y = 5;
+  |         ^ help: if this is intentional, prefix it with an underscore: `_y`
+  |
+  = note: `#[warn(unused_variables)]` on by default
+
+error[E0308]: mismatched types
+ --> foo.rs:2:18
+  |
+2 |     let x: u32 = "a"; //~ ERROR: mismatched
+  |            ---   ^^^ expected `u32`, found `&str`
+  |            |
+  |            expected due to this
+
+error: cannot find macro `bar` in this scope
+ --> bar.rs:1:1
+ --> foo.rs:1:1
+error: aborting due to 2 previous errors; 1 warning emitted
+
+For more information about this error, try `rustc --explain E0308`.
+ --> foo.rs:x:1
+"#;
+    let diagnostics = HumanDiagnostics.parse(Path::new("foo.rs"), stderr);
+    let messages: Vec<Vec<_>> = diagnostics
+        .messages
+        .iter()
+        .map(|msgs| {
+            msgs.iter()
+                .map(|msg| {
+                    (
+                        msg.level,
+                        msg.message.as_str(),
+                        msg.code.as_deref(),
+                        msg.column,
+                    )
+                })
+                .collect()
+        })
+        .collect();
+    assert_eq!(
+        messages,
+        [
+            vec![],
+            vec![],
+            vec![(Level::Error, "mismatched types", Some("E0308"), Some(18))],
+            vec![
+                (Level::Warn, "unused variable: `y`", None, Some(9)),
+                (
+                    Level::Note,
+                    "`#[warn(unused_variables)]` on by default",
+                    None,
+                    Some(9)
+                ),
+            ],
+        ]
+    );
+    let unknown: Vec<_> = diagnostics
+        .messages_from_unknown_file_or_line
+        .iter()
+        .map(|msg| (msg.level, msg.message.as_str()))
+        .collect();
+    assert_eq!(
+        unknown,
+        [(Level::Error, "cannot find macro `bar` in this scope")]
+    );
+    // Everything is kept, except for the annotations.
+    assert_eq!(
+        diagnostics.rendered,
+        stderr.replace(" //~ ERROR: mismatched", "")
+    );
+
+    // Json output is not parsed as human readable output.
+    let fallback = RustcDiagnostics {
+        human_fallback: true,
+        ..Default::default()
+    };
+    let json = br#"{"rendered":"warning: foo\n","spans":[],"level":"warning","message":"foo","code":null,"children":[]}"#;
+    let diagnostics = fallback.parse(Path::new("foo.rs"), json);
+    assert_eq!(diagnostics.messages_from_unknown_file_or_line.len(), 1);
+    assert_eq!(diagnostics.rendered, b"warning: foo\n");
+    let diagnostics = fallback.parse(Path::new("foo.rs"), stderr);
+    assert_eq!(diagnostics.messages.len(), 4);
+    // Without the fallback, which is the default, human readable output has no diagnostics.
+    let diagnostics = RustcDiagnostics::default().parse(Path::new("foo.rs"), stderr);
+    assert!(diagnostics.messages.is_empty());
+}
+
+#[test]
+fn secondary_span_labels() {
+    let s = r"
+fn main() {
+    let x: u32 = //~ ERROR: expected due to this
+        //~^ ERROR: expected due to this
+        'a'; //~ ERROR: mismatched types
+}
+    ";
+    let comments = Comments::parse(s, "//").unwrap();
+    let config = config();
+    let message = |line| Message {
+        message: "mismatched types".to_string(),
+        level: Level::Error,
+        column: Some(9),
+        code: None,
+        location: None,
+        labels: vec![SpanLabel {
+            line,
+            column: 12,
+            label: "expected due to this".to_string(),
+        }],
+        expansions: vec![],
+    };
+    let mut messages = vec![vec![]; 6];
+    messages[5].push(message(3));
+    let mut errors = vec![];
+    check_annotations(
+        messages,
+        vec![],
+        Path::new("moobar"),
+        &mut errors,
+        &config,
+        "",
+        &comments,
+    );
+    // The label can only satisfy one of the two annotations on line 3.
+    match &errors[..] {
+        [Error::PatternNotFound {
+            definition_line: 4, ..
+        }] => {}
+        _ => panic!("{:#?}", errors),
+    }
+
+    // A label match alone is enough for the diagnostic to not be reported as unmatched.
+    let s = r"
+fn main() {
+    let x: u32 = //~ ERROR: expected due to this
+        'a';
+}
+    ";
+    let comments = Comments::parse(s, "//").unwrap();
+    let mut messages = vec![vec![]; 5];
+    messages[4].push(message(3));
+    let mut errors = vec![];
+    check_annotations(
+        messages,
+        vec![],
+        Path::new("moobar"),
+        &mut errors,
+        &config,
+        "",
+        &comments,
+    );
+    assert!(errors.is_empty(), "{errors:#?}");
+
+    // Annotations on lines the diagnostic doesn't point to are not satisfied by its labels.
+    let mut messages = vec![vec![]; 5];
+    messages[4].push(message(2));
+    let mut errors = vec![];
+    check_annotations(
+        messages,
+        vec![],
+        Path::new("moobar"),
+        &mut errors,
+        &config,
+        "",
+        &comments,
+    );
+    match &errors[..] {
+        [Error::PatternNotFound {
+            definition_line: 3, ..
+        }, Error::ErrorsWithoutPattern {
+            path: Some((_, 4)), ..
+        }] => {}
+        _ => panic!("{:#?}", errors),
+    }
+}
+
+#[test]
+fn macro_expansions() {
+    let json = br#"{"rendered":"error: mismatched types\n","spans":[{"file_name":"foo.rs","line_start":3,"column_start":23,"is_primary":true,"label":null,"expansion":{"span":{"file_name":"foo.rs","line_start":9,"column_start":9,"is_primary":false,"label":null,"expansion":{"span":{"file_name":"foo.rs","line_start":14,"column_start":5,"is_primary":false,"label":null,"expansion":null},"macro_decl_name":"outer!"}},"macro_decl_name":"mismatch!"}}],"level":"error","message":"mismatched types","code":null,"children":[]}"#;
+    let diagnostics = RustcDiagnostics::default().parse(Path::new("foo.rs"), json);
+    assert_eq!(diagnostics.messages.len(), 15);
+    let msg = &diagnostics.messages[14][0];
+    assert_eq!(msg.column, Some(5));
+    let expansions: Vec<_> = msg
+        .expansions
+        .iter()
+        .map(|exp| (exp.macro_name.as_str(), exp.location.line))
+        .collect();
+    assert_eq!(expansions, [("mismatch!", 9), ("outer!", 14)]);
+
+    let diagnostics = RustcDiagnostics {
+        expansion_call_site: false,
+        ..Default::default()
+    }
+    .parse(Path::new("foo.rs"), json);
+    assert_eq!(diagnostics.messages.len(), 4);
+    assert_eq!(diagnostics.messages[3][0].column, Some(23));
+
+    // Macros from other files are always reported at the invocation in the test file.
+    let json = json.replace(
+        r#""file_name":"foo.rs","line_start":3"#,
+        r#""file_name":"bar.rs","line_start":3"#,
+    );
+    let diagnostics = RustcDiagnostics {
+        expansion_call_site: false,
+        ..Default::default()
+    }
+    .parse(Path::new("foo.rs"), &json);
+    assert_eq!(diagnostics.messages.len(), 10);
+    assert_eq!(
+        diagnostics.messages[9][0].location.as_ref().unwrap().line,
+        3
+    );
+}
+
+#[test]
+fn internal_compiler_errors() {
+    let stderr = br#"{"rendered":"error: internal compiler error: no type for node\n","spans":[],"level":"error: internal compiler error","message":"no type for node","code":null,"children":[]}
+thread 'rustc' panicked at compiler/rustc_errors/src/lib.rs:1000:9:
+Box<dyn Any>
+stack backtrace:
+   0: std::panicking::begin_panic
+note: rustc 1.70.0-nightly running on x86_64-unknown-linux-gnu
+
+query stack during panic:
+#0 [typeck] type-checking `main`
+#1 [used_trait_imports] finding used_trait_imports `main`
+end of query stack
+"#;
+    let (message, query_stack) = rustc_stderr::internal_compiler_error(stderr).unwrap();
+    assert_eq!(message, "no type for node");
+    assert_eq!(
+        query_stack,
+        [
+            "#0 [typeck] type-checking `main`",
+            "#1 [used_trait_imports] finding used_trait_imports `main`",
+        ]
+    );
+
+    let stderr = b"thread 'rustc' panicked at compiler/rustc_middle/src/ty/mod.rs:10:5:
+explicit panic
+note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace
+
+error: the compiler unexpectedly panicked. this is a bug.
+";
+    let (message, query_stack) = rustc_stderr::internal_compiler_error(stderr).unwrap();
+    assert_eq!(message, "explicit panic");
+    assert!(query_stack.is_empty());
+
+    let stderr = b"thread 'rustc' panicked at 'explicit panic', src/lib.rs:1:1
+error: internal compiler error: unexpected panic
+";
+    let (message, _) = rustc_stderr::internal_compiler_error(stderr).unwrap();
+    assert_eq!(
+        message,
+        "thread 'rustc' panicked at 'explicit panic', src/lib.rs:1:1"
+    );
+
+    // A driver that panics on purpose (as in `Mode::Panic` tests) is not a crash of the compiler.
+    let stderr =
+        b"thread 'rustc' panicked at src/driver.rs:10:5:\nexplicit panic\nnote: run with `RUST_BACKTRACE=1`\n";
+    assert!(rustc_stderr::internal_compiler_error(stderr).is_none());
+
+    let stderr = b"error: rustc interrupted by SIGSEGV, printing backtrace\n";
+    let (message, _) = rustc_stderr::internal_compiler_error(stderr).unwrap();
+    assert_eq!(message, "rustc interrupted by SIGSEGV, printing backtrace");
+
+    let stderr = b"error[E0308]: mismatched types\nthread 'main' panicked at src/main.rs:1:1:\nexplicit panic\n";
+    assert!(rustc_stderr::internal_compiler_error(stderr).is_none());
+}
+
+#[test]
+fn diagnostic_file_and_line_routing() {
+    fn span(file: &str, line: usize, primary: bool) -> String {
+        format!(
+            r#"{{"file_name":{file:?},"line_start":{line},"column_start":1,"is_primary":{primary},"label":null,"expansion":null}}"#
+        )
+    }
+    fn parse(options: RustcDiagnostics, file: &Path, spans: &[String]) -> Diagnostics {
+        let json = format!(
+            r#"{{"rendered":"error: foo\n","spans":[{}],"level":"error","message":"foo","code":null,"children":[]}}"#,
+            spans.join(",")
+        );
+        options.parse(file, json.as_bytes())
+    }
+    let lines = |diagnostics: &Diagnostics| {
+        let lines: Vec<usize> = diagnostics
+            .messages
+            .iter()
+            .enumerate()
+            .filter(|(_, msgs)| !msgs.is_empty())
+            .map(|(line, _)| line)
+            .collect();
+        (lines, diagnostics.messages_from_unknown_file_or_line.len())
+    };
+    let file = Path::new("tests/foo.rs");
+    let options = RustcDiagnostics::default();
+
+    // Crate level diagnostics of some compilers report line 0, which is not a line of the file.
+    let diagnostics = parse(options, file, &[span("tests/foo.rs", 0, true)]);
+    assert_eq!(lines(&diagnostics), (vec![], 1));
+    let diagnostics = parse(options, file, &[span("tests/foo.rs", 1, true)]);
+    assert_eq!(lines(&diagnostics), (vec![1], 0));
+    // No spans at all.
+    let diagnostics = parse(options, file, &[]);
+    assert_eq!(lines(&diagnostics), (vec![], 1));
+    // Other files.
+    let diagnostics = parse(options, file, &[span("tests/bar.rs", 3, true)]);
+    assert_eq!(lines(&diagnostics), (vec![], 1));
+
+    // Differently spelled paths to the same file.
+    let diagnostics = parse(options, file, &[span("./tests/foo.rs", 3, true)]);
+    assert_eq!(lines(&diagnostics), (vec![3], 0));
+    let diagnostics = parse(
+        options,
+        Path::new("C:/tests/foo.rs"),
+        &[span(r"\\?\C:\tests\foo.rs", 3, true)],
+    );
+    assert_eq!(lines(&diagnostics), (vec![3], 0));
+
+    // Secondary spans in the test file are only used if the primary span is elsewhere.
+    let spans = [
+        span("tests/bar.rs", 3, true),
+        span("tests/foo.rs", 5, false),
+    ];
+    let diagnostics = parse(options, file, &spans);
+    assert_eq!(lines(&diagnostics), (vec![5], 0));
+    let no_fallback = RustcDiagnostics {
+        secondary_span_fallback: false,
+        ..options
+    };
+    let diagnostics = parse(no_fallback, file, &spans);
+    assert_eq!(lines(&diagnostics), (vec![], 1));
+    let spans = [
+        span("tests/foo.rs", 2, true),
+        span("tests/foo.rs", 5, false),
+    ];
+    let diagnostics = parse(options, file, &spans);
+    assert_eq!(lines(&diagnostics), (vec![2], 0));
+}
+
+#[cfg(unix)]
+#[test]
+fn diagnostic_in_symlinked_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("foo.rs");
+    std::fs::write(&file, "").unwrap();
+    let link = dir.path().join("link.rs");
+    std::os::unix::fs::symlink(&file, &link).unwrap();
+    let json = format!(
+        r#"{{"rendered":"error: foo\n","spans":[{{"file_name":{:?},"line_start":2,"column_start":1,"is_primary":true,"label":null,"expansion":null}}],"level":"error","message":"foo","code":null,"children":[]}}"#,
+        file.display().to_string()
+    );
+    let diagnostics = RustcDiagnostics::default().parse(&link, json.as_bytes());
+    assert_eq!(diagnostics.messages[2].len(), 1);
+    assert!(diagnostics.messages_from_unknown_file_or_line.is_empty());
+}
+
+#[test]
+fn builtin_normalizations() {
+    let config = Config {
+        out_dir: PathBuf::from("target/ui"),
+        ..config()
+    };
+    let path = Path::new("tests/ui/foo.rs");
+    let tmp = std::env::temp_dir();
+    let text = format!(
+        "tests/ui/foo.rs\r\ntarget/ui/foo\n{tmp}/bar {tmp}x/bar\nC:\\Users\\me\\foo.rs \\\\?\\C:\\foo \"a:\\n\" \"tests\\n\"\r\n",
+        tmp = tmp.display()
+    );
+    let normalized = builtin_normalize(path, text.clone().into_bytes(), &config);
+    assert_eq!(
+        normalized.to_str().unwrap(),
+        format!(
+            "$DIR/foo.rs\n$OUT_DIR/foo\n$TMP/bar {}x/bar\nC:/Users/me/foo.rs C:/foo \"a:\\n\" \"tests\\n\"\n",
+            tmp.display()
+        )
+    );
+    // Backslashes after the directories are only replaced if they are followed by a file name.
+    assert_eq!(
+        forward_slashes(
+            b"tests/ui\\foo\\bar.rs tests/ui\\ \"tests/ui\\\\\" xtests/ui\\foo \"\\t\"",
+            &[Path::new("tests/ui")]
+        ),
+        b"tests/ui/foo/bar.rs tests/ui\\ \"tests/ui\\\\\" xtests/ui\\foo \"\\t\""
+    );
+
+    let config = Config {
+        builtin_normalizations: BuiltinNormalizations {
+            test_dir: false,
+            out_dir: false,
+            temp_dir: false,
+            backslashes: false,
+            line_endings: false,
+        },
+        ..config
+    };
+    let normalized = builtin_normalize(path, text.clone().into_bytes(), &config);
+    assert_eq!(normalized, text.into_bytes());
+}
+
+#[test]
+fn filter_fns() {
+    let mut config = config();
+    config.add_stderr_filter_fn(|text| text.reverse());
+    config.add_stderr_filter_fn(|text| text.push(b'!'));
+    let output = apply_filter_fns(b"abc".to_vec(), &config.stderr_filter_fns, "stderr").unwrap();
+    assert_eq!(output, b"cba!");
+
+    config.add_stderr_filter_fn(|_| panic!("oh no"));
+    config.add_stderr_filter_fn(|_| unreachable!());
+    match apply_filter_fns(b"abc".to_vec(), &config.stderr_filter_fns, "stderr") {
+        Err(Error::FilterFnPanicked {
+            kind,
+            index: 2,
+            message,
+        }) if kind == "stderr" && message == "oh no" => {}
+        other => panic!("{other:#?}"),
+    }
+
+    config.add_stdout_filter_fn(|_| panic!("{}", 42));
+    match apply_filter_fns(vec![], &config.stdout_filter_fns, "a.stdout") {
+        Err(Error::FilterFnPanicked { message, .. }) if message == "42" => {}
+        other => panic!("{other:#?}"),
+    }
+}
+
+#[test]
+fn escape_non_utf8() {
+    assert_eq!(crate::diff::escape_bytes(b"plain\ttext\n"), "plain\ttext\n");
+    assert_eq!(crate::diff::escape_bytes(b"nul: \0!"), "nul: \\x00!");
+    assert_eq!(
+        crate::diff::escape_bytes(b"\xff\xfe \x80\x80 \xe2\x82 \xe2\x82\xac"),
+        "\\xff\\xfe \\x80\\x80 \\xe2\\x82 \u{20ac}"
+    );
+}
+
+#[test]
+fn ansi_escapes() {
+    use crate::ansi::apply;
+    let text = b"\x1b[1m\x1b[31merror\x1b[0m: \x1b]8;;https://example.com\x07link\x1b]8;;\x1b\\ \x1b[2K\x1b(Bdone\n";
+    assert_eq!(
+        apply(text.to_vec(), AnsiEscapes::Strip),
+        b"error: link done\n"
+    );
+    assert_eq!(apply(text.to_vec(), AnsiEscapes::Preserve), text);
+    assert_eq!(
+        apply(text.to_vec(), AnsiEscapes::Render).to_str().unwrap(),
+        "␛[1m␛[31merror␛[0m: ␛]8;;https://example.com␇link␛]8;;␛\\ ␛[2K␛(Bdone\n"
+    );
+    // Incomplete sequences are kept, so they show up in diffs.
+    for incomplete in [&b"a\x1b"[..], b"a\x1b[1", b"a\x1b]8;;b", b"a\x1b[\x01m"] {
+        assert_eq!(apply(incomplete.to_vec(), AnsiEscapes::Strip), incomplete);
+    }
+
+    assert_eq!(
+        crate::diff::render_diff(b"\x1b[1mbold", b"bold", 2, false),
+        "Escape characters in output are shown as `\\x1b`.\n-\\x1b[1mbold\n+bold\n"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn max_test_output_bytes() {
+    let mut cmd = Command::new("sh");
+    cmd.args(["-c", "echo out; echo err >&2; exit 3"]);
+    let output = run_command(&mut cmd, Some(100), Stdio::null())
+        .unwrap()
+        .unwrap();
+    assert_eq!(output.stdout, b"out\n");
+    assert_eq!(output.stderr, b"err\n");
+    assert_eq!(output.status.code(), Some(3));
+
+    // Never terminates on its own.
+    let mut cmd = Command::new("sh");
+    cmd.args(["-c", "echo start >&2; yes"]);
+    match run_command(&mut cmd, Some(100_000), Stdio::null()).unwrap() {
+        Err(Error::OutputTooLarge {
+            stream,
+            limit: 100_000,
+            head,
+            tail,
+        }) => {
+            assert_eq!(stream, "stdout");
+            assert_eq!(head.len(), TRUNCATED_OUTPUT_CONTEXT);
+            assert_eq!(tail.len(), TRUNCATED_OUTPUT_CONTEXT);
+            assert!(head.starts_with(b"y\ny\n"));
+        }
+        other => panic!("{other:#?}"),
+    }
+
+    // A grandchild keeps stderr open long after the child was killed.
+    let start = Instant::now();
+    let mut cmd = Command::new("sh");
+    cmd.args(["-c", "sleep 30 >/dev/null & exec yes"]);
+    match run_command(&mut cmd, Some(100_000), Stdio::null()).unwrap() {
+        Err(Error::OutputTooLarge { stream, .. }) => assert_eq!(stream, "stdout"),
+        other => panic!("{other:#?}"),
+    }
+    assert!(start.elapsed() < Duration::from_secs(20));
+}
+
+#[test]
+fn lenient_whitespace() {
+    assert_eq!(strip_trailing_whitespace(b""), b"");
+    assert_eq!(strip_trailing_whitespace(b"\n\n  \n"), b"");
+    assert_eq!(
+        strip_trailing_whitespace(b"a  \n\n b\t\n\n\n"),
+        b"a\n\n b\n"
+    );
+    assert_eq!(strip_trailing_whitespace(b"a\nb"), b"a\nb\n");
+}
+
+#[test]
+fn render_diff() {
+    use crate::diff::render_diff;
+    use colored::Colorize;
+
+    let expected =
+        b"1\n2\n3\n4\nerror: mismatched types: expected `u32`, found `i32`\n5\n6\n7\n8\n9\n10";
+    let actual =
+        b"1\n2\n3\n4\nerror: mismatched types: expected `u64`, found `i32`\n5\n6\n7\n8\nnew\n9\n10";
+    assert_eq!(
+        render_diff(expected, actual, 2, false),
+        "... 2 lines skipped ...
+ 3
+ 4
+-error: mismatched types: expected `u32`, found `i32`
++error: mismatched types: expected `u64`, found `i32`
+ 5
+ 6
+ 7
+ 8
++new
+ 9
+ 10
+"
+    );
+    assert_eq!(
+        render_diff(expected, actual, 0, false),
+        "... 4 lines skipped ...
+-error: mismatched types: expected `u32`, found `i32`
++error: mismatched types: expected `u64`, found `i32`
+... 4 lines skipped ...
++new
+... 2 lines skipped ...
+"
+    );
+    // A single skipped line is shown instead of a message.
+    assert_eq!(
+        render_diff(b"1\n2\n3", b"1\n2\n3\nend", 2, false),
+        " 1\n 2\n 3\n+end\n"
+    );
+    assert_eq!(render_diff(b"a\nb", b"a", 1, false), " a\n-b\n");
+
+    colored::control::set_override(true);
+    let colored = render_diff(
+        b"expected `u32`, found `i32`",
+        b"expected `u64`, found `i32`",
+        2,
+        true,
+    );
+    let expected_colored = format!(
+        "{}expected `{}`, found `i32`\n{}expected `{}`, found `i32`\n",
+        "-".red(),
+        "u32".red(),
+        "+".green(),
+        "u64".green(),
+    );
+    let only_added = render_diff(b"foo bar", b"foo baz bar", 2, true);
+    let expected_only_added = format!("{}foo {}{}bar\n", "~".yellow(), "baz".green(), " ".green());
+    colored::control::unset_override();
+    assert_eq!(colored, expected_colored);
+    assert_eq!(only_added, expected_only_added);
+}
+
+#[test]
+fn save_failure_artifacts() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        root_dir: PathBuf::from("tests/ui"),
+        target: Some("x86_64-unknown-linux-gnu".into()),
+        failure_artifacts_dir: Some(dir.path().into()),
+        ..config()
+    };
+    assert_eq!(
+        artifact_path(&config, Path::new("tests/ui/sub/foo.stderr")).unwrap(),
+        dir.path().join("sub/foo.stderr")
+    );
+    assert_eq!(
+        artifact_path(&config, Path::new("/elsewhere/../foo.stderr")).unwrap(),
+        dir.path().join("elsewhere/foo.stderr")
+    );
+
+    let path = Path::new("tests/ui/sub/foo.rs");
+    let comments = Comments::parse("//@revisions: a b", "//").unwrap();
+    let mut errors = vec![];
+    save_artifact(
+        &config,
+        Path::new("tests/ui/sub/foo.a.stderr"),
+        b"error",
+        &mut errors,
+    );
+    save_artifact(
+        &config,
+        Path::new("tests/ui/sub/foo.b.stderr"),
+        b"error",
+        &mut errors,
+    );
+    save_command_artifact(
+        path,
+        &config,
+        &comments,
+        "a",
+        &Command::new("rustc"),
+        Command::new("true").status().unwrap(),
+        &mut errors,
+    );
+    assert!(errors.is_empty(), "{errors:?}");
+    // Artifacts that can't be written fail the test.
+    save_artifact(
+        &config,
+        Path::new("tests/ui/sub/foo.a.stderr/foo.stderr"),
+        b"error",
+        &mut errors,
+    );
+    assert!(
+        matches!(&errors[..], [Error::Io { path, .. }] if path.ends_with("foo.a.stderr/foo.stderr")),
+        "{errors:?}"
+    );
+    assert_eq!(
+        failure_artifacts(path, &config, &comments, "a", false),
+        [
+            dir.path().join("sub/foo.a.stderr"),
+            dir.path().join("sub/foo.a.command")
+        ]
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("sub/foo.a.command")).unwrap(),
+        "command: rustc\nstatus: exit status: 0\n"
+    );
+    assert!(failure_artifacts(path, &config, &comments, "b", true).is_empty());
+    assert!(!dir.path().join("sub/foo.b.stderr").exists());
+}
+
+#[test]
+fn stale_output_files() {
+    let comments = Comments::parse("//@revisions: a b\n//@[b] stderr-per-bitwidth", "//").unwrap();
+    let path = Path::new("tests/foo.rs");
+    let mut claimed: HashSet<_> = output_files(path, &comments, "a").into_iter().collect();
+    claimed.extend(output_files(path, &comments, "b"));
+    let unknown = [PathBuf::from("tests/filtered")].into_iter().collect();
+    let found = [
+        "tests/foo.a.stderr",
+        "tests/foo.a.fixed",
+        "tests/foo.32bit.b.stderr",
+        "tests/foo.64bit.b.stdout",
+        "tests/foo.stderr",
+        "tests/foo.c.stderr",
+        "tests/bar.stderr",
+        "tests/filtered.stderr",
+        "tests/filtered.a.stdout",
+        "tests/sub/filtered.stderr",
+    ]
+    .into_iter()
+    .map(PathBuf::from)
+    .collect();
+    assert_eq!(
+        super::stale_output_files(found, &claimed, &unknown),
+        [
+            "tests/foo.stderr",
+            "tests/foo.c.stderr",
+            "tests/bar.stderr",
+            "tests/sub/filtered.stderr",
+        ]
+        .map(PathBuf::from)
+    );
+}
+
+#[test]
+fn remove_outdated_output_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo.rs");
+    let comments = Comments::parse(
+        "//@revisions: a b\n//@[b] stderr-per-bitwidth\n//@[b] run-rustfix",
+        "//",
+    )
+    .unwrap();
+    let files = [
+        // Current
+        "foo.rs",
+        "foo.a.stderr",
+        "foo.32bit.b.stderr",
+        "foo.64bit.b.stderr",
+        "foo.b.fixed",
+        // Outdated
+        "foo.stderr",
+        "foo.c.stdout",
+        "foo.64bit.stderr",
+        "foo.b.stderr",
+        "foo.64bit.c.stderr",
+        // Not named like output files of `foo.rs`
+        "foo.64bit.b.fixed",
+        "foo.a.b.stderr",
+        "foo.a.txt",
+        "foobar.stderr",
+        // Belong to other tests
+        "foo.d.rs",
+        "foo.d.stderr",
+        "foo.e.f.rs",
+        "foo.e.f.stderr",
+    ];
+    for file in files {
+        std::fs::write(dir.path().join(file), "").unwrap();
+    }
+    let removed = super::remove_outdated_output_files(&path, &config(), &comments);
+    let outdated = [
+        "foo.64bit.c.stderr",
+        "foo.64bit.stderr",
+        "foo.b.stderr",
+        "foo.c.stdout",
+        "foo.stderr",
+    ];
+    assert_eq!(removed, outdated.map(|file| dir.path().join(file)));
+    for file in files {
+        assert_eq!(
+            dir.path().join(file).exists(),
+            !outdated.contains(&file),
+            "{file}"
+        );
+    }
+}
+
+#[test]
+fn custom_output_conflict_handling() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo.rs");
+    std::fs::write(dir.path().join("foo.stderr"), "old\n").unwrap();
+    let calls = Arc::new(Mutex::new(vec![]));
+    let config = Config {
+        target: Some("x86_64-unknown-linux-gnu".into()),
+        output_conflict_handling: OutputConflictHandling::Custom({
+            let calls = calls.clone();
+            Arc::new(move |path, actual, expected| {
+                calls.lock().unwrap().push((
+                    path.to_owned(),
+                    actual.to_owned(),
+                    expected.to_owned(),
+                ));
+                if actual == b"accept\n" {
+                    std::fs::write(path, actual).unwrap();
+                    Ok(())
+                } else {
+                    Err(Error::Bug("rejected".into()))
+                }
+            })
+        }),
+        ..config()
+    };
+    let comments = Comments::default();
+    let check = |output: &[u8], errors: &mut Errors| {
+        check_output(
+            output,
+            &path,
+            errors,
+            "stderr".into(),
+            &Filter::default(),
+            &[],
+            &config,
+            &comments,
+            "",
+        )
+    };
+
+    let mut errors = vec![];
+    check(b"old\n", &mut errors);
+    assert!(errors.is_empty(), "{errors:#?}");
+    assert!(calls.lock().unwrap().is_empty());
+
+    check(b"new\n", &mut errors);
+    match &errors[..] {
+        [Error::Bug(msg)] if msg == "rejected" => {}
+        other => panic!("{other:#?}"),
+    }
+    assert_eq!(
+        calls.lock().unwrap().pop().unwrap(),
+        (
+            dir.path().join("foo.stderr"),
+            b"new\n".to_vec(),
+            b"old\n".to_vec()
+        )
+    );
+
+    let mut errors = vec![];
+    check(b"accept\n", &mut errors);
+    assert!(errors.is_empty(), "{errors:#?}");
+    assert_eq!(
+        std::fs::read(dir.path().join("foo.stderr")).unwrap(),
+        b"accept\n"
+    );
+}
+
+#[test]
+fn crlf_line_endings() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo.rs");
+    std::fs::write(dir.path().join("foo.stderr"), "error: foo\r\n --> bar\r\n").unwrap();
+    let mut config = Config {
+        target: Some("x86_64-unknown-linux-gnu".into()),
+        output_conflict_handling: OutputConflictHandling::Error("bless".into()),
+        ..config()
+    };
+    let comments = Comments::default();
+    let check = |config: &Config, output: &[u8]| {
+        let mut errors = vec![];
+        check_output(
+            output,
+            &path,
+            &mut errors,
+            "stderr".into(),
+            &Filter::default(),
+            &[],
+            config,
+            &comments,
+            "",
+        );
+        errors
+    };
+    // A `.stderr` file checked out with CRLF line endings matches LF output, and vice versa.
+    assert!(check(&config, b"error: foo\n --> bar\n").is_empty());
+    std::fs::write(dir.path().join("foo.stderr"), "error: foo\n --> bar\n").unwrap();
+    assert!(check(&config, b"error: foo\r\n --> bar\r\n").is_empty());
+
+    // Without normalizing them, line endings must match exactly.
+    config.builtin_normalizations.line_endings = false;
+    match &check(&config, b"error: foo\r\n --> bar\r\n")[..] {
+        [Error::OutputDiffers {
+            actual, expected, ..
+        }] => {
+            assert_eq!(actual, b"error: foo\r\n --> bar\r\n");
+            assert_eq!(expected, b"error: foo\n --> bar\n");
+        }
+        other => panic!("{other:#?}"),
+    }
+    std::fs::write(dir.path().join("foo.stderr"), "error: foo\r\n --> bar\r\n").unwrap();
+    assert!(check(&config, b"error: foo\r\n --> bar\r\n").is_empty());
+}
+
+#[test]
+fn expected_output_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    let expected = dir.path().join("expected");
+    let config = Config {
+        root_dir: root.clone(),
+        target: Some("x86_64-unknown-linux-gnu".into()),
+        expected_output_dir: Some(expected.clone()),
+        output_conflict_handling: OutputConflictHandling::Bless,
+        ..config()
+    };
+    let path = root.join("sub/foo.rs");
+    assert_eq!(
+        expected_output_base(&config, &path),
+        expected.join("sub/foo.rs")
+    );
+
+    let comments = Comments::default();
+    let text = format!(
+        "{}/foo.fixed {}/foo.rs\n",
+        expected.join("sub").display(),
+        root.join("sub").display()
+    );
+    let output_path = check_output(
+        text.as_bytes(),
+        &path,
+        &mut vec![],
+        "stderr".into(),
+        &Filter::default(),
+        &[],
+        &config,
+        &comments,
+        "",
+    );
+    assert_eq!(output_path, expected.join("sub/foo.stderr"));
+    assert_eq!(
+        std::fs::read_to_string(&output_path).unwrap(),
+        "$DIR/foo.fixed $DIR/foo.rs\n"
+    );
+
+    std::fs::write(expected.join("sub/foo.a.stderr"), "").unwrap();
+    std::fs::create_dir_all(root.join("sub")).unwrap();
+    std::fs::write(&path, "").unwrap();
+    assert_eq!(
+        super::remove_outdated_output_files(&path, &config, &comments),
+        [expected.join("sub/foo.a.stderr")]
+    );
+    assert!(output_path.exists());
+}
+
+#[test]
+fn bless_atomically() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("sub/foo.stderr");
+    bless(&path, b"first\n").unwrap();
+    bless(&path, b"second\n").unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), b"second\n");
+    // No temporary files are left behind.
+    assert_eq!(
+        std::fs::read_dir(path.parent().unwrap()).unwrap().count(),
+        1
+    );
+
+    let mut permissions = std::fs::metadata(&path).unwrap().permissions();
+    permissions.set_readonly(true);
+    std::fs::set_permissions(&path, permissions).unwrap();
+    let err = bless(&path, b"third\n").unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    assert!(bless(&path, b"").is_err());
+    assert_eq!(std::fs::read(&path).unwrap(), b"second\n");
+
+    let mut permissions = std::fs::metadata(&path).unwrap().permissions();
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(false);
+    std::fs::set_permissions(&path, permissions).unwrap();
+    bless(&path, b"").unwrap();
+    assert!(!path.exists());
+    bless(&path, b"").unwrap();
+}
+
+#[test]
+fn placeholders() {
+    let placeholders = vec![
+        ("ROOT".to_string(), Path::new("/home/me/project").into()),
+        (
+            "HASH".to_string(),
+            Regex::new("[0-9a-f]{16}").unwrap().into(),
+        ),
+    ];
+    let expected = b"error[E0308]: mismatched types\n  --> [ROOT]/foo.rs:1:5\nnote: lib-[HASH].rlib took [..] to load\n";
+    let actual = b"error[E0308]: mismatched types\n  --> /home/me/project/foo.rs:1:5\nnote: lib-0123456789abcdef.rlib took 3s to load\n";
+    assert!(placeholders::matches(actual, expected, &placeholders));
+    // `[E0308]` is not a placeholder.
+    assert!(!placeholders::matches(
+        b"error[..]: mismatched types\n  --> /home/me/project/foo.rs:1:5\nnote: lib-0123456789abcdef.rlib took 3s to load\n",
+        expected,
+        &placeholders
+    ));
+    assert!(!placeholders::matches(
+        b"error[E0308]: mismatched types\n  --> /elsewhere/foo.rs:1:5\nnote: lib-0123456789abcdef.rlib took 3s to load\n",
+        expected,
+        &placeholders
+    ));
+    assert!(!placeholders::matches(
+        b"error[E0308]: mismatched types\n  --> /home/me/project/foo.rs:1:5\n",
+        expected,
+        &placeholders
+    ));
+
+    // Blessing keeps the placeholders of lines that still match, and writes new lines concretely.
+    let actual = b"warning: unused\nerror[E0308]: mismatched types\n  --> /home/me/project/bar.rs:1:5\nnote: lib-0123456789abcdef.rlib took 1s to load\n";
+    assert_eq!(
+        placeholders::bless(actual, expected, &placeholders).to_str().unwrap(),
+        "warning: unused\nerror[E0308]: mismatched types\n  --> /home/me/project/bar.rs:1:5\nnote: lib-[HASH].rlib took [..] to load\n"
+    );
+    assert_eq!(placeholders::bless(actual, b"", &placeholders), actual);
+}
+
+#[test]
+fn cache_aux_builds() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo.rs");
+    let aux_dir = dir.path().join("auxiliary");
+    std::fs::create_dir_all(&aux_dir).unwrap();
+    std::fs::write(aux_dir.join("helper.rs"), "pub fn helper() {}\n").unwrap();
+    let comments = Comments::parse("//@aux-build: helper.rs\n", "//").unwrap();
+    let mut config = Config::rustc(dir.path().to_owned());
+    config.out_dir = dir.path().join("out");
+
+    let build = |config: &Config| {
+        let args = build_aux_files(
+            &path,
+            &aux_dir,
+            &comments,
+            "",
+            config,
+            &AuxBuilds::new(&Jobs::new(config.num_test_threads)),
+        )
+        .unwrap_or_else(|(_, errors, _)| panic!("{errors:?}"));
+        let rlib = args[1].split_once('=').unwrap().1.to_owned();
+        (args, std::fs::metadata(rlib).unwrap().modified().unwrap())
+    };
+    let (args, built) = build(&config);
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    // Nothing changed, so the artifact of the first build is reused.
+    assert_eq!(build(&config), (args.clone(), built));
+
+    config.force_rebuild = true;
+    let (_, rebuilt) = build(&config);
+    assert_ne!(rebuilt, built);
+    config.force_rebuild = false;
+    assert_eq!(build(&config).1, rebuilt);
+
+    std::fs::write(aux_dir.join("helper.rs"), "pub fn helper2() {}\n").unwrap();
+    assert_ne!(build(&config).1, rebuilt);
+}
+
+#[test]
+fn incremental() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("foo.rs");
+    std::fs::write(&path, "//@check-pass\nfn main() {}\n").unwrap();
+    let mut config = Config::rustc(dir.path().to_owned());
+    config.out_dir = dir.path().join("out");
+    config.incremental = true;
+    config.fill_host_and_target().unwrap();
+
+    let run = |config: &Config| {
+        let cache = TestCache::load(config);
+        let jobs = Jobs::new(config.num_test_threads);
+        let runs = parse_and_test_file(&path, config, &cache, &jobs);
+        cache.save(
+            runs.iter()
+                .map(|run| (path.as_path(), "", &run.result, run.inputs_hash.unwrap())),
+        );
+        assert_eq!(runs.len(), 1);
+        runs.into_iter().next().unwrap().result
+    };
+    assert!(matches!(run(&config), TestResult::Ok));
+    assert!(matches!(run(&config), TestResult::Cached));
+
+    config.force_rebuild = true;
+    assert!(matches!(run(&config), TestResult::Ok));
+    config.force_rebuild = false;
+    config.program.args.push("--cfg=changed".into());
+    assert!(matches!(run(&config), TestResult::Ok));
+    assert!(matches!(run(&config), TestResult::Cached));
+
+    // Failing tests are not cached.
+    std::fs::write(&path, "//@check-pass\nfn main() { let x: u32 = \"\"; }\n").unwrap();
+    assert!(matches!(run(&config), TestResult::Errored { .. }));
+    assert!(matches!(run(&config), TestResult::Errored { .. }));
+    std::fs::write(&path, "//@check-pass\nfn main() {}\n").unwrap();
+    assert!(matches!(run(&config), TestResult::Ok));
+
+    // Caches written by other versions are discarded.
+    let cache = config.out_dir.join("incremental.cache");
+    let contents = std::fs::read_to_string(&cache).unwrap();
+    std::fs::write(&cache, contents.replacen("v2", "v1", 1)).unwrap();
+    assert!(matches!(run(&config), TestResult::Ok));
+    assert!(matches!(run(&config), TestResult::Cached));
+
+    // Closures can't be compared, unless there's a key standing in for them.
+    config.add_stderr_filter_fn(|_| {});
+    assert!(TestCache::load(&config)
+        .inputs_hash(&path, &config, &Comments::default(), "")
+        .is_none());
+    config.incremental_key = Some("filters v1".into());
+    assert!(matches!(run(&config), TestResult::Ok));
+    assert!(matches!(run(&config), TestResult::Cached));
+    config.incremental_key = Some("filters v2".into());
+    assert!(matches!(run(&config), TestResult::Ok));
+}
+
+#[test]
+fn shards() {
+    let mut config = config();
+    let tests: Vec<_> = (0..50)
+        .flat_map(|i| {
+            let path = PathBuf::from(format!("tests/ui/test_{i}.rs"));
+            [(path.clone(), ""), (path, "rev")]
+        })
+        .collect();
+    let assignment = |config: &mut Config, root: &str| {
+        config.root_dir = root.into();
+        let tests = tests
+            .iter()
+            .map(|(path, revision)| (Path::new(root).join(path), revision));
+        let mut shards = vec![vec![]; 4];
+        for (test, revision) in tests {
+            let assigned: Vec<_> = (0..4)
+                .filter(|&index| {
+                    config.shard = Some((index, 4));
+                    in_shard(config, &test, revision)
+                })
+                .collect();
+            // Every test runs in exactly one shard.
+            assert_eq!(assigned.len(), 1, "{}", test.display());
+            shards[assigned[0]].push((relative_to_root(config, &test), revision));
+        }
+        shards
+    };
+    let shards = assignment(&mut config, "/home/me/project");
+    assert!(shards.iter().all(|shard| !shard.is_empty()));
+    // The assignment does not depend on where the tests are.
+    assert_eq!(assignment(&mut config, "/builds/ci"), shards);
+}
+
+#[test]
+fn retry() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("flaky.rs");
+    let marker = dir.path().join("attempts");
+    // Fails on the first two attempts.
+    let test = format!(
+        "//@run\n//@retry: 3\nfn main() {{\n    let path = {marker:?};\n    let attempts = std::fs::read_to_string(path).map_or(0, |n| n.parse().unwrap()) + 1;\n    std::fs::write(path, attempts.to_string()).unwrap();\n    assert!(attempts >= 3);\n}}\n"
+    );
+    std::fs::write(&path, &test).unwrap();
+    std::fs::write(path.with_extension("stderr"), "").unwrap();
+    let mut config = Config::rustc(dir.path().to_owned());
+    config.out_dir = dir.path().join("out");
+    config.fill_host_and_target().unwrap();
+    config.stderr_filter("(?s).*", "");
+
+    let run = |config: &Config| {
+        let jobs = Jobs::new(config.num_test_threads);
+        let mut runs = parse_and_test_file(&path, config, &TestCache::default(), &jobs);
+        assert_eq!(runs.len(), 1);
+        runs.pop().unwrap().result
+    };
+    assert!(matches!(run(&config), TestResult::Flaky { attempts: 3 }));
+    std::fs::remove_file(&marker).unwrap();
+
+    std::fs::write(&path, test.replace("//@retry: 3", "//@retry: 2")).unwrap();
+    assert!(matches!(run(&config), TestResult::Errored { .. }));
+    // Without `//@retry`, `Config::test_attempts` applies.
+    std::fs::write(&path, test.replace("//@retry: 3\n", "")).unwrap();
+    config.test_attempts = 3;
+    assert!(matches!(run(&config), TestResult::Ok));
+}
+
+#[test]
+fn run_failed_first() {
+    struct Order(Arc<Mutex<Vec<String>>>);
+    impl StatusEmitter for Order {
+        fn failed_test<'a>(
+            &'a self,
+            _revision: &'a str,
+            _path: &'a Path,
+            _cmd: &'a Command,
+            _stderr: &'a [u8],
+        ) -> Box<dyn std::fmt::Debug + 'a> {
+            Box::new(())
+        }
+        fn test_result(&mut self, path: &Path, _revision: &str, _result: &TestResult) {
+            let name = path.file_stem().unwrap().to_str().unwrap();
+            self.0.lock().unwrap().push(name.to_owned());
+        }
+        fn finalize(
+            &self,
+            _failed: usize,
+            _succeeded: usize,
+            _ignored: usize,
+            _filtered: usize,
+        ) -> Box<dyn status_emitter::Summary> {
+            Box::new(())
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    std::fs::create_dir_all(&root).unwrap();
+    for name in ["a", "b", "c", "d"] {
+        std::fs::write(
+            root.join(format!("{name}.rs")),
+            "//@check-pass\nfn main() {}\n",
+        )
+        .unwrap();
+    }
+    std::fs::write(root.join("c.rs"), "fn main() {}\n").unwrap();
+    std::fs::write(root.join("d.rs"), "fn main() {}\n").unwrap();
+    let mut config = Config::rustc(root.clone());
+    config.out_dir = dir.path().join("out");
+    config.num_test_threads = std::num::NonZeroUsize::new(1).unwrap();
+    let run = |config: &Config| {
+        let order = Arc::new(Mutex::new(vec![]));
+        let _ = run_tests_generic(
+            config.clone(),
+            default_file_filter,
+            default_per_file_config,
+            Order(order.clone()),
+        );
+        let order = order.lock().unwrap().clone();
+        order
+    };
+    assert_eq!(run(&config), ["a", "b", "c", "d"]);
+    // `c` and `d` failed, because they lack `//@check-pass`.
+    std::fs::remove_file(root.join("d.rs")).unwrap();
+    assert_eq!(run(&config), ["c", "a", "b"]);
+    std::fs::write(root.join("c.rs"), "//@check-pass\nfn main() {}\n").unwrap();
+    assert_eq!(run(&config), ["c", "a", "b"]);
+    assert_eq!(run(&config), ["a", "b", "c"]);
+
+    std::fs::write(root.join("b.rs"), "fn main() {}\n").unwrap();
+    assert_eq!(run(&config), ["a", "b", "c"]);
+    assert_eq!(run(&config), ["b", "a", "c"]);
+    config.run_failed_first = false;
+    assert_eq!(run(&config), ["a", "b", "c"]);
+}
+
+#[test]
+#[cfg(unix)]
+fn interrupt_kills_children() {
+    let start = std::time::Instant::now();
+    let sleep =
+        std::thread::spawn(|| run_command(Command::new("sleep").arg("30"), None, Stdio::null()));
+    // Wait for the process to be spawned.
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    interrupt::kill_children();
+    let output = sleep.join().unwrap().unwrap().unwrap();
+    assert!(!output.status.success());
+    assert!(start.elapsed() < std::time::Duration::from_secs(10));
+}
+
+#[test]
+fn jobs() {
+    let jobs = Jobs::new(std::num::NonZeroUsize::new(3).unwrap());
+    let running = Mutex::new((0, 0));
+    std::thread::scope(|s| {
+        for _ in 0..10 {
+            s.spawn(|| {
+                let _job = jobs.acquire();
+                let mut guard = running.lock().unwrap();
+                guard.0 += 1;
+                guard.1 = guard.1.max(guard.0);
+                drop(guard);
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                running.lock().unwrap().0 -= 1;
+            });
+        }
+    });
+    assert_eq!(running.into_inner().unwrap(), (0, 3));
+}
+
+#[test]
+fn custom_builds() {
+    struct Wasm(Arc<Mutex<Vec<String>>>);
+    impl Build for Wasm {
+        fn cache_key(&self, args: &str) -> String {
+            args.trim_end_matches(".wat").to_owned()
+        }
+        fn build(&self, args: &str, _config: &Config) -> Result<Vec<OsString>, String> {
+            self.0.lock().unwrap().push(args.to_owned());
+            if args.starts_with("broken") {
+                return Err(format!("could not compile `{args}`"));
+            }
+            Ok(vec!["--cfg".into(), format!("wasm=\"{args}\"").into()])
+        }
+    }
+
+    let built = Arc::new(Mutex::new(vec![]));
+    let mut config = config();
+    config.add_custom_build("wasm", Wasm(built.clone()));
+    let config2 = config.clone();
+    assert_eq!(
+        config.custom_builds.build("wasm", "a.wat", &config),
+        Ok(vec!["--cfg".into(), "wasm=\"a.wat\"".into()])
+    );
+    // Clones of the config share the results, and equal cache keys are only built once.
+    assert_eq!(
+        config2.custom_builds.build("wasm", "a", &config2),
+        Ok(vec!["--cfg".into(), "wasm=\"a.wat\"".into()])
+    );
+    let err = Err("could not compile `broken`".to_string());
+    assert_eq!(config.custom_builds.build("wasm", "broken", &config), err);
+    assert_eq!(config2.custom_builds.build("wasm", "broken", &config), err);
+    assert_eq!(*built.lock().unwrap(), ["a.wat", "broken"]);
+
+    assert_eq!(
+        config.custom_builds.build("js", "a", &config),
+        Err("no custom build called `js`, the registered ones are: `wasm`".to_string())
+    );
+}
+
+#[test]
+fn json_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("a.rs"), "//@check-pass\nfn main() {}\n").unwrap();
+    std::fs::write(root.join("b.rs"), "//@check-pass\nfn main() {}\n").unwrap();
+    std::fs::write(root.join("b.stderr"), "outdated\n").unwrap();
+    let results = dir.path().join("results.json");
+    let mut config = Config::rustc(root);
+    config.out_dir = dir.path().join("out");
+    config.json_output = Some(JsonOutput::File(results.clone()));
+    assert!(run_tests(config).is_err());
+
+    let results = std::fs::read_to_string(results).unwrap();
+    let results: Vec<serde_json::Value> = results
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(results.len(), 3, "{results:#?}");
+    for result in &results {
+        assert_eq!(
+            result["format_version"],
+            status_emitter::Json::FORMAT_VERSION
+        );
+    }
+    let test = |name: &str| {
+        results
+            .iter()
+            .find(|result| result["path"].as_str().map_or(false, |p| p.ends_with(name)))
+            .unwrap()
+    };
+    let a = test("a.rs");
+    assert_eq!(a["type"], "test");
+    assert_eq!(a["id"], "a.rs");
+    assert_eq!(a["outcome"], "passed");
+    assert_eq!(a["errors"], serde_json::json!([]));
+    assert!(a["duration_secs"].as_f64().unwrap() > 0.0);
+    let b = test("b.rs");
+    assert_eq!(b["outcome"], "failed");
+    assert_eq!(b["bless_command"], "cargo test -- -- --bless");
+    assert!(b["expected_files"][0]
+        .as_str()
+        .unwrap()
+        .ends_with("b.stderr"));
+    let error = &b["errors"][0];
+    assert_eq!(error["kind"], "output_differs");
+    assert_eq!(error["expected_len"], 9);
+    assert_eq!(error["actual_len"], 0);
+    assert!(error["diff"].as_str().unwrap().contains("-outdated"));
+    let summary = results.last().unwrap();
+    assert_eq!(summary["type"], "summary");
+    assert_eq!(summary["outcome"], "failed");
+    assert_eq!(summary["passed"], 1);
+    assert_eq!(summary["failed"], 1);
+}
+
+#[test]
+fn summaries_count_warnings() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(
+        root.join("a.rs"),
+        "fn main() {\n    let _x: u32 = \"\";\n    //~^ ERROR: this message has since changed\n}\n",
+    )
+    .unwrap();
+    let results = dir.path().join("results.json");
+    let report = dir.path().join("report.md");
+    let mut config = Config::rustc(root);
+    config.out_dir = dir.path().join("out");
+    config.mode = Mode::Yolo;
+    config.check_annotations_in_yolo = Strictness::Warn;
+    config.output_conflict_handling = OutputConflictHandling::Ignore;
+    config.json_output = Some(JsonOutput::File(results.clone()));
+    config.markdown_report = Some(report.clone());
+    run_tests(config).unwrap();
+
+    let results = std::fs::read_to_string(results).unwrap();
+    let summary: serde_json::Value = serde_json::from_str(results.lines().last().unwrap()).unwrap();
+    assert_eq!(summary["warnings"], 1, "{summary:#}");
+    let report = std::fs::read_to_string(report).unwrap();
+    assert!(report.contains("| Warnings | 1 |"), "{report}");
+}
+
+#[test]
+fn markdown_report() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("a.rs"), "//@check-pass\nfn main() {}\n").unwrap();
+    std::fs::write(root.join("b.rs"), "//@check-pass\nfn main() {}\n").unwrap();
+    std::fs::write(root.join("b.stderr"), "outdated\n".repeat(100)).unwrap();
+    std::fs::write(
+        root.join("c.rs"),
+        "fn main() {\n    let x: u8 = 1u16; //~ ERROR: no such error\n}\n",
+    )
+    .unwrap();
+    let report = dir.path().join("report.md");
+    let mut config = Config::rustc(root);
+    config.out_dir = dir.path().join("out");
+    config.failure_artifacts_dir = Some(dir.path().join("artifacts"));
+    config.markdown_report = Some(report.clone());
+    config.markdown_max_diff_len = 100;
+    assert!(run_tests(config).is_err());
+
+    let report = std::fs::read_to_string(report).unwrap();
+    assert!(report.starts_with("## Test results: FAILED\n"), "{report}");
+    assert!(
+        report.contains("| Passed | 1 |\n| Failed | 2 |\n"),
+        "{report}"
+    );
+    let b = &report[report.find("b.rs</code></summary>").unwrap()..];
+    let b = &b[..b.find("</details>").unwrap()];
+    assert!(b.contains("Command:\n\n```\nrustc "), "{b}");
+    assert!(b.contains("```diff\n"), "{b}");
+    assert!(b.contains("-outdated"), "{b}");
+    assert!(b.contains("The diff was truncated after "), "{b}");
+    assert!(b.contains("b.stderr`."), "{b}");
+    let c = &report[report.find("c.rs</code></summary>").unwrap()..];
+    assert!(
+        c.contains("`no such error` not found in diagnostics, unmatched diagnostics nearby:\n\n* Error (line 2): mismatched types"),
+        "{c}"
+    );
+}
+
+#[test]
+fn github_actions_escaping() {
+    use std::fmt::Write as _;
+    let mut err =
+        github_actions::error("tests/a,b.rs", "Pattern not found (revision: foo)").line(3);
+    write!(err, "100% wrong\r\nsecond line").unwrap();
+    assert_eq!(
+        err.command(),
+        "::error file=tests/a%2Cb.rs,line=3,title=Pattern not found (revision%3A foo)::100%25 wrong%0D%0Asecond line"
+    );
+    let err = github_actions::error("tests/a.rs", "Internal compiler error");
+    assert_eq!(
+        err.command(),
+        "::error file=tests/a.rs,title=Internal compiler error::no message"
+    );
+}
+
+#[test]
+fn ignore_reasons() {
+    let mut config = config();
+    config.host = Some("x86_64-unknown-linux-gnu".into());
+    config.target = Some("x86_64-unknown-linux-gnu".into());
+    let reason = |s: &str| ignore_reason(&Comments::parse(s, "//").unwrap(), &config, "").unwrap();
+    assert_eq!(reason("fn main() {}"), None);
+    assert_eq!(reason("//@ignore-target-windows\nfn main() {}"), None);
+    assert_eq!(
+        reason("//@ignore-target-windows\n//@ignore-target-linux\nfn main() {}"),
+        Some(("`//@ignore-target-linux`".into(), 2))
+    );
+    assert_eq!(
+        reason("//@only-64bit\n//@only-host-windows\nfn main() {}"),
+        Some(("`//@only-host-windows`".into(), 2))
+    );
+    assert_eq!(
+        reason("//@only-on-host\n//@only-32bit\nfn main() {}"),
+        Some(("`//@only-32bit`".into(), 2))
+    );
+}
+
+#[test]
+fn custom_conditions() {
+    let mut config = config();
+    config.host = Some("x86_64-unknown-linux-gnu".into());
+    config.target = Some("x86_64-unknown-linux-gnu".into());
+    let reason =
+        |config: &Config, s: &str| ignore_reason(&Comments::parse(s, "//").unwrap(), config, "");
+    let msg = |config: &Config, s: &str| match reason(config, s) {
+        Err(Error::InvalidComment { msg, line: 1 }) => msg,
+        _ => panic!("{s} should be an error"),
+    };
+    assert_eq!(
+        msg(&config, "//@only-when: experimental"),
+        "unknown condition `experimental`, `Config::custom_conditions` is empty"
+    );
+
+    config
+        .custom_conditions
+        .insert("experimental".into(), false);
+    config.custom_conditions.insert("nightly".into(), true);
+    assert_eq!(
+        reason(
+            &config,
+            "//@ignore-when: experimental\n//@only-when: nightly"
+        )
+        .unwrap(),
+        None
+    );
+    assert_eq!(
+        reason(&config, "//@ignore-when: nightly").unwrap(),
+        Some(("`//@ignore-when: nightly`".into(), 1))
+    );
+    assert_eq!(
+        reason(&config, "//@only-when: experimental").unwrap(),
+        Some(("`//@only-when: experimental`".into(), 1))
+    );
+    assert_eq!(
+        msg(&config, "//@only-when: experimentl"),
+        "unknown condition `experimentl`, expected one of `experimental`, `nightly`"
+    );
+
+    // Clones of the config share the conditions until one of them changes them.
+    let mut clone = config.clone();
+    assert!(std::ptr::eq(
+        &*clone.custom_conditions,
+        &*config.custom_conditions
+    ));
+    clone.custom_conditions.insert("nightly".into(), false);
+    assert!(config.custom_conditions["nightly"]);
+    assert!(!clone.custom_conditions["nightly"]);
+    match &Comments::parse("//@only-when:", "//").unwrap_err()[..] {
+        [Error::InvalidComment { msg, line: 1 }] => assert_eq!(
+            msg,
+            "`when` needs the name of a condition in `Config::custom_conditions`"
+        ),
+        _ => panic!("`when` without a name should be an error"),
+    }
+}
+
+#[test]
+fn debug_assertions_and_opt_level() {
+    let mut config = config();
+    config.host = Some("x86_64-unknown-linux-gnu".into());
+    config.target = Some("x86_64-unknown-linux-gnu".into());
+    let reason =
+        |config: &Config, s: &str| ignore_reason(&Comments::parse(s, "//").unwrap(), config, "");
+    // Whether the program has debug assertions is not guessed.
+    for s in ["//@needs-debug-assertions", "//@ignore-debug-assertions"] {
+        match reason(&config, s) {
+            Err(Error::InvalidComment { msg, line: 1 }) => {
+                assert!(
+                    msg.ends_with("set `Config::program_debug_assertions`"),
+                    "{msg}"
+                )
+            }
+            _ => panic!("{s} should be an error"),
+        }
+    }
+    config.program_debug_assertions = Some(false);
+    assert_eq!(
+        reason(
+            &config,
+            "//@ignore-debug-assertions\n//@needs-debug-assertions"
+        )
+        .unwrap(),
+        Some(("`//@needs-debug-assertions`".into(), 2))
+    );
+    config.program_debug_assertions = Some(true);
+    assert_eq!(
+        reason(
+            &config,
+            "//@ignore-debug-assertions\n//@needs-debug-assertions"
+        )
+        .unwrap(),
+        Some(("`//@ignore-debug-assertions`".into(), 1))
+    );
+
+    assert_eq!(reason(&config, "//@only-opt-level: 0").unwrap(), None);
+    assert_eq!(
+        reason(&config, "//@compile-flags: -O\n//@only-opt-level: 0").unwrap(),
+        Some(("`//@only-opt-level: 0`".into(), 2))
+    );
+    let s = "//@revisions: a b\n//@[a]compile-flags: -O -C opt-level=s\n//@[b]compile-flags: -Copt-level=s -O\n//@only-opt-level: s";
+    let comments = Comments::parse(s, "//").unwrap();
+    assert_eq!(ignore_reason(&comments, &config, "a").unwrap(), None);
+    assert_eq!(
+        ignore_reason(&comments, &config, "b").unwrap(),
+        Some(("`//@only-opt-level: s`".into(), 4))
+    );
+    config.program.args.push("--codegen=opt-level=3".into());
+    assert_eq!(
+        reason(&config, "//@ignore-opt-level: 3").unwrap(),
+        Some(("`//@ignore-opt-level: 3`".into(), 1))
+    );
+    config.test_kind = TestKind::CargoProject;
+    assert!(reason(&config, "//@ignore-opt-level: 3").is_err());
+
+    match &Comments::parse("//@only-opt-level: fast", "//").unwrap_err()[..] {
+        [Error::InvalidComment { msg, line: 1 }] => assert_eq!(
+            msg,
+            "`fast` is not a valid opt-level, expected one of `0`, `1`, `2`, `3`, `s` or `z`"
+        ),
+        _ => panic!("`fast` should not be an opt-level"),
+    }
+}
+
+#[test]
+fn cfg_conditions() {
+    let mut config = Config::rustc(PathBuf::new());
+    config.fill_host_and_target().unwrap();
+    let reason = |s: &str| ignore_reason(&Comments::parse(s, "//").unwrap(), &config, "");
+    let pointer_width = format!(r#"target_pointer_width="{}""#, usize::BITS);
+    assert_eq!(
+        reason(&format!(
+            "//@only-cfg: {pointer_width}\n//@ignore-cfg: miri"
+        ))
+        .unwrap(),
+        None
+    );
+    assert_eq!(
+        reason(&format!("//@ignore-cfg: {pointer_width}")).unwrap(),
+        Some((format!("`//@ignore-cfg: {pointer_width}`"), 1))
+    );
+    assert_eq!(
+        reason("//@only-cfg: target_feature=\"not-a-feature\"").unwrap(),
+        Some(("`//@only-cfg: target_feature=\"not-a-feature\"`".into(), 1))
+    );
+    assert_eq!(
+        reason("//@ignore-cfg: panic=\"unwind\"\n//@ignore-cfg: panic=\"abort\"")
+            .unwrap()
+            .map(|(_, line)| line),
+        Some(if cfg!(panic = "unwind") { 1 } else { 2 })
+    );
+    // Typos are errors instead of silently skipping or running the test.
+    for typo in [
+        "//@only-cfg: target_evn=\"musl\"",
+        "//@ignore-cfg: unxi",
+        "//@only-cfg: target_os",
+    ] {
+        match reason(&format!("\n{typo}")) {
+            Err(Error::InvalidComment { line: 2, .. }) => {}
+            _ => panic!("{typo} should be an error"),
+        }
+    }
+
+    // The flags of the test change the cfgs.
+    assert_eq!(
+        reason("//@compile-flags: -C debug-assertions=on\n//@only-cfg: debug_assertions").unwrap(),
+        None
+    );
+    assert_eq!(
+        reason("//@compile-flags: -Cdebug-assertions=off\n//@only-cfg: debug_assertions").unwrap(),
+        Some(("`//@only-cfg: debug_assertions`".into(), 2))
+    );
+    assert_eq!(
+        reason("//@compile-flags: --cfg ui_test_cfg\n//@only-cfg: ui_test_cfg").unwrap(),
+        None
+    );
+    let comments = Comments::parse("//@revisions: a b\n//@only-cfg: a", "//").unwrap();
+    assert_eq!(ignore_reason(&comments, &config, "a").unwrap(), None);
+    assert_eq!(
+        ignore_reason(&comments, &config, "b").unwrap(),
+        Some(("`//@only-cfg: a`".into(), 2))
+    );
+
+    // Conditions that can't be evaluated fail with the command of the test.
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("a.rs");
+    std::fs::write(&path, "//@ignore-cfg: unxi\nfn main() {}\n").unwrap();
+    let mut config = Config::rustc(dir.path());
+    config.out_dir = dir.path().join("out");
+    match &run_file(config, &path).unwrap()[0].result {
+        TestResult::Errored {
+            command, errors, ..
+        } => {
+            assert!(
+                matches!(&errors[..], [Error::InvalidComment { line: 1, .. }]),
+                "{errors:?}"
+            );
+            assert!(format!("{command:?}").contains("a.rs"), "{command:?}");
+        }
+        _ => panic!("the unknown cfg did not fail the test"),
+    }
+}
+
+#[test]
+fn parse_format() {
+    assert_eq!("pretty".parse(), Ok(Format::Pretty));
+    assert_eq!("terse".parse(), Ok(Format::Terse));
+    assert_eq!("json".parse(), Ok(Format::Json));
+    assert!("junit".parse::<Format>().is_err());
+}
+
+#[test]
+fn only_failures_are_reported_once() {
+    let mut emitter = status_emitter::OnlyFailures::default();
+    let path = Path::new("tests/ui/a.rs");
+    let command = Command::new("rustc");
+    emitter.test_result(path, "", &TestResult::Ok);
+    emitter.test_result(
+        path,
+        "bar",
+        &TestResult::Errored {
+            command: Command::new("rustc"),
+            errors: vec![Error::ExitStatus {
+                mode: Mode::Pass,
+                status: Command::new("false").status().unwrap(),
+                expected: 0,
+            }],
+            stderr: vec![],
+        },
+    );
+    // Already reported failures don't get a header again at the end.
+    assert_eq!(
+        format!("{:?}", emitter.failed_test("bar", path, &command, &[])),
+        "()"
+    );
+    // But failures that are only noticed at the end do.
+    assert_ne!(
+        format!("{:?}", emitter.failed_test("", path, &command, &[])),
+        "()"
+    );
+}
+
+#[test]
+fn slowest_tests() {
+    type Report = (Vec<(PathBuf, String, Timings)>, Timings);
+    #[derive(Default)]
+    struct Slowest(Arc<Mutex<Option<Report>>>);
+    impl StatusEmitter for Slowest {
+        fn failed_test<'a>(
+            &'a self,
+            _revision: &'a str,
+            _path: &'a Path,
+            _cmd: &'a Command,
+            _stderr: &'a [u8],
+        ) -> Box<dyn std::fmt::Debug + 'a> {
+            Box::new(())
+        }
+        fn slowest_tests(&mut self, slowest: &[(PathBuf, String, Timings)], total: &Timings) {
+            *self.0.lock().unwrap() = Some((slowest.to_vec(), *total));
+        }
+        fn finalize(
+            &self,
+            _failed: usize,
+            _succeeded: usize,
+            _ignored: usize,
+            _filtered: usize,
+        ) -> Box<dyn status_emitter::Summary> {
+            Box::new(())
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    std::fs::create_dir_all(root.join("auxiliary")).unwrap();
+    std::fs::write(root.join("auxiliary/helper.rs"), "pub fn helper() {}\n").unwrap();
+    std::fs::write(
+        root.join("a.rs"),
+        "//@check-pass\n//@aux-build: helper.rs\nfn main() {}\n",
+    )
+    .unwrap();
+    std::fs::write(root.join("b.rs"), "//@run\nfn main() {}\n").unwrap();
+    std::fs::write(root.join("c.rs"), "//@ignore-on-host\nfn main() {}\n").unwrap();
+    let mut config = Config::rustc(root);
+    config.out_dir = dir.path().join("out");
+    let run = |config: &Config| {
+        let slowest = Slowest::default();
+        let result = slowest.0.clone();
+        run_tests_generic(
+            config.clone(),
+            default_file_filter,
+            default_per_file_config,
+            slowest,
+        )
+        .unwrap();
+        let result = result.lock().unwrap().take();
+        result
+    };
+    assert!(run(&config).is_none());
+
+    config.slowest_tests = Some(1);
+    let (slowest, total) = run(&config).unwrap();
+    assert_eq!(slowest.len(), 1);
+    let (path, _, timings) = &slowest[0];
+    // The ignored test took no time.
+    assert!(!path.ends_with("c.rs"));
+    assert!(timings.total >= timings.phases().iter().map(|(_, time)| *time).sum());
+    assert!(total.total > timings.total);
+    assert!(total.aux_builds > Duration::ZERO);
+    assert!(total.compile > Duration::ZERO);
+    assert!(total.run > Duration::ZERO);
+}
+
+#[test]
+fn ignored_summary() {
+    type Reasons = Vec<(String, Vec<(PathBuf, String, usize)>)>;
+    #[derive(Default)]
+    struct Ignored(Arc<Mutex<Option<Reasons>>>);
+    impl StatusEmitter for Ignored {
+        fn failed_test<'a>(
+            &'a self,
+            _revision: &'a str,
+            _path: &'a Path,
+            _cmd: &'a Command,
+            _stderr: &'a [u8],
+        ) -> Box<dyn std::fmt::Debug + 'a> {
+            Box::new(())
+        }
+        fn ignored_tests(&mut self, ignored: &[(String, Vec<(PathBuf, String, usize)>)]) {
+            *self.0.lock().unwrap() = Some(ignored.to_vec());
+        }
+        fn finalize(
+            &self,
+            _failed: usize,
+            _succeeded: usize,
+            _ignored: usize,
+            _filtered: usize,
+        ) -> Box<dyn status_emitter::Summary> {
+            Box::new(())
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("a.rs"), "//@check-pass\nfn main() {}\n").unwrap();
+    std::fs::write(
+        root.join("b.rs"),
+        "//@check-pass\n//@ignore-on-host\nfn main() {}\n",
+    )
+    .unwrap();
+    std::fs::write(root.join("c.rs"), "//@ignore-on-host\nfn main() {}\n").unwrap();
+    std::fs::write(root.join("d.rs"), "//@only-16bit\nfn main() {}\n").unwrap();
+    let mut config = Config::rustc(root.clone());
+    config.out_dir = dir.path().join("out");
+    let run = |config: &Config| {
+        let ignored = Ignored::default();
+        let reasons = ignored.0.clone();
+        let result = run_tests_generic(
+            config.clone(),
+            default_file_filter,
+            default_per_file_config,
+            ignored,
+        );
+        let reasons = reasons.lock().unwrap().take();
+        (result, reasons)
+    };
+    let (result, reasons) = run(&config);
+    result.unwrap();
+    assert!(reasons.is_none());
+
+    config.ignored_summary = true;
+    let (result, reasons) = run(&config);
+    result.unwrap();
+    assert_eq!(
+        reasons.unwrap(),
+        vec![
+            (
+                "`//@ignore-on-host`".to_string(),
+                vec![
+                    (root.join("b.rs"), String::new(), 2),
+                    (root.join("c.rs"), String::new(), 1)
+                ]
+            ),
+            (
+                "`//@only-16bit`".to_string(),
+                vec![(root.join("d.rs"), String::new(), 1)]
+            ),
+        ]
+    );
+
+    // Three of four tests are ignored.
+    config.warn_on_ignored = Some(0.75);
+    run(&config).0.unwrap();
+    config.warn_on_ignored = Some(0.5);
+    let err = run(&config).0.unwrap_err();
+    assert!(
+        err.to_string().contains("3 of 4 tests were ignored"),
+        "{err}"
+    );
+}
+
+#[test]
+fn parse_fraction() {
+    assert_eq!(config::parse_fraction("0.5"), Some(0.5));
+    assert_eq!(config::parse_fraction("25%"), Some(0.25));
+    assert_eq!(config::parse_fraction("150%"), None);
+    assert_eq!(config::parse_fraction("half"), None);
+}
+
+#[test]
+fn parse_env_vars() {
+    assert_eq!(config::parse_bless("1"), Ok(true));
+    assert_eq!(config::parse_bless("true"), Ok(true));
+    assert_eq!(config::parse_bless("0"), Ok(false));
+    assert_eq!(config::parse_bless(""), Ok(false));
+    assert!(config::parse_bless("yes").is_err());
+    assert_eq!(
+        config::parse_threads("4").map(std::num::NonZeroUsize::get),
+        Ok(4)
+    );
+    assert!(config::parse_threads("0").is_err());
+    assert!(config::parse_threads("many").is_err());
+}
+
+#[test]
+fn shell_quoting() {
+    use bless_command::{quote_unix, quote_windows};
+    assert_eq!(quote_unix("tests/ui/a-b.rs"), "tests/ui/a-b.rs");
+    assert_eq!(quote_unix(""), "''");
+    assert_eq!(quote_unix("tests/ui/a b.rs"), "'tests/ui/a b.rs'");
+    assert_eq!(quote_unix("it's $HOME"), r"'it'\''s $HOME'");
+    assert_eq!(quote_windows(r"tests\ui\a.rs"), r"tests\ui\a.rs");
+    assert_eq!(quote_windows(""), r#""""#);
+    assert_eq!(quote_windows(r"C:\my tests\a.rs"), r#""C:\my tests\a.rs""#);
+    assert_eq!(quote_windows(r"C:\my tests\"), r#""C:\my tests\\""#);
+    assert_eq!(quote_windows(r#"say \"hi""#), r#""say \\\"hi\"""#);
+}
+
+#[test]
+fn bless_command_per_test() {
+    #[derive(Default)]
+    struct BlessCommand(Arc<Mutex<Option<String>>>);
+    impl StatusEmitter for BlessCommand {
+        fn failed_test<'a>(
+            &'a self,
+            _revision: &'a str,
+            _path: &'a Path,
+            _cmd: &'a Command,
+            _stderr: &'a [u8],
+        ) -> Box<dyn std::fmt::Debug + 'a> {
+            Box::new(())
+        }
+        fn finalize(
+            &self,
+            _failed: usize,
+            _succeeded: usize,
+            _ignored: usize,
+            _filtered: usize,
+        ) -> Box<dyn status_emitter::Summary> {
+            Box::new(BlessCommand(self.0.clone()))
+        }
+    }
+    impl status_emitter::Summary for BlessCommand {
+        fn bless_command(&mut self, command: &str) {
+            *self.0.lock().unwrap() = Some(command.to_owned());
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    std::fs::create_dir_all(root.join("sub dir")).unwrap();
+    let error = "fn main() {\n    let x: u8 = 1u16; //~ ERROR: mismatched types\n}\n";
+    std::fs::write(root.join("a.rs"), error).unwrap();
+    std::fs::write(root.join("sub dir/b.rs"), error).unwrap();
+    let mut config = Config::rustc(root.clone());
+    config.out_dir = dir.path().join("out");
+    config.output_conflict_handling =
+        OutputConflictHandling::Error("cargo test -- -- --bless {filters}".into());
+
+    config.fill_host_and_target().unwrap();
+
+    let runs = parse_and_test_file(
+        &root.join("a.rs"),
+        &config,
+        &TestCache::default(),
+        &Jobs::new(config.num_test_threads),
+    );
+    let errors = match &runs[0].result {
+        TestResult::Errored { errors, .. } => errors,
+        _ => panic!("the test has no .stderr file and should fail"),
+    };
+    let bless_commands: Vec<_> = errors
+        .iter()
+        .filter_map(|error| match error {
+            Error::OutputDiffers { bless_command, .. } => Some(bless_command.as_str()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(bless_commands, ["cargo test -- -- --bless a.rs"]);
+
+    let bless_command = BlessCommand::default();
+    let command = bless_command.0.clone();
+    assert!(run_tests_generic(
+        config,
+        default_file_filter,
+        default_per_file_config,
+        bless_command,
+    )
+    .is_err());
+    let expected = if cfg!(windows) {
+        r#"cargo test -- -- --bless a.rs "sub dir\b.rs""#
+    } else {
+        "cargo test -- -- --bless a.rs 'sub dir/b.rs'"
+    };
+    assert_eq!(command.lock().unwrap().as_deref(), Some(expected));
+}
+
+#[test]
+fn globs() {
+    use glob::Glob;
+    let matches = |glob: &str, path: &str| Glob::parse(glob).unwrap().matches(path);
+    assert!(matches("a.rs", "a.rs"));
+    assert!(!matches("a.rs", "b/a.rs"));
+    assert!(matches("*.rs", "a.rs"));
+    assert!(!matches("*.rs", "b/a.rs"));
+    assert!(matches("**/*.rs", "a.rs"));
+    assert!(matches("**/*.rs", "b/c/a.rs"));
+    assert!(matches("borrowck/**", "borrowck/a/b.rs"));
+    assert!(!matches("borrowck/**", "borrowck2/a.rs"));
+    assert!(matches("borrowck/**/*.rs", "borrowck/a.rs"));
+    assert!(matches("a/**/b/*.rs", "a/x/y/b/c.rs"));
+    assert!(matches("issue-?.rs", "issue-1.rs"));
+    assert!(!matches("issue-?.rs", "issue-12.rs"));
+    assert!(matches("issue-[0-9][0-9].rs", "issue-12.rs"));
+    assert!(!matches("issue-[!0-9].rs", "issue-1.rs"));
+    assert!(matches("[]a].rs", "].rs"));
+    assert!(matches("*a*b*", "xaybz"));
+    assert!(!matches("*a*b*", "xbya"));
+
+    assert!(Glob::parse("").is_err());
+    assert!(Glob::parse("a**.rs").is_err());
+    assert!(Glob::parse("[a-z.rs").is_err());
+    assert!(Glob::parse("a].rs").is_err());
+    assert!(Glob::parse("[z-a].rs").is_err());
+}
+
+#[test]
+fn filters_and_skip() {
+    #[derive(Default)]
+    struct Excluded(Arc<Mutex<Option<(usize, usize)>>>);
+    impl StatusEmitter for Excluded {
+        fn failed_test<'a>(
+            &'a self,
+            _revision: &'a str,
+            _path: &'a Path,
+            _cmd: &'a Command,
+            _stderr: &'a [u8],
+        ) -> Box<dyn std::fmt::Debug + 'a> {
+            Box::new(())
+        }
+        fn excluded_tests(&mut self, by_filters: usize, by_skip: usize) {
+            *self.0.lock().unwrap() = Some((by_filters, by_skip));
+        }
+        fn finalize(
+            &self,
+            _failed: usize,
+            _succeeded: usize,
+            _ignored: usize,
+            _filtered: usize,
+        ) -> Box<dyn status_emitter::Summary> {
+            Box::new(())
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    std::fs::create_dir_all(root.join("borrowck/nested")).unwrap();
+    std::fs::create_dir_all(root.join("parser")).unwrap();
+    let pass = "//@check-pass\nfn main() {}\n";
+    std::fs::write(root.join("borrowck/a.rs"), pass).unwrap();
+    std::fs::write(root.join("borrowck/nested/b.rs"), pass).unwrap();
+    std::fs::write(root.join("borrowck/nested/slow.rs"), pass).unwrap();
+    std::fs::write(root.join("parser/c.rs"), pass).unwrap();
+    // Excluded tests don't run, so they can't fail.
+    std::fs::write(root.join("parser/d.rs"), "//@check-pass\nfn main() { x }\n").unwrap();
+    let mut config = Config::rustc(root);
+    config.out_dir = dir.path().join("out");
+    config.filters = vec!["borrowck/**/*.rs".into(), "parser/c.rs".into()];
+    config.skip = vec!["**/slow.rs".into()];
+    let run = |config: &Config| {
+        let excluded = Excluded::default();
+        let counts = excluded.0.clone();
+        let result = run_tests_generic(
+            config.clone(),
+            default_file_filter,
+            default_per_file_config,
+            excluded,
+        );
+        let counts = counts.lock().unwrap().take();
+        (result, counts)
+    };
+    let (result, counts) = run(&config);
+    result.unwrap();
+    assert_eq!(counts, Some((1, 1)));
+
+    config.skip = vec!["borrowck/[".into()];
+    let err = run(&config).0.unwrap_err().to_string();
+    assert!(err.contains("invalid glob `borrowck/[`"), "{err}");
+}
+
+#[test]
+fn exact_filters() {
+    let mut config = config();
+    config.root_dir = PathBuf::from("tests/ui");
+    let path = Path::new("tests/ui/borrowck/a.rs");
+    assert_eq!(test_id(&config, path, ""), "borrowck/a.rs");
+    assert_eq!(test_id(&config, path, "foo"), "borrowck/a.rs::foo");
+
+    config.exact = true;
+    config.filters = vec!["borrowck/a.rs::foo".into(), "borrowck/*.rs".into()];
+    assert_eq!(excluded(&config, path, "foo"), None);
+    assert_eq!(excluded(&config, path, "bar"), Some(Excluded::Filter));
+    assert_eq!(excluded(&config, path, ""), Some(Excluded::Filter));
+    config.skip = vec!["borrowck/a.rs::foo".into()];
+    assert_eq!(excluded(&config, path, "foo"), Some(Excluded::Skip));
+
+    config.exact = false;
+    config.skip = vec![];
+    assert_eq!(excluded(&config, path, "bar"), None);
+}
+
+#[test]
+fn list_does_not_run_tests() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("a.rs"), "//@check-pass\nfn main() { x }\n").unwrap();
+    let mut config = Config::rustc(root);
+    config.out_dir = dir.path().join("out");
+    config.list = true;
+    run_tests_generic(
+        config,
+        default_file_filter,
+        default_per_file_config,
+        status_emitter::Text,
+    )
+    .unwrap();
+    assert!(!dir.path().join("out").exists());
+}
+
+#[test]
+fn timing_baseline() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("a.rs"), "//@check-pass\nfn main() {}\n").unwrap();
+    std::fs::write(root.join("b.rs"), "//@check-pass\nfn main() {}\n").unwrap();
+    let baseline = dir.path().join("timings.json");
+    let mut config = Config::rustc(root.clone());
+    config.out_dir = dir.path().join("out");
+    config.timing_baseline = Some(baseline.clone());
+    config.timing_min_delta = Duration::ZERO;
+    let run = |config: &Config| {
+        run_tests_generic(
+            config.clone(),
+            default_file_filter,
+            default_per_file_config,
+            status_emitter::Text,
+        )
+    };
+    // Without a baseline, there is nothing to compare to.
+    run(&config).unwrap();
+    assert!(!baseline.exists());
+
+    config.bless_timings = true;
+    run(&config).unwrap();
+    let json: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&baseline).unwrap()).unwrap();
+    assert_eq!(json["version"], 1);
+    assert_eq!(json["tests"][0]["path"], "a.rs");
+    assert_eq!(json["tests"][1]["path"], "b.rs");
+    assert!(json["tests"][0]["secs"].as_f64().unwrap() > 0.0);
+
+    // Pretend `a.rs` used to be a lot faster.
+    let mut json = json;
+    json["tests"][0]["secs"] = 0.000001.into();
+    std::fs::write(&baseline, serde_json::to_vec(&json).unwrap()).unwrap();
+    config.bless_timings = false;
+    assert!(run(&config).is_err());
+    config.timing_samples = 3;
+    assert!(run(&config).is_err());
+    config.timing_regressions = Strictness::Warn;
+    run(&config).unwrap();
+    config.timing_regressions = Strictness::Error;
+    config.timing_min_delta = Duration::from_secs(100);
+    run(&config).unwrap();
+
+    // Blessing forgets removed tests.
+    std::fs::remove_file(root.join("b.rs")).unwrap();
+    config.bless_timings = true;
+    run(&config).unwrap();
+    let json: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&baseline).unwrap()).unwrap();
+    assert_eq!(json["tests"].as_array().unwrap().len(), 1);
+    assert!(json["tests"][0]["secs"].as_f64().unwrap() > 0.000001);
+
+    std::fs::write(&baseline, "{}").unwrap();
+    assert!(run(&config).is_err());
+}
+
+#[test]
+fn median() {
+    let ms = Duration::from_millis;
+    assert_eq!(timing_baseline::median(vec![ms(3)]), ms(3));
+    assert_eq!(timing_baseline::median(vec![ms(9), ms(1), ms(3)]), ms(3));
+    assert_eq!(
+        timing_baseline::median(vec![ms(4), ms(1), ms(2), ms(100)]),
+        ms(3)
+    );
+}
+
+#[test]
+fn run_single_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    std::fs::create_dir_all(&root).unwrap();
+    let path = root.join("a.rs");
+    std::fs::write(
+        &path,
+        "//@revisions: good bad\n//@check-pass\n#[cfg(good)]\nfn main() {}\n#[cfg(bad)]\nfn main() { x }\n",
+    )
+    .unwrap();
+    let mut config = Config::rustc(root);
+    config.out_dir = dir.path().join("out");
+    let outcomes = run_file(config, &path).unwrap();
+    assert_eq!(outcomes.len(), 2);
+    assert_eq!(outcomes[0].revision, "good");
+    assert!(matches!(outcomes[0].result, TestResult::Ok));
+    assert!(outcomes[0].command.is_some());
+    assert_eq!(outcomes[1].revision, "bad");
+    match &outcomes[1].result {
+        TestResult::Errored { errors, .. } => assert!(!errors.is_empty()),
+        _ => panic!("the `bad` revision should fail"),
+    }
+    let stderr = String::from_utf8_lossy(&outcomes[1].stderr);
+    assert!(stderr.contains("cannot find value `x`"), "{stderr}");
+}
+
+#[test]
+fn comment_start_by_extension() {
+    let mut config = config();
+    config.comment_start = "#".into();
+    config.comment_start_by_extension = vec![("rs".into(), "//".into())];
+    assert_eq!(config.comment_start_for(Path::new("a.mylang")), "#");
+    assert_eq!(config.comment_start_for(Path::new("auxiliary/b.rs")), "//");
+    assert_eq!(config.comment_start_for(Path::new("c")), "#");
+}
+
+#[test]
+fn multiple_roots() {
+    type Roots = Vec<(PathBuf, usize, usize, usize, usize)>;
+    #[derive(Default)]
+    struct RootResults(Arc<Mutex<Option<Roots>>>);
+    impl StatusEmitter for RootResults {
+        fn failed_test<'a>(
+            &'a self,
+            _revision: &'a str,
+            _path: &'a Path,
+            _cmd: &'a Command,
+            _stderr: &'a [u8],
+        ) -> Box<dyn std::fmt::Debug + 'a> {
+            Box::new(())
+        }
+        fn test_roots(&mut self, roots: &[(PathBuf, usize, usize, usize, usize)]) {
+            *self.0.lock().unwrap() = Some(roots.to_vec());
+        }
+        fn finalize(
+            &self,
+            _failed: usize,
+            _succeeded: usize,
+            _ignored: usize,
+            _filtered: usize,
+        ) -> Box<dyn status_emitter::Summary> {
+            Box::new(())
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    std::fs::create_dir_all(root.join("pass")).unwrap();
+    std::fs::create_dir_all(root.join("fail")).unwrap();
+    std::fs::write(root.join("pass/a.rs"), "fn main() {}\n").unwrap();
+    std::fs::write(
+        root.join("fail/b.rs"),
+        "fn main() { x } //~ ERROR: cannot find value\n",
+    )
+    .unwrap();
+    std::fs::write(root.join("fail/c.rs"), "fn main() {}\n").unwrap();
+    let mut config = Config::rustc(root.clone());
+    config.out_dir = dir.path().join("out");
+    config.output_conflict_handling = OutputConflictHandling::Bless;
+    // Filters apply to the paths relative to the shared root directory.
+    config.skip = vec!["fail/c.rs".into()];
+    let emitter = RootResults::default();
+    let roots = emitter.0.clone();
+    run_test_roots_generic(
+        config,
+        vec![
+            TestRoot {
+                overrides: Box::new(|config| config.mode = Mode::Pass),
+                ..TestRoot::new("pass")
+            },
+            // Would fail if the mode of `pass` leaked into it.
+            TestRoot::new("fail"),
+        ],
+        default_file_filter,
+        default_per_file_config,
+        emitter,
+    )
+    .unwrap();
+    assert_eq!(
+        roots.lock().unwrap().take().unwrap(),
+        [
+            (root.join("pass"), 0, 1, 0, 0),
+            (root.join("fail"), 0, 1, 0, 1)
+        ]
+    );
+}
+
+#[test]
+fn defaults_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    std::fs::create_dir_all(root.join("sub")).unwrap();
+    std::fs::write(
+        root.join("ui_test.defaults"),
+        "//@compile-flags: --outer\n//@check-pass\n//@edition: 2018\n",
+    )
+    .unwrap();
+    std::fs::write(
+        root.join("sub/ui_test.defaults"),
+        "//@compile-flags: --inner\n//@edition: 2021\n",
+    )
+    .unwrap();
+    std::fs::write(root.join("sub/a.rs"), "//@compile-flags: --own\n").unwrap();
+    std::fs::write(root.join("sub/b.rs"), "//@run\n").unwrap();
+    let config = Config::rustc(root.clone());
+
+    let comments = parse_test_comments(&root.join("sub/a.rs"), &config).unwrap();
+    let defaults = &comments.revisioned[&vec![]];
+    assert_eq!(defaults.compile_flags, ["--outer", "--inner", "--own"]);
+    assert_eq!(defaults.edition, Some(("2021".to_string(), 2)));
+    assert!(
+        matches!(
+            config.mode.maybe_override(&comments, "", &mut vec![]),
+            Mode::Pass
+        ),
+        "the mode of the outer defaults file applies"
+    );
+    let comments = parse_test_comments(&root.join("sub/b.rs"), &config).unwrap();
+    assert!(
+        matches!(
+            config.mode.maybe_override(&comments, "", &mut vec![]),
+            Mode::Run { exit_code: 0 }
+        ),
+        "the test overrides the defaults"
+    );
+
+    // Errors point into the defaults file.
+    std::fs::write(root.join("sub/ui_test.defaults"), "\n//@revisions: a b\n").unwrap();
+    let (_, errors) = parse_test_comments(&root.join("sub/a.rs"), &config).unwrap_err();
+    match &errors[..] {
+        [Error::Defaults { path, errors }] => {
+            assert_eq!(path, &root.join("sub/ui_test.defaults"));
+            assert!(
+                matches!(&errors[..], [Error::InvalidComment { line: 2, .. }]),
+                "{errors:?}"
+            );
+        }
+        _ => panic!("{errors:?}"),
+    }
+}
+
+#[test]
+fn test_hooks() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("a.rs"), "//@check-pass\nfn main() { x }\n").unwrap();
+    std::fs::write(root.join("b.rs"), "//@check-pass\nfn main() {}\n").unwrap();
+    let calls = Arc::new(Mutex::new(vec![]));
+    let mut config = Config::rustc(root.clone());
+    config.out_dir = dir.path().join("out");
+    let before_calls = calls.clone();
+    config.before_test = Some(TestHook::new(move |config, path, _revision| {
+        let name = path.file_name().unwrap().to_str().unwrap().to_owned();
+        before_calls.lock().unwrap().push(format!("before {name}"));
+        assert!(config.out_dir.ends_with("out"));
+        match name.as_str() {
+            "b.rs" => Err("no fixture".into()),
+            _ => Ok(()),
+        }
+    }));
+    let after_calls = calls.clone();
+    config.after_test = Some(TestHook::new(move |_config, path, _revision| {
+        let name = path.file_name().unwrap().to_str().unwrap();
+        after_calls.lock().unwrap().push(format!("after {name}"));
+        Ok(())
+    }));
+
+    // Teardown runs even though the test failed.
+    let outcomes = run_file(config.clone(), &root.join("a.rs")).unwrap();
+    assert!(matches!(outcomes[0].result, TestResult::Errored { .. }));
+    assert_eq!(*calls.lock().unwrap(), ["before a.rs", "after a.rs"]);
+
+    calls.lock().unwrap().clear();
+    let outcomes = run_file(config, &root.join("b.rs")).unwrap();
+    match &outcomes[0].result {
+        TestResult::Errored { errors, .. } => assert!(
+            matches!(&errors[..], [Error::TestHook { hook, message }] if hook == "before_test" && message == "no fixture"),
+            "{errors:?}"
+        ),
+        _ => panic!("a failing `before_test` hook fails the test"),
+    }
+    assert_eq!(*calls.lock().unwrap(), ["before b.rs"]);
+}
+
+#[test]
+fn custom_comments() {
+    #[derive(Debug)]
+    struct Cfg(String);
+    impl Flag for Cfg {
+        fn apply(&self, cmd: &mut Command) {
+            cmd.arg("--cfg").arg(&self.0);
+        }
+    }
+    #[derive(Debug)]
+    struct ExpectStderr(String);
+    impl Flag for ExpectStderr {
+        fn post_test_action(
+            &self,
+            _config: &Config,
+            _path: &Path,
+            output: &Output,
+        ) -> Result<(), String> {
+            if String::from_utf8_lossy(&output.stderr).contains(&self.0) {
+                Ok(())
+            } else {
+                Err(format!("the stderr does not contain `{}`", self.0))
+            }
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(
+        root.join("a.rs"),
+        "//@revisions: a b\n//@check-pass\n//@[a]cfg: foo\n\
+         #[cfg(all(a, not(foo)))]\ncompile_error!(\"foo must be set in a\");\n\
+         #[cfg(all(b, foo))]\ncompile_error!(\"foo must not be set in b\");\n\
+         fn main() {}\n",
+    )
+    .unwrap();
+    std::fs::write(
+        root.join("b.rs"),
+        "//@check-pass\n//@expect-stderr: unused variable\nfn main() {}\n",
+    )
+    .unwrap();
+    let mut config = Config::rustc(root.clone());
+    config.out_dir = dir.path().join("out");
+    config.custom_comments.insert(
+        "cfg".into(),
+        CustomComment::new(|args| Ok(Box::new(Cfg(args.to_owned())))),
+    );
+    config.custom_comments.insert(
+        "expect-stderr".into(),
+        CustomComment::new(|args| Ok(Box::new(ExpectStderr(args.to_owned())))),
+    );
+
+    let outcomes = run_file(config.clone(), &root.join("a.rs")).unwrap();
+    assert_eq!(outcomes.len(), 2);
+    for outcome in &outcomes {
+        assert!(
+            matches!(outcome.result, TestResult::Ok),
+            "{}: {}",
+            outcome.revision,
+            String::from_utf8_lossy(&outcome.stderr)
+        );
+        let command = format!("{:?}", outcome.command.as_ref().unwrap());
+        assert_eq!(
+            command.contains(r#""--cfg" "foo""#),
+            outcome.revision == "a",
+            "{command}"
+        );
+    }
+
+    let outcomes = run_file(config, &root.join("b.rs")).unwrap();
+    match &outcomes[0].result {
+        TestResult::Errored { errors, .. } => assert!(
+            matches!(&errors[..], [Error::CustomComment { name, message, line: 2 }] if name == "expect-stderr" && message.contains("unused variable")),
+            "{errors:?}"
+        ),
+        _ => panic!("a failing custom comment fails the test"),
+    }
+}
+
+#[test]
+fn custom_comment_pre_test_action() {
+    #[derive(Debug)]
+    struct Probe(String);
+    impl Flag for Probe {
+        fn pre_test_action(
+            &self,
+            config: &Config,
+            _path: &Path,
+            _revision: &str,
+        ) -> Result<PreTest, String> {
+            match self.0.as_str() {
+                "missing" => Ok(PreTest::Skip {
+                    reason: "no such hardware".into(),
+                }),
+                "present" => Ok(PreTest::Continue),
+                "replace" => {
+                    let mut cmd = Command::new("rustc");
+                    cmd.arg(config.root_dir.parent().unwrap().join("ok.rs"))
+                        .arg("--out-dir")
+                        .arg(&config.out_dir);
+                    Ok(PreTest::ReplaceCommand(cmd))
+                }
+                other => Err(format!("cannot probe `{other}`")),
+            }
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(dir.path().join("ok.rs"), "fn main() {}\n").unwrap();
+    std::fs::write(
+        root.join("a.rs"),
+        "//@revisions: a b\n//@check-pass\n//@[a]probe: missing\n//@[b]probe: present\nfn main() {}\n",
+    )
+    .unwrap();
+    std::fs::write(
+        root.join("b.rs"),
+        "//@check-pass\n//@probe: replace\nfn main() { x }\n",
+    )
+    .unwrap();
+    std::fs::write(
+        root.join("c.rs"),
+        "//@check-pass\n//@probe: broken\nfn main() {}\n",
+    )
+    .unwrap();
+    let mut config = Config::rustc(root.clone());
+    config.out_dir = dir.path().join("out");
+    config.custom_comments.insert(
+        "probe".into(),
+        CustomComment::new(|args| Ok(Box::new(Probe(args.to_owned())))),
+    );
+
+    let outcomes = run_file(config.clone(), &root.join("a.rs")).unwrap();
+    match &outcomes[0].result {
+        TestResult::Ignored { reason, line } => {
+            assert_eq!(reason, "`//@probe`: no such hardware");
+            assert_eq!(*line, 3);
+        }
+        _ => panic!("revision `a` is skipped"),
+    }
+    assert!(outcomes[0].command.is_none());
+    assert!(matches!(outcomes[1].result, TestResult::Ok));
+
+    // The replacement compiles `ok.rs` instead of the broken test.
+    let outcomes = run_file(config.clone(), &root.join("b.rs")).unwrap();
+    assert!(
+        matches!(outcomes[0].result, TestResult::Ok),
+        "{}",
+        String::from_utf8_lossy(&outcomes[0].stderr)
+    );
+    let command = format!("{:?}", outcomes[0].command.as_ref().unwrap());
+    assert!(command.contains("ok.rs"), "{command}");
+
+    let outcomes = run_file(config, &root.join("c.rs")).unwrap();
+    match &outcomes[0].result {
+        TestResult::Errored { errors, .. } => assert!(
+            matches!(&errors[..], [Error::CustomComment { name, message, line: 2 }] if name == "probe" && message == "cannot probe `broken`"),
+            "{errors:?}"
+        ),
+        _ => panic!("a failing pre-test action fails the test"),
+    }
+}
+
+#[test]
+fn cached_host() {
+    let mut config = Config::rustc("tests");
+    // Asking this program for the host would fail.
+    config.program.program = "ui_test-cached-host-rustc".into();
+    let mut other = config.clone();
+    config.set_host("x86_64-unknown-cached");
+    other.fill_host_and_target().unwrap();
+    assert_eq!(other.host.as_deref(), Some("x86_64-unknown-cached"));
+    assert_eq!(other.target.as_deref(), Some("x86_64-unknown-cached"));
+
+    let mut unknown = Config::rustc("tests");
+    unknown.program.program = "ui_test-unknown-host-rustc".into();
+    assert!(unknown.fill_host_and_target().is_err());
+}
+
+#[test]
+fn test_discovery() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path();
+    for file in [
+        "a.rs",
+        "a.stderr",
+        "a.fixed",
+        "b.mylang",
+        "auxiliary/helper.rs",
+        "nested/auxiliary/helper.rs",
+        "nested/c.rs",
+        "fixtures/d.rs",
+    ] {
+        let path = root.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, "").unwrap();
+    }
+    let find = |config: &Config| {
+        let mut tests = vec![];
+        find_tests(
+            config,
+            root,
+            &|path| config.has_test_extension(path),
+            &mut |path| tests.push(path.strip_prefix(root).unwrap().to_owned()),
+            &mut vec![],
+            &mut vec![],
+        );
+        tests
+    };
+
+    // The files in `auxiliary` directories are never tests by default.
+    let mut config = Config::rustc(root);
+    assert_eq!(
+        find(&config),
+        [
+            Path::new("a.rs"),
+            Path::new("fixtures/d.rs"),
+            Path::new("nested/c.rs")
+        ]
+    );
+
+    config.test_extensions = vec!["rs".into(), "mylang".into()];
+    config.excluded_dirs.push("fixtures".into());
+    assert_eq!(
+        find(&config),
+        [
+            Path::new("a.rs"),
+            Path::new("b.mylang"),
+            Path::new("nested/c.rs")
+        ]
+    );
+}
+
+#[test]
+fn revision_matrix() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(
+        root.join("a.rs"),
+        "//@revisions: {e2015 e2021} x {opt0 opt3}\n//@check-pass\n\
+         //@[e2015] edition: 2015\n//@[opt3] compile-flags: -Copt-level=3\n\
+         #[cfg(not(all(any(e2015, e2021), any(opt0, opt3))))]\n\
+         compile_error!(\"every value of the revision gets a cfg\");\n\
+         fn main() {}\n",
+    )
+    .unwrap();
+    let mut config = Config::rustc(root.clone());
+    config.out_dir = dir.path().join("out");
+    let outcomes = run_file(config, &root.join("a.rs")).unwrap();
+    let revisions: Vec<_> = outcomes.iter().map(|o| o.revision.as_str()).collect();
+    assert_eq!(
+        revisions,
+        ["e2015.opt0", "e2015.opt3", "e2021.opt0", "e2021.opt3"]
+    );
+    for outcome in &outcomes {
+        assert!(
+            matches!(outcome.result, TestResult::Ok),
+            "{}: {}",
+            outcome.revision,
+            String::from_utf8_lossy(&outcome.stderr)
+        );
+        let command = format!("{:?}", outcome.command.as_ref().unwrap());
+        let (edition, opt) = outcome.revision.split_once('.').unwrap();
+        assert!(
+            command.contains(&format!("\"--cfg={edition}\" \"--cfg={opt}\"")),
+            "{command}"
+        );
+        let expected_edition = if edition == "e2015" { "2015" } else { "2021" };
+        assert!(
+            command.contains(&format!("\"--edition\" \"{expected_edition}\"")),
+            "{command}"
+        );
+        assert_eq!(
+            command.contains("-Copt-level=3"),
+            opt == "opt3",
+            "{command}"
+        );
+    }
+}
+
+#[test]
+fn revision_conditions() {
+    #[derive(Default)]
+    struct Counts(Arc<Mutex<Option<(usize, usize, usize)>>>);
+    impl StatusEmitter for Counts {
+        fn failed_test<'a>(
+            &'a self,
+            _revision: &'a str,
+            _path: &'a Path,
+            _cmd: &'a Command,
+            _stderr: &'a [u8],
+        ) -> Box<dyn std::fmt::Debug + 'a> {
+            Box::new(())
+        }
+        fn finalize(
+            &self,
+            failed: usize,
+            succeeded: usize,
+            ignored: usize,
+            _filtered: usize,
+        ) -> Box<dyn status_emitter::Summary> {
+            *self.0.lock().unwrap() = Some((failed, succeeded, ignored));
+            Box::new(())
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(
+        root.join("a.rs"),
+        "//@revisions: everywhere nowhere\n//@check-pass\n//@[nowhere] only-target-nonexistent\nfn main() {}\n",
+    )
+    .unwrap();
+    std::fs::write(
+        root.join("b.rs"),
+        "//@revisions: x y\n//@[x] ignore-on-host\n//@[y] only-host-nonexistent\nfn main() {}\n",
+    )
+    .unwrap();
+    let mut config = Config::rustc(root.clone());
+    config.out_dir = dir.path().join("out");
+
+    // Only the revision with the condition is ignored.
+    let outcomes = run_file(config.clone(), &root.join("a.rs")).unwrap();
+    assert!(matches!(outcomes[0].result, TestResult::Ok));
+    match &outcomes[1].result {
+        TestResult::Ignored { reason, line } => {
+            assert_eq!(reason, "`//@only-target-nonexistent`");
+            assert_eq!(*line, 3);
+        }
+        _ => panic!("the `nowhere` revision is ignored"),
+    }
+
+    // Every revision counts as a test, also if all revisions of a file are ignored.
+    let emitter = Counts::default();
+    let counts = emitter.0.clone();
+    run_tests_generic(
+        config,
+        default_file_filter,
+        default_per_file_config,
+        emitter,
+    )
+    .unwrap();
+    assert_eq!(counts.lock().unwrap().take(), Some((0, 1, 3)));
+}
+
+#[test]
+fn revision_filters() {
+    let mut config = config();
+    config.root_dir = PathBuf::from("tests/ui");
+    let path = Path::new("tests/ui/borrowck/a.rs");
+    config.filters = vec!["borrowck/*.rs::edition*".into()];
+    assert_eq!(excluded(&config, path, "edition2021"), None);
+    assert_eq!(excluded(&config, path, "nightly"), Some(Excluded::Filter));
+    assert_eq!(excluded(&config, path, ""), Some(Excluded::Filter));
+    config.filters = vec![];
+    config.skip = vec!["**/a.rs::nightly".into()];
+    assert_eq!(excluded(&config, path, "nightly"), Some(Excluded::Skip));
+    assert_eq!(excluded(&config, path, "edition2021"), None);
+    assert_eq!(excluded(&config, path, ""), None);
+
+    // Only the filtered revision runs, and its id in the JSON output selects it again.
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(
+        root.join("a.rs"),
+        "//@revisions: good bad\n//@check-pass\n#[cfg(good)]\nfn main() {}\n#[cfg(bad)]\nfn main() { x }\n",
+    )
+    .unwrap();
+    let results = dir.path().join("results.json");
+    let mut config = Config::rustc(root);
+    config.out_dir = dir.path().join("out");
+    config.json_output = Some(JsonOutput::File(results.clone()));
+    config.filters = vec!["a.rs::good".into()];
+    run_tests(config.clone()).unwrap();
+    let tests: Vec<serde_json::Value> = std::fs::read_to_string(&results)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .filter(|result: &serde_json::Value| result["type"] == "test")
+        .collect();
+    assert_eq!(tests.len(), 1, "{tests:#?}");
+    assert_eq!(tests[0]["id"], "a.rs::good");
+    assert_eq!(tests[0]["outcome"], "passed");
+
+    config.exact = true;
+    config.filters = vec![tests[0]["id"].as_str().unwrap().to_owned()];
+    run_tests(config).unwrap();
+}
+
+#[test]
+fn unused_revisions() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    std::fs::create_dir_all(&root).unwrap();
+    let test = "//@revisions: old new noisy\n//@check-pass\n//@[new] compile-flags: --cfg new\nfn main() {}\n";
+    std::fs::write(root.join("a.rs"), test).unwrap();
+    std::fs::write(root.join("a.noisy.stderr"), "").unwrap();
+    std::fs::write(
+        root.join("b.rs"),
+        format!("//@allow-unused-revisions\n{test}"),
+    )
+    .unwrap();
+    let mut config = Config::rustc(root.clone());
+    config.out_dir = dir.path().join("out");
+    config.output_conflict_handling = OutputConflictHandling::Ignore;
+
+    // Not checked by default.
+    let outcomes = run_file(config.clone(), &root.join("a.rs")).unwrap();
+    assert!(outcomes.iter().all(|outcome| outcome.warnings.is_empty()));
+
+    config.unused_revisions = Some(Strictness::Warn);
+    let outcomes = run_file(config.clone(), &root.join("a.rs")).unwrap();
+    let unused: Vec<_> = outcomes
+        .iter()
+        .filter(|outcome| !outcome.warnings.is_empty())
+        .map(|outcome| outcome.revision.as_str())
+        .collect();
+    assert_eq!(unused, ["old"]);
+    assert!(outcomes
+        .iter()
+        .all(|outcome| matches!(outcome.result, TestResult::Ok)));
+    assert!(
+        matches!(
+            &outcomes[0].warnings[..],
+            [Error::Warning(error)] if matches!(&**error, Error::InvalidComment { msg, line: 1 } if msg.contains("`old` is not used"))
+        ),
+        "{:?}",
+        outcomes[0].warnings
+    );
+
+    config.unused_revisions = Some(Strictness::Error);
+    let outcomes = run_file(config.clone(), &root.join("a.rs")).unwrap();
+    assert!(matches!(outcomes[0].result, TestResult::Errored { .. }));
+    assert!(matches!(outcomes[1].result, TestResult::Ok));
+
+    let outcomes = run_file(config, &root.join("b.rs")).unwrap();
+    assert!(outcomes
+        .iter()
+        .all(|outcome| matches!(outcome.result, TestResult::Ok)));
+}
+
+#[test]
+fn revision_aux_builds() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    std::fs::create_dir_all(root.join("auxiliary")).unwrap();
+    std::fs::write(
+        root.join("auxiliary/heavy_dep.rs"),
+        "pub fn answer() -> u32 { 42 }\n",
+    )
+    .unwrap();
+    std::fs::write(
+        root.join("a.rs"),
+        "//@revisions: {fancy plain} x {a b}\n\
+         //@[fancy] aux-build: heavy_dep.rs\n\
+         //@[fancy] check-pass\n\
+         use heavy_dep::answer; //~[plain] ERROR: unresolved import\n\
+         fn main() { let _ = answer(); }\n",
+    )
+    .unwrap();
+    let mut config = Config::rustc(root.clone());
+    config.out_dir = dir.path().join("out");
+    config.output_conflict_handling = OutputConflictHandling::Ignore;
+    let outcomes = run_file(config, &root.join("a.rs")).unwrap();
+    assert_eq!(outcomes.len(), 4);
+    let mut externs = vec![];
+    for outcome in &outcomes {
+        assert!(
+            matches!(outcome.result, TestResult::Ok),
+            "{}: {}",
+            outcome.revision,
+            String::from_utf8_lossy(&outcome.stderr)
+        );
+        let command = format!("{:?}", outcome.command);
+        let linked = command.contains("heavy_dep");
+        assert_eq!(
+            linked,
+            outcome.revision.starts_with("fancy"),
+            "{}: {command}",
+            outcome.revision
+        );
+        if linked {
+            externs.push(
+                command
+                    .split('"')
+                    .find(|arg| arg.starts_with("heavy_dep="))
+                    .unwrap()
+                    .to_owned(),
+            );
+        }
+    }
+    // Both `fancy` revisions link the aux file from the same place.
+    assert_eq!(externs.len(), 2);
+    assert_eq!(externs[0], externs[1]);
+}
+
+#[test]
+fn deep_test_out_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    let deep = (0..12).fold(root.clone(), |dir, i| {
+        dir.join(format!("a_rather_long_directory_name_{i}"))
+    });
+    std::fs::create_dir_all(deep.join("auxiliary")).unwrap();
+    std::fs::write(deep.join("auxiliary/helper.rs"), "pub fn helper() {}\n").unwrap();
+    let path = deep.join("deep.rs");
+    std::fs::write(
+        &path,
+        "//@aux-build: helper.rs\n\
+         //@check-pass\n\
+         fn main() { helper::helper(); }\n",
+    )
+    .unwrap();
+    let mut config = Config::rustc(root);
+    config.out_dir = dir.path().join("out");
+    let outcomes = run_file(config.clone(), &path).unwrap();
+    assert!(
+        matches!(outcomes[0].result, TestResult::Ok),
+        "{}",
+        String::from_utf8_lossy(&outcomes[0].stderr)
+    );
+    let out_dir = test_out_dir(&config, &path);
+    assert!(out_dir.starts_with(&config.out_dir));
+    let name = out_dir.file_name().unwrap().to_str().unwrap();
+    assert!(name.starts_with("deep-"), "{name}");
+    assert!(out_dir.join("helper.lib.cache").exists());
+
+    config.max_test_out_dir_len = usize::MAX;
+    let mirrored = test_out_dir(&config, &path);
+    assert!(mirrored.ends_with("a_rather_long_directory_name_11/deep"));
+    assert!(mirrored.starts_with(&config.out_dir));
+}
+
+#[test]
+#[cfg(windows)]
+fn verbatim_paths() {
+    assert_eq!(
+        strip_verbatim(Path::new(r"\\?\C:\tests\ui\foo.rs")),
+        Path::new(r"C:\tests\ui\foo.rs")
+    );
+    assert_eq!(
+        strip_verbatim(Path::new(r"\\?\UNC\server\share\tests\foo.rs")),
+        Path::new(r"\\server\share\tests\foo.rs")
+    );
+    assert_eq!(
+        strip_verbatim(Path::new(r"C:\tests\foo.rs")),
+        Path::new(r"C:\tests\foo.rs")
+    );
+    let config = Config::rustc(r"C:\tests");
+    assert_eq!(
+        relative_to_root(&config, Path::new(r"\\?\C:\tests\ui\foo.rs")),
+        Path::new(r"ui\foo.rs")
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn symlinked_suite() {
+    let dir = tempfile::tempdir().unwrap();
+    let real = dir.path().join("real");
+    let link = dir.path().join("link");
+    std::fs::create_dir_all(&real).unwrap();
+    std::os::unix::fs::symlink(&real, &link).unwrap();
+    std::fs::write(
+        real.join("a.rs"),
+        "fn main() {\n    let x: u32 = \"\"; //~ ERROR: mismatched types\n}\n",
+    )
+    .unwrap();
+
+    // Diagnostics of tools that resolve symlinks mention the real path.
+    let mut config = Config::rustc(&link);
+    config.out_dir = dir.path().join("out");
+    config.output_conflict_handling = OutputConflictHandling::Ignore;
+    config
+        .program
+        .args
+        .push(format!("--remap-path-prefix={}={}", link.display(), real.display()).into());
+    let outcomes = run_file(config.clone(), &link.join("a.rs")).unwrap();
+    assert!(
+        matches!(outcomes[0].result, TestResult::Ok),
+        "{}",
+        String::from_utf8_lossy(&outcomes[0].stderr)
+    );
+
+    assert_eq!(
+        relative_to_root(&config, &real.join("a.rs")),
+        Path::new("a.rs")
+    );
+    config.root_dir = real.clone();
+    assert_eq!(
+        relative_to_root(&config, &link.join("a.rs")),
+        Path::new("a.rs")
+    );
+    assert_eq!(
+        relative_to_root(&config, &dir.path().join("b.rs")),
+        dir.path().join("b.rs").strip_prefix("/").unwrap()
+    );
+}
+
+#[test]
+fn panicking_revision() {
+    #[derive(Debug)]
+    struct Boom;
+    impl Flag for Boom {
+        fn post_test_action(
+            &self,
+            _config: &Config,
+            _path: &Path,
+            _output: &Output,
+        ) -> Result<(), String> {
+            panic!("boom")
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(
+        root.join("a.rs"),
+        "//@revisions: good bad\n//@check-pass\n//@[bad] boom\nfn main() {}\n",
+    )
+    .unwrap();
+    let mut config = Config::rustc(root.clone());
+    config.out_dir = dir.path().join("out");
+    config
+        .custom_comments
+        .insert("boom".into(), CustomComment::new(|_| Ok(Box::new(Boom))));
+
+    let outcomes = run_file(config.clone(), &root.join("a.rs")).unwrap();
+    assert_eq!(outcomes.len(), 2);
+    assert!(matches!(outcomes[0].result, TestResult::Ok));
+    match &outcomes[1].result {
+        TestResult::Errored {
+            command, errors, ..
+        } => {
+            assert!(matches!(&errors[..], [Error::Bug(msg)] if msg == "boom"));
+            assert!(format!("{command:?}").contains("a.rs"));
+        }
+        _ => panic!("the panicking revision did not fail"),
+    }
+
+    // Tests that disappear fail instead of panicking.
+    let outcomes = run_file(config, &root.join("missing.rs")).unwrap();
+    match &outcomes[0].result {
+        TestResult::Errored { errors, .. } => assert!(
+            matches!(&errors[..], [Error::Io { path, .. }] if path.ends_with("missing.rs")),
+            "{errors:?}"
+        ),
+        _ => panic!("the missing test did not fail"),
+    }
+}
+
+#[test]
+fn missing_program() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = Config::rustc(dir.path());
+    config.out_dir = dir.path().join("out");
+    config.program = CommandBuilder::cmd("ui_test_missing_driver");
+    config
+        .program
+        .envs
+        .push(("PATH".into(), Some(dir.path().into())));
+    let err = run_file(config.clone(), &dir.path().join("a.rs"))
+        .err()
+        .unwrap()
+        .to_string();
+    assert_eq!(
+        err,
+        format!(
+            "the program `ui_test_missing_driver` could not be found in any directory of the `PATH`: {}",
+            dir.path().display()
+        )
+    );
+
+    // Found via the `PATH` of the program's environment.
+    std::fs::write(dir.path().join("ui_test_missing_driver"), "").unwrap();
+    config.program.check_program().unwrap();
+
+    config.program = CommandBuilder::cmd(dir.path().join("bin/cargo-miri"));
+    let err = config.program.check_program().unwrap_err().to_string();
+    assert!(
+        err.ends_with(
+            "does not exist\nIs the `miri` component installed? Try `rustup component add miri`."
+        ),
+        "{err}"
+    );
+}
+
+#[test]
+fn stdin_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    std::fs::create_dir_all(root.join("ui")).unwrap();
+    std::fs::create_dir_all(root.join("fixtures")).unwrap();
+    let echo = "use std::io::Read;\n\
+                fn main() {\n    \
+                    let mut input = String::new();\n    \
+                    std::io::stdin().read_to_string(&mut input).unwrap();\n    \
+                    print!(\"{input}\");\n\
+                }\n";
+    std::fs::write(
+        root.join("ui/a.rs"),
+        format!("//@revisions: own shared\n//@run\n{echo}"),
+    )
+    .unwrap();
+    std::fs::write(root.join("ui/a.own.stdin"), "own").unwrap();
+    std::fs::write(root.join("ui/a.stdin"), "shared").unwrap();
+    std::fs::write(
+        root.join("ui/b.rs"),
+        format!("//@run\n//@stdin: ../fixtures/big_input.txt\n{echo}"),
+    )
+    .unwrap();
+    std::fs::write(root.join("ui/b.stdin"), "ignored").unwrap();
+    std::fs::write(root.join("fixtures/big_input.txt"), "fixture").unwrap();
+    std::fs::write(
+        root.join("ui/c.rs"),
+        format!("//@run\n//@stdin: missing.txt\n{echo}"),
+    )
+    .unwrap();
+    std::fs::write(root.join("ui/d.rs"), format!("//@run\n{echo}")).unwrap();
+    let mut config = Config::rustc(root.clone());
+    config.out_dir = dir.path().join("out");
+    config.output_conflict_handling = OutputConflictHandling::Ignore;
+
+    let stdout = |name: &str| -> Vec<String> {
+        run_file(config.clone(), &root.join("ui").join(name))
+            .unwrap()
+            .iter()
+            .map(|outcome| {
+                assert!(
+                    matches!(outcome.result, TestResult::Ok),
+                    "{name}: {}",
+                    String::from_utf8_lossy(&outcome.stderr)
+                );
+                String::from_utf8(outcome.stdout.clone()).unwrap()
+            })
+            .collect()
+    };
+    assert_eq!(stdout("a.rs"), ["own", "shared"]);
+    assert_eq!(stdout("b.rs"), ["fixture"]);
+    assert_eq!(stdout("d.rs"), [""]);
+
+    let outcomes = run_file(config, &root.join("ui/c.rs")).unwrap();
+    match &outcomes[0].result {
+        TestResult::Errored { errors, .. } => assert!(
+            matches!(
+                &errors[..],
+                [Error::InvalidComment { msg, line: 2 }] if msg.starts_with("could not open the stdin file")
+            ),
+            "{errors:?}"
+        ),
+        _ => panic!("a missing stdin file must fail the test"),
+    }
+}
+
+#[test]
+fn cleanup_policies() {
+    #[derive(Default)]
+    struct Reclaimed(Arc<Mutex<Option<u64>>>);
+    impl StatusEmitter for Reclaimed {
+        fn failed_test<'a>(
+            &'a self,
+            _revision: &'a str,
+            _path: &'a Path,
+            _cmd: &'a Command,
+            _stderr: &'a [u8],
+        ) -> Box<dyn std::fmt::Debug + 'a> {
+            Box::new(())
+        }
+        fn reclaimed(&mut self, bytes: u64) {
+            *self.0.lock().unwrap() = Some(bytes);
+        }
+        fn finalize(
+            &self,
+            _failed: usize,
+            _succeeded: usize,
+            _ignored: usize,
+            _filtered: usize,
+        ) -> Box<dyn status_emitter::Summary> {
+            Box::new(())
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    std::fs::create_dir_all(root.join("auxiliary")).unwrap();
+    std::fs::write(root.join("auxiliary/helper.rs"), "pub fn helper() {}\n").unwrap();
+    let path = root.join("run.rs");
+    std::fs::write(
+        &path,
+        "//@aux-build: helper.rs\n\
+         //@revisions: good bad\n\
+         //@run\n\
+         fn main() {\n    helper::helper();\n    if cfg!(bad) { std::process::exit(1) }\n}\n",
+    )
+    .unwrap();
+    let mut config = Config::rustc(root.clone());
+    config.out_dir = dir.path().join("out");
+    let run = |config: &Config| {
+        let reclaimed = Reclaimed::default();
+        let bytes = reclaimed.0.clone();
+        // The `bad` revision fails.
+        let _ = run_tests_generic(
+            config.clone(),
+            default_file_filter,
+            default_per_file_config,
+            reclaimed,
+        );
+        let bytes = bytes.lock().unwrap().take();
+        bytes
+    };
+
+    // Without cleaning up, all tests compile into the `out_dir` itself.
+    let out_dir_arg = |config: &Config| {
+        let cmd = test_command(config.clone(), &path).unwrap();
+        let args: Vec<_> = cmd.get_args().collect();
+        let index = args.iter().position(|arg| *arg == "--out-dir").unwrap();
+        PathBuf::from(args[index + 1])
+    };
+    assert_eq!(run(&config), None);
+    assert_eq!(out_dir_arg(&config), config.out_dir);
+    let out_dir = test_out_dir(&config, &path);
+    // Only the working directory of the test binary is in the directory of the revision.
+    let files: Vec<_> = std::fs::read_dir(out_dir.join("good.artifacts"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(files, ["cwd"]);
+
+    // Only the passing revision loses its artifacts, the aux build stays cached.
+    config.cleanup = CleanupPolicy::RemoveOnSuccess;
+    assert_eq!(out_dir_arg(&config), out_dir.join("artifacts"));
+    assert!(run(&config).unwrap() > 0);
+    assert!(!out_dir.join("good.artifacts").exists());
+    assert!(out_dir.join("bad.artifacts").exists());
+    assert!(out_dir.join("helper.lib.cache").exists());
+
+    // Artifacts of earlier runs and of deleted tests go away.
+    let marker = out_dir.join("bad.artifacts/marker");
+    std::fs::write(&marker, "").unwrap();
+    let gone = test_out_dir(&config, &root.join("gone.rs"));
+    std::fs::create_dir_all(gone.join("artifacts")).unwrap();
+    std::fs::write(gone.join("helper.lib.cache"), "").unwrap();
+    std::fs::write(gone.join("artifacts/gone"), "").unwrap();
+    config.cleanup = CleanupPolicy::Fresh;
+    assert!(run(&config).unwrap() > 0);
+    assert!(!marker.exists());
+    assert!(!gone.exists());
+    assert!(out_dir.join("bad.artifacts").exists());
+    assert!(out_dir.join("helper.lib.cache").exists());
+
+    // Other shards may still be using the artifacts of the revisions they run.
+    std::fs::write(&marker, "").unwrap();
+    config.shard = Some((0, 2));
+    if in_shard(&config, &path, "bad") {
+        config.shard = Some((1, 2));
+    }
+    run(&config);
+    assert!(marker.exists());
+
+    // Tests with long paths have hashed directories directly in the `out_dir`.
+    config.shard = None;
+    config.max_test_out_dir_len = 0;
+    let out_dir = test_out_dir(&config, &path);
+    let gone = test_out_dir(&config, &root.join("gone.rs"));
+    assert_eq!(out_dir.parent(), Some(config.out_dir.as_path()));
+    let marker = out_dir.join("bad.artifacts/marker");
+    std::fs::create_dir_all(marker.parent().unwrap()).unwrap();
+    std::fs::write(&marker, "").unwrap();
+    std::fs::create_dir_all(gone.join("artifacts")).unwrap();
+    std::fs::write(gone.join("helper.lib.cache"), "").unwrap();
+    assert!(run(&config).unwrap() > 0);
+    assert!(!marker.exists());
+    assert!(!gone.exists());
+    assert!(out_dir.join("helper.lib.cache").exists());
+}
+
+#[test]
+fn sorted_errors() {
+    let not_found = |definition_line| Error::PatternNotFound {
+        pattern: Pattern::SubString("foo".into()),
+        definition_line,
+        line: Some(definition_line),
+        nearby: vec![],
+    };
+    let differs = |path: &str| Error::OutputDiffers {
+        path: path.into(),
+        actual: vec![],
+        expected: vec![],
+        bless_command: String::new(),
+        context: 3,
+    };
+    let mut errors = vec![
+        Error::Bug("first".into()),
+        differs("a.stdout"),
+        differs("a.stderr"),
+        not_found(7),
+        Error::ErrorsWithoutPattern {
+            msgs: vec![],
+            path: Some(("a.rs".into(), 3)),
+        },
+        Error::NoPatternsFound,
+        Error::Bug("last".into()),
+        not_found(2),
+    ];
+    sort_errors(&mut errors);
+    let order: Vec<_> = errors
+        .iter()
+        .map(|error| match error {
+            Error::Bug(msg) => msg.clone(),
+            Error::OutputDiffers { path, .. } => path.display().to_string(),
+            Error::PatternNotFound {
+                definition_line, ..
+            } => format!("pattern {definition_line}"),
+            Error::ErrorsWithoutPattern { .. } => "unmatched 3".into(),
+            Error::NoPatternsFound => "no patterns".into(),
+            _ => unreachable!(),
+        })
+        .collect();
+    assert_eq!(
+        order,
+        [
+            "first",
+            "pattern 2",
+            "unmatched 3",
+            "pattern 7",
+            "no patterns",
+            "a.stderr",
+            "last",
+            "a.stdout",
+        ]
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn copy_pasteable_commands() {
+    let mut cmd = Command::new("rustc");
+    cmd.arg("--cfg")
+        .arg("foo=\"bar baz\"")
+        .arg("tests/ui/it's.rs")
+        .env("RUSTC_BOOTSTRAP", "1")
+        .env("A", "b c")
+        .env_remove("RUST_BACKTRACE")
+        .current_dir("/tmp/some dir");
+    let rendered = display_command(&cmd);
+    assert_eq!(
+        rendered,
+        "cd '/tmp/some dir' && A='b c' RUSTC_BOOTSTRAP=1 env -u RUST_BACKTRACE rustc --cfg \
+         'foo=\"bar baz\"' 'tests/ui/it'\\''s.rs'"
+    );
+}
+
+#[test]
+fn working_dirs() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    std::fs::create_dir_all(root.join("ui")).unwrap();
+    std::fs::create_dir_all(root.join("data")).unwrap();
+    // Each run of `a.rs` starts in an empty directory, so the file of the previous run is gone.
+    std::fs::write(
+        root.join("ui/a.rs"),
+        "//@run\n\
+         fn main() {\n    \
+             print!(\"{}\", std::env::current_dir().unwrap().display());\n    \
+             assert!(std::fs::metadata(\"scratch.txt\").is_err());\n    \
+             std::fs::write(\"scratch.txt\", \"a\").unwrap();\n\
+         }\n",
+    )
+    .unwrap();
+    // The program reads the `@flags.txt` relative to the working dir, and so does the binary.
+    std::fs::write(
+        root.join("ui/b.rs"),
+        "//@run\n\
+         //@working-dir: ../data\n\
+         //@compile-flags: @flags.txt\n\
+         #[cfg(from_file)]\n\
+         fn main() {\n    \
+             print!(\"{}\", std::fs::read_to_string(\"input.txt\").unwrap());\n\
+         }\n",
+    )
+    .unwrap();
+    std::fs::write(root.join("data/flags.txt"), "--cfg=from_file").unwrap();
+    std::fs::write(root.join("data/input.txt"), "input").unwrap();
+    let mut config = Config::rustc(root.clone());
+    config.out_dir = dir.path().join("out");
+    config.output_conflict_handling = OutputConflictHandling::Ignore;
+
+    let stdout = |name: &str| -> String {
+        let outcomes = run_file(config.clone(), &root.join("ui").join(name)).unwrap();
+        assert!(
+            matches!(outcomes[0].result, TestResult::Ok),
+            "{name}: {}",
+            String::from_utf8_lossy(&outcomes[0].stderr)
+        );
+        String::from_utf8(outcomes[0].stdout.clone()).unwrap()
+    };
+    let cwd = stdout("a.rs");
+    assert!(Path::new(&cwd).starts_with(dir.path().join("out")), "{cwd}");
+    assert_eq!(stdout("a.rs"), cwd);
+    assert_eq!(stdout("b.rs"), "input");
+
+    // Commands that can't even be started fail the test instead of panicking.
+    std::fs::write(
+        root.join("ui/c.rs"),
+        "//@working-dir: ../missing\nfn main() {}\n",
+    )
+    .unwrap();
+    let outcomes = run_file(config, &root.join("ui/c.rs")).unwrap();
+    match &outcomes[0].result {
+        TestResult::Errored { errors, .. } => assert!(
+            matches!(&errors[..], [Error::Io { message, .. }] if message.starts_with("could not execute")),
+            "{errors:?}"
+        ),
+        _ => panic!("the test with a missing working dir did not fail"),
+    }
+}
+
+#[test]
+fn determinism_check() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("tests");
+    std::fs::create_dir_all(&root).unwrap();
+    // Neither the outdated `.stderr` file nor the missing annotation matter.
+    std::fs::write(
+        root.join("a.rs"),
+        "fn main() {\n    let x: u32 = \"\";\n}\n",
+    )
+    .unwrap();
+    std::fs::write(root.join("a.stderr"), "outdated\n").unwrap();
+    std::fs::write(
+        root.join("b.rs"),
+        "//@run\n\
+         fn main() {\n    \
+             println!(\"same\");\n    \
+             let nanos = std::time::SystemTime::UNIX_EPOCH.elapsed().unwrap().as_nanos();\n    \
+             println!(\"{nanos}\");\n\
+         }\n",
+    )
+    .unwrap();
+    let mut config = Config::rustc(root.clone());
+    config.out_dir = dir.path().join("out");
+    config.check_determinism = true;
+
+    let outcomes = run_file(config.clone(), &root.join("a.rs")).unwrap();
+    assert!(matches!(outcomes[0].result, TestResult::Ok));
+
+    let outcomes = run_file(config, &root.join("b.rs")).unwrap();
+    match &outcomes[0].result {
+        TestResult::Errored { errors, .. } => match &errors[..] {
+            [Error::Nondeterministic {
+                stream: "stdout",
+                first,
+                second,
+                ..
+            }] => {
+                assert!(first.starts_with(b"same\n"));
+                assert!(second.starts_with(b"same\n"));
+                assert_ne!(first, second);
+            }
+            _ => panic!("{errors:?}"),
+        },
+        _ => panic!("b.rs should have failed"),
+    }
+}
+
+#[test]
+fn cargo_constructors() {
+    let config = Config::cargo("tests");
+    assert_eq!(config.test_kind, TestKind::File);
+    assert!(!config
+        .program
+        .args
+        .iter()
+        .any(|arg| arg == "--message-format=json"));
+    let config = Config::cargo_packages("tests");
+    assert_eq!(config.test_kind, TestKind::CargoProject);
+    assert!(config
+        .program
+        .args
+        .iter()
+        .any(|arg| arg == "--message-format=json"));
+}
+
+#[test]
+fn unused_env_vars_are_not_checked() {
+    let check = |config: &Config, name: &str, value: &str| {
+        config::check_env_var(config, name, Ok(value.into())).is_ok()
+    };
+    let mut config = config();
+    config.output_conflict_handling = OutputConflictHandling::Error("bless it".into());
+    assert!(check(&config, "UI_TEST_BLESS", "1"));
+    assert!(!check(&config, "UI_TEST_BLESS", "yes"));
+    config.output_conflict_handling = OutputConflictHandling::Bless;
+    assert!(check(&config, "UI_TEST_BLESS", "yes"));
+
+    config.num_test_threads = config::fallback_test_threads();
+    assert!(!check(&config, "UI_TEST_THREADS", "many"));
+    config.num_test_threads =
+        std::num::NonZeroUsize::new(config.num_test_threads.get() + 1).unwrap();
+    assert!(check(&config, "UI_TEST_THREADS", "many"));
+}
@@ -0,0 +1,143 @@
+//! Comparing how long tests took to the durations of an earlier run,
+//! see [`Config::timing_baseline`].
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use color_eyre::eyre::{eyre, Result};
+
+use crate::{relative_to_root, Config};
+
+/// The version of the format of [`Baseline`]. Files of other versions are rejected.
+const VERSION: u32 = 1;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Baseline {
+    version: u32,
+    tests: Vec<TestDuration>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct TestDuration {
+    /// Relative to [`Config::root_dir`].
+    path: PathBuf,
+    revision: String,
+    secs: f64,
+}
+
+/// The durations of the tests in the baseline, by their path relative to [`Config::root_dir`]
+/// and their revision.
+pub(crate) struct TimingBaseline {
+    file: PathBuf,
+    durations: HashMap<(PathBuf, String), Duration>,
+}
+
+impl TimingBaseline {
+    /// Read the baseline, if [`Config::timing_baseline`] is set. A missing file is an empty
+    /// baseline, so that blessing can create it.
+    pub(crate) fn load(config: &Config) -> Result<Option<Self>> {
+        let file = match &config.timing_baseline {
+            Some(file) => file.clone(),
+            None => return Ok(None),
+        };
+        let json = match std::fs::read(&file) {
+            Ok(json) => json,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Some(Self {
+                    file,
+                    durations: HashMap::new(),
+                }))
+            }
+            Err(err) => return Err(eyre!("could not read {}: {err}", file.display())),
+        };
+        let baseline: Baseline = serde_json::from_slice(&json)
+            .map_err(|err| eyre!("could not parse {}: {err}", file.display()))?;
+        if baseline.version != VERSION {
+            return Err(eyre!(
+                "{} has version {}, but only version {VERSION} is supported, use `--bless-timings` to recreate it",
+                file.display(),
+                baseline.version,
+            ));
+        }
+        let durations = baseline
+            .tests
+            .into_iter()
+            .filter(|test| test.secs.is_finite() && test.secs >= 0.0)
+            .map(|test| {
+                (
+                    (test.path, test.revision),
+                    Duration::from_secs_f64(test.secs),
+                )
+            })
+            .collect();
+        Ok(Some(Self { file, durations }))
+    }
+
+    /// The duration of the test in the baseline, if it took considerably less time there than
+    /// `actual`, see [`Config::timing_threshold`] and [`Config::timing_min_delta`].
+    pub(crate) fn regressed(
+        &self,
+        config: &Config,
+        path: &Path,
+        revision: &str,
+        actual: Duration,
+    ) -> Option<Duration> {
+        let baseline = *self
+            .durations
+            .get(&(relative_to_root(config, path), revision.to_owned()))?;
+        let limit = baseline.as_secs_f64() * (1.0 + config.timing_threshold);
+        (actual.as_secs_f64() > limit && actual.saturating_sub(baseline) >= config.timing_min_delta)
+            .then_some(baseline)
+    }
+
+    /// Replace the durations of the given tests in the baseline, and write it. Tests that did
+    /// not run keep their duration, unless they don't exist anymore.
+    pub(crate) fn bless<'a>(
+        mut self,
+        config: &Config,
+        durations: impl Iterator<Item = (&'a Path, &'a str, Duration)>,
+    ) -> Result<()> {
+        self.durations
+            .retain(|(path, _), _| config.root_dir.join(path).exists());
+        for (path, revision, duration) in durations {
+            self.durations.insert(
+                (relative_to_root(config, path), revision.to_owned()),
+                duration,
+            );
+        }
+        let mut tests: Vec<_> = self
+            .durations
+            .into_iter()
+            .map(|((path, revision), duration)| TestDuration {
+                path,
+                revision,
+                secs: duration.as_secs_f64(),
+            })
+            .collect();
+        // Sorted, so that the file changes as little as possible.
+        tests.sort_by(|a, b| (&a.path, &a.revision).cmp(&(&b.path, &b.revision)));
+        let json = serde_json::to_vec_pretty(&Baseline {
+            version: VERSION,
+            tests,
+        })?;
+        if let Some(dir) = self.file.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&self.file, json)
+            .map_err(|err| eyre!("could not write {}: {err}", self.file.display()))
+    }
+}
+
+/// The median of the durations, which must not be empty.
+pub(crate) fn median(mut durations: Vec<Duration>) -> Duration {
+    durations.sort();
+    let mid = durations.len() / 2;
+    if durations.len() % 2 == 0 {
+        (durations[mid - 1] + durations[mid]) / 2
+    } else {
+        durations[mid]
+    }
+}
@@ -20,13 +20,12 @@ fn run(name: &str, mode: Mode) -> Result<()> {
     let bless = std::env::args().all(|arg| arg != "--check");
     let mut config = Config {
         mode,
-        ..Config::cargo(root_dir.clone())
+        ..Config::cargo_packages(root_dir)
     };
 
     if bless {
         config.output_conflict_handling = OutputConflictHandling::Bless;
     }
-
     config.program.args = vec![
         "test".into(),
         "--color".into(),
@@ -47,7 +46,7 @@ fn run(name: &str, mode: Mode) -> Result<()> {
         .push(("BLESS".into(), bless.then(|| String::new().into())));
 
     config.stdout_filter("in ([0-9]m )?[0-9\\.]+s", "");
-    config.stderr_filter(r#""--out-dir"(,)? "[^"]+""#, r#""--out-dir"$1 "$$TMP"#);
+    config.stderr_filter("(--out-dir )[^ \n]+", "$1$$TMP");
     config.stderr_filter(
         "( *process didn't exit successfully: `[^-]+)-[0-9a-f]+",
         "$1-HASH",
@@ -56,27 +55,29 @@ fn run(name: &str, mode: Mode) -> Result<()> {
     config.stderr_filter("exit code", "exit status");
     // The order of the `/deps` directory flag is flaky
     config.stderr_filter("/deps", "");
-    config.path_stderr_filter(&std::path::Path::new(path), "$DIR");
+    config.path_stderr_filter(std::path::Path::new(path), "$DIR");
     config.stderr_filter("[0-9a-f]+\\.rmeta", "$$HASH.rmeta");
     // Windows backslashes are sometimes escaped.
     // Insert the replacement filter at the start to make sure the filter for single backslashes
     // runs afterwards.
     config
         .stderr_filters
-        .insert(0, (Match::Exact(b"\\\\".iter().copied().collect()), b"\\"));
+        .insert(0, (Match::Exact(b"\\\\".to_vec()), b"\\"));
     config.stderr_filter("\\.exe", b"");
     config.stderr_filter(r#"(panic.*)\.rs:[0-9]+:[0-9]+"#, "$1.rs");
     config.stderr_filter("   [0-9]: .*", "");
     config.stderr_filter("/target/[^/]+/[^/]+/debug", "/target/$$TMP/$$TRIPLE/debug");
     config.stderr_filter("/target/[^/]+/tests", "/target/$$TMP/tests");
+    config.stderr_filter("/target/\\.tmp[^/ \n]+", "/target/$$TMP");
     // Normalize proc macro filenames on windows to their linux repr
     config.stderr_filter("/([^/\\.]+)\\.dll", "/lib$1.so");
     // Normalize proc macro filenames on mac to their linux repr
     config.stderr_filter("/([^/\\.]+)\\.dylib", "/$1.so");
-    config.stderr_filter("(command: )\"[^<rp][^\"]+", "$1\"$$CMD");
+    config.stderr_filter("(command: )[^<rp'` \n][^ \n]*", "$1$$CMD");
     config.stderr_filter("(src/.*?\\.rs):[0-9]+:[0-9]+", "$1:LL:CC");
     config.stderr_filter("program not found", "No such file or directory");
     config.stderr_filter(" \\(os error [0-9]+\\)", "");
+    config.stderr_filter("(in any directory of the `PATH`: ).*", "$1$$PATH");
 
     run_tests_generic(
         config,
@@ -93,16 +94,14 @@ fn run(name: &str, mode: Mode) -> Result<()> {
                 // on windows there's also a .pdb file, so we get additional errors that aren't there on other platforms
                 return false;
             }
-            path.ends_with("Cargo.toml")
-                && path.parent().unwrap().parent().unwrap() == root_dir
-                && match mode {
-                    Mode::Pass => !fail,
-                    // This is weird, but `cargo test` returns 101 instead of 1 when
-                    // multiple [[test]]s exist. If there's only one test, it returns
-                    // 1 on failure.
-                    Mode::Panic => fail,
-                    Mode::Fix | Mode::Run { .. } | Mode::Yolo | Mode::Fail { .. } => unreachable!(),
-                }
+            match mode {
+                Mode::Pass => !fail,
+                // This is weird, but `cargo test` returns 101 instead of 1 when
+                // multiple [[test]]s exist. If there's only one test, it returns
+                // 1 on failure.
+                Mode::Panic => fail,
+                Mode::Fix | Mode::Run { .. } | Mode::Yolo | Mode::Fail { .. } => unreachable!(),
+            }
         },
         |_, _| None,
         (
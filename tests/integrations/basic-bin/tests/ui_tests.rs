@@ -4,7 +4,7 @@ fn main() -> ui_test::color_eyre::Result<()> {
     let path = "../../../target";
     let mut config = Config {
         dependencies_crate_manifest_path: Some("Cargo.toml".into()),
-        ..Config::rustc("tests/actual_tests".into())
+        ..Config::rustc("tests/actual_tests")
     };
     if std::env::var_os("BLESS").is_some() {
         config.output_conflict_handling = OutputConflictHandling::Bless
@@ -14,6 +14,7 @@ fn main() -> ui_test::color_eyre::Result<()> {
     config.stderr_filter(r"[^ ]*/\.?cargo/registry/.*/", "$$CARGO_REGISTRY");
     config.stderr_filter(r"\.exe", "");
     config.stderr_filter("/target/[^/]+/[^/]+/debug", "/target/$$TMP/$$TRIPLE/debug");
+    config.stderr_filter(r"\$OUT_DIR/[^/]+/debug", "$$OUT_DIR/$$TRIPLE/debug");
     config.path_stderr_filter(&std::path::Path::new(path), "$DIR");
 
     // hide binaries generated for successfully passing tests
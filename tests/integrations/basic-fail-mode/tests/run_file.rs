@@ -1,10 +1,10 @@
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use ui_test::color_eyre::{eyre::ensure, Result};
 use ui_test::*;
 
 #[test]
 fn run_file() -> Result<()> {
-    let mut config = Config::rustc(PathBuf::new());
+    let mut config = Config::rustc("");
 
     let tmp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
     let tmp_dir = tmp_dir.path();
@@ -24,7 +24,7 @@ fn run_file() -> Result<()> {
 
 #[test]
 fn fail_run_file() {
-    let mut config = Config::rustc(PathBuf::new());
+    let mut config = Config::rustc("");
     config.program = CommandBuilder::cmd("invalid_alsdkfjalsdfjalskdfj");
 
     let _ = ui_test::test_command(
@@ -43,7 +43,7 @@ fn fail_run_file() {
 fn run_file_no_deps() -> Result<()> {
     let path = "../../../target";
 
-    let mut config = Config::rustc(PathBuf::new());
+    let mut config = Config::rustc("");
 
     let tmp_dir = tempfile::tempdir_in(path)?;
     let tmp_dir = tmp_dir.path();
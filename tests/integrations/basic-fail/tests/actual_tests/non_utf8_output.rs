@@ -0,0 +1,8 @@
+//@run
+use std::io::Write;
+
+fn main() {
+    std::io::stdout()
+        .write_all(b"nul: \0, invalid: \xff\xfe, truncated: \xe2\x82\n")
+        .unwrap();
+}
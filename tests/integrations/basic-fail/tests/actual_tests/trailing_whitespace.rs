@@ -0,0 +1,5 @@
+//@run
+
+fn main() {
+    print!("hello  \nworld\t\n\n\n");
+}
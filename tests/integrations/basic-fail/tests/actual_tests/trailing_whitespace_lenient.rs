@@ -0,0 +1,6 @@
+//@run
+//@compare: lenient-whitespace
+
+fn main() {
+    print!("hello  \nworld\t\n\n\n");
+}
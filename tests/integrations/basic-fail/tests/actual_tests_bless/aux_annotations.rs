@@ -0,0 +1,4 @@
+//@aux-build:annotated.rs
+//@check-pass
+
+fn main() {}
@@ -0,0 +1,5 @@
+//@aux-build: cycle_a.rs
+
+fn main() {
+    cycle_a::a();
+}
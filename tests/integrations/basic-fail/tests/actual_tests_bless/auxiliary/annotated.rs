@@ -0,0 +1,2 @@
+fn unused() {}
+//~^ WARN: is used
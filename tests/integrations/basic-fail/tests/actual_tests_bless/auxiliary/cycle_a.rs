@@ -0,0 +1,3 @@
+//@aux-build: cycle_b.rs
+
+pub fn a() {}
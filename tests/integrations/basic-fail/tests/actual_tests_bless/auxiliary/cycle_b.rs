@@ -0,0 +1,3 @@
+//@aux-build: cycle_a.rs
+
+pub fn b() {}
@@ -0,0 +1,8 @@
+fn main() {
+    println!("server ready");
+    // Keep signalling readiness, as the test removes the file left over by earlier runs first.
+    loop {
+        std::fs::write(std::env::args().nth(1).unwrap(), "").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+}
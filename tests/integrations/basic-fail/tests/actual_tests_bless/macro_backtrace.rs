@@ -0,0 +1,15 @@
+macro_rules! mismatch {
+    () => {
+        let _x: u32 = "a";
+    };
+}
+
+macro_rules! outer {
+    () => {
+        mismatch!()
+    };
+}
+
+fn main() {
+    outer!();
+}
@@ -0,0 +1,12 @@
+//@run
+//@run-aux-bin: ready_server.rs ../../../target/run_aux_bin_fail.ready
+//@working-dir: ../..
+
+fn main() {
+    let ready = "../../../target/run_aux_bin_fail.ready";
+    let _ = std::fs::remove_file(ready);
+    while std::fs::metadata(ready).is_err() {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    std::process::exit(1);
+}
@@ -0,0 +1,11 @@
+//@run-rustfix
+//@check-fixed
+
+fn unused() {}
+//~^ WARN: never used
+
+fn main() {
+    let mut x = 42;
+    //~^ WARN: does not need to be mutable
+    println!("{x}");
+}
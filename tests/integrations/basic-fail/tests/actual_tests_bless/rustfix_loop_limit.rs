@@ -0,0 +1,6 @@
+//@run-rustfix: loop
+
+fn main() {
+    let _a = ((((((((((((1))))))))))));
+    //~^ WARN: unnecessary parentheses
+}
@@ -0,0 +1,4 @@
+fn main() {
+    let _x: u32 = "";
+    //~^ ERROR: this message has since changed
+}
@@ -11,13 +11,15 @@ fn main() -> ui_test::color_eyre::Result<()> {
         ),
         // Make sure our tests are ordered for reliable output.
         num_test_threads: NonZeroUsize::new(1).unwrap(),
-        ..Config::rustc("tests/actual_tests".into())
+        ..Config::rustc("tests/actual_tests")
     };
 
     // hide binaries generated for successfully passing tests
     let tmp_dir = tempfile::tempdir_in(path)?;
     let tmp_dir = tmp_dir.path();
     config.out_dir = tmp_dir.into();
+    config.failure_artifacts_dir = Some(format!("{path}/ui_failure_artifacts").into());
+    config.check_stale_output_files = true;
     config.path_stderr_filter(tmp_dir, "$TMP");
 
     config.stderr_filter("in ([0-9]m )?[0-9\\.]+s", "");
@@ -1,3 +1,8 @@
 pub fn add(left: usize, right: usize) -> usize {
     left + right
 }
+
+#[cfg(feature = "special")]
+pub fn special() -> usize {
+    42
+}
@@ -0,0 +1,6 @@
+//@aux-build:annotated.rs
+//@check-pass
+
+fn main() {
+    annotated::add(1, 2);
+}
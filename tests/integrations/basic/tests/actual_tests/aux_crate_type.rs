@@ -0,0 +1,7 @@
+//@aux-build: edition_2015_dylib.rs
+//@compile-flags: -C prefer-dynamic
+//@check-pass
+
+fn main() {
+    assert_eq!(edition_2015_dylib::r#async(), 42);
+}
@@ -0,0 +1,7 @@
+//@aux-build: diamond_left.rs
+//@aux-build: diamond_right.rs
+//@check-pass
+
+fn main() {
+    assert_eq!(diamond_left::left(), diamond_right::right());
+}
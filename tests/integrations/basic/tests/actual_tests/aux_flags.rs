@@ -0,0 +1,6 @@
+//@aux-build: special.rs -- --cfg special
+//@check-pass
+
+fn main() {
+    assert_eq!(special::special(), 42);
+}
@@ -0,0 +1,8 @@
+//@aux-build: helper.rs
+//@aux-build: wrapper.rs
+//@check-pass
+
+// `helper.rs` and `nested/helper.rs` are different crates with the same name.
+fn main() {
+    assert_eq!(helper::one() + wrapper::two(), 3);
+}
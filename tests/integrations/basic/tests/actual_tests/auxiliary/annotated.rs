@@ -0,0 +1,6 @@
+pub fn add(left: usize, right: usize) -> usize {
+    left + right
+}
+
+fn unused() {}
+//~^ WARN: never used
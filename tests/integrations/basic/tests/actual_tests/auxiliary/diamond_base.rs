@@ -0,0 +1,3 @@
+pub fn base() -> usize {
+    1
+}
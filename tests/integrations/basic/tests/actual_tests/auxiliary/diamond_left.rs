@@ -0,0 +1,5 @@
+//@aux-build: diamond_base.rs
+
+pub fn left() -> usize {
+    diamond_base::base()
+}
@@ -0,0 +1,5 @@
+//@aux-build: diamond_base.rs
+
+pub fn right() -> usize {
+    diamond_base::base()
+}
@@ -0,0 +1,8 @@
+//@edition: 2015
+//@crate-type: dylib
+//@compile-flags: -C prefer-dynamic
+
+// `async` is not a keyword in edition 2015.
+pub fn async() -> usize {
+    42
+}
@@ -0,0 +1,3 @@
+pub fn two() -> usize {
+    2
+}
@@ -0,0 +1,19 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+
+fn main() {
+    let port_file = std::env::args().nth(1).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let tmp = format!("{port_file}.tmp");
+    std::fs::write(&tmp, port.to_string()).unwrap();
+    std::fs::rename(tmp, port_file).unwrap();
+    for stream in listener.incoming() {
+        let mut stream = stream.unwrap();
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line).unwrap();
+        if line == "ping\n" {
+            stream.write_all(b"pong\n").unwrap();
+        }
+    }
+}
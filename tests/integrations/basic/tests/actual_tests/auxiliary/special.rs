@@ -0,0 +1,4 @@
+#[cfg(special)]
+pub fn special() -> usize {
+    42
+}
@@ -0,0 +1,5 @@
+//@aux-build: nested/helper.rs
+
+pub fn two() -> usize {
+    helper::two()
+}
@@ -0,0 +1,5 @@
+fn main() {
+    let _x: (u32, u32) = ("", "");
+    //~^ ERROR@col:27 mismatched types
+    //~| ERROR@col:31: mismatched types
+}
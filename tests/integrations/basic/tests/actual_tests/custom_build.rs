@@ -0,0 +1,5 @@
+//@custom-build: cfg: generated
+//@check-pass
+
+#[cfg(generated)]
+fn main() {}
@@ -0,0 +1,5 @@
+//@check-pass
+
+fn main() {
+    assert_eq!(basic::special(), 42);
+}
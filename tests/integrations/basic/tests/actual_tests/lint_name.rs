@@ -0,0 +1,6 @@
+fn main() {
+    let _x: u32 = (1);
+    //~^ WARN [unused_parens]
+    let _y: u32 = "";
+    //~^ ERROR [E0308]
+}
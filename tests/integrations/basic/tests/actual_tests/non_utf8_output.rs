@@ -0,0 +1,11 @@
+//@run
+use std::io::Write;
+
+fn main() {
+    std::io::stdout()
+        .write_all(b"nul: \0, invalid: \xff\xfe, truncated: \xe2\x82\n")
+        .unwrap();
+    std::io::stderr()
+        .write_all(b"continuation: \x80\x80, nul: \0\n")
+        .unwrap();
+}
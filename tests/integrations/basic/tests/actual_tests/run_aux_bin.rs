@@ -0,0 +1,25 @@
+//@run
+//@run-aux-bin: ping_server.rs ../../../target/run_aux_bin.port
+//@working-dir: ../..
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+fn main() {
+    let start = Instant::now();
+    // The port file may still be there from an earlier run until the server replaces it.
+    while start.elapsed() < Duration::from_secs(60) {
+        if let Ok(port) = std::fs::read_to_string("../../../target/run_aux_bin.port") {
+            if let Ok(mut stream) = TcpStream::connect(("127.0.0.1", port.parse().unwrap())) {
+                stream.write_all(b"ping\n").unwrap();
+                let mut line = String::new();
+                BufReader::new(&stream).read_line(&mut line).unwrap();
+                assert_eq!(line, "pong\n");
+                return;
+            }
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    panic!("the server did not start");
+}
@@ -0,0 +1,8 @@
+//@run-rustfix
+//@check-fixed
+
+fn main() {
+    let mut x = 42;
+    //~^ WARN: does not need to be mutable
+    println!("{x}");
+}
@@ -0,0 +1,11 @@
+//@run-rustfix
+//@apply-suggestions: maybe-incorrect
+
+fn foo() -> Result<(), ()> {
+    Ok(())
+}
+
+fn main() {
+    foo();
+    //~^ WARN: unused `Result` that must be used
+}
@@ -0,0 +1,8 @@
+//@run-rustfix
+
+fn main() {
+    let _a = (1);
+    //~^ WARN@no-fix: unnecessary parentheses
+    let _b = (2);
+    //~^ WARN: unnecessary parentheses
+}
@@ -1,16 +1,29 @@
+use std::ffi::OsString;
 use std::num::NonZeroUsize;
 use ui_test::*;
 
+/// Passes `--cfg ARGS` to the tests requesting it.
+struct CfgBuild;
+
+impl Build for CfgBuild {
+    fn build(&self, args: &str, _config: &Config) -> Result<Vec<OsString>, String> {
+        Ok(vec!["--cfg".into(), args.into()])
+    }
+}
+
 fn main() -> ui_test::color_eyre::Result<()> {
     let path = "../../../target";
     let mut config = Config {
         dependencies_crate_manifest_path: Some("Cargo.toml".into()),
+        dependency_features: vec!["special".into()],
         num_test_threads: NonZeroUsize::new(1).unwrap(),
-        ..Config::rustc("tests/actual_tests".into())
+        check_stale_output_files: true,
+        ..Config::rustc("tests/actual_tests")
     };
     if std::env::var_os("BLESS").is_some() {
         config.output_conflict_handling = OutputConflictHandling::Bless;
     }
+    config.add_custom_build("cfg", CfgBuild);
     config.stderr_filter("in ([0-9]m )?[0-9\\.]+s", "");
     config.stdout_filter("in ([0-9]m )?[0-9\\.]+s", "");
     config.stderr_filter(r"[^ ]*/\.?cargo/registry/.*/", "$$CARGO_REGISTRY");